@@ -1,15 +1,297 @@
-use crate::hashes::Hash;
+use bitcoin::bech32::{self, u5, FromBase32 as _, ToBase32 as _, Variant};
+use bitcoin::hashes::{sha256, Hash as _};
+use bitcoin::secp256k1::PublicKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use subtle::{Choice, ConstantTimeEq};
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
-pub struct Address(pub Hash);
+/// Length of the program of every address version, a 32 byte hash or key.
+const PROGRAM_LENGTH: usize = 32;
+
+/// Versioned address, the version selects the authorization scheme that
+/// spends outputs sent to it.
+///
+/// Encodings carry the version, so new schemes only need new versions and
+/// no changes to the address format. Versions fit in a bech32 character,
+/// there are at most `Address::MAX_VERSION + 1` of them.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Address {
+    /// Hash of a public key.
+    V0PubkeyHash([u8; PROGRAM_LENGTH]),
+    /// Hash of a spending policy, see `Policy::address`.
+    V1ScriptHash([u8; PROGRAM_LENGTH]),
+    /// X-only key committing to an internal key and a tree of policies, see
+    /// `TaprootCommitment`.
+    V2Taproot([u8; PROGRAM_LENGTH]),
+    /// Version without a defined scheme yet, `version` is greater than 2.
+    /// Construct it with `Address::new` to keep that invariant.
+    Reserved {
+        version: u8,
+        program: [u8; PROGRAM_LENGTH],
+    },
+}
 
 impl Address {
+    pub const MAX_VERSION: u8 = 31;
+    /// Length of the binary encoding, the version byte and the program.
+    pub const ENCODED_LENGTH: usize = 1 + PROGRAM_LENGTH;
+
+    /// Provably unspendable address, no key hashes to the zero program and
+    /// `validate_body` rejects spends of burn outputs anyway.
+    pub const fn burn() -> Self {
+        Self::V0PubkeyHash([0; PROGRAM_LENGTH])
+    }
+
+    pub fn is_burn(&self) -> bool {
+        *self == Self::burn()
+    }
+
+    /// Pubkey hash address of a secp256k1 key, as used for payment codes and
+    /// vanity addresses. Authorizations spending outputs sent to it have to
+    /// derive addresses the same way.
+    pub fn from_public_key(key: &PublicKey) -> Self {
+        Self::V0PubkeyHash(sha256::Hash::hash(&key.serialize()).into_inner())
+    }
+
+    pub fn new(version: u8, program: [u8; PROGRAM_LENGTH]) -> Result<Self, AddressParseError> {
+        match version {
+            0 => Ok(Self::V0PubkeyHash(program)),
+            1 => Ok(Self::V1ScriptHash(program)),
+            2 => Ok(Self::V2Taproot(program)),
+            version if version <= Self::MAX_VERSION => Ok(Self::Reserved { version, program }),
+            version => Err(AddressParseError::InvalidVersion { version }),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        match self {
+            Self::V0PubkeyHash(_) => 0,
+            Self::V1ScriptHash(_) => 1,
+            Self::V2Taproot(_) => 2,
+            Self::Reserved { version, .. } => *version,
+        }
+    }
+
+    pub fn program(&self) -> &[u8; PROGRAM_LENGTH] {
+        match self {
+            Self::V0PubkeyHash(program)
+            | Self::V1ScriptHash(program)
+            | Self::V2Taproot(program)
+            | Self::Reserved { program, .. } => program,
+        }
+    }
+
+    /// Version byte followed by the program.
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LENGTH] {
+        let mut bytes = [0; Self::ENCODED_LENGTH];
+        bytes[0] = self.version();
+        bytes[1..].copy_from_slice(self.program());
+        bytes
+    }
+
+    /// Inverse of `Address::to_bytes`.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, AddressParseError> {
+        let Some((&version, program)) = bytes.split_first() else {
+            return Err(AddressParseError::WrongLength { length: 0 });
+        };
+        let program = program
+            .try_into()
+            .map_err(|_| AddressParseError::WrongLength {
+                length: bytes.len(),
+            })?;
+        Self::new(version, program)
+    }
+
     pub fn to_base58(self) -> String {
-        bs58::encode(self.0)
+        bs58::encode(self.to_bytes())
             .with_alphabet(bs58::Alphabet::BITCOIN)
             .with_check()
             .into_string()
     }
+
+    /// Bech32m string with the human readable part of a network, e.g.
+    /// `MAINNET_HRP`. The version is the first character of the data part,
+    /// like the witness version of segwit addresses. Fails if `hrp` is not
+    /// a valid human readable part.
+    pub fn to_bech32m(self, hrp: &str) -> Result<String, bech32::Error> {
+        let mut data = vec![u5::try_from_u8(self.version())?];
+        data.extend(self.program().to_base32());
+        bech32::encode(hrp, data, Variant::Bech32m)
+    }
+}
+
+/// Address parsed from user input whose network hasn't been checked yet.
+///
+/// Only converts to an `Address` through `require_network`, or
+/// `assume_checked` where any network is fine, so that addresses of other
+/// networks aren't accepted by mistake. Addresses are only parsed into, and
+/// deserialized from user input as, this type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UncheckedAddress {
+    address: Address,
+    format: AddressFormat,
+}
+
+impl UncheckedAddress {
+    pub fn format(&self) -> &AddressFormat {
+        &self.format
+    }
+
+    /// Base58check addresses don't encode a network, so they are valid for
+    /// none of them.
+    pub fn is_valid_for_network(&self, hrp: &str) -> bool {
+        match &self.format {
+            AddressFormat::Base58 => false,
+            AddressFormat::Bech32m { hrp: found } => found.eq_ignore_ascii_case(hrp),
+        }
+    }
+
+    /// Fails for base58check addresses, which could be of any network, use
+    /// `assume_checked` to accept them anyway.
+    pub fn require_network(self, hrp: &str) -> Result<Address, AddressParseError> {
+        match self.format {
+            AddressFormat::Bech32m { hrp: found } if !found.eq_ignore_ascii_case(hrp) => {
+                Err(AddressParseError::WrongNetwork {
+                    expected: hrp.to_lowercase(),
+                    found,
+                })
+            }
+            AddressFormat::Bech32m { .. } => Ok(self.address),
+            AddressFormat::Base58 => Err(AddressParseError::NoNetwork {
+                expected: hrp.to_lowercase(),
+            }),
+        }
+    }
+
+    pub fn assume_checked(self) -> Address {
+        self.address
+    }
+}
+
+/// Accepts either a base58check or a bech32m address.
+impl std::str::FromStr for UncheckedAddress {
+    type Err = AddressParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match bech32::decode(s) {
+            Ok((hrp, data, variant)) => {
+                if variant != Variant::Bech32m {
+                    return Err(AddressParseError::NotBech32m);
+                }
+                let Some((version, program)) = data.split_first() else {
+                    return Err(AddressParseError::WrongLength { length: 0 });
+                };
+                let program = Vec::<u8>::from_base32(program)?;
+                let program = program.try_into().map_err(|program: Vec<u8>| {
+                    AddressParseError::WrongLength {
+                        length: 1 + program.len(),
+                    }
+                })?;
+                Ok(Self {
+                    address: Address::new(version.to_u8(), program)?,
+                    format: AddressFormat::Bech32m { hrp },
+                })
+            }
+            Err(err) if looks_like_bech32(s) => Err(err.into()),
+            Err(_) => {
+                let address = bs58::decode(s)
+                    .with_alphabet(bs58::Alphabet::BITCOIN)
+                    .with_check(None)
+                    .into_vec()?;
+                // Addresses from before versioning are programs alone.
+                let address = match <[u8; PROGRAM_LENGTH]>::try_from(address.as_slice()) {
+                    Ok(program) => Address::V0PubkeyHash(program),
+                    Err(_) => Address::from_slice(&address)?,
+                };
+                Ok(Self {
+                    address,
+                    format: AddressFormat::Base58,
+                })
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UncheckedAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let address = String::deserialize(deserializer)?;
+        address.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Human readable part of mainnet bech32m addresses.
+pub const MAINNET_HRP: &str = "sc";
+/// Human readable part of testnet bech32m addresses.
+pub const TESTNET_HRP: &str = "tsc";
+
+/// Encoding an address was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddressFormat {
+    Base58,
+    Bech32m { hrp: String },
+}
+
+// Base58 strings can contain `1` too, but are almost never single case.
+fn looks_like_bech32(s: &str) -> bool {
+    let single_case =
+        !s.chars().any(|c| c.is_ascii_lowercase()) || !s.chars().any(|c| c.is_ascii_uppercase());
+    single_case && s.rfind('1').is_some_and(|separator| separator > 0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AddressParseError {
+    #[error("address checksum is invalid")]
+    BadChecksum,
+    #[error("address is {length} bytes long, not 33, or 32 for base58check")]
+    WrongLength { length: usize },
+    #[error("address version {version} is greater than 31")]
+    InvalidVersion { version: u8 },
+    #[error("address is for network {found}, expected {expected}")]
+    WrongNetwork { expected: String, found: String },
+    #[error("base58check address has no network, expected {expected}")]
+    NoNetwork { expected: String },
+    #[error("address is bech32, not bech32m")]
+    NotBech32m,
+    #[error("invalid base58check address")]
+    Base58(bs58::decode::Error),
+    #[error("invalid bech32m address")]
+    Bech32(bech32::Error),
+}
+
+impl From<bs58::decode::Error> for AddressParseError {
+    fn from(err: bs58::decode::Error) -> Self {
+        match err {
+            bs58::decode::Error::InvalidChecksum { .. } => Self::BadChecksum,
+            err => Self::Base58(err),
+        }
+    }
+}
+
+impl From<bech32::Error> for AddressParseError {
+    fn from(err: bech32::Error) -> Self {
+        match err {
+            bech32::Error::InvalidChecksum => Self::BadChecksum,
+            err => Self::Bech32(err),
+        }
+    }
+}
+
+/// Same order as the binary encoding, by version and then by program.
+impl Ord for Address {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_bytes().cmp(&other.to_bytes())
+    }
+}
+
+impl PartialOrd for Address {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Use this instead of `==` when one side may be attacker supplied.
+impl ConstantTimeEq for Address {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.to_bytes()[..].ct_eq(&other.to_bytes()[..])
+    }
 }
 
 impl std::fmt::Display for Address {
@@ -24,20 +306,38 @@ impl std::fmt::Debug for Address {
     }
 }
 
-impl From<Hash> for Address {
-    fn from(other: Hash) -> Self {
-        Self(other)
+/// Pubkey hash address.
+impl From<[u8; 32]> for Address {
+    fn from(other: [u8; 32]) -> Self {
+        Self::V0PubkeyHash(other)
     }
 }
 
-impl std::str::FromStr for Address {
-    type Err = bs58::decode::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let address = bs58::decode(s)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .with_check(None)
-            .into_vec()?;
-        assert_eq!(address.len(), 32);
-        Ok(Address(address.try_into().unwrap()))
+/// Base58 string for human readable formats, raw bytes otherwise.
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_base58())
+        } else {
+            (self.version(), self.program()).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            let (version, program) = <(u8, [u8; PROGRAM_LENGTH])>::deserialize(deserializer)?;
+            return Self::new(version, program).map_err(serde::de::Error::custom);
+        }
+        // Only the base58check strings `serialize` writes, user input with a
+        // network goes through `UncheckedAddress`.
+        let address = UncheckedAddress::deserialize(deserializer)?;
+        if address.format != AddressFormat::Base58 {
+            return Err(serde::de::Error::custom(
+                "expected a base58check address, deserialize bech32m ones as UncheckedAddress",
+            ));
+        }
+        Ok(address.assume_checked())
     }
 }
@@ -0,0 +1,111 @@
+use crate::encoding::{decode_canonical, encode_canonical, EncodeError};
+use crate::types::*;
+use bitcoin::util::bip32::DerivationPath;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Wallet metadata of an address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBookEntry {
+    pub label: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Path of the key of the address, `None` for addresses of others.
+    pub derivation_path: Option<DerivationPath>,
+}
+
+/// Labels of addresses, for wallet frontends.
+///
+/// Entries are kept in address order, so iteration and exports are
+/// deterministic. Serializable with any serde format, `export` and `import`
+/// use the canonical binary encoding.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBook {
+    entries: BTreeMap<Address, AddressBookEntry>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the previous entry of `address`.
+    pub fn insert(
+        &mut self,
+        address: Address,
+        entry: AddressBookEntry,
+    ) -> Option<AddressBookEntry> {
+        self.entries.insert(address, entry)
+    }
+
+    pub fn remove(&mut self, address: &Address) -> Option<AddressBookEntry> {
+        self.entries.remove(address)
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&AddressBookEntry> {
+        self.entries.get(address)
+    }
+
+    pub fn label(&self, address: &Address) -> Option<&str> {
+        self.get(address).map(|entry| entry.label.as_str())
+    }
+
+    /// Renames an existing entry, returns false if there is none.
+    pub fn set_label(&mut self, address: &Address, label: impl Into<String>) -> bool {
+        match self.entries.get_mut(address) {
+            Some(entry) => {
+                entry.label = label.into();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Entries in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Address, &AddressBookEntry)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds the entries of `other`, keeping the older entry of addresses in
+    /// both books.
+    pub fn merge(&mut self, other: AddressBook) {
+        for (address, entry) in other.entries {
+            match self.entries.get(&address) {
+                Some(existing) if existing.created_at <= entry.created_at => {}
+                _ => {
+                    self.entries.insert(address, entry);
+                }
+            }
+        }
+    }
+
+    pub fn export(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_canonical(self)
+    }
+
+    pub fn import(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        decode_canonical(bytes)
+    }
+}
+
+impl Extend<(Address, AddressBookEntry)> for AddressBook {
+    fn extend<I: IntoIterator<Item = (Address, AddressBookEntry)>>(&mut self, iter: I) {
+        self.entries.extend(iter);
+    }
+}
+
+impl FromIterator<(Address, AddressBookEntry)> for AddressBook {
+    fn from_iter<I: IntoIterator<Item = (Address, AddressBookEntry)>>(iter: I) -> Self {
+        Self {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::utxo::UtxoMap;
+use std::future::Future;
+
+/// Async read access to a set of unspent outputs, for sources that can't be
+/// read without blocking like remote stores or RPC proxies.
+pub trait AsyncUtxoMap: Sync {
+    type OutPoint: Sync;
+    type Output: Send;
+
+    /// Returns `None` if any of the outpoints is missing.
+    fn get_utxos(
+        &self,
+        outpoints: &[Self::OutPoint],
+    ) -> impl Future<Output = Option<Vec<Self::Output>>> + Send;
+
+    fn get_utxo(
+        &self,
+        outpoint: &Self::OutPoint,
+    ) -> impl Future<Output = Option<Self::Output>> + Send {
+        async move { self.get_utxos(std::slice::from_ref(outpoint)).await?.pop() }
+    }
+}
+
+/// `AsyncUtxoMap` answering immediately from a `UtxoMap`.
+#[derive(Debug, Clone, Default)]
+pub struct ReadyUtxoMap<M>(pub M);
+
+impl<M> AsyncUtxoMap for ReadyUtxoMap<M>
+where
+    M: UtxoMap + Sync,
+    M::OutPoint: Sync,
+    M::Output: Send,
+{
+    type OutPoint = M::OutPoint;
+    type Output = M::Output;
+
+    fn get_utxos(
+        &self,
+        outpoints: &[M::OutPoint],
+    ) -> impl Future<Output = Option<Vec<M::Output>>> + Send {
+        std::future::ready(self.0.get_utxos(outpoints))
+    }
+
+    fn get_utxo(&self, outpoint: &M::OutPoint) -> impl Future<Output = Option<M::Output>> + Send {
+        std::future::ready(self.0.get_utxo(outpoint))
+    }
+}
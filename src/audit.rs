@@ -0,0 +1,318 @@
+use crate::params::MAX_MONEY;
+use crate::state::StateMachine;
+use crate::store::{ReadStore, Table};
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::ControlFlow;
+
+/// Result of `StateMachine::check_invariants`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvariantReport {
+    pub violations: Vec<Violation>,
+    pub block_count: u32,
+    pub utxo_count: usize,
+    /// Value of unspent and locked outputs.
+    pub total_value: u128,
+}
+
+impl InvariantReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Entry can't be decoded.
+    Malformed {
+        table: Table,
+        key: Vec<u8>,
+    },
+    /// Unspent, locked or pending outpoint has no output.
+    MissingOutput {
+        table: Table,
+        outpoint: OutPoint,
+    },
+    UnspentAndLocked {
+        outpoint: OutPoint,
+    },
+    LockedNotWithdrawal {
+        outpoint: OutPoint,
+    },
+    MissingAddressEntry {
+        address: Address,
+        outpoint: OutPoint,
+    },
+    /// Address index entry of an outpoint that is not unspent or belongs to
+    /// another address.
+    StaleAddressEntry {
+        address: Address,
+        outpoint: OutPoint,
+    },
+    TotalValueAboveMaxMoney {
+        total_value: u128,
+    },
+    MissingBlock {
+        height: u32,
+    },
+    MissingHeader {
+        block_hash: BlockHash,
+    },
+    WrongBlockHeight {
+        block_hash: BlockHash,
+        height: u32,
+        found: Option<u32>,
+    },
+    WrongPrevSideHash {
+        height: u32,
+        prev_side_hash: BlockHash,
+        expected: BlockHash,
+    },
+    /// Block order has entries above the tip.
+    BlockAboveTip {
+        height: u32,
+    },
+    WrongTip {
+        tip: BlockHash,
+        expected: BlockHash,
+    },
+    CoinbaseHeightAboveTip {
+        outpoint: OutPoint,
+        height: u32,
+    },
+}
+
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn collect<S: ReadStore>(store: &S, table: Table) -> Result<Entries, S::Error> {
+    let mut entries = Vec::new();
+    store.iterate(table, &mut |key, value| {
+        entries.push((key.to_vec(), value.to_vec()));
+        ControlFlow::Continue(())
+    })?;
+    Ok(entries)
+}
+
+fn decode_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn decode_hash(bytes: &[u8]) -> Option<BlockHash> {
+    Some(Hash::from_slice(bytes)?.into())
+}
+
+impl<A, C: GetValue + DeserializeOwned, S: ReadStore, H: HashFunction> StateMachine<A, C, S, H> {
+    /// Checks consistency of the stored state, reading every table.
+    ///
+    /// Only store errors are returned as errors, everything else is reported.
+    /// Debug builds run it after every block `connect_block` connects.
+    pub fn check_invariants(&self) -> Result<InvariantReport, S::Error> {
+        let store = self.store();
+        let mut report = InvariantReport::default();
+        let mut violations = Vec::new();
+
+        // Header chain.
+        let block_order: BTreeMap<u32, Option<BlockHash>> = collect(store, Table::BlockOrder)?
+            .into_iter()
+            .filter_map(|(key, value)| match decode_u32(&key) {
+                Some(height) => Some((height, decode_hash(&value))),
+                None => {
+                    violations.push(Violation::Malformed {
+                        table: Table::BlockOrder,
+                        key,
+                    });
+                    None
+                }
+            })
+            .collect();
+        let block_count = self.get_height().map_or(0, |height| height + 1);
+        let mut prev_side_hash = BlockHash::ZERO;
+        for height in 0..block_count {
+            let block_hash = match block_order.get(&height) {
+                Some(Some(block_hash)) => *block_hash,
+                Some(None) => {
+                    violations.push(Violation::Malformed {
+                        table: Table::BlockOrder,
+                        key: height.to_be_bytes().to_vec(),
+                    });
+                    continue;
+                }
+                None => {
+                    violations.push(Violation::MissingBlock { height });
+                    continue;
+                }
+            };
+            let found = store
+                .get(Table::Heights, &block_hash.0)?
+                .and_then(|height| decode_u32(&height));
+            if found != Some(height) {
+                violations.push(Violation::WrongBlockHeight {
+                    block_hash,
+                    height,
+                    found,
+                });
+            }
+            match store.get(Table::Headers, &block_hash.0)? {
+                Some(header) => match bincode::deserialize::<Header>(&header) {
+                    Ok(header) => {
+                        if header.hash_with::<H>() != block_hash {
+                            violations.push(Violation::Malformed {
+                                table: Table::Headers,
+                                key: block_hash.0.to_vec(),
+                            });
+                        }
+                        if header.prev_side_hash != prev_side_hash {
+                            violations.push(Violation::WrongPrevSideHash {
+                                height,
+                                prev_side_hash: header.prev_side_hash,
+                                expected: prev_side_hash,
+                            });
+                        }
+                    }
+                    Err(_) => violations.push(Violation::Malformed {
+                        table: Table::Headers,
+                        key: block_hash.0.to_vec(),
+                    }),
+                },
+                None => violations.push(Violation::MissingHeader { block_hash }),
+            }
+            prev_side_hash = block_hash;
+        }
+        for height in block_order.range(block_count..).map(|(height, _)| *height) {
+            violations.push(Violation::BlockAboveTip { height });
+        }
+        if self.get_tip() != prev_side_hash {
+            violations.push(Violation::WrongTip {
+                tip: self.get_tip(),
+                expected: prev_side_hash,
+            });
+        }
+        report.block_count = block_count;
+
+        // UTXO set.
+        // In key order, so that the report is deterministic.
+        let mut decode_outpoints = |table| -> Result<Vec<OutPoint>, S::Error> {
+            let mut outpoints = Vec::new();
+            for (key, _) in collect(store, table)? {
+                match bincode::deserialize::<OutPoint>(&key) {
+                    Ok(outpoint) => outpoints.push(outpoint),
+                    Err(_) => violations.push(Violation::Malformed { table, key }),
+                }
+            }
+            Ok(outpoints)
+        };
+        let unspent = decode_outpoints(Table::UnspentOutpoints)?;
+        let locked = decode_outpoints(Table::LockedOutpoints)?;
+        let pending = decode_outpoints(Table::PendingDeposits)?;
+        let mut addresses = HashMap::new();
+        let mut total_value: u128 = 0;
+        for (table, outpoints) in [
+            (Table::UnspentOutpoints, &unspent),
+            (Table::LockedOutpoints, &locked),
+            (Table::PendingDeposits, &pending),
+        ] {
+            for outpoint in outpoints {
+                let key = bincode::serialize(outpoint).unwrap_or_default();
+                let output = match store.get(Table::Outputs, &key)? {
+                    Some(output) => output,
+                    None => {
+                        violations.push(Violation::MissingOutput {
+                            table,
+                            outpoint: *outpoint,
+                        });
+                        continue;
+                    }
+                };
+                let output: Output<C> = match bincode::deserialize(&output) {
+                    Ok(output) => output,
+                    Err(_) => {
+                        violations.push(Violation::Malformed {
+                            table: Table::Outputs,
+                            key,
+                        });
+                        continue;
+                    }
+                };
+                if table == Table::PendingDeposits {
+                    continue;
+                }
+                total_value += u128::from(output.get_value());
+                if table == Table::UnspentOutpoints {
+                    addresses.insert(*outpoint, output.address);
+                } else if !output.content.is_withdrawal() {
+                    violations.push(Violation::LockedNotWithdrawal {
+                        outpoint: *outpoint,
+                    });
+                }
+            }
+        }
+        let unspent_set: HashSet<_> = unspent.iter().collect();
+        for outpoint in locked
+            .iter()
+            .filter(|outpoint| unspent_set.contains(outpoint))
+        {
+            violations.push(Violation::UnspentAndLocked {
+                outpoint: *outpoint,
+            });
+        }
+        if total_value > u128::from(MAX_MONEY) {
+            violations.push(Violation::TotalValueAboveMaxMoney { total_value });
+        }
+        report.utxo_count = unspent.len();
+        report.total_value = total_value;
+
+        // Address index.
+        let mut indexed = HashSet::new();
+        for (key, _) in collect(store, Table::AddressOutpoints)? {
+            let address_len = Address::ENCODED_LENGTH;
+            let entry = key.get(..address_len).zip(key.get(address_len..));
+            let decoded = entry.and_then(|(address, outpoint)| {
+                let address = Address::from_slice(address).ok()?;
+                let outpoint: OutPoint = bincode::deserialize(outpoint).ok()?;
+                Some((address, outpoint))
+            });
+            let Some((address, outpoint)) = decoded else {
+                violations.push(Violation::Malformed {
+                    table: Table::AddressOutpoints,
+                    key,
+                });
+                continue;
+            };
+            if addresses.get(&outpoint) != Some(&address) {
+                violations.push(Violation::StaleAddressEntry { address, outpoint });
+            }
+            indexed.insert((address, outpoint));
+        }
+        for outpoint in &unspent {
+            if let Some(address) = addresses.get(outpoint) {
+                if !indexed.contains(&(*address, *outpoint)) {
+                    violations.push(Violation::MissingAddressEntry {
+                        address: *address,
+                        outpoint: *outpoint,
+                    });
+                }
+            }
+        }
+
+        // Coinbase heights.
+        for (key, value) in collect(store, Table::CoinbaseHeights)? {
+            let decoded = bincode::deserialize::<OutPoint>(&key)
+                .ok()
+                .zip(decode_u32(&value));
+            match decoded {
+                Some((outpoint, height)) if height >= block_count => {
+                    violations.push(Violation::CoinbaseHeightAboveTip { outpoint, height });
+                }
+                Some(_) => {}
+                None => violations.push(Violation::Malformed {
+                    table: Table::CoinbaseHeights,
+                    key,
+                }),
+            }
+        }
+
+        report.violations = violations;
+        Ok(report)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::encoding::EncodeError;
+use crate::types::*;
+use bitcoin::secp256k1::{ecdsa, Message, PublicKey, Secp256k1, SecretKey, Signing};
+use serde::{Deserialize, Serialize};
+
+/// ECDSA signature of a txid by the key of an `Address::from_public_key`
+/// address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyAuthorization {
+    pub public_key: PublicKey,
+    pub signature: ecdsa::Signature,
+}
+
+impl KeyAuthorization {
+    /// Largest encoding, with the longest DER signature.
+    pub const MAX_ENCODED_SIZE: u64 = 113;
+
+    pub fn sign<S: Signing>(secp: &Secp256k1<S>, secret_key: &SecretKey, txid: &Txid) -> Self {
+        let message = Message::from_slice(&txid.0).expect("txids are 32 bytes long");
+        Self {
+            public_key: PublicKey::from_secret_key(secp, secret_key),
+            signature: secp.sign_ecdsa(&message, secret_key),
+        }
+    }
+}
+
+impl GetAddress for KeyAuthorization {
+    fn get_address(&self) -> Address {
+        Address::from_public_key(&self.public_key)
+    }
+}
+
+impl<C: Serialize> Verify<C> for KeyAuthorization {
+    type Error = KeyAuthorizationError;
+
+    fn verify_transaction<H: HashFunction>(
+        transaction: &AuthorizedTransaction<Self, C>,
+    ) -> Result<(), Self::Error> {
+        let secp = Secp256k1::verification_only();
+        let txid = transaction.transaction.try_txid_with::<H>()?;
+        let message = Message::from_slice(&txid.0)?;
+        for authorization in &transaction.authorizations {
+            secp.verify_ecdsa(
+                &message,
+                &authorization.signature,
+                &authorization.public_key,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn verify_body<H: HashFunction>(body: &Body<Self, C>) -> Result<(), Self::Error> {
+        let secp = Secp256k1::verification_only();
+        let mut authorizations = body.authorizations.iter();
+        for transaction in &body.transactions {
+            let txid = transaction.try_txid_with::<H>()?;
+            let message = Message::from_slice(&txid.0)?;
+            for authorization in authorizations.by_ref().take(transaction.inputs.len()) {
+                secp.verify_ecdsa(
+                    &message,
+                    &authorization.signature,
+                    &authorization.public_key,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyAuthorizationError {
+    #[error("invalid signature")]
+    Secp256k1(#[from] bitcoin::secp256k1::Error),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
@@ -0,0 +1,243 @@
+use crate::utxo::{UtxoMap, UtxoMapRef, UtxoScan, UtxoVisitor, UtxoWrite};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bloom filter over outpoints.
+///
+/// `contains` never misses an inserted outpoint, but may return true for
+/// outpoints that were never inserted. Outpoints can't be removed.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    hasher: RandomState,
+}
+
+impl BloomFilter {
+    /// Filter sized for `capacity` outpoints at the given false positive
+    /// rate, which must be in `(0, 1)`.
+    pub fn with_rate(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let bits = (bits as usize).max(64);
+        let hashes = ((bits as f64 / capacity) * ln2).round().max(1.0);
+        Self {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes: hashes as u32,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn insert<P: Hash>(&mut self, outpoint: &P) {
+        for index in self.indices(outpoint) {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+    }
+
+    pub fn contains<P: Hash>(&self, outpoint: &P) -> bool {
+        self.indices(outpoint)
+            .all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Size of the filter in bits.
+    pub fn bit_len(&self) -> usize {
+        self.bits.len() * 64
+    }
+
+    pub fn hash_count(&self) -> u32 {
+        self.hashes
+    }
+
+    fn indices<P: Hash>(&self, outpoint: &P) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(outpoint);
+        // Double hashing, the second hash is odd so that it cycles through
+        // all positions.
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        let bit_len = self.bit_len() as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_len) as usize)
+    }
+}
+
+impl<P: Hash> Extend<P> for BloomFilter {
+    fn extend<I: IntoIterator<Item = P>>(&mut self, iter: I) {
+        for outpoint in iter {
+            self.insert(&outpoint);
+        }
+    }
+}
+
+/// `UtxoMap` that checks a `BloomFilter` before the inner map.
+///
+/// Lookups of outpoints the filter has never seen are answered without
+/// touching the inner map, which saves I/O on disk-backed maps where most
+/// `is_spent` checks are negative. Filter false positives fall through to
+/// the inner map, so results are always exact.
+///
+/// The filter must contain every outpoint in the inner map, so it has to be
+/// built from the full set before wrapping, after that writes through
+/// `UtxoWrite` keep it up to date. Spent outpoints stay in the filter and
+/// count as false positives once looked up, rebuild the filter when
+/// `false_positives` grows too large.
+#[derive(Debug)]
+pub struct FilteredUtxoMap<M> {
+    inner: M,
+    filter: BloomFilter,
+    false_positives: AtomicU64,
+}
+
+impl<M> FilteredUtxoMap<M> {
+    pub fn new(inner: M, filter: BloomFilter) -> Self {
+        Self {
+            inner,
+            filter,
+            false_positives: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn filter(&self) -> &BloomFilter {
+        &self.filter
+    }
+
+    /// Replaces the filter and resets the false positive count.
+    pub fn set_filter(&mut self, filter: BloomFilter) {
+        self.filter = filter;
+        *self.false_positives.get_mut() = 0;
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Lookups that passed the filter but were missing from the inner map.
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+
+    fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<M> UtxoMap for FilteredUtxoMap<M>
+where
+    M: UtxoMap,
+    M::OutPoint: Hash,
+{
+    type OutPoint = M::OutPoint;
+    type Output = M::Output;
+
+    fn get_utxo(&self, outpoint: &M::OutPoint) -> Option<M::Output> {
+        if !self.filter.contains(outpoint) {
+            return None;
+        }
+        let output = self.inner.get_utxo(outpoint);
+        if output.is_none() {
+            self.record_false_positive();
+        }
+        output
+    }
+
+    fn prefetch(&self, outpoints: &[M::OutPoint]) {
+        self.inner.prefetch(outpoints)
+    }
+
+    fn get_utxos(&self, outpoints: &[M::OutPoint]) -> Option<Vec<M::Output>> {
+        if !outpoints
+            .iter()
+            .all(|outpoint| self.filter.contains(outpoint))
+        {
+            return None;
+        }
+        let outputs = self.inner.get_utxos(outpoints);
+        if outputs.is_none() {
+            self.record_false_positive();
+        }
+        outputs
+    }
+
+    fn is_spent(&self, outpoint: &M::OutPoint) -> bool {
+        if !self.filter.contains(outpoint) {
+            return true;
+        }
+        let spent = self.inner.is_spent(outpoint);
+        if spent {
+            self.record_false_positive();
+        }
+        spent
+    }
+
+    fn any_spent(&self, outpoints: &[M::OutPoint]) -> bool {
+        if !outpoints
+            .iter()
+            .all(|outpoint| self.filter.contains(outpoint))
+        {
+            return true;
+        }
+        let spent = self.inner.any_spent(outpoints);
+        if spent {
+            self.record_false_positive();
+        }
+        spent
+    }
+
+    fn is_mature(&self, outpoint: &M::OutPoint) -> bool {
+        self.inner.is_mature(outpoint)
+    }
+}
+
+impl<M> UtxoMapRef for FilteredUtxoMap<M>
+where
+    M: UtxoMapRef,
+    M::OutPoint: Hash,
+{
+    fn get_utxo_ref(&self, outpoint: &M::OutPoint) -> Option<&M::Output> {
+        if !self.filter.contains(outpoint) {
+            return None;
+        }
+        let output = self.inner.get_utxo_ref(outpoint);
+        if output.is_none() {
+            self.record_false_positive();
+        }
+        output
+    }
+}
+
+impl<M> UtxoScan for FilteredUtxoMap<M>
+where
+    M: UtxoScan,
+    M::OutPoint: Hash,
+{
+    type ScanError = M::ScanError;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, M::OutPoint, M::Output>) -> Result<(), M::ScanError> {
+        self.inner.scan(f)
+    }
+}
+
+impl<M> UtxoWrite for FilteredUtxoMap<M>
+where
+    M: UtxoWrite,
+    M::OutPoint: Hash,
+{
+    type Error = M::Error;
+
+    fn insert(&mut self, outpoint: M::OutPoint, output: M::Output) -> Result<(), M::Error> {
+        self.filter.insert(&outpoint);
+        self.inner.insert(outpoint, output)
+    }
+
+    fn remove(&mut self, outpoint: &M::OutPoint) -> Result<(), M::Error> {
+        self.inner.remove(outpoint)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::spv;
+use crate::types::*;
+use bitcoin::{BlockHeader, Script, TxMerkleNode};
+use serde::{Deserialize, Serialize};
+
+/// Tag of blind merged mining commitments in mainchain coinbases, see BIP 301.
+pub const BMM_TAG: [u8; 4] = [0xD1, 0x61, 0x73, 0x68];
+
+impl Header {
+    /// Commitment h* of BIP 301, the block hash. It covers `prev_main_hash`,
+    /// so it can only be mined in a child of that mainchain block.
+    pub fn bmm_commitment(&self) -> [u8; 32] {
+        self.hash().0
+    }
+
+    /// OP_RETURN script pushing `BMM_TAG` followed by the commitment, to be
+    /// added to the mainchain coinbase.
+    pub fn bmm_script(&self) -> Script {
+        let mut data = [0; 36];
+        data[..4].copy_from_slice(&BMM_TAG);
+        data[4..].copy_from_slice(&self.bmm_commitment());
+        Script::new_op_return(&data)
+    }
+}
+
+/// Mainchain coinbase with the merkle branch linking it to its block header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MainCoinbaseProof {
+    pub header: BlockHeader,
+    pub coinbase: bitcoin::Transaction,
+    /// Siblings of the coinbase txid from the leaves up, the coinbase is
+    /// always the first leaf.
+    pub merkle_branch: Vec<TxMerkleNode>,
+}
+
+impl MainCoinbaseProof {
+    /// Merkle root the branch computes for the coinbase.
+    pub fn compute_merkle_root(&self) -> TxMerkleNode {
+        spv::compute_merkle_root(&self.coinbase.txid(), 0, &self.merkle_branch)
+    }
+}
+
+/// Checks that `header` was blind merged mined in the mainchain block of
+/// `proof`: the block is a child of `header.prev_main_hash`, has valid proof
+/// of work for its own target and its coinbase commits to `header`.
+///
+/// Whether the mainchain block is in the best mainchain is left to the
+/// caller.
+pub fn verify_bmm(header: &Header, proof: &MainCoinbaseProof) -> Result<(), BmmError> {
+    if proof.header.prev_blockhash != header.prev_main_hash {
+        return Err(BmmError::WrongPrevMainHash {
+            prev_main_hash: header.prev_main_hash,
+            parent: proof.header.prev_blockhash,
+        });
+    }
+    proof
+        .header
+        .validate_pow(&proof.header.target())
+        .map_err(|_| BmmError::InvalidProofOfWork)?;
+    if !proof.coinbase.is_coin_base() {
+        return Err(BmmError::NotCoinbase);
+    }
+    if proof.compute_merkle_root() != proof.header.merkle_root {
+        return Err(BmmError::WrongMerkleRoot);
+    }
+    let script = header.bmm_script();
+    if !proof
+        .coinbase
+        .output
+        .iter()
+        .any(|output| output.script_pubkey == script)
+    {
+        return Err(BmmError::NoCommitment);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BmmError {
+    #[error("mainchain block is a child of {parent}, not of {prev_main_hash}")]
+    WrongPrevMainHash {
+        prev_main_hash: bitcoin::BlockHash,
+        parent: bitcoin::BlockHash,
+    },
+    #[error("mainchain block hash is above its target")]
+    InvalidProofOfWork,
+    #[error("transaction is not a coinbase")]
+    NotCoinbase,
+    #[error("merkle branch doesn't lead to the mainchain block's merkle root")]
+    WrongMerkleRoot,
+    #[error("coinbase doesn't commit to the block")]
+    NoCommitment,
+}
@@ -0,0 +1,239 @@
+use crate::mempool::FeeRate;
+use crate::types::*;
+
+/// Branch and bound gives up after visiting this many selections.
+const MAX_TRIES: usize = 100_000;
+
+/// Sizes and fee rates coin selection trades off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionParams {
+    pub fee_rate: FeeRate,
+    /// Rate the wallet expects to pay eventually, spending inputs now rather
+    /// than later wastes the difference.
+    pub long_term_fee_rate: FeeRate,
+    /// Bytes of the transaction without inputs and change.
+    pub base_size: u64,
+    /// Bytes each input adds, with its authorization.
+    pub input_size: u64,
+    /// Fee of creating a change output now and of spending it later.
+    pub change_cost: u64,
+}
+
+impl SelectionParams {
+    pub fn input_fee(&self) -> u64 {
+        self.fee_rate.fee_for(self.input_size)
+    }
+
+    /// Value an input adds after paying for itself, `None` if it costs more
+    /// than it is worth.
+    pub fn effective_value(&self, value: u64) -> Option<u64> {
+        value
+            .checked_sub(self.input_fee())
+            .filter(|value| *value > 0)
+    }
+
+    fn input_waste(&self) -> i64 {
+        let long_term_fee = self.long_term_fee_rate.fee_for(self.input_size);
+        self.input_fee() as i64 - long_term_fee as i64
+    }
+
+    /// Waste of spending `inputs` inputs with `excess` left over.
+    ///
+    /// Like Bitcoin Core: inputs waste what they cost above the long term
+    /// fee rate, and the excess is either wasted as fee or pays for a
+    /// change output.
+    pub fn waste(&self, inputs: usize, excess: u64) -> i64 {
+        let leftover = if excess > self.change_cost {
+            self.change_cost
+        } else {
+            excess
+        };
+        self.input_waste() * inputs as i64 + leftover as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoinSelectionAlgorithm {
+    /// Largest effective values first, fewest inputs.
+    LargestFirst,
+    /// Candidates in the given order, oldest first spends old coins.
+    Fifo,
+    /// Searches for inputs matching the target closely enough to need no
+    /// change, with the least waste.
+    BranchAndBound,
+}
+
+/// Inputs chosen by `select_coins`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelection<C> {
+    pub inputs: Vec<(OutPoint, Output<C>)>,
+    /// Value of the inputs.
+    pub value: u64,
+    /// Fee of the transaction without change.
+    pub fee: u64,
+    /// Value left after the target and the fee, for change or extra fee.
+    pub excess: u64,
+    pub waste: i64,
+}
+
+impl<C> CoinSelection<C> {
+    /// Whether the excess is worth a change output.
+    pub fn needs_change(&self, params: &SelectionParams) -> bool {
+        self.excess > params.change_cost
+    }
+}
+
+/// Chooses inputs from `candidates` paying `target` and the fee at
+/// `params.fee_rate`. Candidates worth less than their own fee are skipped.
+pub fn select_coins<C: Clone + GetValue>(
+    algorithm: CoinSelectionAlgorithm,
+    candidates: &[(OutPoint, Output<C>)],
+    target: u64,
+    params: &SelectionParams,
+) -> Result<CoinSelection<C>, CoinSelectionError> {
+    let needed = target + params.fee_rate.fee_for(params.base_size);
+    let mut usable: Vec<(usize, u64)> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, output))| {
+            Some((index, params.effective_value(output.get_value())?))
+        })
+        .collect();
+    let available = usable.iter().map(|(_, value)| value).sum();
+    if available < needed {
+        return Err(CoinSelectionError::InsufficientFunds { needed, available });
+    }
+    let selected = match algorithm {
+        CoinSelectionAlgorithm::LargestFirst => {
+            usable.sort_by_key(|(index, value)| (std::cmp::Reverse(*value), *index));
+            accumulate(&usable, needed)
+        }
+        CoinSelectionAlgorithm::Fifo => accumulate(&usable, needed),
+        CoinSelectionAlgorithm::BranchAndBound => {
+            branch_and_bound(&usable, needed, params).ok_or(CoinSelectionError::NoMatch)?
+        }
+    };
+    let effective: u64 = selected.iter().map(|(_, value)| value).sum();
+    let inputs: Vec<_> = selected
+        .iter()
+        .map(|(index, _)| candidates[*index].clone())
+        .collect();
+    let value = inputs.iter().map(|(_, output)| output.get_value()).sum();
+    let excess = effective - needed;
+    Ok(CoinSelection {
+        fee: value - target - excess,
+        waste: params.waste(inputs.len(), excess),
+        inputs,
+        value,
+        excess,
+    })
+}
+
+/// Runs every algorithm and keeps the selection with the least waste,
+/// preferring fewer inputs on ties.
+pub fn select_coins_min_waste<C: Clone + GetValue>(
+    candidates: &[(OutPoint, Output<C>)],
+    target: u64,
+    params: &SelectionParams,
+) -> Result<CoinSelection<C>, CoinSelectionError> {
+    let mut best: Option<CoinSelection<C>> = None;
+    let algorithms = [
+        CoinSelectionAlgorithm::BranchAndBound,
+        CoinSelectionAlgorithm::LargestFirst,
+        CoinSelectionAlgorithm::Fifo,
+    ];
+    for algorithm in algorithms {
+        let selection = match select_coins(algorithm, candidates, target, params) {
+            Ok(selection) => selection,
+            Err(CoinSelectionError::NoMatch) => continue,
+            Err(err) => return Err(err),
+        };
+        let better = best.as_ref().is_none_or(|best| {
+            (selection.waste, selection.inputs.len()) < (best.waste, best.inputs.len())
+        });
+        if better {
+            best = Some(selection);
+        }
+    }
+    Ok(best.expect("largest first succeeds with enough funds"))
+}
+
+/// Takes candidates in order until they cover `needed`.
+fn accumulate(candidates: &[(usize, u64)], needed: u64) -> Vec<(usize, u64)> {
+    let mut total = 0;
+    candidates
+        .iter()
+        .take_while(|(_, value)| {
+            let take = total < needed;
+            total += value;
+            take
+        })
+        .copied()
+        .collect()
+}
+
+fn branch_and_bound(
+    candidates: &[(usize, u64)],
+    needed: u64,
+    params: &SelectionParams,
+) -> Option<Vec<(usize, u64)>> {
+    let mut sorted = candidates.to_vec();
+    sorted.sort_by_key(|(index, value)| (std::cmp::Reverse(*value), *index));
+    let mut search = Search {
+        candidates: &sorted,
+        needed,
+        upper_bound: needed + params.change_cost,
+        params,
+        tries: 0,
+        selected: Vec::new(),
+        best: None,
+    };
+    let remaining = sorted.iter().map(|(_, value)| value).sum();
+    search.visit(0, 0, remaining);
+    let best = search.best?.1;
+    Some(best.into_iter().map(|position| sorted[position]).collect())
+}
+
+struct Search<'a> {
+    /// Largest first, so overshooting branches are cut early.
+    candidates: &'a [(usize, u64)],
+    needed: u64,
+    upper_bound: u64,
+    params: &'a SelectionParams,
+    tries: usize,
+    /// Positions in `candidates`.
+    selected: Vec<usize>,
+    best: Option<(i64, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    /// `remaining` is the value of the candidates from `position` on.
+    fn visit(&mut self, position: usize, value: u64, remaining: u64) {
+        if self.tries >= MAX_TRIES || value > self.upper_bound || value + remaining < self.needed {
+            return;
+        }
+        self.tries += 1;
+        if value >= self.needed {
+            let waste = self.params.waste(self.selected.len(), value - self.needed);
+            if self.best.as_ref().is_none_or(|(best, _)| waste < *best) {
+                self.best = Some((waste, self.selected.clone()));
+            }
+            return;
+        }
+        let Some((_, candidate)) = self.candidates.get(position) else {
+            return;
+        };
+        self.selected.push(position);
+        self.visit(position + 1, value + candidate, remaining - candidate);
+        self.selected.pop();
+        self.visit(position + 1, value, remaining - candidate);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum CoinSelectionError {
+    #[error("{needed} is needed, candidates are worth {available} after fees")]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("no selection needs no change")]
+    NoMatch,
+}
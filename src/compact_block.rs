@@ -0,0 +1,292 @@
+use crate::encoding::EncodeError;
+use crate::mempool::Mempool;
+use crate::short_id::{match_short_ids, DuplicateShortId, ShortId, ShortIdKeys};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// Block relayed as its header, its coinbase, short ids of its transactions
+/// and the transactions the receiver likely lacks, as in BIP 152.
+///
+/// Well-synced peers have most transactions in their mempool already, so
+/// only the ones they lack are sent, see `PartialBlock`.
+///
+/// Short id keys, block hashes and merkle roots are computed with the
+/// chain's `HashFunction` `H`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactBlock<A, C, H = DefaultHashFunction> {
+    pub header: Header,
+    /// Nonce of the short id keys, see `ShortIdKeys::new`.
+    pub nonce: u64,
+    pub coinbase: Vec<Output<C>>,
+    /// Short ids of the transactions that are not prefilled, in block order.
+    pub short_ids: Vec<ShortId>,
+    /// Transactions sent in full, sorted by position.
+    pub prefilled: Vec<PrefilledTransaction<A, C>>,
+    #[serde(skip)]
+    _marker: PhantomData<H>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrefilledTransaction<A, C> {
+    /// Position of the transaction in the block.
+    pub index: u32,
+    pub transaction: AuthorizedTransaction<A, C>,
+}
+
+/// Request for the transactions at `indexes` of a block whose short ids
+/// didn't match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetBlockTxn {
+    pub block_hash: BlockHash,
+    /// Sorted positions in the block.
+    pub indexes: Vec<u32>,
+}
+
+/// Answer to a `GetBlockTxn`, the transactions in request order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockTxn<A, C> {
+    pub block_hash: BlockHash,
+    pub transactions: Vec<AuthorizedTransaction<A, C>>,
+}
+
+/// Compact block with the transactions found so far.
+#[derive(Debug, Clone)]
+pub struct PartialBlock<A, C, H = DefaultHashFunction> {
+    header: Header,
+    coinbase: Vec<Output<C>>,
+    transactions: Vec<Option<AuthorizedTransaction<A, C>>>,
+    _marker: PhantomData<H>,
+}
+
+impl<A: Clone, C: Clone + Serialize, H: HashFunction> CompactBlock<A, C, H> {
+    /// Compact form of the block, sending the transactions at the positions
+    /// in `prefill` in full, for example the ones the receiver can't have
+    /// seen yet.
+    pub fn new(
+        header: Header,
+        body: &Body<A, C>,
+        nonce: u64,
+        prefill: &[usize],
+    ) -> Result<Self, CompactBlockError> {
+        let keys = ShortIdKeys::new(&header.hash_with::<H>(), nonce);
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for (index, transaction) in authorized_transactions(body)?.into_iter().enumerate() {
+            if prefill.contains(&index) {
+                prefilled.push(PrefilledTransaction {
+                    index: index as u32,
+                    transaction,
+                });
+            } else {
+                short_ids.push(keys.short_id(&transaction.transaction.try_txid_with::<H>()?));
+            }
+        }
+        Ok(Self {
+            header,
+            nonce,
+            coinbase: body.coinbase.clone(),
+            short_ids,
+            prefilled,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn keys(&self) -> ShortIdKeys {
+        ShortIdKeys::new(&self.header.hash_with::<H>(), self.nonce)
+    }
+
+    /// Fills in the prefilled transactions and the ones of `candidates`
+    /// matching a short id.
+    pub fn reconstruct<'a>(
+        &self,
+        candidates: impl IntoIterator<Item = (Txid, &'a AuthorizedTransaction<A, C>)>,
+    ) -> Result<PartialBlock<A, C, H>, CompactBlockError>
+    where
+        A: 'a,
+        C: 'a,
+    {
+        let count = self.short_ids.len() + self.prefilled.len();
+        let mut transactions: Vec<Option<AuthorizedTransaction<A, C>>> = vec![None; count];
+        let mut next_index = 0;
+        for prefilled in &self.prefilled {
+            let index = prefilled.index as usize;
+            if index < next_index || index >= count {
+                return Err(CompactBlockError::InvalidPrefilledIndex {
+                    index: prefilled.index,
+                });
+            }
+            transactions[index] = Some(prefilled.transaction.clone());
+            next_index = index + 1;
+        }
+        let matched = match_short_ids(&self.keys(), &self.short_ids, candidates)?;
+        for (slot, candidate) in transactions
+            .iter_mut()
+            .filter(|slot| slot.is_none())
+            .zip(matched)
+        {
+            *slot = candidate.cloned();
+        }
+        Ok(PartialBlock {
+            header: self.header,
+            coinbase: self.coinbase.clone(),
+            transactions,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Reconstructs the block from the transactions of `mempool`.
+    pub fn reconstruct_from_mempool(
+        &self,
+        mempool: &Mempool<A, C, H>,
+    ) -> Result<PartialBlock<A, C, H>, CompactBlockError> {
+        self.reconstruct(mempool.iter().map(|entry| (entry.txid, &entry.transaction)))
+    }
+}
+
+impl<A, C: Clone + GetValue + Serialize, H: HashFunction> PartialBlock<A, C, H> {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Positions of the transactions still missing.
+    pub fn missing(&self) -> Vec<u32> {
+        (0u32..)
+            .zip(&self.transactions)
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.transactions.iter().all(Option::is_some)
+    }
+
+    /// Request for the missing transactions, `None` if there are none.
+    pub fn get_block_txn(&self) -> Option<GetBlockTxn> {
+        let indexes = self.missing();
+        if indexes.is_empty() {
+            return None;
+        }
+        Some(GetBlockTxn {
+            block_hash: self.header.hash_with::<H>(),
+            indexes,
+        })
+    }
+
+    /// Adds the transactions answering `get_block_txn`.
+    pub fn fill(&mut self, block_txn: BlockTxn<A, C>) -> Result<(), CompactBlockError> {
+        let block_hash = self.header.hash_with::<H>();
+        if block_txn.block_hash != block_hash {
+            return Err(CompactBlockError::WrongBlock {
+                block_hash: block_txn.block_hash,
+                expected: block_hash,
+            });
+        }
+        let missing = self.missing();
+        if block_txn.transactions.len() != missing.len() {
+            return Err(CompactBlockError::WrongTransactionCount {
+                count: block_txn.transactions.len(),
+                expected: missing.len(),
+            });
+        }
+        for (index, transaction) in missing.into_iter().zip(block_txn.transactions) {
+            self.transactions[index as usize] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// Body of the complete block. Fails if the merkle root doesn't match,
+    /// which happens when a mempool transaction collides with a short id,
+    /// then the full block has to be requested.
+    pub fn into_body(self) -> Result<Body<A, C>, CompactBlockError> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(CompactBlockError::Incomplete { missing });
+        }
+        let transactions = self.transactions.into_iter().flatten().collect();
+        let body = Body::new(transactions, self.coinbase);
+        if body.try_compute_merkle_root_with::<H>()? != self.header.merkle_root {
+            return Err(CompactBlockError::WrongMerkleRoot);
+        }
+        Ok(body)
+    }
+}
+
+impl GetBlockTxn {
+    /// Answers the request out of the block's body.
+    pub fn respond<A: Clone, C: Clone>(
+        &self,
+        body: &Body<A, C>,
+    ) -> Result<BlockTxn<A, C>, CompactBlockError> {
+        let transactions = authorized_transactions(body)?;
+        let transactions = self
+            .indexes
+            .iter()
+            .map(|&index| {
+                transactions
+                    .get(index as usize)
+                    .cloned()
+                    .ok_or(CompactBlockError::UnknownIndex { index })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BlockTxn {
+            block_hash: self.block_hash,
+            transactions,
+        })
+    }
+}
+
+/// Transactions of `body` with their authorizations.
+fn authorized_transactions<A: Clone, C: Clone>(
+    body: &Body<A, C>,
+) -> Result<Vec<AuthorizedTransaction<A, C>>, CompactBlockError> {
+    let mut authorizations = body.authorizations.iter().cloned();
+    let transactions: Vec<_> = body
+        .transactions
+        .iter()
+        .map(|transaction| AuthorizedTransaction {
+            transaction: transaction.clone(),
+            authorizations: authorizations
+                .by_ref()
+                .take(transaction.inputs.len())
+                .collect(),
+        })
+        .collect();
+    let inputs: usize = body.transactions.iter().map(|tx| tx.inputs.len()).sum();
+    if body.authorizations.len() != inputs {
+        return Err(CompactBlockError::WrongAuthorizationCount {
+            authorizations: body.authorizations.len(),
+            inputs,
+        });
+    }
+    Ok(transactions)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompactBlockError {
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("body has {authorizations} authorizations for {inputs} inputs")]
+    WrongAuthorizationCount {
+        authorizations: usize,
+        inputs: usize,
+    },
+    #[error("prefilled transaction index {index} is out of order or out of range")]
+    InvalidPrefilledIndex { index: u32 },
+    #[error("duplicate short id")]
+    DuplicateShortId(#[from] DuplicateShortId),
+    #[error("transactions are for block {block_hash}, expected {expected}")]
+    WrongBlock {
+        block_hash: BlockHash,
+        expected: BlockHash,
+    },
+    #[error("got {count} transactions, expected {expected}")]
+    WrongTransactionCount { count: usize, expected: usize },
+    #[error("block has no transaction at index {index}")]
+    UnknownIndex { index: u32 },
+    #[error("transactions at {missing:?} are missing")]
+    Incomplete { missing: Vec<u32> },
+    #[error("reconstructed block doesn't match the header's merkle root")]
+    WrongMerkleRoot,
+}
@@ -0,0 +1,370 @@
+use crate::encoding::encode_canonical;
+use crate::types::*;
+use bincode::Options;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// Limits on what a peer can make a node decode.
+///
+/// They are checked while decoding, before anything is allocated for the
+/// counted items, so a crafted length prefix fails right away instead of
+/// making the node allocate gigabytes. `max_size` bounds everything the
+/// counts don't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecodeLimits {
+    /// Length of the encoded value.
+    pub max_size: u64,
+    pub max_inputs: usize,
+    pub max_outputs: usize,
+    /// Transactions per body.
+    pub max_transactions: usize,
+    /// Encoded length of one output, which bounds data carried by custom
+    /// content.
+    pub max_output_size: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 4_000_000,
+            max_inputs: 10_000,
+            max_outputs: 10_000,
+            max_transactions: 100_000,
+            max_output_size: 10_000,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Canonical decoding, see `decode_canonical`, failing once more than
+    /// `max_size` bytes would be read.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, bincode::Error> {
+        self.decode_seed(bytes, PhantomData)
+    }
+
+    pub fn decode_transaction<C: Serialize + DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<Transaction<C>, bincode::Error> {
+        self.decode_seed(bytes, TransactionSeed::new(self))
+    }
+
+    /// Decodes an authorized transaction, with at most one authorization per
+    /// input.
+    pub fn decode_authorized_transaction<A: DeserializeOwned, C: Serialize + DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<AuthorizedTransaction<A, C>, bincode::Error> {
+        self.decode_seed(bytes, AuthorizedTransactionSeed::new(self))
+    }
+
+    /// Decodes a body, with at most one authorization per input.
+    pub fn decode_body<A: DeserializeOwned, C: Serialize + DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<Body<A, C>, bincode::Error> {
+        self.decode_seed(bytes, BodySeed::new(self))
+    }
+
+    fn decode_seed<'de, T: DeserializeSeed<'de>>(
+        &self,
+        bytes: &'de [u8],
+        seed: T,
+    ) -> Result<T::Value, bincode::Error> {
+        if bytes.len() as u64 > self.max_size {
+            return Err(de::Error::custom(format!(
+                "{} bytes exceed the limit of {}",
+                bytes.len(),
+                self.max_size
+            )));
+        }
+        // Same options as `decode_canonical`, with a limit.
+        bincode::DefaultOptions::new()
+            .with_little_endian()
+            .with_fixint_encoding()
+            .with_limit(self.max_size)
+            .reject_trailing_bytes()
+            .deserialize_seed(seed, bytes)
+    }
+}
+
+/// Sequence of at most `max` elements decoded with `element`.
+struct LimitedSeq<S> {
+    max: usize,
+    name: &'static str,
+    element: S,
+}
+
+impl<'de, S: DeserializeSeed<'de> + Clone> DeserializeSeed<'de> for LimitedSeq<S> {
+    type Value = Vec<S::Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, S: DeserializeSeed<'de> + Clone> Visitor<'de> for LimitedSeq<S> {
+    type Value = Vec<S::Value>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "at most {} {}", self.max, self.name)
+    }
+
+    fn visit_seq<V: SeqAccess<'de>>(self, mut seq: V) -> Result<Self::Value, V::Error> {
+        let too_many = |count: usize| {
+            de::Error::custom(format!(
+                "{count} {} exceed the limit of {}",
+                self.name, self.max
+            ))
+        };
+        let count = seq.size_hint().unwrap_or(0);
+        if count > self.max {
+            return Err(too_many(count));
+        }
+        let mut elements = Vec::with_capacity(count);
+        while let Some(element) = seq.next_element_seed(self.element.clone())? {
+            if elements.len() == self.max {
+                return Err(too_many(elements.len() + 1));
+            }
+            elements.push(element);
+        }
+        Ok(elements)
+    }
+}
+
+/// Plain `Deserialize` of `T`.
+struct Plain<T>(PhantomData<T>);
+
+impl<T> Clone for Plain<T> {
+    fn clone(&self) -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for Plain<T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+        T::deserialize(deserializer)
+    }
+}
+
+/// Output of at most `max_size` encoded bytes.
+struct OutputSeed<C> {
+    max_size: u64,
+    content: PhantomData<C>,
+}
+
+impl<C> Clone for OutputSeed<C> {
+    fn clone(&self) -> Self {
+        Self {
+            max_size: self.max_size,
+            content: PhantomData,
+        }
+    }
+}
+
+impl<'de, C: Serialize + Deserialize<'de>> DeserializeSeed<'de> for OutputSeed<C> {
+    type Value = Output<C>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Output<C>, D::Error> {
+        let output = Output::<C>::deserialize(deserializer)?;
+        let size = encode_canonical(&output).map_err(de::Error::custom)?.len() as u64;
+        if size > self.max_size {
+            return Err(de::Error::custom(format!(
+                "output of {size} bytes exceeds the limit of {}",
+                self.max_size
+            )));
+        }
+        Ok(output)
+    }
+}
+
+fn outputs<C>(max: usize, limits: &DecodeLimits) -> LimitedSeq<OutputSeed<C>> {
+    LimitedSeq {
+        max,
+        name: "outputs",
+        element: OutputSeed {
+            max_size: limits.max_output_size,
+            content: PhantomData,
+        },
+    }
+}
+
+fn next<'de, V: SeqAccess<'de>, S: DeserializeSeed<'de>>(
+    seq: &mut V,
+    seed: S,
+    index: usize,
+    expected: &dyn de::Expected,
+) -> Result<S::Value, V::Error> {
+    seq.next_element_seed(seed)?
+        .ok_or_else(|| de::Error::invalid_length(index, expected))
+}
+
+struct TransactionSeed<C> {
+    limits: DecodeLimits,
+    content: PhantomData<C>,
+}
+
+impl<C> TransactionSeed<C> {
+    fn new(limits: &DecodeLimits) -> Self {
+        Self {
+            limits: *limits,
+            content: PhantomData,
+        }
+    }
+}
+
+impl<C> Clone for TransactionSeed<C> {
+    fn clone(&self) -> Self {
+        Self::new(&self.limits)
+    }
+}
+
+impl<'de, C: Serialize + Deserialize<'de>> DeserializeSeed<'de> for TransactionSeed<C> {
+    type Value = Transaction<C>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_struct("Transaction", &["inputs", "outputs"], self)
+    }
+}
+
+impl<'de, C: Serialize + Deserialize<'de>> Visitor<'de> for TransactionSeed<C> {
+    type Value = Transaction<C>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a transaction")
+    }
+
+    fn visit_seq<V: SeqAccess<'de>>(self, mut seq: V) -> Result<Self::Value, V::Error> {
+        let inputs = LimitedSeq {
+            max: self.limits.max_inputs,
+            name: "inputs",
+            element: Plain(PhantomData),
+        };
+        let inputs = next(&mut seq, inputs, 0, &self)?;
+        let outputs = next(
+            &mut seq,
+            outputs(self.limits.max_outputs, &self.limits),
+            1,
+            &self,
+        )?;
+        Ok(Transaction { inputs, outputs })
+    }
+}
+
+struct AuthorizedTransactionSeed<A, C> {
+    limits: DecodeLimits,
+    types: PhantomData<(A, C)>,
+}
+
+impl<A, C> AuthorizedTransactionSeed<A, C> {
+    fn new(limits: &DecodeLimits) -> Self {
+        Self {
+            limits: *limits,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<'de, A: Deserialize<'de>, C: Serialize + Deserialize<'de>> DeserializeSeed<'de>
+    for AuthorizedTransactionSeed<A, C>
+{
+    type Value = AuthorizedTransaction<A, C>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_struct(
+            "AuthorizedTransaction",
+            &["transaction", "authorizations"],
+            self,
+        )
+    }
+}
+
+impl<'de, A: Deserialize<'de>, C: Serialize + Deserialize<'de>> Visitor<'de>
+    for AuthorizedTransactionSeed<A, C>
+{
+    type Value = AuthorizedTransaction<A, C>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an authorized transaction")
+    }
+
+    fn visit_seq<V: SeqAccess<'de>>(self, mut seq: V) -> Result<Self::Value, V::Error> {
+        let transaction: Transaction<C> =
+            next(&mut seq, TransactionSeed::new(&self.limits), 0, &self)?;
+        let authorizations = LimitedSeq {
+            max: transaction.inputs.len(),
+            name: "authorizations",
+            element: Plain(PhantomData),
+        };
+        let authorizations = next(&mut seq, authorizations, 1, &self)?;
+        Ok(AuthorizedTransaction {
+            transaction,
+            authorizations,
+        })
+    }
+}
+
+struct BodySeed<A, C> {
+    limits: DecodeLimits,
+    types: PhantomData<(A, C)>,
+}
+
+impl<A, C> BodySeed<A, C> {
+    fn new(limits: &DecodeLimits) -> Self {
+        Self {
+            limits: *limits,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<'de, A: Deserialize<'de>, C: Serialize + Deserialize<'de>> DeserializeSeed<'de>
+    for BodySeed<A, C>
+{
+    type Value = Body<A, C>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_struct(
+            "Body",
+            &["coinbase", "transactions", "authorizations"],
+            self,
+        )
+    }
+}
+
+impl<'de, A: Deserialize<'de>, C: Serialize + Deserialize<'de>> Visitor<'de> for BodySeed<A, C> {
+    type Value = Body<A, C>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a body")
+    }
+
+    fn visit_seq<V: SeqAccess<'de>>(self, mut seq: V) -> Result<Self::Value, V::Error> {
+        let coinbase = next(
+            &mut seq,
+            outputs(self.limits.max_outputs, &self.limits),
+            0,
+            &self,
+        )?;
+        let transactions = LimitedSeq {
+            max: self.limits.max_transactions,
+            name: "transactions",
+            element: TransactionSeed::new(&self.limits),
+        };
+        let transactions: Vec<Transaction<C>> = next(&mut seq, transactions, 1, &self)?;
+        let authorizations = LimitedSeq {
+            max: transactions.iter().map(|tx| tx.inputs.len()).sum(),
+            name: "authorizations",
+            element: Plain(PhantomData),
+        };
+        let authorizations = next(&mut seq, authorizations, 2, &self)?;
+        Ok(Body {
+            coinbase,
+            transactions,
+            authorizations,
+        })
+    }
+}
@@ -0,0 +1,211 @@
+use crate::params::SidechainParams;
+use crate::spv::{self, MerkleBranch, SpvError};
+use crate::types::*;
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::hashes::{hex::ToHex as _, sha256, Hash as _};
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHeader, Script, Transaction, TxOut};
+use serde::{Deserialize, Serialize};
+
+/// OP_RETURN script of a deposit, it pushes the versioned address bytes of
+/// the sidechain recipient, see `Address::to_bytes`.
+pub fn deposit_script(address: &Address) -> Script {
+    Script::new_op_return(&address.to_bytes())
+}
+
+/// Zero value output carrying `deposit_script`, to be added to the
+/// mainchain deposit transaction.
+pub fn deposit_output(address: &Address) -> TxOut {
+    TxOut {
+        value: 0,
+        script_pubkey: deposit_script(address),
+    }
+}
+
+/// OP_RETURN script pushing `sidechain_number` and then the address, see
+/// `DepositScriptTemplate::NumberedAddress`.
+pub(crate) fn numbered_deposit_script(sidechain_number: u8, address: &Address) -> Script {
+    Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_slice(&[sidechain_number])
+        .push_slice(&address.to_bytes())
+        .into_script()
+}
+
+/// Inverse of `deposit_script`.
+pub fn deposit_address(script: &Script) -> Result<Address, DepositScriptError> {
+    parse_deposit_script(script, None)
+}
+
+/// Parses a deposit script, pushing `sidechain_number` before the address if
+/// there is one.
+pub(crate) fn parse_deposit_script(
+    script: &Script,
+    sidechain_number: Option<u8>,
+) -> Result<Address, DepositScriptError> {
+    let mut instructions = script.instructions();
+    match instructions.next() {
+        Some(Ok(Instruction::Op(OP_RETURN))) => {}
+        _ => return Err(DepositScriptError::NotOpReturn),
+    }
+    if let Some(expected) = sidechain_number {
+        match instructions.next() {
+            Some(Ok(Instruction::PushBytes(&[found]))) if found == expected => {}
+            Some(Ok(Instruction::PushBytes(&[found]))) => {
+                return Err(DepositScriptError::WrongSidechain { found, expected })
+            }
+            _ => return Err(DepositScriptError::NoSidechainNumber),
+        }
+    }
+    let address = match instructions.next() {
+        Some(Ok(Instruction::PushBytes(address))) => Address::from_slice(address)?,
+        _ => return Err(DepositScriptError::NoAddress),
+    };
+    if instructions.next().is_some() {
+        return Err(DepositScriptError::TrailingData);
+    }
+    Ok(address)
+}
+
+/// Recipient of a deposit transaction, from its first OP_RETURN output.
+pub fn find_deposit_address(transaction: &Transaction) -> Result<Address, DepositScriptError> {
+    let output = transaction
+        .output
+        .iter()
+        .find(|output| output.script_pubkey.is_op_return())
+        .ok_or(DepositScriptError::NotOpReturn)?;
+    deposit_address(&output.script_pubkey)
+}
+
+/// Deposit address shown to users, naming the sidechain slot and the
+/// recipient: `s<sidechain_number>_<address>_<checksum>`, where the checksum
+/// is the first 6 hex digits of the sha256 of the text before it.
+///
+/// The mainchain outputs paying to it are built by
+/// `SidechainParams::deposit_outputs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PegInAddress {
+    pub sidechain_number: u8,
+    pub address: Address,
+}
+
+impl PegInAddress {
+    fn checksum(prefix: &str) -> String {
+        sha256::Hash::hash(prefix.as_bytes()).to_hex()[..6].to_string()
+    }
+}
+
+impl std::fmt::Display for PegInAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = format!("s{}_{}", self.sidechain_number, self.address);
+        write!(f, "{prefix}_{}", Self::checksum(&prefix))
+    }
+}
+
+impl std::str::FromStr for PegInAddress {
+    type Err = PegInAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, checksum) = s.rsplit_once('_').ok_or(PegInAddressError::Format)?;
+        let (sidechain_number, address) = prefix
+            .strip_prefix('s')
+            .and_then(|prefix| prefix.split_once('_'))
+            .ok_or(PegInAddressError::Format)?;
+        if checksum != Self::checksum(prefix) {
+            return Err(PegInAddressError::BadChecksum);
+        }
+        Ok(Self {
+            sidechain_number: sidechain_number
+                .parse()
+                .map_err(|_| PegInAddressError::Format)?,
+            address: address.parse::<UncheckedAddress>()?.assume_checked(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PegInAddressError {
+    #[error("deposit address is not s<sidechain number>_<address>_<checksum>")]
+    Format,
+    #[error("deposit address checksum is invalid")]
+    BadChecksum,
+    #[error("invalid sidechain address")]
+    Address(#[from] AddressParseError),
+}
+
+/// SPV proof that a deposit transaction is in the mainchain, so it can be
+/// accepted without trusting whoever reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepositProof {
+    pub tx: Transaction,
+    pub merkle_branch: MerkleBranch,
+    /// Header of the block including `tx`, followed by the headers built on
+    /// top of it.
+    pub headers: Vec<BlockHeader>,
+}
+
+impl DepositProof {
+    /// Mainchain confirmations the proof shows, the including block is the
+    /// first one.
+    pub fn confirmations(&self) -> u32 {
+        self.headers.len() as u32
+    }
+
+    /// Checks that `tx` is in the first header's block and that the headers
+    /// have at least `min_work` total work, returns the deposit recipient
+    /// according to `sidechain`.
+    ///
+    /// Whether the headers are in the best mainchain is left to the caller.
+    pub fn verify(
+        &self,
+        sidechain: &SidechainParams,
+        min_work: Uint256,
+    ) -> Result<Address, DepositProofError> {
+        let block = self.headers.first().ok_or(DepositProofError::NoHeaders)?;
+        // 64 byte transactions can pass for inner merkle nodes.
+        if bitcoin::consensus::serialize(&self.tx).len() == 64 {
+            return Err(DepositProofError::AmbiguousTransaction);
+        }
+        if self.merkle_branch.compute_root(&self.tx.txid()) != Some(block.merkle_root) {
+            return Err(DepositProofError::WrongMerkleRoot);
+        }
+        if spv::verify_header_chain(&self.headers)? < min_work {
+            return Err(DepositProofError::InsufficientWork);
+        }
+        Ok(sidechain.find_deposit_address(&self.tx)?)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DepositProofError {
+    #[error("deposit proof has no headers")]
+    NoHeaders,
+    #[error("deposit transaction is 64 bytes long")]
+    AmbiguousTransaction,
+    #[error("merkle branch doesn't lead to the block's merkle root")]
+    WrongMerkleRoot,
+    #[error("invalid header chain")]
+    Spv(#[from] SpvError),
+    #[error("header chain has too little work")]
+    InsufficientWork,
+    #[error("invalid deposit script")]
+    Script(#[from] DepositScriptError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DepositScriptError {
+    #[error("deposit script is not an OP_RETURN script")]
+    NotOpReturn,
+    #[error("deposit script does not push a sidechain number")]
+    NoSidechainNumber,
+    #[error("deposit is for sidechain {found}, expected {expected}")]
+    WrongSidechain { found: u8, expected: u8 },
+    #[error("deposit script does not push an address")]
+    NoAddress,
+    #[error("deposit script has data after the address")]
+    TrailingData,
+    #[error("deposit transaction doesn't pay to the sidechain treasury")]
+    NoTreasuryOutput,
+    #[error("invalid deposit address")]
+    Address(#[from] AddressParseError),
+}
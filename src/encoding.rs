@@ -0,0 +1,129 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Bincode configuration hashes are computed over.
+///
+/// Pinned to little endian, fixed width integers and `u64` length prefixes,
+/// the encoding of `bincode::serialize` in bincode 1, so that txids don't
+/// change with bincode defaults. Decoding rejects trailing bytes.
+fn canonical_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .with_no_limit()
+        .reject_trailing_bytes()
+}
+
+pub fn encode_canonical<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    Ok(canonical_options().serialize(value)?)
+}
+
+pub fn encode_canonical_into<W: std::io::Write, T: Serialize + ?Sized>(
+    writer: W,
+    value: &T,
+) -> Result<(), EncodeError> {
+    Ok(canonical_options().serialize_into(writer, value)?)
+}
+
+/// Serialization failed, only possible with custom content types.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to encode value")]
+pub struct EncodeError(#[from] bincode::Error);
+
+pub fn decode_canonical<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::Error> {
+    canonical_options().deserialize(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use bitcoin::hashes::Hash as _;
+
+    fn output() -> Output<()> {
+        Output {
+            address: Address::V0PubkeyHash([0x11; 32]),
+            content: Content::Value(1000),
+        }
+    }
+
+    fn transaction() -> Transaction<()> {
+        Transaction {
+            inputs: vec![OutPoint::Regular {
+                txid: Txid([0x22; 32]),
+                vout: 1,
+            }],
+            outputs: vec![output()],
+        }
+    }
+
+    // Version, program, `Content::Value` tag and the value.
+    const OUTPUT: &str = concat!(
+        "00",
+        "1111111111111111111111111111111111111111111111111111111111111111",
+        "01000000",
+        "e803000000000000",
+    );
+
+    #[test]
+    fn output_encoding() {
+        let bytes = encode_canonical(&output()).unwrap();
+        assert_eq!(hex::encode(&bytes), OUTPUT);
+        assert_eq!(decode_canonical::<Output<()>>(&bytes).unwrap(), output());
+    }
+
+    #[test]
+    fn transaction_encoding() {
+        let expected = [
+            // One input, `OutPoint::Regular` tag, txid and vout.
+            "0100000000000000",
+            "00000000",
+            "2222222222222222222222222222222222222222222222222222222222222222",
+            "01000000",
+            // One output.
+            "0100000000000000",
+            OUTPUT,
+        ]
+        .concat();
+        let bytes = encode_canonical(&transaction()).unwrap();
+        assert_eq!(hex::encode(&bytes), expected);
+        let decoded: Transaction<()> = decode_canonical(&bytes).unwrap();
+        assert_eq!(encode_canonical(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn header_encoding() {
+        let header = Header {
+            merkle_root: Hash::from_bytes([0x33; 32]).into(),
+            prev_side_hash: BlockHash([0x44; 32]),
+            prev_main_hash: bitcoin::BlockHash::from_inner([0x55; 32]),
+        };
+        let expected = concat!(
+            "3333333333333333333333333333333333333333333333333333333333333333",
+            "4444444444444444444444444444444444444444444444444444444444444444",
+            // Bitcoin hashes are encoded as byte slices, with a length.
+            "2000000000000000",
+            "5555555555555555555555555555555555555555555555555555555555555555",
+        );
+        let bytes = encode_canonical(&header).unwrap();
+        assert_eq!(hex::encode(&bytes), expected);
+        assert_eq!(decode_canonical::<Header>(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn txid_and_merkle_root() {
+        assert_eq!(
+            transaction().txid_with::<Blake3>().to_string(),
+            "2d9247a104f12677897f78e49c7932ab521a29f5796afa9b95f9b54105a6c2eb",
+        );
+        let body = Body::<(), ()> {
+            coinbase: vec![output()],
+            transactions: vec![transaction()],
+            authorizations: Vec::new(),
+        };
+        assert_eq!(
+            body.compute_merkle_root_with::<Blake3>().to_string(),
+            "7e950e10dbc6ca65966b4c13a4ba8827a4d23c19a6a9961902e20a3a0ca0e3f6",
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::encoding::EncodeError;
+use crate::mempool::{FeeRate, Mempool};
+use crate::types::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+const BUCKET_COUNT: usize = 48;
+/// Share of a block's bytes paying less than its floor fee rate.
+const FLOOR_PERCENTILE: f64 = 0.1;
+/// Chance of confirming within the target the estimates aim for.
+const CONFIDENCE: f64 = 0.95;
+
+/// Bytes of transactions per fee rate bucket.
+///
+/// The first bucket starts at zero, the second at one satoshi per byte, and
+/// every further bucket starts a quarter higher than the previous one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeHistogram {
+    bytes: [u64; BUCKET_COUNT],
+}
+
+impl Default for FeeHistogram {
+    fn default() -> Self {
+        Self {
+            bytes: [0; BUCKET_COUNT],
+        }
+    }
+}
+
+impl FeeHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lowest fee rate of the `index`th bucket.
+    pub fn bucket_start(index: usize) -> FeeRate {
+        if index == 0 {
+            return FeeRate::new(0, 1);
+        }
+        // Millisatoshis per byte.
+        let start = (1..index).fold(1000u64, |start, _| start * 5 / 4);
+        FeeRate::new(start, 1000)
+    }
+
+    fn bucket_index(fee_rate: &FeeRate) -> usize {
+        (1..BUCKET_COUNT)
+            .take_while(|index| Self::bucket_start(*index) <= *fee_rate)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Counts `fee_rate.size` bytes at `fee_rate`.
+    pub fn add(&mut self, fee_rate: FeeRate) {
+        self.bytes[Self::bucket_index(&fee_rate)] += fee_rate.size;
+    }
+
+    /// Bytes in each bucket, lowest fee rates first.
+    pub fn buckets(&self) -> &[u64] {
+        &self.bytes
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+
+    /// Start of the bucket holding the byte `fraction` of the way up from
+    /// the lowest fee rate, `None` for an empty histogram.
+    pub fn percentile(&self, fraction: f64) -> Option<FeeRate> {
+        let total = self.total_size();
+        if total == 0 {
+            return None;
+        }
+        let target = (total as f64 * fraction.clamp(0.0, 1.0)) as u64;
+        let mut seen = 0;
+        for (index, bytes) in self.bytes.iter().enumerate() {
+            seen += bytes;
+            if seen > target {
+                return Some(Self::bucket_start(index));
+            }
+        }
+        Some(Self::bucket_start(BUCKET_COUNT - 1))
+    }
+}
+
+impl FromIterator<FeeRate> for FeeHistogram {
+    fn from_iter<I: IntoIterator<Item = FeeRate>>(iter: I) -> Self {
+        let mut histogram = Self::new();
+        for fee_rate in iter {
+            histogram.add(fee_rate);
+        }
+        histogram
+    }
+}
+
+/// Estimates fee rates from the fee rates of recent blocks and the current
+/// mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeEstimator {
+    /// Most recent block last.
+    blocks: VecDeque<FeeHistogram>,
+    max_blocks: usize,
+    block_size: u64,
+}
+
+impl FeeEstimator {
+    /// Remembers the last `max_blocks` blocks, a block holds up to
+    /// `block_size` bytes of transactions.
+    pub fn new(max_blocks: usize, block_size: u64) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(max_blocks),
+            max_blocks,
+            block_size,
+        }
+    }
+
+    pub fn record_block(&mut self, histogram: FeeHistogram) {
+        if self.max_blocks == 0 {
+            return;
+        }
+        if self.blocks.len() == self.max_blocks {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(histogram);
+    }
+
+    /// Records the transactions of `body` found in `mempool`, the fees of
+    /// others aren't known. Call before `Mempool::apply_block` removes them.
+    pub fn record_mempool_block<A, C: Serialize, H: HashFunction>(
+        &mut self,
+        mempool: &Mempool<A, C, H>,
+        body: &Body<A, C>,
+    ) -> Result<(), EncodeError> {
+        let mut histogram = FeeHistogram::new();
+        for transaction in &body.transactions {
+            if let Some(entry) = mempool.get(&transaction.try_txid_with::<H>()?) {
+                histogram.add(entry.fee_rate());
+            }
+        }
+        self.record_block(histogram);
+        Ok(())
+    }
+
+    /// Recorded blocks, most recent last.
+    pub fn blocks(&self) -> impl Iterator<Item = &FeeHistogram> {
+        self.blocks.iter()
+    }
+
+    /// Fee rate confirming within `target_blocks` blocks.
+    ///
+    /// History: a transaction confirms in a block if it pays its floor fee
+    /// rate, so the estimate is the floor rate only a few of the recent
+    /// blocks exceeded, fewer for longer targets. Mempool: a transaction has
+    /// to outbid the transactions filling the next `target_blocks` blocks.
+    /// Never below the minimum fee rate of the mempool.
+    pub fn estimate_fee_rate<A, C, H>(
+        &self,
+        target_blocks: u32,
+        mempool: &Mempool<A, C, H>,
+    ) -> FeeRate {
+        let target_blocks = target_blocks.max(1);
+        let mut estimate = mempool.min_fee_rate();
+        if let Some(history) = self.history_estimate(target_blocks) {
+            estimate = estimate.max(history);
+        }
+        let depth = self.block_size.saturating_mul(u64::from(target_blocks));
+        let mut size = 0;
+        let outbid = mempool.iter_by_fee_rate().find(|entry| {
+            size += entry.size;
+            size > depth
+        });
+        if let Some(entry) = outbid {
+            estimate = estimate.max(entry.fee_rate());
+        }
+        estimate
+    }
+
+    fn history_estimate(&self, target_blocks: u32) -> Option<FeeRate> {
+        let mut floors: Vec<_> = self
+            .blocks
+            .iter()
+            .filter_map(|block| block.percentile(FLOOR_PERCENTILE))
+            .collect();
+        if floors.is_empty() {
+            return None;
+        }
+        floors.sort();
+        // Missing all of `target_blocks` blocks must be unlikely.
+        let quantile = 1.0 - (1.0 - CONFIDENCE).powf(1.0 / f64::from(target_blocks));
+        let index = ((floors.len() as f64 * quantile).ceil() as usize).clamp(1, floors.len());
+        Some(floors[index - 1])
+    }
+}
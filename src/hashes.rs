@@ -1,20 +1,250 @@
+use crate::encoding::{encode_canonical_into, EncodeError};
 use bitcoin::hashes::Hash as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::Write as _;
+use std::marker::PhantomData;
+use subtle::{Choice, ConstantTimeEq};
 
-const BLAKE3_LENGTH: usize = 32;
-pub type Hash = [u8; BLAKE3_LENGTH];
+const HASH_LENGTH: usize = 32;
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
-pub struct BlockHash(pub Hash);
+/// Output of a `HashFunction`.
+///
+/// Kept distinct from byte arrays so that hashes don't get mixed up with
+/// keys or addresses, which are 32 bytes long as well.
+#[derive(Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Hash([u8; HASH_LENGTH]);
+
+impl Hash {
+    pub const LENGTH: usize = HASH_LENGTH;
+
+    pub const fn from_bytes(bytes: [u8; HASH_LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns `None` unless `bytes` is exactly `Hash::LENGTH` long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub const fn to_bytes(self) -> [u8; HASH_LENGTH] {
+        self.0
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; HASH_LENGTH] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; HASH_LENGTH]> for Hash {
+    fn from(other: [u8; HASH_LENGTH]) -> Self {
+        Self(other)
+    }
+}
+
+impl From<Hash> for [u8; HASH_LENGTH] {
+    fn from(other: Hash) -> Self {
+        other.0
+    }
+}
+
+impl ConstantTimeEq for Hash {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0[..].ct_eq(&other.0[..])
+    }
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for Hash {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hash = Self::default();
+        hex::decode_to_slice(s, &mut hash.0)?;
+        Ok(hash)
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hash(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_hash(deserializer).map(Self)
+    }
+}
+
+/// Hash function used for txids, block hashes and merkle roots.
+pub trait HashFunction {
+    /// Incremental hasher, data is fed to it with `Write`.
+    type Engine: Default + std::io::Write;
+
+    fn finalize(engine: Self::Engine) -> Hash;
+
+    fn hash_bytes(data: &[u8]) -> Hash {
+        let mut engine = Self::Engine::default();
+        engine
+            .write_all(data)
+            .expect("hash engines never fail to write");
+        Self::finalize(engine)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blake3;
+
+impl HashFunction for Blake3 {
+    type Engine = blake3::Hasher;
+
+    fn finalize(engine: blake3::Hasher) -> Hash {
+        Hash(engine.finalize().into())
+    }
+
+    fn hash_bytes(data: &[u8]) -> Hash {
+        Hash(blake3::hash(data).into())
+    }
+}
+
+/// Double SHA-256, as used by Bitcoin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256d;
+
+impl HashFunction for Sha256d {
+    type Engine = bitcoin::hashes::sha256::HashEngine;
+
+    fn finalize(engine: bitcoin::hashes::sha256::HashEngine) -> Hash {
+        Hash(bitcoin::hashes::sha256d::Hash::from_engine(engine).into_inner())
+    }
+
+    fn hash_bytes(data: &[u8]) -> Hash {
+        Hash(bitcoin::hashes::sha256d::Hash::hash(data).into_inner())
+    }
+}
+
+/// Hash function used by `hash`, `Sha256d` with the `sha256d` feature and
+/// `Blake3` otherwise.
+#[cfg(not(feature = "sha256d"))]
+pub type DefaultHashFunction = Blake3;
+#[cfg(feature = "sha256d")]
+pub type DefaultHashFunction = Sha256d;
+
+/// Key separating the hashes of one network from another, e.g. testnet from
+/// mainnet, or a genesis hash.
+pub trait NetworkKey {
+    const KEY: Hash;
+}
+
+/// `H` over `N::KEY` followed by the data.
+///
+/// Chains that hash with different keys never share txids, block hashes or
+/// merkle roots, so transactions signed on one can't be replayed on the other
+/// even when everything else is identical. Pass it as the `H` parameter of
+/// `StateMachine`, `Mempool` and `HeaderChain`.
+pub struct Keyed<H, N>(PhantomData<fn() -> (H, N)>);
+
+// Implemented by hand, deriving would require `H` and `N` to implement them.
+impl<H, N> std::fmt::Debug for Keyed<H, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Keyed")
+    }
+}
+
+impl<H, N> Clone for Keyed<H, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H, N> Copy for Keyed<H, N> {}
+
+impl<H, N> Default for Keyed<H, N> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<H: HashFunction, N: NetworkKey> HashFunction for Keyed<H, N> {
+    type Engine = KeyedEngine<H, N>;
+
+    fn finalize(engine: KeyedEngine<H, N>) -> Hash {
+        H::finalize(engine.engine)
+    }
+}
+
+/// Engine of `Keyed`, starts out with the key already written.
+pub struct KeyedEngine<H: HashFunction, N> {
+    engine: H::Engine,
+    _marker: PhantomData<fn() -> N>,
+}
+
+impl<H: HashFunction, N: NetworkKey> Default for KeyedEngine<H, N> {
+    fn default() -> Self {
+        let mut engine = H::Engine::default();
+        engine
+            .write_all(N::KEY.as_bytes())
+            .expect("hash engines never fail to write");
+        Self {
+            engine,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<H: HashFunction, N> std::io::Write for KeyedEngine<H, N> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.engine.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.engine.flush()
+    }
+}
+
+#[derive(Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BlockHash(pub [u8; HASH_LENGTH]);
+
+impl BlockHash {
+    /// Parent of the genesis block, and the tip of an empty chain.
+    pub const ZERO: Self = Self([0; HASH_LENGTH]);
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
 
 impl From<Hash> for BlockHash {
     fn from(other: Hash) -> Self {
-        Self(other)
+        Self(other.0)
     }
 }
 
 impl From<BlockHash> for Hash {
     fn from(other: BlockHash) -> Self {
-        other.0
+        Self(other.0)
+    }
+}
+
+impl From<[u8; HASH_LENGTH]> for BlockHash {
+    fn from(other: [u8; HASH_LENGTH]) -> Self {
+        Self(other)
     }
 }
 
@@ -26,8 +256,13 @@ impl From<BlockHash> for Vec<u8> {
 
 impl From<BlockHash> for bitcoin::BlockHash {
     fn from(other: BlockHash) -> Self {
-        let inner: [u8; 32] = other.into();
-        Self::from_inner(inner)
+        Self::from_inner(other.0)
+    }
+}
+
+impl ConstantTimeEq for BlockHash {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0[..].ct_eq(&other.0[..])
     }
 }
 
@@ -43,18 +278,36 @@ impl std::fmt::Debug for BlockHash {
     }
 }
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
-pub struct MerkleRoot(Hash);
+impl Serialize for BlockHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hash(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_hash(deserializer).map(Self)
+    }
+}
+
+#[derive(Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MerkleRoot([u8; HASH_LENGTH]);
 
 impl From<Hash> for MerkleRoot {
     fn from(other: Hash) -> Self {
-        Self(other)
+        Self(other.0)
     }
 }
 
 impl From<MerkleRoot> for Hash {
     fn from(other: MerkleRoot) -> Self {
-        other.0
+        Self(other.0)
+    }
+}
+
+impl ConstantTimeEq for MerkleRoot {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0[..].ct_eq(&other.0[..])
     }
 }
 
@@ -70,8 +323,20 @@ impl std::fmt::Debug for MerkleRoot {
     }
 }
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
-pub struct Txid(pub Hash);
+impl Serialize for MerkleRoot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hash(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MerkleRoot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_hash(deserializer).map(Self)
+    }
+}
+
+#[derive(Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Txid(pub [u8; HASH_LENGTH]);
 
 impl Txid {
     pub fn as_slice(&self) -> &[u8] {
@@ -81,22 +346,34 @@ impl Txid {
 
 impl From<Hash> for Txid {
     fn from(other: Hash) -> Self {
-        Self(other)
+        Self(other.0)
     }
 }
 
 impl From<Txid> for Hash {
     fn from(other: Txid) -> Self {
-        other.0
+        Self(other.0)
     }
 }
 
-impl<'a> From<&'a Txid> for &'a Hash {
+impl From<[u8; HASH_LENGTH]> for Txid {
+    fn from(other: [u8; HASH_LENGTH]) -> Self {
+        Self(other)
+    }
+}
+
+impl<'a> From<&'a Txid> for &'a [u8; HASH_LENGTH] {
     fn from(other: &'a Txid) -> Self {
         &other.0
     }
 }
 
+impl ConstantTimeEq for Txid {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0[..].ct_eq(&other.0[..])
+    }
+}
+
 impl std::fmt::Display for Txid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", hex::encode(self.0))
@@ -109,8 +386,59 @@ impl std::fmt::Debug for Txid {
     }
 }
 
+impl Serialize for Txid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hash(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Txid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_hash(deserializer).map(Self)
+    }
+}
+
+/// Hex string for human readable formats, raw bytes otherwise.
+pub(crate) fn serialize_hash<S: Serializer>(
+    hash: &[u8; HASH_LENGTH],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&hex::encode(hash))
+    } else {
+        hash.serialize(serializer)
+    }
+}
+
+pub(crate) fn deserialize_hash<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; HASH_LENGTH], D::Error> {
+    if deserializer.is_human_readable() {
+        let hex = String::deserialize(deserializer)?;
+        let mut hash = [0; HASH_LENGTH];
+        hex::decode_to_slice(hex, &mut hash).map_err(serde::de::Error::custom)?;
+        Ok(hash)
+    } else {
+        <[u8; HASH_LENGTH]>::deserialize(deserializer)
+    }
+}
+
+/// Panics if `data` fails to serialize, see `try_hash`.
 pub fn hash<T: serde::Serialize>(data: &T) -> Hash {
-    let data_serialized =
-        bincode::serialize(data).expect("failed to serialize a type to compute a hash");
-    blake3::hash(&data_serialized).into()
+    hash_with::<DefaultHashFunction, T>(data)
+}
+
+pub fn hash_with<H: HashFunction, T: serde::Serialize>(data: &T) -> Hash {
+    try_hash_with::<H, T>(data).expect("failed to serialize a type to compute a hash")
+}
+
+pub fn try_hash<T: serde::Serialize>(data: &T) -> Result<Hash, EncodeError> {
+    try_hash_with::<DefaultHashFunction, T>(data)
+}
+
+/// Hashes the encoding as it is produced, without buffering it.
+pub fn try_hash_with<H: HashFunction, T: serde::Serialize>(data: &T) -> Result<Hash, EncodeError> {
+    let mut engine = H::Engine::default();
+    encode_canonical_into(&mut engine, data)?;
+    Ok(H::finalize(engine))
 }
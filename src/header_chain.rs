@@ -0,0 +1,201 @@
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// Default limit on the number of buffered orphan headers.
+pub const MAX_ORPHAN_HEADERS: usize = 10_000;
+
+/// Header tree for headers-first sync.
+///
+/// Headers can be inserted in any order. Headers whose parent is not known yet
+/// are buffered as orphans and linked as soon as the parent arrives. The best
+/// chain is the longest one, on ties the first seen tip wins. Block hashes
+/// are computed with `H`, as in the `StateMachine` the chain is synced to.
+#[derive(Debug, Clone)]
+pub struct HeaderChain<H = DefaultHashFunction> {
+    headers: HashMap<BlockHash, Header>,
+    heights: HashMap<BlockHash, u32>,
+    /// Orphan headers keyed by their missing parent.
+    orphans: HashMap<BlockHash, Vec<Header>>,
+    orphan_count: usize,
+    max_orphans: usize,
+    best_tip: Option<BlockHash>,
+    _marker: PhantomData<H>,
+}
+
+/// Block hashes from a tip back to genesis, for finding the last block two
+/// peers share, as in Bitcoin's `getheaders`.
+///
+/// The first 10 hashes are one block apart, after that the gaps double, so
+/// a locator has about 10 + log2(height) hashes. The genesis hash is always
+/// the last one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLocator {
+    pub hashes: Vec<BlockHash>,
+}
+
+impl BlockLocator {
+    /// Heights of the locator of a chain with its tip at `tip_height`.
+    pub fn heights(tip_height: u32) -> Vec<u32> {
+        let mut heights = Vec::new();
+        let mut height = tip_height;
+        let mut step: u32 = 1;
+        loop {
+            heights.push(height);
+            if height == 0 {
+                return heights;
+            }
+            if heights.len() >= 10 {
+                step = step.saturating_mul(2);
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderStatus {
+    /// Header is linked to the root at this height.
+    Connected { height: u32 },
+    /// Parent is not known yet, header is buffered.
+    Orphan,
+    /// Header was already inserted.
+    Duplicate,
+    /// Parent is not known and the orphan pool is full, header is dropped.
+    OrphanPoolFull,
+}
+
+impl<H: HashFunction> Default for HeaderChain<H> {
+    fn default() -> Self {
+        Self::with_max_orphans(MAX_ORPHAN_HEADERS)
+    }
+}
+
+impl<H: HashFunction> HeaderChain<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_orphans(max_orphans: usize) -> Self {
+        Self {
+            headers: HashMap::new(),
+            heights: HashMap::new(),
+            orphans: HashMap::new(),
+            orphan_count: 0,
+            max_orphans,
+            best_tip: None,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn insert(&mut self, header: Header) -> HeaderStatus {
+        let block_hash = header.hash_with::<H>();
+        if self.headers.contains_key(&block_hash) || self.is_orphan(&header) {
+            return HeaderStatus::Duplicate;
+        }
+        let parent_height = if header.prev_side_hash.is_zero() {
+            None
+        } else {
+            match self.heights.get(&header.prev_side_hash) {
+                Some(height) => Some(*height),
+                None => {
+                    if self.orphan_count >= self.max_orphans {
+                        return HeaderStatus::OrphanPoolFull;
+                    }
+                    self.orphans
+                        .entry(header.prev_side_hash)
+                        .or_default()
+                        .push(header);
+                    self.orphan_count += 1;
+                    return HeaderStatus::Orphan;
+                }
+            }
+        };
+        let height = parent_height.map_or(0, |height| height + 1);
+        self.link(header, height);
+        // Link every orphan descending from this header.
+        let mut stack = vec![(block_hash, height)];
+        while let Some((parent, parent_height)) = stack.pop() {
+            for orphan in self.orphans.remove(&parent).unwrap_or_default() {
+                self.orphan_count -= 1;
+                self.link(orphan, parent_height + 1);
+                stack.push((orphan.hash_with::<H>(), parent_height + 1));
+            }
+        }
+        HeaderStatus::Connected { height }
+    }
+
+    fn link(&mut self, header: Header, height: u32) {
+        let block_hash = header.hash_with::<H>();
+        self.headers.insert(block_hash, header);
+        self.heights.insert(block_hash, height);
+        let best_height = self.best_tip.map(|tip| self.heights[&tip]);
+        if best_height.is_none_or(|best_height| height > best_height) {
+            self.best_tip = Some(block_hash);
+        }
+    }
+
+    fn is_orphan(&self, header: &Header) -> bool {
+        self.orphans
+            .get(&header.prev_side_hash)
+            .is_some_and(|orphans| orphans.contains(header))
+    }
+
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.headers.contains_key(block_hash)
+    }
+
+    pub fn get_header(&self, block_hash: &BlockHash) -> Option<&Header> {
+        self.headers.get(block_hash)
+    }
+
+    pub fn get_height(&self, block_hash: &BlockHash) -> Option<u32> {
+        self.heights.get(block_hash).copied()
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphan_count
+    }
+
+    pub fn best_tip(&self) -> Option<BlockHash> {
+        self.best_tip
+    }
+
+    pub fn best_height(&self) -> Option<u32> {
+        self.best_tip.map(|tip| self.heights[&tip])
+    }
+
+    /// Returns linked headers from the root up to and including `tip`.
+    pub fn get_chain(&self, tip: &BlockHash) -> Vec<Header> {
+        let mut chain = Vec::new();
+        let mut block_hash = *tip;
+        while let Some(header) = self.headers.get(&block_hash) {
+            chain.push(*header);
+            block_hash = header.prev_side_hash;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Locator of the best chain, to request the headers after it.
+    pub fn block_locator(&self) -> BlockLocator {
+        let chain = self.best_chain();
+        let hashes = match chain.len().checked_sub(1) {
+            Some(tip_height) => BlockLocator::heights(tip_height as u32)
+                .into_iter()
+                .map(|height| chain[height as usize].hash_with::<H>())
+                .collect(),
+            None => Vec::new(),
+        };
+        BlockLocator { hashes }
+    }
+
+    /// Returns the best chain from the root, suitable for
+    /// `StateMachine::reorg_to`.
+    pub fn best_chain(&self) -> Vec<Header> {
+        self.best_tip
+            .map(|tip| self.get_chain(&tip))
+            .unwrap_or_default()
+    }
+}
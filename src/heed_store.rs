@@ -0,0 +1,357 @@
+use crate::store::{ReadStore, StateStore, Table, Visitor, WriteBatch, WriteOp};
+use heed::types::Bytes;
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Current on-disk schema version.
+pub const SCHEMA_VERSION: u32 = 12;
+/// Oldest schema version `HeedStore::open` migrates, older stores have to be
+/// synced again from genesis.
+///
+/// Versions 3 to 9 added tables that can't be filled in for blocks already
+/// connected, or changed the encoding of outputs and block hashes. Version
+/// 12 keeps the block body in `Table::Undo`, and bodies aren't stored
+/// anywhere else, so no store written before it can be upgraded.
+pub const MIN_SCHEMA_VERSION: u32 = 12;
+/// Address space reserved for the memory map, actual file grows lazily.
+pub const DEFAULT_MAP_SIZE: usize = 1 << 40;
+
+const SCHEMA_DB: &str = "schema";
+const SCHEMA_VERSION_KEY: &[u8] = b"version";
+
+type Migration = fn(&HeedStore, &mut RwTxn) -> Result<(), HeedStoreError>;
+
+/// `MIGRATIONS[i]` upgrades the schema from version `MIN_SCHEMA_VERSION + i`
+/// to the next one.
+///
+/// Every new table and every encoding change bumps the version. A change
+/// that can't be applied to the blocks already connected raises
+/// `MIN_SCHEMA_VERSION` instead, and drops the migrations below it.
+const MIGRATIONS: &[Migration] = &[];
+
+/// Persistent `StateStore` backed by LMDB.
+///
+/// Every `write` is one LMDB transaction, so a block is either fully
+/// committed or not at all. After a crash the store reopens at the last
+/// committed block, operations spanning several blocks are rolled back with
+/// `StateMachine::recover`.
+///
+/// Stores written at a schema version below `MIN_SCHEMA_VERSION` fail to
+/// open with `HeedStoreError::ResyncRequired`, delete them and sync again.
+#[derive(Clone)]
+pub struct HeedStore {
+    env: Env,
+    schema: Database<Bytes, Bytes>,
+    tables: Vec<Database<Bytes, Bytes>>,
+}
+
+impl HeedStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, HeedStoreError> {
+        Self::open_with_map_size(path, DEFAULT_MAP_SIZE)
+    }
+
+    pub fn open_with_map_size(
+        path: impl AsRef<Path>,
+        map_size: usize,
+    ) -> Result<Self, HeedStoreError> {
+        std::fs::create_dir_all(&path)?;
+        // SAFETY: The environment must not be opened more than once per
+        // process, and the files must not be modified by anything but LMDB.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(map_size)
+                .max_dbs(Table::ALL.len() as u32 + 1)
+                .open(path)?
+        };
+        // Readers of crashed processes may hold on to old pages.
+        env.clear_stale_readers()?;
+        let mut wtxn = env.write_txn()?;
+        let schema = env.create_database(&mut wtxn, Some(SCHEMA_DB))?;
+        let mut tables = Vec::with_capacity(Table::ALL.len());
+        for table in Table::ALL {
+            tables.push(env.create_database(&mut wtxn, Some(table.name()))?);
+        }
+        let store = Self {
+            env: env.clone(),
+            schema,
+            tables,
+        };
+        store.migrate(&mut wtxn)?;
+        wtxn.commit()?;
+        Ok(store)
+    }
+
+    pub fn schema_version(&self) -> Result<u32, HeedStoreError> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.read_schema_version(&rtxn)?.unwrap_or(SCHEMA_VERSION))
+    }
+
+    fn read_schema_version(&self, rtxn: &heed::RoTxn) -> Result<Option<u32>, HeedStoreError> {
+        match self.schema.get(rtxn, SCHEMA_VERSION_KEY)? {
+            Some(version) => {
+                let version = version
+                    .try_into()
+                    .map_err(|_| HeedStoreError::MalformedSchemaVersion)?;
+                Ok(Some(u32::from_be_bytes(version)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn migrate(&self, wtxn: &mut RwTxn) -> Result<(), HeedStoreError> {
+        // A store without a version was just created.
+        let mut version = self.read_schema_version(wtxn)?.unwrap_or(SCHEMA_VERSION);
+        if version > SCHEMA_VERSION {
+            return Err(HeedStoreError::UnsupportedSchemaVersion { version });
+        }
+        if version < MIN_SCHEMA_VERSION {
+            return Err(HeedStoreError::ResyncRequired { version });
+        }
+        while version < SCHEMA_VERSION {
+            MIGRATIONS[(version - MIN_SCHEMA_VERSION) as usize](self, wtxn)?;
+            version += 1;
+        }
+        self.schema
+            .put(wtxn, SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn database(&self, table: Table) -> Database<Bytes, Bytes> {
+        self.tables[table as usize]
+    }
+
+    /// Flushes buffers to disk, only needed when the environment is opened
+    /// with relaxed sync flags.
+    pub fn sync(&self) -> Result<(), HeedStoreError> {
+        Ok(self.env.force_sync()?)
+    }
+}
+
+/// Read-only view of a `HeedStore`, backed by one LMDB read transaction.
+///
+/// Clones share the transaction. Long lived views keep LMDB from reusing
+/// pages freed by later writes, so the file grows until they are dropped.
+#[derive(Clone)]
+pub struct HeedView {
+    rtxn: Arc<Mutex<RoTxn<'static>>>,
+    tables: Vec<Database<Bytes, Bytes>>,
+}
+
+impl HeedView {
+    fn database(&self, table: Table) -> Database<Bytes, Bytes> {
+        self.tables[table as usize]
+    }
+
+    fn rtxn(&self) -> MutexGuard<'_, RoTxn<'static>> {
+        // The transaction is only read, so it's fine to use after a panic.
+        self.rtxn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn get(
+    database: Database<Bytes, Bytes>,
+    rtxn: &RoTxn,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, HeedStoreError> {
+    let value = database.get(rtxn, key)?;
+    Ok(value.map(<[u8]>::to_vec))
+}
+
+fn iterate(
+    database: Database<Bytes, Bytes>,
+    rtxn: &RoTxn,
+    f: &mut Visitor<'_>,
+) -> Result<(), HeedStoreError> {
+    for item in database.iter(rtxn)? {
+        let (key, value) = item?;
+        if f(key, value).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn iterate_prefix(
+    database: Database<Bytes, Bytes>,
+    rtxn: &RoTxn,
+    prefix: &[u8],
+    f: &mut Visitor<'_>,
+) -> Result<(), HeedStoreError> {
+    for item in database.prefix_iter(rtxn, prefix)? {
+        let (key, value) = item?;
+        if f(key, value).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl ReadStore for HeedStore {
+    type Error = HeedStoreError;
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, HeedStoreError> {
+        get(self.database(table), &self.env.read_txn()?, key)
+    }
+
+    fn iterate(&self, table: Table, f: &mut Visitor<'_>) -> Result<(), HeedStoreError> {
+        iterate(self.database(table), &self.env.read_txn()?, f)
+    }
+
+    fn iterate_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+    ) -> Result<(), HeedStoreError> {
+        iterate_prefix(self.database(table), &self.env.read_txn()?, prefix, f)
+    }
+}
+
+impl ReadStore for HeedView {
+    type Error = HeedStoreError;
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, HeedStoreError> {
+        get(self.database(table), &self.rtxn(), key)
+    }
+
+    fn iterate(&self, table: Table, f: &mut Visitor<'_>) -> Result<(), HeedStoreError> {
+        iterate(self.database(table), &self.rtxn(), f)
+    }
+
+    fn iterate_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+    ) -> Result<(), HeedStoreError> {
+        iterate_prefix(self.database(table), &self.rtxn(), prefix, f)
+    }
+}
+
+impl StateStore for HeedStore {
+    type View = HeedView;
+
+    fn view(&self) -> Result<HeedView, HeedStoreError> {
+        let rtxn = self.env.clone().static_read_txn()?;
+        Ok(HeedView {
+            rtxn: Arc::new(Mutex::new(rtxn)),
+            tables: self.tables.clone(),
+        })
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<(), HeedStoreError> {
+        let mut wtxn = self.env.write_txn()?;
+        for op in batch {
+            match op {
+                WriteOp::Put { table, key, value } => {
+                    self.database(table).put(&mut wtxn, &key, &value)?;
+                }
+                WriteOp::Delete { table, key } => {
+                    self.database(table).delete(&mut wtxn, &key)?;
+                }
+            }
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeedStoreError {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("lmdb error")]
+    Heed(#[from] heed::Error),
+    #[error("unsupported schema version {version}, latest is {SCHEMA_VERSION}")]
+    UnsupportedSchemaVersion { version: u32 },
+    #[error(
+        "schema version {version} can't be migrated, stores older than {MIN_SCHEMA_VERSION} have to be synced again"
+    )]
+    ResyncRequired { version: u32 },
+    #[error("schema version is malformed")]
+    MalformedSchemaVersion,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Temporary store directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("sdk_types-heed-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const MAP_SIZE: usize = 1 << 20;
+
+    /// Creates a store and rewrites its schema version, as if it was written
+    /// by an older or newer release.
+    fn write_store(dir: &TempDir, version: u32) {
+        let mut store = HeedStore::open_with_map_size(&dir.0, MAP_SIZE).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(Table::Meta, b"key".to_vec(), b"value".to_vec());
+        store.write(batch).unwrap();
+        let mut wtxn = store.env.write_txn().unwrap();
+        store
+            .schema
+            .put(&mut wtxn, SCHEMA_VERSION_KEY, &version.to_be_bytes())
+            .unwrap();
+        wtxn.commit().unwrap();
+    }
+
+    #[test]
+    fn reopens_current_store() {
+        let dir = TempDir::new("current");
+        write_store(&dir, SCHEMA_VERSION);
+        let store = HeedStore::open_with_map_size(&dir.0, MAP_SIZE).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(
+            store.get(Table::Meta, b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn refuses_old_store() {
+        for version in [1, 2, 9, 11] {
+            let dir = TempDir::new(&format!("v{version}"));
+            write_store(&dir, version);
+            assert!(matches!(
+                HeedStore::open_with_map_size(&dir.0, MAP_SIZE),
+                Err(HeedStoreError::ResyncRequired { version: v }) if v == version
+            ));
+            // The refused store is left as it was.
+            let result = HeedStore::open_with_map_size(&dir.0, MAP_SIZE);
+            assert!(matches!(result, Err(HeedStoreError::ResyncRequired { .. })));
+        }
+    }
+
+    #[test]
+    fn refuses_newer_store() {
+        let dir = TempDir::new("newer");
+        write_store(&dir, SCHEMA_VERSION + 1);
+        assert!(matches!(
+            HeedStore::open_with_map_size(&dir.0, MAP_SIZE),
+            Err(HeedStoreError::UnsupportedSchemaVersion { version })
+                if version == SCHEMA_VERSION + 1
+        ));
+    }
+}
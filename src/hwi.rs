@@ -0,0 +1,174 @@
+use crate::authorization::KeyAuthorization;
+use crate::encoding::EncodeError;
+use crate::psst::Psst;
+use crate::types::*;
+use bitcoin::secp256k1::{ecdsa, Message, PublicKey, Secp256k1};
+use bitcoin::util::bip32::DerivationPath;
+use serde::{Deserialize, Serialize};
+
+/// What an external signer, like a hardware wallet, is asked to sign.
+///
+/// Every input signs the same message, the txid, the metadata lets the
+/// device show what is spent before signing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignRequest {
+    pub sighash: [u8; 32],
+    pub inputs: Vec<SignRequestInput>,
+    /// Outputs of the transaction, in output order.
+    pub outputs: Vec<(Address, u64)>,
+    pub fee: u64,
+    pub change_vout: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignRequestInput {
+    /// Position of the input in the transaction.
+    pub input: usize,
+    pub address: Address,
+    /// Path of the key from the master key of the device.
+    pub derivation_path: DerivationPath,
+    pub value: u64,
+}
+
+/// Signature returned by an external signer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalSignature {
+    pub input: usize,
+    pub public_key: PublicKey,
+    pub signature: ecdsa::Signature,
+}
+
+/// Device signing the inputs of a `SignRequest` with the keys at their
+/// derivation paths.
+///
+/// It may sign only some of the inputs, for example the ones of its own
+/// master key.
+pub trait ExternalSigner {
+    type Error;
+    fn sign(&self, request: &SignRequest) -> Result<Vec<ExternalSignature>, Self::Error>;
+}
+
+impl<C: GetValue + Serialize> Psst<KeyAuthorization, C> {
+    /// Request for the unauthorized inputs with a derivation path, `None` if
+    /// there are none.
+    pub fn sign_request(&self) -> Result<Option<SignRequest>, EncodeError> {
+        self.sign_request_with::<DefaultHashFunction>()
+    }
+
+    /// `sign_request` for chains hashing with `H`.
+    pub fn sign_request_with<H: HashFunction>(&self) -> Result<Option<SignRequest>, EncodeError> {
+        let inputs: Vec<_> = self
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| input.authorization.is_none())
+            .filter_map(|(index, input)| {
+                Some(SignRequestInput {
+                    input: index,
+                    address: input.spent.address,
+                    derivation_path: input.derivation_path.clone()?,
+                    value: input.spent.get_value(),
+                })
+            })
+            .collect();
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(SignRequest {
+            sighash: self.txid_with::<H>()?.0,
+            inputs,
+            outputs: self
+                .transaction
+                .outputs
+                .iter()
+                .map(|output| (output.address, output.get_value()))
+                .collect(),
+            fee: self.fee,
+            change_vout: self.change_vout,
+        }))
+    }
+
+    /// Adds the signatures returned for the request of `sign_request`, after
+    /// checking each signs the txid with the key of its input's address.
+    /// Returns how many inputs were authorized.
+    pub fn add_external_signatures(
+        &mut self,
+        signatures: &[ExternalSignature],
+    ) -> Result<usize, ExternalSignatureError> {
+        self.add_external_signatures_with::<DefaultHashFunction>(signatures)
+    }
+
+    /// `add_external_signatures` for chains hashing with `H`.
+    pub fn add_external_signatures_with<H: HashFunction>(
+        &mut self,
+        signatures: &[ExternalSignature],
+    ) -> Result<usize, ExternalSignatureError> {
+        let secp = Secp256k1::verification_only();
+        let message =
+            Message::from_slice(&self.txid_with::<H>()?.0).expect("txids are 32 bytes long");
+        let mut signed = 0;
+        for signature in signatures {
+            let input = signature.input;
+            let psst_input = self
+                .inputs
+                .get_mut(input)
+                .ok_or(ExternalSignatureError::UnknownInput { input })?;
+            if Address::from_public_key(&signature.public_key) != psst_input.spent.address {
+                return Err(ExternalSignatureError::WrongKey { input });
+            }
+            secp.verify_ecdsa(&message, &signature.signature, &signature.public_key)
+                .map_err(|_| ExternalSignatureError::InvalidSignature { input })?;
+            if psst_input.authorization.is_none() {
+                signed += 1;
+            }
+            psst_input.authorization = Some(KeyAuthorization {
+                public_key: signature.public_key,
+                signature: signature.signature,
+            });
+        }
+        Ok(signed)
+    }
+
+    /// Asks `signer` to sign and adds its signatures, returns how many inputs
+    /// it authorized.
+    pub fn sign_external<S: ExternalSigner>(
+        &mut self,
+        signer: &S,
+    ) -> Result<usize, ExternalSignError<S::Error>> {
+        self.sign_external_with::<DefaultHashFunction, S>(signer)
+    }
+
+    /// `sign_external` for chains hashing with `H`.
+    pub fn sign_external_with<H: HashFunction, S: ExternalSigner>(
+        &mut self,
+        signer: &S,
+    ) -> Result<usize, ExternalSignError<S::Error>> {
+        let Some(request) = self.sign_request_with::<H>()? else {
+            return Ok(0);
+        };
+        let signatures = signer.sign(&request).map_err(ExternalSignError::Signer)?;
+        Ok(self.add_external_signatures_with::<H>(&signatures)?)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExternalSignatureError {
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("signature for input {input}, which doesn't exist")]
+    UnknownInput { input: usize },
+    #[error("signature for input {input} is made with another key")]
+    WrongKey { input: usize },
+    #[error("invalid signature for input {input}")]
+    InvalidSignature { input: usize },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExternalSignError<E> {
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("external signer error")]
+    Signer(#[source] E),
+    #[error("invalid external signature")]
+    Signature(#[from] ExternalSignatureError),
+}
@@ -0,0 +1,283 @@
+use crate::encoding::{decode_canonical, encode_canonical, EncodeError};
+use crate::wallet::{Wallet, WalletError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore as _;
+use serde::{Deserialize, Serialize};
+use std::io::Write as _;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+const VERSION: u8 = 1;
+const KEY_LENGTH: usize = 32;
+
+/// Argon2id cost parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The OWASP recommendation of 64 MiB and three passes.
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Ceilings for parameters read from keystore files, 4 GiB of memory,
+    /// so a crafted file can't make unlocking run out of memory or hang.
+    pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+    pub const MAX_ITERATIONS: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 64;
+
+    fn check(&self) -> Result<(), KeystoreError> {
+        if self.memory_kib > Self::MAX_MEMORY_KIB
+            || self.iterations > Self::MAX_ITERATIONS
+            || self.parallelism > Self::MAX_PARALLELISM
+        {
+            return Err(KeystoreError::KdfParamsTooHigh { kdf: *self });
+        }
+        Ok(())
+    }
+
+    fn derive_key(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; KEY_LENGTH]>, KeystoreError> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_LENGTH),
+        )?;
+        let mut key = Zeroizing::new([0; KEY_LENGTH]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+            password,
+            salt,
+            key.as_mut(),
+        )?;
+        Ok(key)
+    }
+}
+
+/// What a keystore file holds, the seed encrypted with XChaCha20-Poly1305
+/// under a key derived from the password with Argon2id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct EncryptedSeed {
+    version: u8,
+    kdf: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedSeed {
+    fn encrypt(seed: &[u8], password: &[u8], kdf: KdfParams) -> Result<Self, KeystoreError> {
+        // Keystores that couldn't be loaded back aren't created.
+        kdf.check()?;
+        let mut rng = rand::rngs::OsRng;
+        let mut encrypted = Self {
+            version: VERSION,
+            kdf,
+            salt: [0; 16],
+            nonce: [0; 24],
+            ciphertext: Vec::new(),
+        };
+        rng.fill_bytes(&mut encrypted.salt);
+        rng.fill_bytes(&mut encrypted.nonce);
+        let key = kdf.derive_key(password, &encrypted.salt)?;
+        let payload = Payload {
+            msg: seed,
+            aad: &encrypted.associated_data()?,
+        };
+        encrypted.ciphertext = XChaCha20Poly1305::new(key.as_ref().into())
+            .encrypt(XNonce::from_slice(&encrypted.nonce), payload)
+            .map_err(|_| KeystoreError::Encryption)?;
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, password: &[u8]) -> Result<Zeroizing<Vec<u8>>, KeystoreError> {
+        let key = self.kdf.derive_key(password, &self.salt)?;
+        let payload = Payload {
+            msg: &self.ciphertext,
+            aad: &self.associated_data()?,
+        };
+        XChaCha20Poly1305::new(key.as_ref().into())
+            .decrypt(XNonce::from_slice(&self.nonce), payload)
+            .map(Zeroizing::new)
+            .map_err(|_| KeystoreError::WrongPassword)
+    }
+
+    /// Everything but the ciphertext is authenticated, so the parameters
+    /// can't be swapped.
+    fn associated_data(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_canonical(&(self.version, &self.kdf, &self.salt, &self.nonce))
+    }
+}
+
+/// Wallet seed encrypted with a password.
+///
+/// Unlocking keeps the decrypted seed in memory until `lock` or drop, it is
+/// zeroed either way.
+pub struct Keystore {
+    encrypted: EncryptedSeed,
+    seed: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl Keystore {
+    /// Encrypts `seed`, the keystore starts unlocked.
+    pub fn create(seed: &[u8], password: &[u8], kdf: KdfParams) -> Result<Self, KeystoreError> {
+        Ok(Self {
+            encrypted: EncryptedSeed::encrypt(seed, password, kdf)?,
+            seed: Some(Zeroizing::new(seed.to_vec())),
+        })
+    }
+
+    /// Keystore with a new random 32 byte seed.
+    pub fn generate(password: &[u8], kdf: KdfParams) -> Result<Self, KeystoreError> {
+        let mut seed = Zeroizing::new([0; 32]);
+        rand::rngs::OsRng.fill_bytes(seed.as_mut());
+        Self::create(seed.as_ref(), password, kdf)
+    }
+
+    /// Locked keystore from the bytes of `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeystoreError> {
+        let encrypted: EncryptedSeed = decode_canonical(bytes)?;
+        if encrypted.version != VERSION {
+            return Err(KeystoreError::UnsupportedVersion {
+                version: encrypted.version,
+            });
+        }
+        encrypted.kdf.check()?;
+        Ok(Self {
+            encrypted,
+            seed: None,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, KeystoreError> {
+        Ok(encode_canonical(&self.encrypted)?)
+    }
+
+    /// Locked keystore read from the file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KeystoreError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Writes the encrypted seed to a temporary file next to `path` and
+    /// moves it over `path`, so a crash never leaves half a keystore.
+    ///
+    /// On Unix the file is only readable by its owner, and both the file and
+    /// its directory are synced before returning.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), KeystoreError> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        // A stale temporary file would keep its permissions.
+        match std::fs::remove_file(&temporary) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(&self.to_bytes()?)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temporary, path)?;
+        // The rename itself is only durable once the directory is synced.
+        #[cfg(unix)]
+        {
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            std::fs::File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.seed.is_none()
+    }
+
+    pub fn unlock(&mut self, password: &[u8]) -> Result<(), KeystoreError> {
+        if self.seed.is_none() {
+            self.seed = Some(self.encrypted.decrypt(password)?);
+        }
+        Ok(())
+    }
+
+    /// Zeroes and forgets the decrypted seed.
+    pub fn lock(&mut self) {
+        self.seed = None;
+    }
+
+    /// Decrypted seed, `None` while locked.
+    pub fn seed(&self) -> Option<&[u8]> {
+        self.seed.as_deref().map(Vec::as_slice)
+    }
+
+    /// Encrypts the seed again under `new_password`, requires `password`
+    /// even while unlocked.
+    pub fn change_password(
+        &mut self,
+        password: &[u8],
+        new_password: &[u8],
+        kdf: KdfParams,
+    ) -> Result<(), KeystoreError> {
+        let seed = self.encrypted.decrypt(password)?;
+        self.encrypted = EncryptedSeed::encrypt(&seed, new_password, kdf)?;
+        Ok(())
+    }
+
+    /// Wallet with the default account of the seed.
+    pub fn wallet(&self) -> Result<Wallet, KeystoreError> {
+        let seed = self.seed().ok_or(KeystoreError::Locked)?;
+        Ok(Wallet::from_seed(seed)?)
+    }
+}
+
+impl std::fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keystore")
+            .field("kdf", &self.encrypted.kdf)
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeystoreError {
+    #[error("wrong password or corrupted keystore")]
+    WrongPassword,
+    #[error("keystore is locked")]
+    Locked,
+    #[error("unsupported keystore version {version}")]
+    UnsupportedVersion { version: u8 },
+    #[error("key derivation parameters {kdf:?} exceed the limits")]
+    KdfParamsTooHigh { kdf: KdfParams },
+    #[error("key derivation error")]
+    Kdf(#[from] argon2::Error),
+    #[error("encryption failed")]
+    Encryption,
+    #[error("wallet error")]
+    Wallet(#[from] WalletError),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("decode error")]
+    Decode(#[from] bincode::Error),
+}
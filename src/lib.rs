@@ -1,9 +1,120 @@
 mod address;
+mod address_book;
+#[cfg(feature = "async")]
+mod async_utxo;
+mod audit;
+mod authorization;
+mod bloom;
+mod bmm;
+mod coin_selection;
+mod compact_block;
+mod decode_limits;
+mod deposit;
+mod encoding;
+mod fee_estimator;
 mod hashes;
+mod header_chain;
+#[cfg(feature = "heed")]
+mod heed_store;
+#[cfg(feature = "hwi")]
+mod hwi;
+#[cfg(feature = "keystore")]
+mod keystore;
+mod listener;
+mod mainchain;
+mod mainchain_tracker;
+mod mempool;
+mod multisig;
+mod params;
+#[cfg(feature = "payment-codes")]
+mod payment_code;
+mod policy;
+#[cfg(feature = "proto")]
+/// Messages for talking to a CUSF enforcer, the mainchain node validating the
+/// drivechain rules, with conversions to the crate's types.
+///
+/// Hashes are in internal byte order, transactions in consensus encoding and
+/// sidechain addresses in the encoding of `Address::to_bytes`.
+pub mod proto;
+mod psst;
+/// JSON-RPC 2.0 requests and responses of the standard node methods, so that
+/// nodes and GUIs agree on their JSON.
+///
+/// Blocks and sidechain transactions are hex encoded in the encoding of
+/// `encode_canonical`, bundle transactions in consensus encoding.
+pub mod rpc;
+mod shared_utxo;
+mod short_id;
+#[cfg(feature = "sled")]
+mod sled_utxo;
+mod spv;
+pub mod state;
+mod stats;
+mod store;
+mod taproot;
+mod transaction_builder;
 mod types;
+mod utxo;
 mod validator;
+#[cfg(feature = "vanity")]
+mod vanity;
+mod wallet;
+mod wallet_scanner;
+mod watch_only;
+mod withdrawal;
 
-pub use types::*;
-pub use validator::*;
+pub use address_book::*;
+#[cfg(feature = "async")]
+pub use async_utxo::*;
+pub use audit::*;
+pub use authorization::*;
 pub use bitcoin;
+pub use bloom::*;
+pub use bmm::*;
 pub use bs58;
+pub use coin_selection::*;
+pub use compact_block::*;
+#[cfg(feature = "dashmap")]
+pub use dashmap;
+pub use decode_limits::*;
+pub use deposit::*;
+pub use encoding::*;
+pub use fee_estimator::*;
+pub use header_chain::*;
+#[cfg(feature = "heed")]
+pub use heed_store::*;
+#[cfg(feature = "hwi")]
+pub use hwi::*;
+#[cfg(feature = "keystore")]
+pub use keystore::*;
+pub use listener::*;
+pub use mainchain::*;
+pub use mainchain_tracker::*;
+pub use mempool::*;
+pub use multisig::*;
+pub use params::*;
+#[cfg(feature = "payment-codes")]
+pub use payment_code::*;
+pub use policy::*;
+pub use psst::*;
+pub use short_id::*;
+#[cfg(feature = "sled")]
+pub use sled;
+#[cfg(feature = "sled")]
+pub use sled_utxo::*;
+pub use spv::*;
+pub use state::{PendingDeposit, StateMachine, WalletBalance};
+pub use stats::*;
+pub use store::*;
+pub use subtle;
+pub use taproot::*;
+pub use transaction_builder::*;
+pub use types::*;
+pub use utxo::*;
+pub use validator::*;
+#[cfg(feature = "vanity")]
+pub use vanity::*;
+pub use wallet::*;
+pub use wallet_scanner::*;
+pub use watch_only::*;
+pub use withdrawal::*;
@@ -0,0 +1,86 @@
+use crate::types::*;
+use std::sync::Arc;
+
+/// Receives `StateMachine` changes after they are committed to the store.
+///
+/// UTXO events of a block are fired before the block event. Disconnecting a
+/// block fires `on_utxo_spent` for outputs it created and `on_utxo_created`
+/// for outputs it spent. Locking a withdrawal output into a bundle fires
+/// `on_utxo_spent`, unlocking it fires `on_utxo_created`.
+pub trait StateListener<C> {
+    fn on_block_connected(&self, _header: &Header, _height: u32) {}
+    fn on_block_disconnected(&self, _header: &Header, _height: u32) {}
+    fn on_utxo_created(&self, _outpoint: &OutPoint, _output: &Output<C>) {}
+    fn on_utxo_spent(&self, _outpoint: &OutPoint) {}
+    fn on_main_block_connected(&self, _events: &MainBlockEvents<C>) {}
+    fn on_main_block_disconnected(&self, _events: &MainBlockEvents<C>) {}
+}
+
+pub(crate) struct Listeners<C>(Vec<Arc<dyn StateListener<C> + Send + Sync>>);
+
+impl<C> Listeners<C> {
+    pub(crate) fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub(crate) fn push(&mut self, listener: Arc<dyn StateListener<C> + Send + Sync>) {
+        self.0.push(listener);
+    }
+
+    pub(crate) fn utxos_created<'a>(
+        &self,
+        utxos: impl IntoIterator<Item = &'a (OutPoint, Output<C>)>,
+    ) where
+        C: 'a,
+    {
+        for (outpoint, output) in utxos {
+            for listener in &self.0 {
+                listener.on_utxo_created(outpoint, output);
+            }
+        }
+    }
+
+    pub(crate) fn utxos_spent<'a>(&self, outpoints: impl IntoIterator<Item = &'a OutPoint>) {
+        for outpoint in outpoints {
+            for listener in &self.0 {
+                listener.on_utxo_spent(outpoint);
+            }
+        }
+    }
+
+    pub(crate) fn block_connected(&self, header: &Header, height: u32) {
+        for listener in &self.0 {
+            listener.on_block_connected(header, height);
+        }
+    }
+
+    pub(crate) fn main_block_connected(&self, events: &MainBlockEvents<C>) {
+        for listener in &self.0 {
+            listener.on_main_block_connected(events);
+        }
+    }
+
+    pub(crate) fn main_block_disconnected(&self, events: &MainBlockEvents<C>) {
+        for listener in &self.0 {
+            listener.on_main_block_disconnected(events);
+        }
+    }
+
+    pub(crate) fn block_disconnected(&self, header: &Header, height: u32) {
+        for listener in &self.0 {
+            listener.on_block_disconnected(header, height);
+        }
+    }
+}
+
+impl<C> Clone for Listeners<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C> std::fmt::Debug for Listeners<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Listeners({})", self.0.len())
+    }
+}
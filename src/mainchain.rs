@@ -0,0 +1,81 @@
+use crate::deposit::{DepositProof, DepositProofError, DepositScriptError};
+use crate::params::SidechainParams;
+use crate::types::*;
+use bitcoin::util::uint::Uint256;
+
+/// Parent chain the sidechain is pegged to.
+///
+/// Peg code written against it works over any parent chain, or a mock chain
+/// in tests. `Bitcoin` implements it with the types `StateMachine` stores.
+pub trait MainChain {
+    /// Identifies a deposit on the parent chain.
+    type OutPoint;
+    /// Where withdrawals are paid out on the parent chain.
+    type Address;
+    /// Evidence that a deposit is in the parent chain.
+    type DepositProof;
+    type Error;
+
+    /// Checks `proof` and returns the deposit and the sidechain output it
+    /// creates.
+    fn verify_deposit<C>(
+        &self,
+        proof: &Self::DepositProof,
+    ) -> Result<(Self::OutPoint, Output<C>), Self::Error>;
+
+    /// Whether withdrawals can be paid out to `address`.
+    fn is_valid_payout_address(&self, address: &Self::Address) -> bool;
+
+    /// Smallest value the parent chain relays in a payout to `address`.
+    fn dust_value(&self, address: &Self::Address) -> u64;
+}
+
+/// Bitcoin as the parent chain, with SPV deposit proofs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitcoin {
+    pub network: bitcoin::Network,
+    pub sidechain: SidechainParams,
+    /// Work deposit proofs must show, see `DepositProof::verify`.
+    pub min_work: Uint256,
+}
+
+impl MainChain for Bitcoin {
+    type OutPoint = bitcoin::OutPoint;
+    type Address = bitcoin::Address;
+    type DepositProof = DepositProof;
+    type Error = DepositProofError;
+
+    /// The deposit value is the value of the treasury output, see
+    /// `SidechainParams::parse_deposit`.
+    fn verify_deposit<C>(
+        &self,
+        proof: &DepositProof,
+    ) -> Result<(bitcoin::OutPoint, Output<C>), DepositProofError> {
+        let address = proof.verify(&self.sidechain, self.min_work)?;
+        let treasury_script = self.sidechain.treasury_script();
+        let (vout, treasury) = proof
+            .tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == treasury_script)
+            .ok_or(DepositScriptError::NoTreasuryOutput)?;
+        let outpoint = bitcoin::OutPoint {
+            txid: proof.tx.txid(),
+            vout: vout as u32,
+        };
+        let output = Output {
+            address,
+            content: Content::Value(treasury.value),
+        };
+        Ok((outpoint, output))
+    }
+
+    fn is_valid_payout_address(&self, address: &bitcoin::Address) -> bool {
+        address.is_valid_for_network(self.network)
+    }
+
+    fn dust_value(&self, address: &bitcoin::Address) -> u64 {
+        address.script_pubkey().dust_value().to_sat()
+    }
+}
@@ -0,0 +1,130 @@
+use crate::state::{Error, StateMachine};
+use crate::store::StateStore;
+use crate::types::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Mainchain block connected to the state, with the events it was connected
+/// with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackedMainBlock<C> {
+    pub block_hash: bitcoin::BlockHash,
+    pub events: MainBlockEvents<C>,
+}
+
+/// Mainchain blocks connected to a `StateMachine`, in connection order.
+///
+/// Remembers the events of every block so a mainchain reorg can be applied
+/// from the hash of the fork point alone. It can be serialized to persist it
+/// next to the state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MainchainTracker<C> {
+    /// Mainchain block the tracked blocks build on, `None` if they start at
+    /// the mainchain genesis.
+    base: Option<bitcoin::BlockHash>,
+    blocks: Vec<TrackedMainBlock<C>>,
+}
+
+impl<C> Default for MainchainTracker<C> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl<C> MainchainTracker<C> {
+    /// Tracker for blocks connected after `base`.
+    pub fn new(base: Option<bitcoin::BlockHash>) -> Self {
+        Self {
+            base,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Last connected mainchain block, the base if none was connected.
+    pub fn tip(&self) -> Option<bitcoin::BlockHash> {
+        self.blocks
+            .last()
+            .map(|block| block.block_hash)
+            .or(self.base)
+    }
+
+    pub fn blocks(&self) -> &[TrackedMainBlock<C>] {
+        &self.blocks
+    }
+
+    pub fn get_events(&self, block_hash: &bitcoin::BlockHash) -> Option<&MainBlockEvents<C>> {
+        self.blocks
+            .iter()
+            .rev()
+            .find(|block| block.block_hash == *block_hash)
+            .map(|block| &block.events)
+    }
+
+    pub fn contains(&self, block_hash: &bitcoin::BlockHash) -> bool {
+        self.base == Some(*block_hash) || self.get_events(block_hash).is_some()
+    }
+
+    /// Forgets the oldest blocks, keeping the last `depth`, for mainchain
+    /// reorgs deeper than that are not expected. The newest forgotten block
+    /// becomes the base.
+    pub fn prune(&mut self, depth: usize) {
+        let Some(count) = self.blocks.len().checked_sub(depth) else {
+            return;
+        };
+        if let Some(block) = count.checked_sub(1).map(|last| &self.blocks[last]) {
+            self.base = Some(block.block_hash);
+        }
+        self.blocks.drain(..count);
+    }
+}
+
+impl<C: Clone + Serialize + DeserializeOwned> MainchainTracker<C> {
+    /// Connects the peg events of the mainchain block `block_hash` to `state`
+    /// and records them.
+    pub fn connect<A, S: StateStore, H: HashFunction>(
+        &mut self,
+        state: &mut StateMachine<A, C, S, H>,
+        block_hash: bitcoin::BlockHash,
+        events: MainBlockEvents<C>,
+    ) -> Result<(), Error> {
+        state.connect_main_block(&events)?;
+        self.blocks.push(TrackedMainBlock { block_hash, events });
+        Ok(())
+    }
+
+    /// Disconnects every block after `block_hash` from `state`, newest first,
+    /// after the mainchain reorged to a chain forking at `block_hash`.
+    ///
+    /// Returns the sidechain blocks disconnected because they spent reverted
+    /// deposits, starting from the old tip. If a block fails to disconnect
+    /// the blocks before it stay tracked and connected.
+    pub fn reorg_to<A, S: StateStore, H: HashFunction>(
+        &mut self,
+        state: &mut StateMachine<A, C, S, H>,
+        block_hash: bitcoin::BlockHash,
+    ) -> Result<Vec<BlockHash>, MainchainTrackerError> {
+        let keep = if self.base == Some(block_hash) {
+            0
+        } else {
+            self.blocks
+                .iter()
+                .rposition(|block| block.block_hash == block_hash)
+                .ok_or(MainchainTrackerError::UnknownMainBlock { block_hash })?
+                + 1
+        };
+        let mut disconnected = Vec::new();
+        while self.blocks.len() > keep {
+            let block = self.blocks.last().expect("more blocks than kept");
+            disconnected.extend(state.disconnect_main_block(&block.events)?);
+            self.blocks.pop();
+        }
+        Ok(disconnected)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MainchainTrackerError {
+    #[error("mainchain block {block_hash} is not tracked")]
+    UnknownMainBlock { block_hash: bitcoin::BlockHash },
+    #[error("state error")]
+    State(#[from] Error),
+}
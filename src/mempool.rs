@@ -0,0 +1,1715 @@
+use crate::encoding::{encode_canonical, EncodeError};
+use crate::state::ReorgOutcome;
+use crate::types::*;
+use crate::utxo::UtxoMap;
+use crate::validator;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Fee per encoded byte, compared without rounding.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FeeRate {
+    pub fee: u64,
+    pub size: u64,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, size: u64) -> Self {
+        Self { fee, size }
+    }
+
+    pub fn sats_per_byte(&self) -> f64 {
+        self.fee as f64 / self.size.max(1) as f64
+    }
+
+    /// Fee of `size` bytes at this rate, rounded up.
+    pub fn fee_for(&self, size: u64) -> u64 {
+        let fee = (u128::from(self.fee) * u128::from(size)).div_ceil(u128::from(self.size.max(1)));
+        u64::try_from(fee).unwrap_or(u64::MAX)
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let this = u128::from(self.fee) * u128::from(other.size);
+        let other = u128::from(other.fee) * u128::from(self.size);
+        this.cmp(&other)
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// Rules for accepting transactions conflicting with mempool transactions,
+/// in the style of BIP 125.
+///
+/// A replacement evicts the transactions it conflicts with and all their
+/// descendants. It must pay a higher fee rate than each transaction it
+/// conflicts with, and at least the fees of all evicted transactions plus
+/// `incremental_fee_rate` for its own size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplacementRules {
+    pub incremental_fee_rate: FeeRate,
+    /// Most transactions one replacement can evict.
+    pub max_replaced: usize,
+}
+
+impl Default for ReplacementRules {
+    fn default() -> Self {
+        Self {
+            incremental_fee_rate: FeeRate::new(1, 1),
+            max_replaced: 100,
+        }
+    }
+}
+
+/// Bounds of a `Mempool`.
+///
+/// When the transactions take more than `max_size` bytes, the packages of
+/// transactions with the lowest fee rate of them and their descendants are
+/// evicted. The minimum fee rate then rises to the highest evicted package
+/// fee rate and halves every `min_fee_half_life`, until it falls
+/// back to `min_relay_fee_rate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolLimits {
+    /// Most bytes of encoded transactions kept.
+    pub max_size: u64,
+    pub min_relay_fee_rate: FeeRate,
+    pub min_fee_half_life: Duration,
+    /// Transactions pending for longer are evicted, `None` keeps them
+    /// forever.
+    pub expiry: Option<Duration>,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 300_000_000,
+            min_relay_fee_rate: FeeRate::new(1, 1),
+            min_fee_half_life: Duration::from_secs(12 * 60 * 60),
+            expiry: Some(Duration::from_secs(14 * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Why a transaction left the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemovalReason {
+    /// Included in a connected block.
+    Confirmed,
+    /// A connected block, or a transaction of a disconnected one, spends the
+    /// same outputs, or outputs it depends on.
+    Conflict,
+    /// Evicted to fit into the size limit.
+    SizeLimit,
+    /// Pending for longer than the expiry of the limits.
+    Expired,
+    /// Spends outputs of a disconnected block that were not added back.
+    InputsMissing,
+    /// Removed by `Mempool::remove`.
+    Requested,
+}
+
+/// Receives changes of a `Mempool`.
+///
+/// Removing a transaction removes its descendants as well, each gets its
+/// own event.
+pub trait MempoolListener<A, C> {
+    fn on_transaction_added(&self, _entry: &MempoolEntry<A, C>) {}
+    fn on_transaction_removed(&self, _entry: &MempoolEntry<A, C>, _reason: RemovalReason) {}
+    /// `replaced` was evicted by `replacement`, which conflicts with it or
+    /// with one of its ancestors. Replaced transactions get no removal event.
+    fn on_transaction_replaced(&self, _replaced: &MempoolEntry<A, C>, _replacement: &Txid) {}
+}
+
+/// `MempoolListener` changes as owned values, see `Mempool::subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MempoolEvent {
+    TxAdded { txid: Txid, fee: u64, size: u64 },
+    TxRemoved { txid: Txid, reason: RemovalReason },
+    TxReplaced { replaced: Txid, replacement: Txid },
+}
+
+/// Sends every change as a `MempoolEvent`, changes are dropped once the
+/// receiver is gone.
+impl<A, C> MempoolListener<A, C> for mpsc::Sender<MempoolEvent> {
+    fn on_transaction_added(&self, entry: &MempoolEntry<A, C>) {
+        let _ = self.send(MempoolEvent::TxAdded {
+            txid: entry.txid,
+            fee: entry.fee,
+            size: entry.size,
+        });
+    }
+
+    fn on_transaction_removed(&self, entry: &MempoolEntry<A, C>, reason: RemovalReason) {
+        let _ = self.send(MempoolEvent::TxRemoved {
+            txid: entry.txid,
+            reason,
+        });
+    }
+
+    fn on_transaction_replaced(&self, replaced: &MempoolEntry<A, C>, replacement: &Txid) {
+        let _ = self.send(MempoolEvent::TxReplaced {
+            replaced: replaced.txid,
+            replacement: *replacement,
+        });
+    }
+}
+
+struct MempoolListeners<A, C>(Vec<Arc<dyn MempoolListener<A, C> + Send + Sync>>);
+
+impl<A, C> Clone for MempoolListeners<A, C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A, C> std::fmt::Debug for MempoolListeners<A, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MempoolListeners({})", self.0.len())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry<A, C> {
+    pub txid: Txid,
+    pub transaction: AuthorizedTransaction<A, C>,
+    pub fee: u64,
+    /// Length of the encoded authorized transaction.
+    pub size: u64,
+    entered: Instant,
+    /// Mempool transactions this one spends outputs of.
+    parents: HashSet<Txid>,
+    /// Mempool transactions spending outputs of this one.
+    children: HashSet<Txid>,
+    /// The transaction with its mempool ancestors.
+    ancestor_totals: Totals,
+    /// The transaction with its mempool descendants.
+    descendant_totals: Totals,
+}
+
+impl<A, C> MempoolEntry<A, C> {
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee, self.size)
+    }
+
+    /// Fee rate of the transaction with its mempool ancestors, what a block
+    /// including it is paid.
+    pub fn ancestor_fee_rate(&self) -> FeeRate {
+        self.ancestor_totals.fee_rate()
+    }
+
+    /// Fee rate of the transaction with its mempool descendants, what
+    /// evicting it loses.
+    pub fn descendant_fee_rate(&self) -> FeeRate {
+        self.descendant_totals.fee_rate()
+    }
+
+    fn totals(&self) -> Totals {
+        Totals {
+            count: 1,
+            fee: self.fee,
+            size: self.size,
+        }
+    }
+
+    /// When the transaction was added to the mempool.
+    pub fn entered(&self) -> Instant {
+        self.entered
+    }
+
+    pub fn parents(&self) -> &HashSet<Txid> {
+        &self.parents
+    }
+
+    pub fn children(&self) -> &HashSet<Txid> {
+        &self.children
+    }
+}
+
+/// Count, fee and size of a set of mempool transactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Totals {
+    count: usize,
+    fee: u64,
+    size: u64,
+}
+
+impl Totals {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee, self.size)
+    }
+
+    fn add(self, other: Totals) -> Self {
+        Self {
+            count: self.count + other.count,
+            fee: self.fee + other.fee,
+            size: self.size + other.size,
+        }
+    }
+
+    fn sub(self, other: Totals) -> Self {
+        Self {
+            count: self.count - other.count,
+            fee: self.fee - other.fee,
+            size: self.size - other.size,
+        }
+    }
+}
+
+/// Summary of a `Mempool`, see `Mempool::info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolInfo {
+    pub count: usize,
+    pub total_size: u64,
+    pub total_fee: u64,
+    /// `None` without limits.
+    pub max_size: Option<u64>,
+    pub min_fee_rate: FeeRate,
+}
+
+/// Metadata of a mempool transaction, see `Mempool::entry_info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolEntryInfo {
+    pub txid: Txid,
+    pub fee: u64,
+    pub size: u64,
+    pub fee_rate: FeeRate,
+    /// Seconds since the Unix epoch when the transaction was added.
+    pub time: u64,
+    /// Direct parents and children, in txid order.
+    pub parents: Vec<Txid>,
+    pub children: Vec<Txid>,
+    /// Ancestor and descendant counts, sizes and fees include the
+    /// transaction itself.
+    pub ancestor_count: usize,
+    pub ancestor_size: u64,
+    pub ancestor_fee: u64,
+    pub descendant_count: usize,
+    pub descendant_size: u64,
+    pub descendant_fee: u64,
+}
+
+/// Transactions that are only valid together, ordered parents first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub txids: Vec<Txid>,
+    pub fee: u64,
+    pub size: u64,
+}
+
+impl Package {
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee, self.size)
+    }
+}
+
+/// Validated transactions waiting to be included in a block.
+///
+/// Transactions can spend outputs of other mempool transactions, but no two
+/// transactions spend the same outpoint. Conflicting transactions are
+/// rejected unless replacement rules are set. The mempool is unbounded
+/// unless limits are set. Coinbase outputs can only be spent once they are
+/// mature, which the mempool asks the UTXO map for, see
+/// `UtxoMap::is_mature`.
+///
+/// Txids are computed with `H`, which must match the hash function of the
+/// `StateMachine` the mempool follows. The mempool has to follow the chain,
+/// see `apply_block` and `apply_disconnect`.
+#[derive(Debug, Clone)]
+pub struct Mempool<A, C, H = DefaultHashFunction> {
+    entries: HashMap<Txid, MempoolEntry<A, C>>,
+    /// Mempool transaction spending each outpoint.
+    spent: HashMap<OutPoint, Txid>,
+    /// Outputs created by mempool transactions.
+    outputs: HashMap<OutPoint, Output<C>>,
+    /// Highest fee rate first, ties broken by txid.
+    by_fee_rate: BTreeSet<(Reverse<FeeRate>, Txid)>,
+    /// Highest ancestor fee rate first, ties broken by txid.
+    by_ancestor_fee_rate: BTreeSet<(Reverse<FeeRate>, Txid)>,
+    /// Lowest descendant fee rate first, ties broken by reverse txid.
+    by_descendant_fee_rate: BTreeSet<(FeeRate, Reverse<Txid>)>,
+    /// Sum of the sizes of all entries.
+    total_size: u64,
+    replacement_rules: Option<ReplacementRules>,
+    limits: Option<MempoolLimits>,
+    /// Fee rate of the last package evicted for size, and when.
+    evicted_fee_rate: Option<(FeeRate, Instant)>,
+    listeners: MempoolListeners<A, C>,
+    _marker: PhantomData<H>,
+}
+
+impl<A, C, H> Default for Mempool<A, C, H> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            outputs: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            by_ancestor_fee_rate: BTreeSet::new(),
+            by_descendant_fee_rate: BTreeSet::new(),
+            total_size: 0,
+            replacement_rules: None,
+            limits: None,
+            evicted_fee_rate: None,
+            listeners: MempoolListeners(Vec::new()),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<A, C, H> Mempool<A, C, H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replacement_rules(&self) -> Option<&ReplacementRules> {
+        self.replacement_rules.as_ref()
+    }
+
+    /// `None` disables replacements.
+    pub fn set_replacement_rules(&mut self, rules: Option<ReplacementRules>) {
+        self.replacement_rules = rules;
+    }
+
+    pub fn limits(&self) -> Option<&MempoolLimits> {
+        self.limits.as_ref()
+    }
+
+    /// `None` removes all bounds, limits are enforced by the next insert.
+    pub fn set_limits(&mut self, limits: Option<MempoolLimits>) {
+        self.limits = limits;
+        if limits.is_none() {
+            self.evicted_fee_rate = None;
+        }
+    }
+
+    /// Fee rate a transaction has to pay at least to be accepted.
+    pub fn min_fee_rate(&self) -> FeeRate {
+        let Some(limits) = &self.limits else {
+            return FeeRate::new(0, 1);
+        };
+        let Some((evicted, at)) = self.evicted_fee_rate else {
+            return limits.min_relay_fee_rate;
+        };
+        let half_life = limits.min_fee_half_life.as_secs_f64().max(f64::EPSILON);
+        let halvings = (at.elapsed().as_secs_f64() / half_life) as u32;
+        let decayed = FeeRate::new(evicted.fee.checked_shr(halvings).unwrap_or(0), evicted.size);
+        decayed.max(limits.min_relay_fee_rate)
+    }
+
+    pub fn add_listener(&mut self, listener: Arc<dyn MempoolListener<A, C> + Send + Sync>) {
+        self.listeners.0.push(listener);
+    }
+
+    /// Channel receiving all changes from now on, every subscriber gets its
+    /// own.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<MempoolEvent>
+    where
+        A: 'static,
+        C: 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.add_listener(Arc::new(sender));
+        receiver
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of all encoded transactions.
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub fn info(&self) -> MempoolInfo {
+        MempoolInfo {
+            count: self.entries.len(),
+            total_size: self.total_size,
+            total_fee: self.entries.values().map(|entry| entry.fee).sum(),
+            max_size: self.limits.map(|limits| limits.max_size),
+            min_fee_rate: self.min_fee_rate(),
+        }
+    }
+
+    pub fn entry_info(&self, txid: &Txid) -> Option<MempoolEntryInfo> {
+        let entry = self.entries.get(txid)?;
+        let (ancestors, descendants) = (entry.ancestor_totals, entry.descendant_totals);
+        let time = SystemTime::now()
+            .checked_sub(entry.entered.elapsed())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_secs());
+        let mut parents: Vec<_> = entry.parents.iter().copied().collect();
+        parents.sort();
+        let mut children: Vec<_> = entry.children.iter().copied().collect();
+        children.sort();
+        Some(MempoolEntryInfo {
+            txid: *txid,
+            fee: entry.fee,
+            size: entry.size,
+            fee_rate: entry.fee_rate(),
+            time,
+            parents,
+            children,
+            ancestor_count: ancestors.count,
+            ancestor_size: ancestors.size,
+            ancestor_fee: ancestors.fee,
+            descendant_count: descendants.count,
+            descendant_size: descendants.size,
+            descendant_fee: descendants.fee,
+        })
+    }
+
+    /// Metadata of all entries, by fee rate like `iter_by_fee_rate`.
+    pub fn entries(&self) -> impl Iterator<Item = MempoolEntryInfo> + '_ {
+        self.by_fee_rate
+            .iter()
+            .filter_map(|(_, txid)| self.entry_info(txid))
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&MempoolEntry<A, C>> {
+        self.entries.get(txid)
+    }
+
+    /// Mempool transaction spending `outpoint`.
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&Txid> {
+        self.spent.get(outpoint)
+    }
+
+    /// Output created by a mempool transaction.
+    pub fn get_output(&self, outpoint: &OutPoint) -> Option<&Output<C>> {
+        self.outputs.get(outpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry<A, C>> {
+        self.entries.values()
+    }
+
+    /// Entries by fee rate, highest first, ties broken by txid.
+    pub fn iter_by_fee_rate(&self) -> impl Iterator<Item = &MempoolEntry<A, C>> {
+        self.by_fee_rate.iter().map(|(_, txid)| &self.entries[txid])
+    }
+
+    /// Mempool transactions `txid` depends on, directly or not.
+    pub fn ancestors(&self, txid: &Txid) -> HashSet<Txid> {
+        self.closure(txid, |entry| &entry.parents)
+    }
+
+    /// Mempool transactions depending on `txid`, directly or not.
+    pub fn descendants(&self, txid: &Txid) -> HashSet<Txid> {
+        self.closure(txid, |entry| &entry.children)
+    }
+
+    fn closure(
+        &self,
+        txid: &Txid,
+        next: impl Fn(&MempoolEntry<A, C>) -> &HashSet<Txid>,
+    ) -> HashSet<Txid> {
+        let mut found = HashSet::new();
+        let mut pending: Vec<Txid> = self
+            .entries
+            .get(txid)
+            .map_or(vec![], |entry| next(entry).iter().copied().collect());
+        while let Some(txid) = pending.pop() {
+            if found.insert(txid) {
+                pending.extend(next(&self.entries[&txid]).iter().copied());
+            }
+        }
+        found
+    }
+
+    /// `txid` with its ancestors, the transactions a block must include to
+    /// include it. CPFP: a child paying a high fee raises the fee rate of
+    /// the package of its parents.
+    pub fn ancestor_package(&self, txid: &Txid) -> Option<Package> {
+        self.package_excluding(txid, &HashSet::new())
+    }
+
+    /// `txid` with its descendants, the transactions evicting it evicts.
+    pub fn descendant_package(&self, txid: &Txid) -> Option<Package> {
+        self.entries.get(txid)?;
+        let mut txids = self.descendants(txid);
+        txids.insert(*txid);
+        Some(self.package(txids))
+    }
+
+    /// Ancestor package of `txid` without the transactions in `excluded`.
+    fn package_excluding(&self, txid: &Txid, excluded: &HashSet<Txid>) -> Option<Package> {
+        self.entries.get(txid)?;
+        let mut txids = self.ancestors(txid);
+        txids.insert(*txid);
+        txids.retain(|txid| !excluded.contains(txid));
+        Some(self.package(txids))
+    }
+
+    /// Ancestors always have fewer ancestors than their descendants, so
+    /// sorting by ancestor count puts parents first.
+    fn package(&self, txids: HashSet<Txid>) -> Package {
+        let mut txids: Vec<_> = txids
+            .into_iter()
+            .map(|txid| (self.entries[&txid].ancestor_totals.count, txid))
+            .collect();
+        txids.sort();
+        let txids: Vec<_> = txids.into_iter().map(|(_, txid)| txid).collect();
+        let (fee, size) = txids.iter().fold((0, 0), |(fee, size), txid| {
+            let entry = &self.entries[txid];
+            (fee + entry.fee, size + entry.size)
+        });
+        Package { txids, fee, size }
+    }
+
+    /// Removes a transaction and all transactions depending on it, returns
+    /// them parents first.
+    pub fn remove(&mut self, txid: &Txid) -> Vec<MempoolEntry<A, C>> {
+        let removed = self.remove_with_descendants(txid);
+        self.notify_removed(&removed, RemovalReason::Requested);
+        removed
+    }
+
+    fn remove_with_descendants(&mut self, txid: &Txid) -> Vec<MempoolEntry<A, C>> {
+        let Some(entry) = self.entries.get(txid) else {
+            return Vec::new();
+        };
+        let mut txids: Vec<_> = self
+            .descendants(txid)
+            .into_iter()
+            .map(|txid| (self.entries[&txid].ancestor_totals.count, txid))
+            .collect();
+        txids.push((entry.ancestor_totals.count, *txid));
+        txids.sort();
+        // Children first, so that every removed transaction is a leaf.
+        let mut removed: Vec<_> = txids
+            .into_iter()
+            .rev()
+            .filter_map(|(_, txid)| self.remove_entry(&txid))
+            .collect();
+        removed.reverse();
+        removed
+    }
+
+    /// Evicts transactions pending for longer than the expiry of the limits,
+    /// and their descendants.
+    pub fn expire(&mut self) -> Vec<MempoolEntry<A, C>> {
+        let Some(expiry) = self.limits.and_then(|limits| limits.expiry) else {
+            return Vec::new();
+        };
+        let expired: Vec<_> = self
+            .entries
+            .values()
+            .filter(|entry| entry.entered.elapsed() > expiry)
+            .map(|entry| entry.txid)
+            .collect();
+        let mut evicted = Vec::new();
+        for txid in expired {
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+        self.notify_removed(&evicted, RemovalReason::Expired);
+        evicted
+    }
+
+    /// Evicts the packages with the lowest descendant fee rate until the
+    /// transactions fit into the size limit.
+    pub fn trim_to_size(&mut self) -> Vec<MempoolEntry<A, C>> {
+        let Some(limits) = self.limits else {
+            return Vec::new();
+        };
+        let mut evicted = Vec::new();
+        while self.total_size > limits.max_size {
+            let Some(&(fee_rate, Reverse(txid))) = self.by_descendant_fee_rate.first() else {
+                break;
+            };
+            if self
+                .evicted_fee_rate
+                .is_none_or(|(evicted, _)| evicted < fee_rate)
+            {
+                self.evicted_fee_rate = Some((fee_rate, Instant::now()));
+            }
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+        self.notify_removed(&evicted, RemovalReason::SizeLimit);
+        evicted
+    }
+
+    fn notify_removed(&self, removed: &[MempoolEntry<A, C>], reason: RemovalReason) {
+        for entry in removed {
+            for listener in &self.listeners.0 {
+                listener.on_transaction_removed(entry, reason);
+            }
+        }
+    }
+
+    /// Removes transactions included in `body`, and transactions conflicting
+    /// with it together with their dependents. Returns the removed
+    /// conflicting transactions.
+    ///
+    /// Call after `StateMachine::connect_block`, use `apply_reorg` after
+    /// `reorg_to`.
+    pub fn apply_block(&mut self, body: &Body<A, C>) -> Result<Vec<MempoolEntry<A, C>>, EncodeError>
+    where
+        C: Serialize,
+        H: HashFunction,
+    {
+        let mut confirmed = Vec::new();
+        for transaction in &body.transactions {
+            confirmed.extend(self.remove_entry(&transaction.try_txid_with::<H>()?));
+        }
+        self.notify_removed(&confirmed, RemovalReason::Confirmed);
+        let mut conflicts = Vec::new();
+        let inputs = body
+            .transactions
+            .iter()
+            .flat_map(|transaction| transaction.inputs.iter());
+        for input in inputs {
+            if let Some(spender) = self.spent.get(input).copied() {
+                conflicts.extend(self.remove_with_descendants(&spender));
+            }
+        }
+        self.notify_removed(&conflicts, RemovalReason::Conflict);
+        Ok(conflicts)
+    }
+
+    /// Removes one transaction, its dependents are kept.
+    fn remove_entry(&mut self, txid: &Txid) -> Option<MempoolEntry<A, C>> {
+        let ancestors = self.ancestors(txid);
+        let descendants = self.descendants(txid);
+        let entry = self.entries.remove(txid)?;
+        self.by_fee_rate.remove(&(Reverse(entry.fee_rate()), *txid));
+        self.by_ancestor_fee_rate
+            .remove(&(Reverse(entry.ancestor_fee_rate()), *txid));
+        self.by_descendant_fee_rate
+            .remove(&(entry.descendant_fee_rate(), Reverse(*txid)));
+        self.total_size -= entry.size;
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for input in &entry.transaction.transaction.inputs {
+            self.spent.remove(input);
+        }
+        let outputs = entry.transaction.transaction.outputs.len() as u32;
+        for vout in 0..outputs {
+            self.outputs
+                .remove(&OutPoint::Regular { txid: *txid, vout });
+        }
+        if ancestors.is_empty() || descendants.is_empty() {
+            // Only the transaction itself leaves the totals of the others.
+            for ancestor in ancestors {
+                self.update_totals(&ancestor, |ancestors, descendants| {
+                    (ancestors, descendants.sub(entry.totals()))
+                });
+            }
+            for descendant in descendants {
+                self.update_totals(&descendant, |ancestors, descendants| {
+                    (ancestors.sub(entry.totals()), descendants)
+                });
+            }
+        } else {
+            // Paths through the transaction are cut, the others may lose more
+            // than the transaction itself.
+            self.recompute_totals(ancestors.into_iter().chain(descendants));
+        }
+        Some(entry)
+    }
+
+    /// Replaces the ancestor and descendant totals of `txid`, keeping the
+    /// indexes in sync.
+    fn update_totals(
+        &mut self,
+        txid: &Txid,
+        update: impl FnOnce(Totals, Totals) -> (Totals, Totals),
+    ) {
+        let Some(entry) = self.entries.get_mut(txid) else {
+            return;
+        };
+        self.by_ancestor_fee_rate
+            .remove(&(Reverse(entry.ancestor_fee_rate()), *txid));
+        self.by_descendant_fee_rate
+            .remove(&(entry.descendant_fee_rate(), Reverse(*txid)));
+        (entry.ancestor_totals, entry.descendant_totals) =
+            update(entry.ancestor_totals, entry.descendant_totals);
+        self.by_ancestor_fee_rate
+            .insert((Reverse(entry.ancestor_fee_rate()), *txid));
+        self.by_descendant_fee_rate
+            .insert((entry.descendant_fee_rate(), Reverse(*txid)));
+    }
+
+    /// Sums the totals of `txids` from scratch, for changes in the middle of
+    /// a dependency graph.
+    fn recompute_totals(&mut self, txids: impl IntoIterator<Item = Txid>) {
+        for txid in txids {
+            let sum = |txids: HashSet<Txid>| {
+                txids
+                    .iter()
+                    .fold(self.entries[&txid].totals(), |totals, txid| {
+                        totals.add(self.entries[txid].totals())
+                    })
+            };
+            let ancestors = sum(self.ancestors(&txid));
+            let descendants = sum(self.descendants(&txid));
+            self.update_totals(&txid, |_, _| (ancestors, descendants));
+        }
+    }
+}
+
+impl<A: Clone, C: Clone + GetValue + Serialize, H> Mempool<A, C, H> {
+    /// Body of the transactions paying the highest fee rates that fit into
+    /// `max_size` bytes, and the fees they pay.
+    ///
+    /// Transactions are selected by the fee rate of their ancestor package,
+    /// without the ancestors already selected, so children can pay for
+    /// their parents. Transactions spending coinbase outputs that are not
+    /// mature in `utxos`, the state the block extends, are left out with
+    /// their descendants, they may have become immature in a reorg. The
+    /// coinbase splits the fees evenly between `coinbase_addresses`, the
+    /// first address gets the remainder. Without addresses the fees are not
+    /// collected.
+    pub fn build_block_template<M>(
+        &self,
+        utxos: &M,
+        max_size: u64,
+        coinbase_addresses: &[Address],
+    ) -> (Body<A, C>, u64)
+    where
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        let mut included = HashSet::new();
+        let mut skipped = HashSet::new();
+        for entry in self.entries.values() {
+            if spends_immature(utxos, &entry.transaction.transaction.inputs).is_some() {
+                skipped.insert(entry.txid);
+                skipped.extend(self.descendants(&entry.txid));
+            }
+        }
+        // Ancestor totals without the included ancestors, for the
+        // descendants of included transactions.
+        let mut modified: HashMap<Txid, Totals> = HashMap::new();
+        let mut candidates = self.by_ancestor_fee_rate.clone();
+        let mut transactions = Vec::new();
+        let mut size = 0;
+        let mut fees = 0;
+        while let Some((_, txid)) = candidates.pop_first() {
+            if skipped.contains(&txid) {
+                continue;
+            }
+            let package = self
+                .package_excluding(&txid, &included)
+                .expect("candidates are mempool entries");
+            if size + package.size > max_size {
+                skipped.insert(txid);
+                continue;
+            }
+            size += package.size;
+            fees += package.fee;
+            for txid in &package.txids {
+                let entry = &self.entries[txid];
+                transactions.push(entry.transaction.clone());
+                included.insert(*txid);
+                let ancestors = modified.remove(txid).unwrap_or(entry.ancestor_totals);
+                candidates.remove(&(Reverse(ancestors.fee_rate()), *txid));
+            }
+            for txid in &package.txids {
+                let totals = self.entries[txid].totals();
+                for descendant in self.descendants(txid) {
+                    if included.contains(&descendant) {
+                        continue;
+                    }
+                    let ancestors = modified
+                        .get(&descendant)
+                        .copied()
+                        .unwrap_or(self.entries[&descendant].ancestor_totals);
+                    let removed = candidates.remove(&(Reverse(ancestors.fee_rate()), descendant));
+                    let ancestors = ancestors.sub(totals);
+                    if removed {
+                        candidates.insert((Reverse(ancestors.fee_rate()), descendant));
+                    }
+                    modified.insert(descendant, ancestors);
+                }
+            }
+        }
+        let coinbase = coinbase_outputs(fees, coinbase_addresses);
+        (Body::new(transactions, coinbase), fees)
+    }
+}
+
+/// First coinbase input that can't be spent in the next block yet.
+fn spends_immature<M: UtxoMap<OutPoint = OutPoint>>(
+    utxos: &M,
+    inputs: &[OutPoint],
+) -> Option<OutPoint> {
+    inputs
+        .iter()
+        .find(|input| matches!(input, OutPoint::Coinbase { .. }) && !utxos.is_mature(input))
+        .copied()
+}
+
+fn coinbase_outputs<C>(fees: u64, addresses: &[Address]) -> Vec<Output<C>> {
+    if addresses.is_empty() {
+        return Vec::new();
+    }
+    let count = addresses.len() as u64;
+    let share = fees / count;
+    let remainder = fees % count;
+    addresses
+        .iter()
+        .enumerate()
+        .map(|(index, address)| {
+            let value = if index == 0 { share + remainder } else { share };
+            (address, value)
+        })
+        .filter(|(_, value)| *value > 0)
+        .map(|(address, value)| Output {
+            address: *address,
+            content: Content::Value(value),
+        })
+        .collect()
+}
+
+impl<A, C, H> Mempool<A, C, H>
+where
+    A: GetAddress + Verify<C> + Serialize,
+    A::Error: std::error::Error + Send + Sync + 'static,
+    C: Clone + GetValue + Serialize,
+    H: HashFunction,
+{
+    /// Validates a transaction against `utxos` and the outputs of mempool
+    /// transactions and adds it, returns its txid.
+    pub fn insert<M>(
+        &mut self,
+        utxos: &M,
+        transaction: AuthorizedTransaction<A, C>,
+    ) -> Result<Txid, MempoolError>
+    where
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        self.expire();
+        let replace = self.replacement_rules.is_some();
+        let checked = self.check(utxos, &PackageOverlay::default(), transaction, replace)?;
+        self.check_fee_rate(FeeRate::new(checked.fee, checked.size))?;
+        if let Some(rules) = &self.replacement_rules {
+            self.check_replacement(rules, &checked)?;
+        }
+        let txid = checked.txid;
+        let replaced = self.add(checked);
+        self.notify_added(&txid);
+        let evicted = self.trim_to_size();
+        if !self.entries.contains_key(&txid) {
+            self.restore(replaced, &evicted);
+            return Err(MempoolError::MempoolFull);
+        }
+        for replaced in &replaced {
+            for listener in &self.listeners.0 {
+                listener.on_transaction_replaced(replaced, &txid);
+            }
+        }
+        Ok(txid)
+    }
+
+    /// Adds back the transactions a replacement evicted, after the
+    /// replacement itself was evicted for size. Those spending outputs of
+    /// `evicted` transactions are dropped.
+    fn restore(&mut self, mut replaced: Vec<MempoolEntry<A, C>>, evicted: &[MempoolEntry<A, C>]) {
+        // Parents first, so that dropping spreads to descendants.
+        replaced.sort_by_key(|entry| entry.ancestor_totals.count);
+        let mut missing: HashSet<Txid> = evicted.iter().map(|entry| entry.txid).collect();
+        let mut dropped = Vec::new();
+        for entry in replaced {
+            let spends_missing = entry.transaction.transaction.inputs.iter().any(
+                |input| matches!(input, OutPoint::Regular { txid, .. } if missing.contains(txid)),
+            );
+            if spends_missing {
+                missing.insert(entry.txid);
+                dropped.push(entry);
+                continue;
+            }
+            let (txid, entered) = (entry.txid, entry.entered);
+            self.add(CheckedTransaction {
+                txid,
+                transaction: entry.transaction,
+                fee: entry.fee,
+                size: entry.size,
+                conflicts: HashSet::new(),
+                replaced: HashSet::new(),
+            });
+            if let Some(entry) = self.entries.get_mut(&txid) {
+                entry.entered = entered;
+            }
+        }
+        self.notify_removed(&dropped, RemovalReason::SizeLimit);
+    }
+
+    /// Validates interdependent transactions together and adds all of them
+    /// or none, returns their txids.
+    ///
+    /// Transactions can spend outputs of earlier transactions of the
+    /// package, parents come first. The package as a whole has to pay the
+    /// minimum fee rate, so a child can pay for a parent paying too little
+    /// to be accepted alone. Packages can't replace mempool transactions.
+    pub fn insert_package<M>(
+        &mut self,
+        utxos: &M,
+        transactions: Vec<AuthorizedTransaction<A, C>>,
+    ) -> Result<Vec<Txid>, MempoolError>
+    where
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        self.expire();
+        let mut package = PackageOverlay::default();
+        let mut checked = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            let transaction = self
+                .check(utxos, &package, transaction, false)
+                .map_err(|err| MempoolError::InvalidPackageTransaction {
+                    index,
+                    source: Box::new(err),
+                })?;
+            package.push(&transaction);
+            checked.push(transaction);
+        }
+        let fee = checked.iter().map(|transaction| transaction.fee).sum();
+        let size = checked.iter().map(|transaction| transaction.size).sum();
+        self.check_fee_rate(FeeRate::new(fee, size))?;
+        let txids: Vec<_> = checked
+            .into_iter()
+            .map(|transaction| {
+                let txid = transaction.txid;
+                self.add(transaction);
+                self.notify_added(&txid);
+                txid
+            })
+            .collect();
+        self.trim_to_size();
+        if txids.iter().any(|txid| !self.entries.contains_key(txid)) {
+            for txid in &txids {
+                let removed = self.remove_with_descendants(txid);
+                self.notify_removed(&removed, RemovalReason::SizeLimit);
+            }
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(txids)
+    }
+
+    /// Validates a transaction against `utxos`, mempool outputs and the
+    /// outputs of earlier transactions of `package`, without changing the
+    /// mempool. Conflicts with mempool transactions are only allowed if
+    /// `replace` is set, `add` evicts them.
+    fn check<M>(
+        &self,
+        utxos: &M,
+        package: &PackageOverlay<C>,
+        transaction: AuthorizedTransaction<A, C>,
+        replace: bool,
+    ) -> Result<CheckedTransaction<A, C>, MempoolError>
+    where
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        let txid = transaction.transaction.try_txid_with::<H>()?;
+        if self.entries.contains_key(&txid) || package.txids.contains(&txid) {
+            return Err(MempoolError::AlreadyExists { txid });
+        }
+        let inputs = &transaction.transaction.inputs;
+        let mut seen_inputs = HashSet::with_capacity(inputs.len());
+        let mut conflicts = HashSet::new();
+        for input in inputs {
+            if !seen_inputs.insert(input) || package.spent.contains(input) {
+                return Err(validator::Error::DoubleSpent { input: *input }.into());
+            }
+            if let Some(spender) = self.spent.get(input) {
+                if !replace {
+                    return Err(MempoolError::Conflict {
+                        input: *input,
+                        spender: *spender,
+                    });
+                }
+                conflicts.insert(*spender);
+            }
+        }
+        let mut replaced = conflicts.clone();
+        for conflict in &conflicts {
+            replaced.extend(self.descendants(conflict));
+        }
+        for input in inputs {
+            if let OutPoint::Regular { txid: parent, .. } = input {
+                if replaced.contains(parent) {
+                    return Err(MempoolError::SpendsReplaced { input: *input });
+                }
+            }
+        }
+        let spent_utxos = inputs
+            .iter()
+            .map(|input| {
+                package
+                    .outputs
+                    .get(input)
+                    .or_else(|| self.outputs.get(input))
+                    .cloned()
+                    .or_else(|| utxos.get_utxo(input))
+                    .ok_or(validator::Error::UtxoDoesNotExist { outpoint: *input })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(input) = spends_immature(utxos, inputs) {
+            return Err(MempoolError::ImmatureCoinbase { input });
+        }
+        let fee = validator::validate_authorized_transaction(&spent_utxos, &transaction)?;
+        A::verify_transaction::<H>(&transaction)
+            .map_err(|err| MempoolError::BadAuthorization(Box::new(err)))?;
+        let size = encode_canonical(&transaction)?.len() as u64;
+        Ok(CheckedTransaction {
+            txid,
+            transaction,
+            fee,
+            size,
+            conflicts,
+            replaced,
+        })
+    }
+
+    fn check_fee_rate(&self, fee_rate: FeeRate) -> Result<(), MempoolError> {
+        let min_fee_rate = self.min_fee_rate();
+        if fee_rate < min_fee_rate {
+            return Err(MempoolError::FeeRateTooLow {
+                fee_rate,
+                min_fee_rate,
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds a checked transaction, evicting the transactions it replaces.
+    /// Returns the evicted transactions, listeners are told about neither.
+    fn add(&mut self, checked: CheckedTransaction<A, C>) -> Vec<MempoolEntry<A, C>> {
+        let CheckedTransaction {
+            txid,
+            transaction,
+            fee,
+            size,
+            conflicts,
+            replaced,
+        } = checked;
+        let mut replaced_entries = Vec::with_capacity(replaced.len());
+        for conflict in &conflicts {
+            replaced_entries.extend(self.remove_with_descendants(conflict));
+        }
+        let inputs = &transaction.transaction.inputs;
+        let parents: HashSet<Txid> = inputs
+            .iter()
+            .filter_map(|input| match input {
+                OutPoint::Regular { txid, .. } if self.entries.contains_key(txid) => Some(*txid),
+                _ => None,
+            })
+            .collect();
+        for parent in &parents {
+            self.entries
+                .get_mut(parent)
+                .expect("parents are mempool entries")
+                .children
+                .insert(txid);
+        }
+        for input in inputs {
+            self.spent.insert(*input, txid);
+        }
+        let mut children = HashSet::new();
+        for (vout, output) in transaction.transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint::Regular {
+                txid,
+                vout: vout as u32,
+            };
+            // Spent already if the transaction comes back after a disconnect.
+            if let Some(child) = self.spent.get(&outpoint) {
+                children.insert(*child);
+            }
+            if output.address.is_burn() {
+                continue;
+            }
+            self.outputs.insert(outpoint, output.clone());
+        }
+        for child in &children {
+            self.entries
+                .get_mut(child)
+                .expect("spenders are mempool entries")
+                .parents
+                .insert(txid);
+        }
+        let entry = MempoolEntry {
+            txid,
+            transaction,
+            fee,
+            size,
+            entered: Instant::now(),
+            parents,
+            children,
+            ancestor_totals: Totals::default(),
+            descendant_totals: Totals::default(),
+        };
+        let totals = entry.totals();
+        let has_children = !entry.children.is_empty();
+        self.by_fee_rate.insert((Reverse(entry.fee_rate()), txid));
+        self.total_size += size;
+        self.entries.insert(txid, entry);
+        let ancestors = self.ancestors(&txid);
+        if has_children {
+            // Only after a disconnect, descendants may be reachable from the
+            // ancestors through other paths already.
+            let descendants = self.descendants(&txid);
+            self.recompute_totals(ancestors.into_iter().chain(descendants).chain([txid]));
+        } else {
+            let own = ancestors.iter().fold(totals, |own, ancestor| {
+                own.add(self.entries[ancestor].totals())
+            });
+            self.update_totals(&txid, |_, _| (own, totals));
+            for ancestor in ancestors {
+                self.update_totals(&ancestor, |ancestors, descendants| {
+                    (ancestors, descendants.add(totals))
+                });
+            }
+        }
+        replaced_entries
+    }
+
+    fn notify_added(&self, txid: &Txid) {
+        let entry = &self.entries[txid];
+        for listener in &self.listeners.0 {
+            listener.on_transaction_added(entry);
+        }
+    }
+
+    /// Adds the transactions of a disconnected block back, `utxos` is the
+    /// state after disconnecting it. Returns the txids of the transactions
+    /// added back.
+    ///
+    /// Transactions of the block bypass expiry, replacement and fee rate
+    /// rules, mempool transactions spending the same outputs are evicted
+    /// instead, then the mempool is trimmed to its size limit. Transactions
+    /// that are no longer valid are dropped, together with mempool
+    /// transactions spending outputs of the block that didn't come back and
+    /// aren't unspent in `utxos` either. Call after
+    /// `StateMachine::disconnect_block`, passing the state machine as
+    /// `utxos`. Disconnect and apply blocks one at a time, tip first, so every
+    /// block is added back against its own parent state.
+    pub fn apply_disconnect<M>(
+        &mut self,
+        utxos: &M,
+        body: &Body<A, C>,
+    ) -> Result<Vec<Txid>, EncodeError>
+    where
+        A: Clone,
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        let mut restored = Vec::new();
+        let mut authorizations = body.authorizations.iter();
+        for transaction in &body.transactions {
+            let transaction = AuthorizedTransaction {
+                authorizations: authorizations
+                    .by_ref()
+                    .take(transaction.inputs.len())
+                    .cloned()
+                    .collect(),
+                transaction: transaction.clone(),
+            };
+            match self.check(utxos, &PackageOverlay::default(), transaction, true) {
+                Ok(checked) => {
+                    let txid = checked.txid;
+                    let conflicts = self.add(checked);
+                    self.notify_removed(&conflicts, RemovalReason::Conflict);
+                    self.notify_added(&txid);
+                    restored.push(txid);
+                }
+                Err(MempoolError::Encode(err)) => return Err(err),
+                Err(_) => {}
+            }
+        }
+        for outpoint in body.try_get_outputs_with::<H>()?.keys() {
+            if self.outputs.contains_key(outpoint) || !utxos.is_spent(outpoint) {
+                continue;
+            }
+            if let Some(spender) = self.spent.get(outpoint).copied() {
+                let removed = self.remove_with_descendants(&spender);
+                self.notify_removed(&removed, RemovalReason::InputsMissing);
+            }
+        }
+        self.trim_to_size();
+        restored.retain(|txid| self.entries.contains_key(txid));
+        Ok(restored)
+    }
+
+    /// Follows `StateMachine::reorg_to`, `utxos` is the state after the reorg
+    /// and `connected` holds the bodies of `outcome.connected` in the same
+    /// order. Returns the txids added back.
+    ///
+    /// Transactions of the disconnected blocks are added back oldest block
+    /// first, so parents come back before their children, unless the new
+    /// branch confirmed them or spends the same outputs. Mempool transactions
+    /// conflicting with the new branch are then removed as in `apply_block`.
+    pub fn apply_reorg<M>(
+        &mut self,
+        utxos: &M,
+        outcome: &ReorgOutcome<A, C>,
+        connected: &[Body<A, C>],
+    ) -> Result<Vec<Txid>, EncodeError>
+    where
+        A: Clone,
+        M: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        let mut restored = Vec::new();
+        for body in outcome.disconnected_bodies.iter().rev() {
+            restored.extend(self.apply_disconnect(utxos, body)?);
+        }
+        for body in connected {
+            self.apply_block(body)?;
+        }
+        restored.retain(|txid| self.entries.contains_key(txid));
+        Ok(restored)
+    }
+
+    fn check_replacement(
+        &self,
+        rules: &ReplacementRules,
+        checked: &CheckedTransaction<A, C>,
+    ) -> Result<(), MempoolError> {
+        let (replaced, fee, size) = (&checked.replaced, checked.fee, checked.size);
+        if replaced.len() > rules.max_replaced {
+            return Err(MempoolError::TooManyReplaced {
+                count: replaced.len(),
+                max: rules.max_replaced,
+            });
+        }
+        let fee_rate = FeeRate::new(fee, size);
+        for conflict in &checked.conflicts {
+            if self.entries[conflict].fee_rate() >= fee_rate {
+                return Err(MempoolError::ReplacementFeeRateTooLow {
+                    conflict: *conflict,
+                });
+            }
+        }
+        let replaced_fees: u64 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+        let incremental_fee = rules.incremental_fee_rate.fee_for(size);
+        let required = u128::from(replaced_fees) + u128::from(incremental_fee);
+        if u128::from(fee) < required {
+            return Err(MempoolError::InsufficientReplacementFee {
+                fee,
+                required: u64::try_from(required).unwrap_or(u64::MAX),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Transaction that passed `Mempool::check`.
+struct CheckedTransaction<A, C> {
+    txid: Txid,
+    transaction: AuthorizedTransaction<A, C>,
+    fee: u64,
+    size: u64,
+    /// Mempool transactions spending the same outpoints.
+    conflicts: HashSet<Txid>,
+    /// Conflicts and their descendants.
+    replaced: HashSet<Txid>,
+}
+
+/// Checked transactions of a package that aren't added yet.
+struct PackageOverlay<C> {
+    txids: HashSet<Txid>,
+    spent: HashSet<OutPoint>,
+    outputs: HashMap<OutPoint, Output<C>>,
+}
+
+impl<C> Default for PackageOverlay<C> {
+    fn default() -> Self {
+        Self {
+            txids: HashSet::new(),
+            spent: HashSet::new(),
+            outputs: HashMap::new(),
+        }
+    }
+}
+
+impl<C: Clone> PackageOverlay<C> {
+    fn push<A>(&mut self, checked: &CheckedTransaction<A, C>) {
+        self.txids.insert(checked.txid);
+        let transaction = &checked.transaction.transaction;
+        self.spent.extend(transaction.inputs.iter().copied());
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            if output.address.is_burn() {
+                continue;
+            }
+            let outpoint = OutPoint::Regular {
+                txid: checked.txid,
+                vout: vout as u32,
+            };
+            self.outputs.insert(outpoint, output.clone());
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MempoolError {
+    #[error("transaction {txid} is already in the mempool")]
+    AlreadyExists { txid: Txid },
+    #[error("outpoint {input} is already spent by mempool transaction {spender}")]
+    Conflict { input: OutPoint, spender: Txid },
+    #[error("replacement spends {input}, an output of a transaction it replaces")]
+    SpendsReplaced { input: OutPoint },
+    #[error("replacement would evict {count} transactions, at most {max} are allowed")]
+    TooManyReplaced { count: usize, max: usize },
+    #[error("replacement doesn't pay a higher fee rate than {conflict}")]
+    ReplacementFeeRateTooLow { conflict: Txid },
+    #[error("replacement pays a fee of {fee}, at least {required} is required")]
+    InsufficientReplacementFee { fee: u64, required: u64 },
+    #[error(
+        "fee rate of {:.3} per byte is below the minimum of {:.3}",
+        .fee_rate.sats_per_byte(),
+        .min_fee_rate.sats_per_byte()
+    )]
+    FeeRateTooLow {
+        fee_rate: FeeRate,
+        min_fee_rate: FeeRate,
+    },
+    #[error("transaction {index} of the package is invalid")]
+    InvalidPackageTransaction {
+        index: usize,
+        source: Box<MempoolError>,
+    },
+    #[error("mempool is full")]
+    MempoolFull,
+    #[error("coinbase output {input} is not mature yet")]
+    ImmatureCoinbase { input: OutPoint },
+    #[error("validation error")]
+    Validation(#[from] validator::Error),
+    #[error("bad authorization")]
+    BadAuthorization(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::KeyAuthorization;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    type TestMempool = Mempool<KeyAuthorization, ()>;
+    type Utxos = HashMap<OutPoint, Output<()>>;
+
+    const VALUE: u64 = 100_000;
+
+    fn secret_key() -> SecretKey {
+        SecretKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::from_public_key(&PublicKey::from_secret_key(
+            &Secp256k1::new(),
+            &secret_key(),
+        ))
+    }
+
+    fn utxo(index: u8) -> OutPoint {
+        OutPoint::Regular {
+            txid: Txid([index; 32]),
+            vout: 0,
+        }
+    }
+
+    fn utxos() -> Utxos {
+        (0..4)
+            .map(|index| {
+                let output = Output {
+                    address: address(),
+                    content: Content::Value(VALUE),
+                };
+                (utxo(index), output)
+            })
+            .collect()
+    }
+
+    /// Spends `input` worth `value` into `outputs` equal outputs, paying
+    /// `fee`.
+    fn spend(
+        input: OutPoint,
+        value: u64,
+        outputs: u64,
+        fee: u64,
+    ) -> AuthorizedTransaction<KeyAuthorization, ()> {
+        let mut outputs: Vec<_> = (0..outputs)
+            .map(|_| Output {
+                address: address(),
+                content: Content::Value((value - fee) / outputs),
+            })
+            .collect();
+        let remainder = (value - fee) % outputs.len() as u64;
+        if let Some(Output {
+            content: Content::Value(first),
+            ..
+        }) = outputs.first_mut()
+        {
+            *first += remainder;
+        }
+        let transaction = Transaction {
+            inputs: vec![input],
+            outputs,
+        };
+        let authorization =
+            KeyAuthorization::sign(&Secp256k1::new(), &secret_key(), &transaction.txid());
+        AuthorizedTransaction {
+            transaction,
+            authorizations: vec![authorization],
+        }
+    }
+
+    fn size(transaction: &AuthorizedTransaction<KeyAuthorization, ()>) -> u64 {
+        encode_canonical(transaction).unwrap().len() as u64
+    }
+
+    fn child(parent: &Txid, value: u64, fee: u64) -> AuthorizedTransaction<KeyAuthorization, ()> {
+        spend(
+            OutPoint::Regular {
+                txid: *parent,
+                vout: 0,
+            },
+            value,
+            1,
+            fee,
+        )
+    }
+
+    #[test]
+    fn replacement_rules() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        let original = mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 1000))
+            .unwrap();
+        assert!(matches!(
+            mempool.insert(&utxos, spend(utxo(0), VALUE, 1, 5000)),
+            Err(MempoolError::Conflict { .. })
+        ));
+
+        mempool.set_replacement_rules(Some(ReplacementRules::default()));
+        let events = mempool.subscribe();
+        assert!(matches!(
+            mempool.insert(&utxos, spend(utxo(0), VALUE, 1, 900)),
+            Err(MempoolError::ReplacementFeeRateTooLow { .. })
+        ));
+        // A higher fee rate, but not enough to pay for its own size too.
+        let underpaying = 1000 + size(&spend(utxo(0), VALUE, 1, 0)) / 2;
+        assert!(matches!(
+            mempool.insert(&utxos, spend(utxo(0), VALUE, 1, underpaying)),
+            Err(MempoolError::InsufficientReplacementFee { .. })
+        ));
+        let replacement = mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 5000))
+            .unwrap();
+        assert!(!mempool.contains(&original));
+        assert_eq!(mempool.spender(&utxo(0)), Some(&replacement));
+        let events: Vec<_> = events.try_iter().collect();
+        assert!(events.contains(&MempoolEvent::TxReplaced {
+            replaced: original,
+            replacement,
+        }));
+
+        // Replacing evicts the descendants too, at most `max_replaced`.
+        let child = mempool
+            .insert(&utxos, child(&replacement, VALUE - 5000, 1000))
+            .unwrap();
+        mempool.set_replacement_rules(Some(ReplacementRules {
+            max_replaced: 1,
+            ..ReplacementRules::default()
+        }));
+        assert!(matches!(
+            mempool.insert(&utxos, spend(utxo(0), VALUE, 1, 20_000)),
+            Err(MempoolError::TooManyReplaced { count: 2, max: 1 })
+        ));
+        mempool.set_replacement_rules(Some(ReplacementRules::default()));
+        mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 20_000))
+            .unwrap();
+        assert!(!mempool.contains(&replacement));
+        assert!(!mempool.contains(&child));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn ancestor_and_descendant_totals() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        let parent = mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 100))
+            .unwrap();
+        let child = mempool
+            .insert(&utxos, child(&parent, VALUE - 100, 200))
+            .unwrap();
+        let grandchild = mempool
+            .insert(&utxos, self::child(&child, VALUE - 300, 300))
+            .unwrap();
+
+        let info = mempool.entry_info(&parent).unwrap();
+        assert_eq!((info.ancestor_count, info.ancestor_fee), (1, 100));
+        assert_eq!((info.descendant_count, info.descendant_fee), (3, 600));
+        let info = mempool.entry_info(&grandchild).unwrap();
+        assert_eq!((info.ancestor_count, info.ancestor_fee), (3, 600));
+        assert_eq!(info.ancestor_size, mempool.total_size());
+        assert_eq!(
+            mempool.ancestor_package(&grandchild).unwrap().txids,
+            [parent, child, grandchild]
+        );
+        assert_eq!(
+            mempool.descendant_package(&child).unwrap().txids,
+            [child, grandchild]
+        );
+
+        let removed = mempool.remove(&child);
+        assert_eq!(removed.len(), 2);
+        let info = mempool.entry_info(&parent).unwrap();
+        assert_eq!((info.descendant_count, info.descendant_fee), (1, 100));
+        assert!(info.children.is_empty());
+    }
+
+    #[test]
+    fn trim_evicts_lowest_descendant_fee_rate() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        let low = spend(utxo(0), VALUE, 1, 1000);
+        // Signatures vary in length by a byte or two.
+        let max_size = 2 * size(&low) + 4;
+        mempool.set_limits(Some(MempoolLimits {
+            max_size,
+            min_relay_fee_rate: FeeRate::new(0, 1),
+            ..MempoolLimits::default()
+        }));
+        let low = mempool.insert(&utxos, low).unwrap();
+        let high = mempool
+            .insert(&utxos, spend(utxo(1), VALUE, 1, 3000))
+            .unwrap();
+        let middle = mempool
+            .insert(&utxos, spend(utxo(2), VALUE, 1, 2000))
+            .unwrap();
+        assert!(!mempool.contains(&low));
+        assert!(mempool.contains(&high) && mempool.contains(&middle));
+        assert!(mempool.total_size() <= max_size);
+
+        // The minimum fee rate rose to the evicted one.
+        let evicted = FeeRate::new(1000, size(&spend(utxo(0), VALUE, 1, 1000)));
+        assert!(mempool.min_fee_rate() >= evicted);
+        assert!(matches!(
+            mempool.insert(&utxos, spend(utxo(3), VALUE, 1, 1000)),
+            Err(MempoolError::FeeRateTooLow { .. })
+        ));
+    }
+
+    #[test]
+    fn block_template_lets_children_pay_for_parents() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        let parent = mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 100))
+            .unwrap();
+        let child = mempool
+            .insert(&utxos, child(&parent, VALUE - 100, 10_000))
+            .unwrap();
+        let other = spend(utxo(1), VALUE, 1, 3000);
+        let size = size(&other);
+        let other = mempool.insert(&utxos, other).unwrap();
+
+        let miners = [Address::from([2; 32]), Address::from([3; 32])];
+        let (body, fees) = mempool.build_block_template(&utxos, u64::MAX, &miners);
+        assert_eq!(fees, 13_100);
+        let txids: Vec<_> = body.transactions.iter().map(Transaction::txid).collect();
+        assert_eq!(txids, [parent, child, other]);
+        let coinbase: Vec<_> = body.coinbase.iter().map(GetValue::get_value).collect();
+        assert_eq!(coinbase, [6550, 6550]);
+
+        // Only room for one package, parent and child pay more together.
+        let (body, fees) = mempool.build_block_template(&utxos, 2 * size + 10, &[]);
+        assert_eq!(fees, 10_100);
+        let txids: Vec<_> = body.transactions.iter().map(Transaction::txid).collect();
+        assert_eq!(txids, [parent, child]);
+        assert!(body.coinbase.is_empty());
+    }
+
+    #[test]
+    fn replacement_evicted_for_size_restores_replaced() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        mempool.set_replacement_rules(Some(ReplacementRules::default()));
+        let original = spend(utxo(0), VALUE, 1, 1000);
+        let other = spend(utxo(1), VALUE, 1, 50_000);
+        mempool.set_limits(Some(MempoolLimits {
+            max_size: size(&original) + size(&other),
+            min_relay_fee_rate: FeeRate::new(0, 1),
+            ..MempoolLimits::default()
+        }));
+        let original = mempool.insert(&utxos, original).unwrap();
+        let other = mempool.insert(&utxos, other).unwrap();
+        let events = mempool.subscribe();
+
+        // Pays for the replacement, but is larger and pays a lower fee rate
+        // than `other`, so it is evicted to make room.
+        let replacement = spend(utxo(0), VALUE, 4, 5000);
+        assert!(size(&replacement) > mempool.get(&original).unwrap().size);
+        assert!(matches!(
+            mempool.insert(&utxos, replacement),
+            Err(MempoolError::MempoolFull)
+        ));
+        assert!(mempool.contains(&original));
+        assert!(mempool.contains(&other));
+        assert_eq!(mempool.len(), 2);
+        assert!(!events.try_iter().any(|event| match event {
+            MempoolEvent::TxReplaced { .. } => true,
+            MempoolEvent::TxRemoved { txid, .. } => txid == original,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn disconnected_transactions_evict_conflicts() {
+        let utxos = utxos();
+        let mut mempool = TestMempool::new();
+        mempool.set_limits(Some(MempoolLimits {
+            min_relay_fee_rate: FeeRate::new(1, 1),
+            ..MempoolLimits::default()
+        }));
+        let conflict = mempool
+            .insert(&utxos, spend(utxo(0), VALUE, 1, 5000))
+            .unwrap();
+        // Pays less than the minimum fee rate, it was mined anyway.
+        let mined = spend(utxo(0), VALUE, 1, 0);
+        let mined_txid = mined.transaction.txid();
+        let body = Body::new(vec![mined], Vec::new());
+
+        let restored = mempool.apply_disconnect(&utxos, &body).unwrap();
+        assert_eq!(restored, [mined_txid]);
+        assert!(!mempool.contains(&conflict));
+        assert_eq!(mempool.spender(&utxo(0)), Some(&mined_txid));
+    }
+}
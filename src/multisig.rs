@@ -0,0 +1,423 @@
+use crate::authorization::KeyAuthorization;
+use crate::encoding::{decode_canonical, encode_canonical, EncodeError};
+use crate::policy::{Policy, PolicyAuthorization, PolicySpend};
+use crate::psst::PsstSignError;
+use crate::transaction_builder::{TransactionBuilder, TransactionBuilderError};
+use crate::types::*;
+use crate::wallet::{discover_next_index, IsMine, KeyChain, KeyIndex, Signer};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::bip32::{self, ChildNumber, ExtendedPubKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Account cosigners derive their multisig keys under by default, BIP 48
+/// style, so they don't share keys with their single key wallets.
+pub const DEFAULT_MULTISIG_ACCOUNT_PATH: &str = "m/48'/0'/0'/2'";
+
+/// Shared `threshold` of `cosigners` wallet, every address is a
+/// `Policy::Multisig` of the cosigners' keys at the same `chain/index`.
+///
+/// Holds no secret keys: addresses come from the cosigners' account
+/// xpubs, transactions are drafted as `MultisigPsst`s which the cosigners
+/// sign and merge out of band. A cosigner's `Wallet` needs its account at
+/// the multisig account path and its next indices past the drafted inputs
+/// to sign them.
+#[derive(Debug, Clone)]
+pub struct MultisigWallet {
+    secp: Secp256k1<VerifyOnly>,
+    threshold: u32,
+    /// In encoding order, so every cosigner derives the same addresses.
+    cosigners: Vec<ExtendedPubKey>,
+    next_receive: u32,
+    next_change: u32,
+    addresses: HashMap<Address, (KeyIndex, Policy)>,
+}
+
+impl MultisigWallet {
+    pub fn new(threshold: u32, mut cosigners: Vec<ExtendedPubKey>) -> Result<Self, MultisigError> {
+        if threshold == 0 || threshold as usize > cosigners.len() {
+            return Err(MultisigError::InvalidThreshold {
+                threshold,
+                cosigners: cosigners.len(),
+            });
+        }
+        cosigners.sort_by_key(ExtendedPubKey::encode);
+        if cosigners.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(MultisigError::DuplicateCosigner);
+        }
+        Ok(Self {
+            secp: Secp256k1::verification_only(),
+            threshold,
+            cosigners,
+            next_receive: 0,
+            next_change: 0,
+            addresses: HashMap::new(),
+        })
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn cosigners(&self) -> &[ExtendedPubKey] {
+        &self.cosigners
+    }
+
+    /// Policy of the address at `index` of `chain`, member addresses are
+    /// sorted.
+    pub fn policy_at(&self, chain: KeyChain, index: u32) -> Result<Policy, MultisigError> {
+        let path = [chain.child_number(), ChildNumber::from_normal_idx(index)?];
+        let mut addresses = self
+            .cosigners
+            .iter()
+            .map(|cosigner| {
+                let key = cosigner.derive_pub(&self.secp, &path)?;
+                Ok(Address::from_public_key(&key.public_key))
+            })
+            .collect::<Result<Vec<_>, bip32::Error>>()?;
+        addresses.sort();
+        Ok(Policy::Multisig {
+            threshold: self.threshold,
+            addresses,
+        })
+    }
+
+    /// Derives the next unused address of `chain`.
+    pub fn new_address(&mut self, chain: KeyChain) -> Result<Address, MultisigError> {
+        let index = self.next_index(chain);
+        let address = self.derive_address(chain, index)?;
+        match chain {
+            KeyChain::Receive => self.next_receive += 1,
+            KeyChain::Change => self.next_change += 1,
+        }
+        Ok(address)
+    }
+
+    pub fn receive_address(&mut self) -> Result<Address, MultisigError> {
+        self.new_address(KeyChain::Receive)
+    }
+
+    pub fn change_address(&mut self) -> Result<Address, MultisigError> {
+        self.new_address(KeyChain::Change)
+    }
+
+    /// Index the next address of `chain` is derived at.
+    pub fn next_index(&self, chain: KeyChain) -> u32 {
+        match chain {
+            KeyChain::Receive => self.next_receive,
+            KeyChain::Change => self.next_change,
+        }
+    }
+
+    /// Restores the next indices of both chains, past the last address
+    /// `is_used` reports, scanning until `gap_limit` addresses in a row are
+    /// unused. Addresses of the gap stay derived, so outputs sent to them
+    /// are recognized.
+    pub fn discover_addresses(
+        &mut self,
+        gap_limit: u32,
+        mut is_used: impl FnMut(&Address) -> bool,
+    ) -> Result<(), MultisigError> {
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            let next_index = discover_next_index(
+                gap_limit,
+                |index| self.derive_address(chain, index),
+                &mut is_used,
+            )?;
+            self.set_next_index(chain, next_index)?;
+        }
+        Ok(())
+    }
+
+    /// Derives all addresses of `chain` below `next_index` that aren't
+    /// derived yet, indices never go back.
+    pub fn set_next_index(
+        &mut self,
+        chain: KeyChain,
+        next_index: u32,
+    ) -> Result<(), MultisigError> {
+        for index in self.next_index(chain)..next_index {
+            self.derive_address(chain, index)?;
+        }
+        match chain {
+            KeyChain::Receive => self.next_receive = self.next_receive.max(next_index),
+            KeyChain::Change => self.next_change = self.next_change.max(next_index),
+        }
+        Ok(())
+    }
+
+    fn derive_address(&mut self, chain: KeyChain, index: u32) -> Result<Address, MultisigError> {
+        let policy = self.policy_at(chain, index)?;
+        let address = policy.address();
+        self.addresses
+            .insert(address, (KeyIndex { chain, index }, policy));
+        Ok(address)
+    }
+
+    pub fn is_mine(&self, address: &Address) -> bool {
+        self.addresses.contains_key(address)
+    }
+
+    pub fn key_index(&self, address: &Address) -> Option<KeyIndex> {
+        self.addresses.get(address).map(|(key_index, _)| *key_index)
+    }
+
+    pub fn policy(&self, address: &Address) -> Option<&Policy> {
+        self.addresses.get(address).map(|(_, policy)| policy)
+    }
+
+    /// Derived addresses, in no particular order.
+    pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+        self.addresses.keys()
+    }
+
+    /// Encoded size of a `PolicyAuthorization` spend with `threshold`
+    /// signatures.
+    fn authorization_size(&self) -> Result<u64, MultisigError> {
+        let policy = self.policy_at(KeyChain::Receive, 0)?;
+        // Enum tag, signature count and no preimage around the policy.
+        let size = 4 + encode_canonical(&policy)?.len() as u64 + 8 + 1;
+        Ok(size + u64::from(self.threshold) * KeyAuthorization::MAX_ENCODED_SIZE)
+    }
+
+    fn psst_input<A, C>(&self, spent: Output<C>) -> Result<MultisigPsstInput<A, C>, MultisigError> {
+        let (key_index, policy) =
+            self.addresses
+                .get(&spent.address)
+                .cloned()
+                .ok_or(MultisigError::NotMine {
+                    address: spent.address,
+                })?;
+        Ok(MultisigPsstInput {
+            spent,
+            key_index,
+            policy,
+            signatures: BTreeMap::new(),
+        })
+    }
+
+    /// Drafts a transaction spending `candidates`, change goes to the next
+    /// change address, which is only used up if there is change. The fee
+    /// accounts for `threshold` signatures per input.
+    pub fn create_psst<A, C: Clone + GetValue + Serialize>(
+        &mut self,
+        builder: &TransactionBuilder<C>,
+        candidates: &[(OutPoint, Output<C>)],
+    ) -> Result<MultisigPsst<A, C>, MultisigError> {
+        let index = self.next_index(KeyChain::Change);
+        let change_address = self.derive_address(KeyChain::Change, index)?;
+        let unsigned = builder
+            .clone()
+            .authorization_size(self.authorization_size()?)
+            .change_address(change_address)
+            .build(candidates)?;
+        let inputs = unsigned
+            .spent
+            .into_iter()
+            .map(|spent| self.psst_input(spent))
+            .collect::<Result<_, _>>()?;
+        if unsigned.change_vout.is_some() {
+            self.next_change += 1;
+        }
+        Ok(MultisigPsst {
+            transaction: unsigned.transaction,
+            inputs,
+            fee: unsigned.fee,
+            change_vout: unsigned.change_vout,
+        })
+    }
+}
+
+impl IsMine for MultisigWallet {
+    fn is_mine(&self, address: &Address) -> bool {
+        MultisigWallet::is_mine(self, address)
+    }
+}
+
+/// Draft of a multisig transaction collecting the cosigners' signatures.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigPsst<A, C> {
+    pub transaction: Transaction<C>,
+    /// One per transaction input, in input order.
+    pub inputs: Vec<MultisigPsstInput<A, C>>,
+    pub fee: u64,
+    /// Position of the change output.
+    pub change_vout: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisigPsstInput<A, C> {
+    /// Output spent by the input.
+    pub spent: Output<C>,
+    /// Where cosigners derive their member key, under their account.
+    pub key_index: KeyIndex,
+    /// Policy of `spent.address`.
+    pub policy: Policy,
+    /// Signatures collected so far, by member address.
+    pub signatures: BTreeMap<Address, A>,
+}
+
+impl<A, C> MultisigPsstInput<A, C> {
+    fn members(&self) -> (u32, &[Address]) {
+        match &self.policy {
+            Policy::Multisig {
+                threshold,
+                addresses,
+            } => (*threshold, addresses),
+            Policy::Htlc { .. } => (u32::MAX, &[]),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        let (threshold, _) = self.members();
+        self.signatures.len() >= threshold as usize
+    }
+}
+
+impl<A: Clone + GetAddress, C> MultisigPsst<A, C> {
+    /// Adds the signatures of `other`, a copy of the same draft signed by
+    /// other cosigners.
+    pub fn combine(&mut self, other: MultisigPsst<A, C>) -> Result<(), MultisigError>
+    where
+        C: Serialize,
+    {
+        if self.transaction.try_txid()? != other.transaction.try_txid()?
+            || self.inputs.len() != other.inputs.len()
+        {
+            return Err(MultisigError::DifferentTransactions);
+        }
+        for (input, other) in self.inputs.iter_mut().zip(other.inputs) {
+            let members = input.members().1.to_vec();
+            for (address, signature) in other.signatures {
+                if signature.get_address() != address || !members.contains(&address) {
+                    return Err(MultisigError::NotAMember { address });
+                }
+                input.signatures.entry(address).or_insert(signature);
+            }
+        }
+        Ok(())
+    }
+
+    /// Indices of the inputs with fewer than `threshold` signatures.
+    pub fn incomplete_inputs(&self) -> Vec<usize> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| !input.is_complete())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.inputs.iter().all(MultisigPsstInput::is_complete)
+    }
+
+    /// The authorized transaction, once every input has `threshold`
+    /// signatures. Extra signatures are left out, members sorted.
+    pub fn finalize(
+        self,
+    ) -> Result<AuthorizedTransaction<PolicyAuthorization<A>, C>, MultisigError> {
+        let mut authorizations = Vec::with_capacity(self.inputs.len());
+        for (index, input) in self.inputs.into_iter().enumerate() {
+            let (threshold, _) = input.members();
+            if input.signatures.len() < threshold as usize {
+                return Err(MultisigError::NotEnoughSignatures {
+                    input: index,
+                    signatures: input.signatures.len(),
+                    threshold,
+                });
+            }
+            let signatures = input
+                .signatures
+                .into_values()
+                .take(threshold as usize)
+                .collect();
+            authorizations.push(PolicyAuthorization::Policy(PolicySpend {
+                policy: input.policy,
+                authorizations: signatures,
+                preimage: None,
+            }));
+        }
+        Ok(AuthorizedTransaction {
+            transaction: self.transaction,
+            authorizations,
+        })
+    }
+}
+
+impl<A, C: Serialize> MultisigPsst<A, C> {
+    pub fn txid(&self) -> Result<Txid, EncodeError> {
+        self.txid_with::<DefaultHashFunction>()
+    }
+
+    pub fn txid_with<H: HashFunction>(&self) -> Result<Txid, EncodeError> {
+        self.transaction.try_txid_with::<H>()
+    }
+
+    /// Adds the signatures `signer` can make for members that haven't signed
+    /// yet, returns how many it added.
+    pub fn sign<S: Signer<A>>(&mut self, signer: &S) -> Result<usize, PsstSignError<S::Error>> {
+        self.sign_with::<DefaultHashFunction, S>(signer)
+    }
+
+    /// `sign` for chains hashing with `H`.
+    pub fn sign_with<H: HashFunction, S: Signer<A>>(
+        &mut self,
+        signer: &S,
+    ) -> Result<usize, PsstSignError<S::Error>> {
+        let txid = self.txid_with::<H>()?;
+        let mut signed = 0;
+        for input in &mut self.inputs {
+            let members = input.members().1.to_vec();
+            for member in members {
+                if input.signatures.contains_key(&member) {
+                    continue;
+                }
+                let signature = signer.sign(&member, &txid).map_err(PsstSignError::Signer)?;
+                if let Some(signature) = signature {
+                    input.signatures.insert(member, signature);
+                    signed += 1;
+                }
+            }
+        }
+        Ok(signed)
+    }
+}
+
+impl<A: Serialize, C: Serialize> MultisigPsst<A, C> {
+    pub fn export(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_canonical(self)
+    }
+}
+
+impl<A: DeserializeOwned, C: DeserializeOwned> MultisigPsst<A, C> {
+    pub fn import(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        decode_canonical(bytes)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MultisigError {
+    #[error("threshold {threshold} is invalid for {cosigners} cosigners")]
+    InvalidThreshold { threshold: u32, cosigners: usize },
+    #[error("cosigner added more than once")]
+    DuplicateCosigner,
+    #[error("{address} is not an address of the wallet")]
+    NotMine { address: Address },
+    #[error("{address} is not a member of the multisig")]
+    NotAMember { address: Address },
+    #[error("pssts are drafts of different transactions")]
+    DifferentTransactions,
+    #[error("input {input} has {signatures} of {threshold} signatures")]
+    NotEnoughSignatures {
+        input: usize,
+        signatures: usize,
+        threshold: u32,
+    },
+    #[error("key derivation error")]
+    Bip32(#[from] bip32::Error),
+    #[error("failed to build transaction")]
+    Build(#[from] TransactionBuilderError),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
@@ -0,0 +1,191 @@
+use crate::deposit::{self, DepositScriptError, PegInAddress};
+use crate::mempool::FeeRate;
+use crate::types::*;
+use crate::withdrawal::{
+    self, BundleError, WithdrawalBundle, MAX_BUNDLE_WEIGHT, MIN_RELAY_FEE_RATE,
+};
+use bitcoin::{Script, Transaction, TxOut};
+use serde::{Deserialize, Serialize};
+
+/// Upper bound on the total value, the 21 million BTC mainchain supply in
+/// satoshis.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Consensus parameters of a sidechain.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+    /// Number of blocks after which coinbase outputs can be spent, a coinbase
+    /// output created at height `h` can be spent in block `h + maturity`.
+    /// Zero disables the check.
+    pub coinbase_maturity: u32,
+    /// Number of mainchain confirmations after which deposits enter the UTXO
+    /// set, the mainchain block including a deposit is its first
+    /// confirmation. Until then they are pending deposits. Zero and one add
+    /// deposits right away.
+    pub deposit_confirmations: u32,
+    /// What happens to the value of outputs sent to `Address::burn`.
+    pub burned_value: BurnedValue,
+    /// Whether withdrawals of a failed bundle are refunded as regular value
+    /// outputs to the same address, instead of staying withdrawals that can
+    /// be bundled again. The refund needs no signature.
+    pub refund_failed_withdrawals: bool,
+    /// Mainchain slot and peg parameters of the sidechain.
+    pub sidechain: SidechainParams,
+}
+
+/// Burn outputs never enter the UTXO set, their value leaves the supply or
+/// goes to the block producer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BurnedValue {
+    #[default]
+    Destroyed,
+    /// Counts towards the fees the coinbase can collect.
+    Fee,
+}
+
+/// Two-way peg parameters of the mainchain sidechain slot, so one binary can
+/// serve several slots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SidechainParams {
+    /// Slot of the sidechain on the mainchain (BIP300).
+    pub sidechain_number: u8,
+    pub deposit_script_template: DepositScriptTemplate,
+    pub bundle_limits: BundleLimits,
+}
+
+/// How deposit transactions name their sidechain recipient.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepositScriptTemplate {
+    /// OP_RETURN pushing the recipient address, see `deposit_script`.
+    #[default]
+    Address,
+    /// OP_RETURN pushing the sidechain number and then the recipient address,
+    /// for mainchains where one OP_RETURN format serves every slot.
+    NumberedAddress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleLimits {
+    /// Bundle transaction weight limit.
+    pub max_weight: usize,
+    /// Fee rate M6 transactions must pay on the mainchain, in sats per vbyte.
+    pub min_fee_rate: FeeRate,
+}
+
+impl Default for BundleLimits {
+    fn default() -> Self {
+        Self {
+            max_weight: MAX_BUNDLE_WEIGHT,
+            min_fee_rate: MIN_RELAY_FEE_RATE,
+        }
+    }
+}
+
+impl SidechainParams {
+    /// OP_RETURN script of a deposit to `address`.
+    pub fn deposit_script(&self, address: &Address) -> Script {
+        match self.deposit_script_template {
+            DepositScriptTemplate::Address => deposit::deposit_script(address),
+            DepositScriptTemplate::NumberedAddress => {
+                deposit::numbered_deposit_script(self.sidechain_number, address)
+            }
+        }
+    }
+
+    /// Zero value output carrying `deposit_script`.
+    pub fn deposit_output(&self, address: &Address) -> TxOut {
+        TxOut {
+            value: 0,
+            script_pubkey: self.deposit_script(address),
+        }
+    }
+
+    /// Inverse of `deposit_script`.
+    pub fn deposit_address(&self, script: &Script) -> Result<Address, DepositScriptError> {
+        let sidechain_number = match self.deposit_script_template {
+            DepositScriptTemplate::Address => None,
+            DepositScriptTemplate::NumberedAddress => Some(self.sidechain_number),
+        };
+        deposit::parse_deposit_script(script, sidechain_number)
+    }
+
+    /// Recipient of a deposit transaction, from its first OP_RETURN output.
+    pub fn find_deposit_address(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Address, DepositScriptError> {
+        let output = transaction
+            .output
+            .iter()
+            .find(|output| output.script_pubkey.is_op_return())
+            .ok_or(DepositScriptError::NotOpReturn)?;
+        self.deposit_address(&output.script_pubkey)
+    }
+
+    /// Deposit address users pay to for a deposit to `address`.
+    pub fn peg_in_address(&self, address: Address) -> PegInAddress {
+        PegInAddress {
+            sidechain_number: self.sidechain_number,
+            address,
+        }
+    }
+
+    /// Mainchain outputs of a deposit of `value` to `address`: the treasury
+    /// output followed by the deposit output. The enforcer credits the
+    /// increase of the treasury, so a transaction also spending the old
+    /// treasury adds its value to `value`.
+    pub fn deposit_outputs(&self, address: &Address, value: u64) -> [TxOut; 2] {
+        [
+            TxOut {
+                value,
+                script_pubkey: self.treasury_script(),
+            },
+            self.deposit_output(address),
+        ]
+    }
+
+    /// Inverse of `deposit_outputs`, returns the recipient and the value of
+    /// the treasury output.
+    pub fn parse_deposit(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<(Address, u64), DepositScriptError> {
+        let treasury_script = self.treasury_script();
+        let treasury = transaction
+            .output
+            .iter()
+            .find(|output| output.script_pubkey == treasury_script)
+            .ok_or(DepositScriptError::NoTreasuryOutput)?;
+        Ok((self.find_deposit_address(transaction)?, treasury.value))
+    }
+
+    /// Script holding the treasury of the sidechain slot.
+    pub fn treasury_script(&self) -> Script {
+        withdrawal::treasury_script(self.sidechain_number)
+    }
+
+    /// Builds a bundle within `bundle_limits`, see `build_withdrawal_bundle`.
+    pub fn build_withdrawal_bundle(
+        &self,
+        withdrawals: impl IntoIterator<Item = (OutPoint, WithdrawalOutput)>,
+    ) -> Result<Option<WithdrawalBundle>, BundleError> {
+        withdrawal::build_withdrawal_bundle(withdrawals, self.bundle_limits.max_weight)
+    }
+
+    /// Checks that `bundle` pays `bundle_limits.min_fee_rate`, see
+    /// `WithdrawalBundle::check_relay_fee`.
+    pub fn check_bundle_fee(&self, bundle: &WithdrawalBundle) -> Result<(), BundleError> {
+        bundle.check_relay_fee(self.bundle_limits.min_fee_rate)
+    }
+
+    /// M6 transaction paying out `bundle` from the slot's treasury, see
+    /// `WithdrawalBundle::to_m6`.
+    pub fn to_m6(
+        &self,
+        bundle: &WithdrawalBundle,
+        treasury: bitcoin::OutPoint,
+        treasury_value: u64,
+    ) -> Result<Transaction, BundleError> {
+        bundle.to_m6(treasury, treasury_value, self.sidechain_number)
+    }
+}
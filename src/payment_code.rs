@@ -0,0 +1,154 @@
+use crate::types::*;
+use bitcoin::hashes::{sha256, Hash as _, HashEngine as _};
+use bitcoin::secp256k1::{ecdh::SharedSecret, PublicKey, Scalar, Secp256k1, SecretKey};
+use bitcoin::secp256k1::{Signing, Verification};
+
+const PAYMENT_CODE_LENGTH: usize = 66;
+const OUTPUT_TWEAK_TAG: &[u8] = b"sdk_types payment code output";
+
+/// Reusable address, in the style of silent payments.
+///
+/// Senders derive a fresh one time address for every payment from the
+/// payment code and the key of their transaction, the published code never
+/// appears on chain. The recipient finds its outputs with
+/// `PaymentCodeScanner`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PaymentCode {
+    pub scan: PublicKey,
+    pub spend: PublicKey,
+}
+
+impl PaymentCode {
+    /// One time address of the `index`th output paid to this code by the
+    /// holder of `sender_key` in one transaction. Indexes have to start at 0
+    /// and be consecutive, the scanner stops at the first missing one.
+    pub fn one_time_address<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        sender_key: &SecretKey,
+        index: u32,
+    ) -> Result<Address, PaymentCodeError> {
+        let shared_secret = SharedSecret::new(&self.scan, sender_key);
+        let key = self
+            .spend
+            .add_exp_tweak(secp, &output_tweak(&shared_secret, index)?)?;
+        Ok(Address::from_public_key(&key))
+    }
+
+    pub fn to_base58(&self) -> String {
+        let mut bytes = Vec::with_capacity(PAYMENT_CODE_LENGTH);
+        bytes.extend_from_slice(&self.scan.serialize());
+        bytes.extend_from_slice(&self.spend.serialize());
+        bs58::encode(bytes)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .with_check()
+            .into_string()
+    }
+}
+
+impl std::fmt::Display for PaymentCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_base58())
+    }
+}
+
+impl std::str::FromStr for PaymentCode {
+    type Err = PaymentCodeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .with_check(None)
+            .into_vec()
+            .map_err(AddressParseError::from)?;
+        if bytes.len() != PAYMENT_CODE_LENGTH {
+            return Err(AddressParseError::WrongLength {
+                length: bytes.len(),
+            }
+            .into());
+        }
+        let (scan, spend) = bytes.split_at(PAYMENT_CODE_LENGTH / 2);
+        Ok(Self {
+            scan: PublicKey::from_slice(scan)?,
+            spend: PublicKey::from_slice(spend)?,
+        })
+    }
+}
+
+/// Output found by `PaymentCodeScanner::scan`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectedPayment {
+    pub vout: u32,
+    /// Added to the spend secret key to get the key of the output, see
+    /// `PaymentCodeScanner::output_secret_key`.
+    pub tweak: [u8; 32],
+}
+
+/// Wallet side of a `PaymentCode`, it can detect payments without the spend
+/// secret key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentCodeScanner {
+    pub scan_secret: SecretKey,
+    pub spend: PublicKey,
+}
+
+impl PaymentCodeScanner {
+    pub fn payment_code<C: Signing>(&self, secp: &Secp256k1<C>) -> PaymentCode {
+        PaymentCode {
+            scan: PublicKey::from_secret_key(secp, &self.scan_secret),
+            spend: self.spend,
+        }
+    }
+
+    /// Outputs of a transaction from the holder of `sender_key` paying
+    /// this payment code.
+    pub fn scan<C: Verification, O: GetAddress>(
+        &self,
+        secp: &Secp256k1<C>,
+        sender_key: &PublicKey,
+        outputs: &[O],
+    ) -> Result<Vec<DetectedPayment>, PaymentCodeError> {
+        let shared_secret = SharedSecret::new(sender_key, &self.scan_secret);
+        let mut detected = Vec::new();
+        for index in 0.. {
+            let tweak = output_tweak(&shared_secret, index)?;
+            let address = Address::from_public_key(&self.spend.add_exp_tweak(secp, &tweak)?);
+            let Some(vout) = outputs.iter().position(|o| o.get_address() == address) else {
+                break;
+            };
+            detected.push(DetectedPayment {
+                vout: vout as u32,
+                tweak: tweak.to_be_bytes(),
+            });
+        }
+        Ok(detected)
+    }
+
+    /// Secret key of a detected output.
+    pub fn output_secret_key(
+        spend_secret: SecretKey,
+        payment: &DetectedPayment,
+    ) -> Result<SecretKey, PaymentCodeError> {
+        let tweak =
+            Scalar::from_be_bytes(payment.tweak).map_err(|_| PaymentCodeError::InvalidTweak)?;
+        Ok(spend_secret.add_tweak(&tweak)?)
+    }
+}
+
+fn output_tweak(shared_secret: &SharedSecret, index: u32) -> Result<Scalar, PaymentCodeError> {
+    let mut engine = sha256::Hash::engine();
+    engine.input(OUTPUT_TWEAK_TAG);
+    engine.input(&shared_secret.secret_bytes());
+    engine.input(&index.to_be_bytes());
+    let tweak = sha256::Hash::from_engine(engine).into_inner();
+    Scalar::from_be_bytes(tweak).map_err(|_| PaymentCodeError::InvalidTweak)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PaymentCodeError {
+    #[error("invalid payment code")]
+    Encoding(#[from] AddressParseError),
+    #[error("secp256k1 error")]
+    Secp256k1(#[from] bitcoin::secp256k1::Error),
+    #[error("derived tweak is not a valid scalar")]
+    InvalidTweak,
+}
@@ -0,0 +1,162 @@
+use crate::types::*;
+use bitcoin::hashes::{sha256, Hash as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Separates policy addresses from addresses derived from keys.
+const POLICY_ADDRESS_TAG: &[u8] = b"sdk_types policy address";
+
+/// Spending policy an output can be locked to through its address, like a
+/// P2SH script.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Policy {
+    /// Spendable with authorizations for `threshold` distinct addresses out
+    /// of `addresses`.
+    Multisig {
+        threshold: u32,
+        addresses: Vec<Address>,
+    },
+    /// Spendable by `recipient` with the SHA-256 preimage of
+    /// `payment_hash`, or by `refund` from height `timeout` on. SHA-256
+    /// makes swaps with mainchain HTLCs possible.
+    Htlc {
+        payment_hash: [u8; 32],
+        recipient: Address,
+        refund: Address,
+        timeout: u32,
+    },
+}
+
+impl Policy {
+    /// Address outputs are sent to in order to lock them to this policy.
+    pub fn address(&self) -> Address {
+        Address::V1ScriptHash(hash(&(POLICY_ADDRESS_TAG, self)).to_bytes())
+    }
+}
+
+/// Spend of an output locked to a policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicySpend<A> {
+    /// Revealed policy, it must hash to the address of the spent output.
+    pub policy: Policy,
+    /// Authorizations satisfying the policy, their signatures are checked
+    /// by `Verify` like any other authorization.
+    pub authorizations: Vec<A>,
+    /// Payment preimage of an HTLC claim.
+    pub preimage: Option<[u8; 32]>,
+}
+
+impl<A: GetAddress> PolicySpend<A> {
+    /// Checks that the authorizations and preimage satisfy the policy at
+    /// `height`, without verifying the authorizations themselves.
+    pub fn check(&self, height: u32) -> Result<(), PolicyError> {
+        match &self.policy {
+            Policy::Multisig {
+                threshold,
+                addresses,
+            } => {
+                if *threshold == 0 || *threshold as usize > addresses.len() {
+                    return Err(PolicyError::InvalidThreshold {
+                        threshold: *threshold,
+                        addresses: addresses.len(),
+                    });
+                }
+                let members: HashSet<Address> = addresses.iter().copied().collect();
+                let mut signers = HashSet::with_capacity(self.authorizations.len());
+                for authorization in &self.authorizations {
+                    let address = authorization.get_address();
+                    if !members.contains(&address) {
+                        return Err(PolicyError::NotAMember { address });
+                    }
+                    if !signers.insert(address) {
+                        return Err(PolicyError::DuplicateSigner { address });
+                    }
+                }
+                if signers.len() < *threshold as usize {
+                    return Err(PolicyError::NotEnoughSigners {
+                        threshold: *threshold,
+                        signers: signers.len(),
+                    });
+                }
+            }
+            Policy::Htlc {
+                payment_hash,
+                recipient,
+                refund,
+                timeout,
+            } => {
+                let expected = match self.preimage {
+                    Some(preimage) => {
+                        if sha256::Hash::hash(&preimage).into_inner() != *payment_hash {
+                            return Err(PolicyError::WrongPreimage);
+                        }
+                        *recipient
+                    }
+                    None => {
+                        if height < *timeout {
+                            return Err(PolicyError::TimeoutNotReached {
+                                timeout: *timeout,
+                                height,
+                            });
+                        }
+                        *refund
+                    }
+                };
+                match self.authorizations.as_slice() {
+                    [authorization] if authorization.get_address() == expected => {}
+                    _ => return Err(PolicyError::WrongHtlcAuthorization { expected }),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Authorization of an output locked either to a key or to a policy.
+///
+/// The address of a policy spend is the policy address, so `validate_body`
+/// rejects spends revealing a policy other than the one committed to by the
+/// spent output. `Verify` implementations still have to call
+/// `PolicySpend::check` and verify every authorization of `authorizations`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PolicyAuthorization<A> {
+    Key(A),
+    Policy(PolicySpend<A>),
+}
+
+impl<A> PolicyAuthorization<A> {
+    /// Key authorizations this authorization consists of.
+    pub fn authorizations(&self) -> &[A] {
+        match self {
+            Self::Key(authorization) => std::slice::from_ref(authorization),
+            Self::Policy(spend) => &spend.authorizations,
+        }
+    }
+}
+
+impl<A: GetAddress> GetAddress for PolicyAuthorization<A> {
+    fn get_address(&self) -> Address {
+        match self {
+            Self::Key(authorization) => authorization.get_address(),
+            Self::Policy(spend) => spend.policy.address(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PolicyError {
+    #[error("multisig threshold {threshold} is invalid for {addresses} addresses")]
+    InvalidThreshold { threshold: u32, addresses: usize },
+    #[error("{address} is not a multisig member")]
+    NotAMember { address: Address },
+    #[error("{address} signed more than once")]
+    DuplicateSigner { address: Address },
+    #[error("multisig needs {threshold} signers, got {signers}")]
+    NotEnoughSigners { threshold: u32, signers: usize },
+    #[error("preimage does not match the payment hash")]
+    WrongPreimage,
+    #[error("htlc refund is possible from height {timeout}, height is {height}")]
+    TimeoutNotReached { timeout: u32, height: u32 },
+    #[error("htlc must be authorized by {expected} alone")]
+    WrongHtlcAuthorization { expected: Address },
+}
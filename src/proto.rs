@@ -0,0 +1,185 @@
+use crate::types::{Address, AddressParseError, Content, MainBlockEvents, Output};
+use crate::withdrawal::WithdrawalBundle;
+use bitcoin::hashes::Hash as _;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OutPoint {
+    #[prost(bytes = "vec", tag = "1")]
+    pub txid: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub vout: u32,
+}
+
+impl From<bitcoin::OutPoint> for OutPoint {
+    fn from(outpoint: bitcoin::OutPoint) -> Self {
+        Self {
+            txid: outpoint.txid.to_vec(),
+            vout: outpoint.vout,
+        }
+    }
+}
+
+impl TryFrom<OutPoint> for bitcoin::OutPoint {
+    type Error = ProtoError;
+
+    fn try_from(outpoint: OutPoint) -> Result<Self, ProtoError> {
+        Ok(Self {
+            txid: bitcoin::Txid::from_slice(&outpoint.txid)
+                .map_err(|_| ProtoError::InvalidHash { field: "txid" })?,
+            vout: outpoint.vout,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetChainTipRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetChainTipResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub height: u32,
+}
+
+impl GetChainTipResponse {
+    pub fn new(block_hash: bitcoin::BlockHash, height: u32) -> Self {
+        Self {
+            block_hash: block_hash.to_vec(),
+            height,
+        }
+    }
+
+    pub fn tip(&self) -> Result<bitcoin::BlockHash, ProtoError> {
+        bitcoin::BlockHash::from_slice(&self.block_hash).map_err(|_| ProtoError::InvalidHash {
+            field: "block_hash",
+        })
+    }
+}
+
+/// Deposits to `sidechain_number` in the mainchain block with `block_hash`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetDepositsRequest {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub block_hash: Vec<u8>,
+}
+
+impl GetDepositsRequest {
+    pub fn new(sidechain_number: u8, block_hash: bitcoin::BlockHash) -> Self {
+        Self {
+            sidechain_number: sidechain_number.into(),
+            block_hash: block_hash.to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Deposit {
+    #[prost(message, optional, tag = "1")]
+    pub outpoint: Option<OutPoint>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub address: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub value: u64,
+}
+
+impl Deposit {
+    pub fn new(outpoint: bitcoin::OutPoint, address: &Address, value: u64) -> Self {
+        Self {
+            outpoint: Some(outpoint.into()),
+            address: address.to_bytes().to_vec(),
+            value,
+        }
+    }
+
+    /// Outpoint and sidechain output of the deposit.
+    pub fn to_output<C>(&self) -> Result<(bitcoin::OutPoint, Output<C>), ProtoError> {
+        let outpoint = self
+            .outpoint
+            .clone()
+            .ok_or(ProtoError::MissingField { field: "outpoint" })?;
+        let output = Output {
+            address: Address::from_slice(&self.address)?,
+            content: Content::Value(self.value),
+        };
+        Ok((outpoint.try_into()?, output))
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetDepositsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub deposits: Vec<Deposit>,
+}
+
+impl GetDepositsResponse {
+    /// Events of the block with its deposits only, bundle events are
+    /// reported separately.
+    pub fn to_events<C>(&self) -> Result<MainBlockEvents<C>, ProtoError> {
+        Ok(MainBlockEvents {
+            deposits: self
+                .deposits
+                .iter()
+                .map(Deposit::to_output)
+                .collect::<Result<_, _>>()?,
+            ..MainBlockEvents::default()
+        })
+    }
+}
+
+/// Proposes a bundle transaction as the next M6 of `sidechain_number`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BroadcastWithdrawalBundleRequest {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub transaction: Vec<u8>,
+}
+
+impl BroadcastWithdrawalBundleRequest {
+    pub fn new(sidechain_number: u8, bundle: &WithdrawalBundle) -> Self {
+        Self {
+            sidechain_number: sidechain_number.into(),
+            transaction: bitcoin::consensus::serialize(&bundle.transaction),
+        }
+    }
+
+    pub fn transaction(&self) -> Result<bitcoin::Transaction, ProtoError> {
+        Ok(bitcoin::consensus::deserialize(&self.transaction)?)
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BroadcastWithdrawalBundleResponse {
+    /// Blinded M6 id of the bundle, see `WithdrawalBundle::hash`.
+    #[prost(bytes = "vec", tag = "1")]
+    pub bundle_hash: Vec<u8>,
+}
+
+impl BroadcastWithdrawalBundleResponse {
+    pub fn new(bundle_hash: bitcoin::Txid) -> Self {
+        Self {
+            bundle_hash: bundle_hash.to_vec(),
+        }
+    }
+
+    pub fn bundle_hash(&self) -> Result<bitcoin::Txid, ProtoError> {
+        bitcoin::Txid::from_slice(&self.bundle_hash).map_err(|_| ProtoError::InvalidHash {
+            field: "bundle_hash",
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtoError {
+    #[error("{field} is not a 32 byte hash")]
+    InvalidHash { field: &'static str },
+    #[error("{field} is missing")]
+    MissingField { field: &'static str },
+    #[error("invalid sidechain address")]
+    Address(#[from] AddressParseError),
+    #[error("invalid transaction")]
+    Transaction(#[from] bitcoin::consensus::encode::Error),
+}
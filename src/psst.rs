@@ -0,0 +1,160 @@
+use crate::encoding::{decode_canonical, encode_canonical, EncodeError};
+use crate::transaction_builder::UnsignedTransaction;
+use crate::types::*;
+use crate::wallet::Signer;
+use bitcoin::util::bip32::DerivationPath;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Partially signed sidechain transaction, an unsigned transaction with
+/// everything an external signer needs to authorize its inputs.
+///
+/// Signers only ever sign the txid computed from `transaction`, so a
+/// tampered PSST can't get them to authorize another transaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Psst<A, C> {
+    pub transaction: Transaction<C>,
+    /// One per transaction input, in input order.
+    pub inputs: Vec<PsstInput<A, C>>,
+    pub fee: u64,
+    /// Position of the change output.
+    pub change_vout: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsstInput<A, C> {
+    /// Output spent by the input.
+    pub spent: Output<C>,
+    /// Path of the key of `spent.address` from the master key, if known.
+    pub derivation_path: Option<DerivationPath>,
+    pub authorization: Option<A>,
+}
+
+impl<A, C> Psst<A, C> {
+    /// Looks up the derivation path of every spent address with
+    /// `derivation_path`.
+    pub fn new(
+        unsigned: UnsignedTransaction<C>,
+        mut derivation_path: impl FnMut(&Address) -> Option<DerivationPath>,
+    ) -> Self {
+        let inputs = unsigned
+            .spent
+            .into_iter()
+            .map(|spent| PsstInput {
+                derivation_path: derivation_path(&spent.address),
+                spent,
+                authorization: None,
+            })
+            .collect();
+        Self {
+            transaction: unsigned.transaction,
+            inputs,
+            fee: unsigned.fee,
+            change_vout: unsigned.change_vout,
+        }
+    }
+
+    /// Indices of the inputs without an authorization.
+    pub fn missing_authorizations(&self) -> Vec<usize> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| input.authorization.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.inputs
+            .iter()
+            .all(|input| input.authorization.is_some())
+    }
+
+    /// The authorized transaction, once every input is authorized, with
+    /// authorizations in input order.
+    pub fn finalize(self) -> Result<AuthorizedTransaction<A, C>, PsstError> {
+        if self.inputs.len() != self.transaction.inputs.len() {
+            return Err(PsstError::InputCountMismatch {
+                inputs: self.transaction.inputs.len(),
+                psst_inputs: self.inputs.len(),
+            });
+        }
+        let missing = self.missing_authorizations();
+        if !missing.is_empty() {
+            return Err(PsstError::MissingAuthorizations { inputs: missing });
+        }
+        Ok(AuthorizedTransaction {
+            transaction: self.transaction,
+            authorizations: self
+                .inputs
+                .into_iter()
+                .filter_map(|input| input.authorization)
+                .collect(),
+        })
+    }
+}
+
+impl<A, C: Serialize> Psst<A, C> {
+    pub fn txid(&self) -> Result<Txid, EncodeError> {
+        self.txid_with::<DefaultHashFunction>()
+    }
+
+    pub fn txid_with<H: HashFunction>(&self) -> Result<Txid, EncodeError> {
+        self.transaction.try_txid_with::<H>()
+    }
+
+    /// Authorizes every unauthorized input `signer` holds the key of,
+    /// returns how many it authorized.
+    pub fn sign<S: Signer<A>>(&mut self, signer: &S) -> Result<usize, PsstSignError<S::Error>> {
+        self.sign_with::<DefaultHashFunction, S>(signer)
+    }
+
+    /// `sign` for chains hashing with `H`.
+    pub fn sign_with<H: HashFunction, S: Signer<A>>(
+        &mut self,
+        signer: &S,
+    ) -> Result<usize, PsstSignError<S::Error>> {
+        let txid = self.txid_with::<H>()?;
+        let mut signed = 0;
+        for input in &mut self.inputs {
+            if input.authorization.is_some() {
+                continue;
+            }
+            let authorization = signer
+                .sign(&input.spent.address, &txid)
+                .map_err(PsstSignError::Signer)?;
+            if authorization.is_some() {
+                input.authorization = authorization;
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+}
+
+impl<A: Serialize, C: Serialize> Psst<A, C> {
+    pub fn export(&self) -> Result<Vec<u8>, EncodeError> {
+        encode_canonical(self)
+    }
+}
+
+impl<A: DeserializeOwned, C: DeserializeOwned> Psst<A, C> {
+    pub fn import(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        decode_canonical(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PsstError {
+    #[error("inputs {inputs:?} are not authorized")]
+    MissingAuthorizations { inputs: Vec<usize> },
+    #[error("transaction has {inputs} inputs, the psst has {psst_inputs}")]
+    InputCountMismatch { inputs: usize, psst_inputs: usize },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PsstSignError<E> {
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("signer error")]
+    Signer(#[source] E),
+}
@@ -0,0 +1,400 @@
+use crate::encoding::{decode_canonical, encode_canonical, EncodeError};
+use crate::types::*;
+use crate::withdrawal::{BundleError, BundleStatus, WithdrawalBundle};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Bincode does not support the flattened and untagged enums used here, so
+/// the whole envelope only works with human readable formats.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: Version,
+    pub id: Id,
+    #[serde(flatten)]
+    pub method: Method,
+}
+
+impl Request {
+    pub fn new(id: Id, method: Method) -> Self {
+        Self {
+            jsonrpc: Version,
+            id,
+            method,
+        }
+    }
+}
+
+/// Method name and its named parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum Method {
+    /// Answered with a `GetBlockResult`.
+    GetBlock(GetBlockParams),
+    /// Answered with a `GetRawTransactionResult`.
+    GetRawTransaction(GetRawTransactionParams),
+    /// Answered with the `Txid` of the accepted transaction.
+    SendRawTransaction(SendRawTransactionParams),
+    /// Answered with a list of `UnspentOutput`s.
+    ListUnspent(ListUnspentParams),
+    /// Answered with the current `WithdrawalBundleInfo`, if there is one.
+    GetWithdrawalBundle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetBlockParams {
+    pub hash: BlockHash,
+    /// Return a `BlockInfo` instead of the hex encoded block.
+    #[serde(default = "default_true")]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetRawTransactionParams {
+    pub txid: Txid,
+    /// Return a `TransactionInfo` instead of the hex encoded transaction.
+    #[serde(default)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendRawTransactionParams {
+    /// Canonical encoding of an `AuthorizedTransaction`, decode it with
+    /// `DecodeLimits::decode_authorized_transaction`.
+    pub hex: HexBytes,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListUnspentParams {
+    /// Only outputs to these addresses, all of the wallet's outputs if empty.
+    #[serde(default)]
+    pub addresses: Vec<Address>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Result of `getblock`, hex encoded `(Header, Body)` unless verbose.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GetBlockResult {
+    Hex(HexBytes),
+    Verbose(BlockInfo),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub hash: BlockHash,
+    pub height: u32,
+    pub header: Header,
+    pub coinbase_value: u64,
+    /// Txids in block order.
+    pub transactions: Vec<Txid>,
+}
+
+impl BlockInfo {
+    pub fn new<A, C: Clone + GetValue + Serialize>(
+        height: u32,
+        header: Header,
+        body: &Body<A, C>,
+    ) -> Result<Self, BlockInfoError> {
+        Self::new_with::<DefaultHashFunction, A, C>(height, header, body)
+    }
+
+    /// `new` for chains hashing with `H`.
+    pub fn new_with<H: HashFunction, A, C: Clone + GetValue + Serialize>(
+        height: u32,
+        header: Header,
+        body: &Body<A, C>,
+    ) -> Result<Self, BlockInfoError> {
+        Ok(Self {
+            hash: header.hash_with::<H>(),
+            height,
+            header,
+            coinbase_value: body
+                .get_coinbase_value()
+                .ok_or(BlockInfoError::ValueOverflow)?,
+            transactions: body
+                .transactions
+                .iter()
+                .map(Transaction::try_txid_with::<H>)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlockInfoError {
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("coinbase value overflows u64")]
+    ValueOverflow,
+}
+
+/// Result of `getrawtransaction`, hex encoded `AuthorizedTransaction` unless
+/// verbose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GetRawTransactionResult<C> {
+    Hex(HexBytes),
+    Verbose(TransactionInfo<C>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionInfo<C> {
+    pub txid: Txid,
+    /// Canonical encoding of the `AuthorizedTransaction`.
+    pub hex: HexBytes,
+    pub transaction: Transaction<C>,
+    /// `None` while the transaction is in the mempool.
+    pub block_hash: Option<BlockHash>,
+    /// Zero while the transaction is in the mempool.
+    pub confirmations: u32,
+}
+
+impl<C: Clone + Serialize> TransactionInfo<C> {
+    pub fn new<A: Serialize>(
+        transaction: &AuthorizedTransaction<A, C>,
+        block_hash: Option<BlockHash>,
+        confirmations: u32,
+    ) -> Result<Self, EncodeError> {
+        Self::new_with::<DefaultHashFunction, A>(transaction, block_hash, confirmations)
+    }
+
+    /// `new` for chains hashing with `H`.
+    pub fn new_with<H: HashFunction, A: Serialize>(
+        transaction: &AuthorizedTransaction<A, C>,
+        block_hash: Option<BlockHash>,
+        confirmations: u32,
+    ) -> Result<Self, EncodeError> {
+        Ok(Self {
+            txid: transaction.transaction.try_txid_with::<H>()?,
+            hex: HexBytes::encode(transaction)?,
+            transaction: transaction.transaction.clone(),
+            block_hash,
+            confirmations,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnspentOutput<C> {
+    pub outpoint: OutPoint,
+    pub output: Output<C>,
+    /// Value of `output`, so that clients don't need to know how to value
+    /// custom content.
+    pub value: u64,
+}
+
+impl<C: GetValue> UnspentOutput<C> {
+    pub fn new(outpoint: OutPoint, output: Output<C>) -> Self {
+        Self {
+            outpoint,
+            value: output.get_value(),
+            output,
+        }
+    }
+}
+
+/// Result of `getwithdrawalbundle`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalBundleInfo {
+    /// Blinded M6 id, see `WithdrawalBundle::hash`.
+    pub m6id: bitcoin::Txid,
+    pub status: BundleStatus,
+    pub spent_utxos: Vec<OutPoint>,
+    pub fee: u64,
+    pub payout_value: u64,
+    /// Consensus encoding of the bundle transaction.
+    pub transaction: HexBytes,
+}
+
+impl WithdrawalBundleInfo {
+    pub fn new(bundle: &WithdrawalBundle, status: BundleStatus) -> Result<Self, BundleError> {
+        Ok(Self {
+            m6id: bundle.hash(),
+            status,
+            spent_utxos: bundle.spent_utxos.clone(),
+            fee: bundle.fee()?,
+            payout_value: bundle.payout_value()?,
+            transaction: HexBytes(bitcoin::consensus::serialize(&bundle.transaction)),
+        })
+    }
+
+    pub fn to_bundle(&self) -> Result<WithdrawalBundle, bitcoin::consensus::encode::Error> {
+        Ok(WithdrawalBundle {
+            spent_utxos: self.spent_utxos.clone(),
+            transaction: bitcoin::consensus::deserialize(&self.transaction.0)?,
+        })
+    }
+}
+
+/// Either `result` or `error`, depending on the outcome.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response<R> {
+    pub jsonrpc: Version,
+    pub id: Id,
+    #[serde(flatten)]
+    pub outcome: Outcome<R>,
+}
+
+impl<R> Response<R> {
+    pub fn result(id: Id, result: R) -> Self {
+        Self {
+            jsonrpc: Version,
+            id,
+            outcome: Outcome::Result(result),
+        }
+    }
+
+    pub fn error(id: Id, error: RpcError) -> Self {
+        Self {
+            jsonrpc: Version,
+            id,
+            outcome: Outcome::Error(error),
+        }
+    }
+
+    pub fn into_result(self) -> Result<R, RpcError> {
+        match self.outcome {
+            Outcome::Result(result) => Ok(result),
+            Outcome::Error(error) => Err(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome<R> {
+    Result(R),
+    Error(RpcError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("rpc error {code}: {message}")]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub const PARSE_ERROR: i32 = -32700;
+    pub const INVALID_REQUEST: i32 = -32600;
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    pub const INVALID_PARAMS: i32 = -32602;
+    pub const INTERNAL_ERROR: i32 = -32603;
+    /// Unknown block, transaction or address, as in Bitcoin Core.
+    pub const NOT_FOUND: i32 = -5;
+    /// Transaction failed validation, as in Bitcoin Core.
+    pub const TRANSACTION_REJECTED: i32 = -26;
+
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Request id, echoed back in the response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(i64),
+    String(String),
+    Null,
+}
+
+/// `"2.0"`, the only protocol version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Version;
+
+const VERSION: &str = "2.0";
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(VERSION)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        if version != VERSION {
+            return Err(serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&version),
+                &VERSION,
+            ));
+        }
+        Ok(Version)
+    }
+}
+
+/// Hex string for human readable formats, raw bytes otherwise.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl HexBytes {
+    /// Canonical encoding of `value`, see `encode_canonical`.
+    pub fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Self, EncodeError> {
+        Ok(Self(encode_canonical(value)?))
+    }
+
+    /// Canonical decoding without limits, untrusted data should be decoded
+    /// with `DecodeLimits` instead.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, bincode::Error> {
+        decode_canonical(&self.0)
+    }
+}
+
+impl AsRef<[u8]> for HexBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for HexBytes {
+    fn from(other: Vec<u8>) -> Self {
+        Self(other)
+    }
+}
+
+impl std::fmt::Display for HexBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl std::fmt::Debug for HexBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl std::str::FromStr for HexBytes {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::decode(s)?))
+    }
+}
+
+impl Serialize for HexBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(&self.0))
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            hex.parse().map_err(serde::de::Error::custom)
+        } else {
+            Vec::deserialize(deserializer).map(Self)
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use crate::utxo::{UtxoBatch, UtxoMap, UtxoScan, UtxoVisitor, UtxoWrite};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+fn read<M>(lock: &RwLock<M>) -> RwLockReadGuard<'_, M> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<M>(lock: &RwLock<M>) -> RwLockWriteGuard<'_, M> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<M: UtxoMap + ?Sized> UtxoMap for Arc<M> {
+    type OutPoint = M::OutPoint;
+    type Output = M::Output;
+
+    fn get_utxo(&self, outpoint: &M::OutPoint) -> Option<M::Output> {
+        (**self).get_utxo(outpoint)
+    }
+
+    fn prefetch(&self, outpoints: &[M::OutPoint]) {
+        (**self).prefetch(outpoints)
+    }
+
+    fn get_utxos(&self, outpoints: &[M::OutPoint]) -> Option<Vec<M::Output>> {
+        (**self).get_utxos(outpoints)
+    }
+
+    fn is_spent(&self, outpoint: &M::OutPoint) -> bool {
+        (**self).is_spent(outpoint)
+    }
+
+    fn any_spent(&self, outpoints: &[M::OutPoint]) -> bool {
+        (**self).any_spent(outpoints)
+    }
+
+    fn is_mature(&self, outpoint: &M::OutPoint) -> bool {
+        (**self).is_mature(outpoint)
+    }
+}
+
+impl<M: UtxoScan + ?Sized> UtxoScan for Arc<M> {
+    type ScanError = M::ScanError;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, M::OutPoint, M::Output>) -> Result<(), M::ScanError> {
+        (**self).scan(f)
+    }
+}
+
+/// Every call takes the read lock, so `get_utxos` and `any_spent` see one
+/// consistent state. A panic while the lock was held doesn't poison reads.
+impl<M: UtxoMap> UtxoMap for RwLock<M> {
+    type OutPoint = M::OutPoint;
+    type Output = M::Output;
+
+    fn get_utxo(&self, outpoint: &M::OutPoint) -> Option<M::Output> {
+        read(self).get_utxo(outpoint)
+    }
+
+    fn prefetch(&self, outpoints: &[M::OutPoint]) {
+        read(self).prefetch(outpoints)
+    }
+
+    fn get_utxos(&self, outpoints: &[M::OutPoint]) -> Option<Vec<M::Output>> {
+        read(self).get_utxos(outpoints)
+    }
+
+    fn is_spent(&self, outpoint: &M::OutPoint) -> bool {
+        read(self).is_spent(outpoint)
+    }
+
+    fn any_spent(&self, outpoints: &[M::OutPoint]) -> bool {
+        read(self).any_spent(outpoints)
+    }
+
+    fn is_mature(&self, outpoint: &M::OutPoint) -> bool {
+        read(self).is_mature(outpoint)
+    }
+}
+
+/// Holds the read lock for the whole scan, writers wait until it's done.
+impl<M: UtxoScan> UtxoScan for RwLock<M> {
+    type ScanError = M::ScanError;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, M::OutPoint, M::Output>) -> Result<(), M::ScanError> {
+        read(self).scan(f)
+    }
+}
+
+impl<M: UtxoWrite> UtxoWrite for RwLock<M> {
+    type Error = M::Error;
+
+    fn insert(&mut self, outpoint: M::OutPoint, output: M::Output) -> Result<(), M::Error> {
+        write(self).insert(outpoint, output)
+    }
+
+    fn remove(&mut self, outpoint: &M::OutPoint) -> Result<(), M::Error> {
+        write(self).remove(outpoint)
+    }
+}
+
+/// Writes through a shared handle, the batch is applied under one write
+/// lock so readers never see it half applied.
+impl<M: UtxoWrite> UtxoWrite for Arc<RwLock<M>> {
+    type Error = M::Error;
+
+    fn insert(&mut self, outpoint: M::OutPoint, output: M::Output) -> Result<(), M::Error> {
+        write(self).insert(outpoint, output)
+    }
+
+    fn remove(&mut self, outpoint: &M::OutPoint) -> Result<(), M::Error> {
+        write(self).remove(outpoint)
+    }
+
+    fn apply_batch(&mut self, batch: UtxoBatch<M::OutPoint, M::Output>) -> Result<(), M::Error> {
+        write(self).apply_batch(batch)
+    }
+}
+
+#[cfg(feature = "dashmap")]
+mod dashmap_utxo {
+    use super::*;
+    use dashmap::DashMap;
+    use std::convert::Infallible;
+    use std::hash::Hash;
+
+    impl<P: Eq + Hash, O: Clone> UtxoMap for DashMap<P, O> {
+        type OutPoint = P;
+        type Output = O;
+
+        fn get_utxo(&self, outpoint: &P) -> Option<O> {
+            self.get(outpoint).map(|output| output.clone())
+        }
+
+        fn is_spent(&self, outpoint: &P) -> bool {
+            !self.contains_key(outpoint)
+        }
+    }
+
+    /// Shards are locked one at a time, so concurrent writes may be seen
+    /// partially.
+    impl<P: Eq + Hash, O: Clone> UtxoScan for DashMap<P, O> {
+        type ScanError = Infallible;
+
+        fn scan(&self, f: &mut UtxoVisitor<'_, P, O>) -> Result<(), Infallible> {
+            for entry in self.iter() {
+                if f(entry.key(), entry.value()).is_break() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<P: Eq + Hash, O: Clone> UtxoWrite for DashMap<P, O> {
+        type Error = Infallible;
+
+        fn insert(&mut self, outpoint: P, output: O) -> Result<(), Infallible> {
+            DashMap::insert(self, outpoint, output);
+            Ok(())
+        }
+
+        fn remove(&mut self, outpoint: &P) -> Result<(), Infallible> {
+            DashMap::remove(self, outpoint);
+            Ok(())
+        }
+    }
+
+    /// Writes through a shared handle. Unlike `Arc<RwLock<M>>` batches are
+    /// not atomic, readers may see a batch partially applied.
+    impl<P: Eq + Hash, O: Clone> UtxoWrite for Arc<DashMap<P, O>> {
+        type Error = Infallible;
+
+        fn insert(&mut self, outpoint: P, output: O) -> Result<(), Infallible> {
+            DashMap::insert(self, outpoint, output);
+            Ok(())
+        }
+
+        fn remove(&mut self, outpoint: &P) -> Result<(), Infallible> {
+            DashMap::remove(self, outpoint);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::hashes::{BlockHash, Txid};
+use bitcoin::hashes::{sha256, siphash24, Hash as _, HashEngine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const SHORT_ID_LENGTH: usize = 6;
+
+/// Txid truncated to 6 bytes of its SipHash-2-4, as in BIP 152 compact
+/// blocks.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct ShortId(pub [u8; SHORT_ID_LENGTH]);
+
+impl ShortId {
+    pub fn from_u64(short_id: u64) -> Self {
+        let mut bytes = [0; SHORT_ID_LENGTH];
+        bytes.copy_from_slice(&short_id.to_le_bytes()[..SHORT_ID_LENGTH]);
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Display for ShortId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for ShortId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// SipHash keys of the short ids of one compact block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShortIdKeys {
+    pub k0: u64,
+    pub k1: u64,
+}
+
+impl ShortIdKeys {
+    /// Keys derived from SHA-256 of the block hash and the sender's nonce,
+    /// so that peers can't precompute colliding transactions.
+    pub fn new(block_hash: &BlockHash, nonce: u64) -> Self {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&block_hash.0);
+        engine.input(&nonce.to_le_bytes());
+        let hash = sha256::Hash::from_engine(engine).into_inner();
+        let (k0, k1) = hash.split_at(8);
+        Self {
+            k0: u64::from_le_bytes(k0.try_into().unwrap()),
+            k1: u64::from_le_bytes(k1[..8].try_into().unwrap()),
+        }
+    }
+
+    pub fn short_id(&self, txid: &Txid) -> ShortId {
+        txid.short_id(self.k0, self.k1)
+    }
+}
+
+impl Txid {
+    pub fn short_id(&self, k0: u64, k1: u64) -> ShortId {
+        ShortId::from_u64(siphash24::Hash::hash_to_u64_with_keys(k0, k1, &self.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("short id {short_id} appears more than once")]
+pub struct DuplicateShortId {
+    pub short_id: ShortId,
+}
+
+/// Matches `short_ids` against known transactions, e.g. the mempool.
+///
+/// Returns one entry per short id, `None` where no candidate or more than
+/// one candidate matches, these have to be requested by txid. Fails if
+/// `short_ids` has duplicates, then the block can't be reconstructed from
+/// short ids at all.
+pub fn match_short_ids<T>(
+    keys: &ShortIdKeys,
+    short_ids: &[ShortId],
+    candidates: impl IntoIterator<Item = (Txid, T)>,
+) -> Result<Vec<Option<T>>, DuplicateShortId> {
+    let mut positions = HashMap::with_capacity(short_ids.len());
+    for (position, short_id) in short_ids.iter().enumerate() {
+        if positions.insert(*short_id, position).is_some() {
+            return Err(DuplicateShortId {
+                short_id: *short_id,
+            });
+        }
+    }
+    let mut matched: Vec<Option<T>> = short_ids.iter().map(|_| None).collect();
+    let mut collided = vec![false; short_ids.len()];
+    for (txid, candidate) in candidates {
+        let Some(&position) = positions.get(&keys.short_id(&txid)) else {
+            continue;
+        };
+        if matched[position].is_some() {
+            collided[position] = true;
+        }
+        matched[position] = Some(candidate);
+    }
+    for (entry, collided) in matched.iter_mut().zip(collided) {
+        if collided {
+            *entry = None;
+        }
+    }
+    Ok(matched)
+}
@@ -0,0 +1,209 @@
+use crate::utxo::{UtxoBatch, UtxoMap, UtxoScan, UtxoVisitor, UtxoWrite};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// Options used by `SledUtxoMap::open_with_options`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SledOptions {
+    /// Page cache size in bytes.
+    pub cache_capacity: u64,
+    /// Rewrite data more often to keep the files small, at the cost of
+    /// write throughput. Sled has no manual compaction, this is the only way
+    /// to trade throughput for disk space.
+    pub low_space: bool,
+    /// Interval of background flushes, `None` only flushes on `flush`.
+    pub flush_every_ms: Option<u64>,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        Self {
+            cache_capacity: 1 << 30,
+            low_space: false,
+            flush_every_ms: Some(500),
+        }
+    }
+}
+
+impl SledOptions {
+    pub fn config(&self, path: impl AsRef<Path>) -> sled::Config {
+        let mode = if self.low_space {
+            sled::Mode::LowSpace
+        } else {
+            sled::Mode::HighThroughput
+        };
+        sled::Config::new()
+            .path(path)
+            .cache_capacity(self.cache_capacity)
+            .mode(mode)
+            .flush_every_ms(self.flush_every_ms)
+    }
+}
+
+/// Persistent UTXO map stored in one sled tree.
+///
+/// Each map uses the tree named after its namespace, so several maps can
+/// share one database. Outpoints and outputs are encoded with bincode.
+/// Read errors are reported as missing outputs by `UtxoMap`, like
+/// `StateMachine` does.
+pub struct SledUtxoMap<P, O> {
+    db: sled::Db,
+    tree: sled::Tree,
+    _marker: PhantomData<fn() -> (P, O)>,
+}
+
+impl<P, O> Clone for SledUtxoMap<P, O> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            tree: self.tree.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<P, O> SledUtxoMap<P, O> {
+    pub fn open(path: impl AsRef<Path>, namespace: &str) -> Result<Self, SledUtxoError> {
+        Self::open_with_options(path, namespace, &SledOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        namespace: &str,
+        options: &SledOptions,
+    ) -> Result<Self, SledUtxoError> {
+        let db = options.config(path).open()?;
+        Self::with_db(&db, namespace)
+    }
+
+    /// Map in the `namespace` tree of an already open database.
+    pub fn with_db(db: &sled::Db, namespace: &str) -> Result<Self, SledUtxoError> {
+        Ok(Self {
+            db: db.clone(),
+            tree: db.open_tree(namespace)?,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+
+    pub fn namespace(&self) -> String {
+        String::from_utf8_lossy(&self.tree.name()).into_owned()
+    }
+
+    /// Number of outputs, this walks the whole tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn clear(&mut self) -> Result<(), SledUtxoError> {
+        Ok(self.tree.clear()?)
+    }
+
+    /// Writes dirty pages to disk, returns the number of bytes flushed.
+    pub fn flush(&self) -> Result<usize, SledUtxoError> {
+        Ok(self.tree.flush()?)
+    }
+
+    /// Size of the whole database, including other namespaces.
+    pub fn size_on_disk(&self) -> Result<u64, SledUtxoError> {
+        Ok(self.db.size_on_disk()?)
+    }
+}
+
+impl<P: Serialize, O: DeserializeOwned> SledUtxoMap<P, O> {
+    /// Like `UtxoMap::get_utxo`, but returns read errors.
+    pub fn try_get_utxo(&self, outpoint: &P) -> Result<Option<O>, SledUtxoError> {
+        match self.tree.get(bincode::serialize(outpoint)?)? {
+            Some(output) => Ok(Some(bincode::deserialize(&output)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<P: Serialize, O: DeserializeOwned> UtxoMap for SledUtxoMap<P, O> {
+    type OutPoint = P;
+    type Output = O;
+
+    fn get_utxo(&self, outpoint: &P) -> Option<O> {
+        self.try_get_utxo(outpoint).ok().flatten()
+    }
+
+    fn is_spent(&self, outpoint: &P) -> bool {
+        let contains = bincode::serialize(outpoint)
+            .ok()
+            .and_then(|key| self.tree.contains_key(key).ok());
+        !contains.unwrap_or(false)
+    }
+}
+
+impl<P, O> UtxoWrite for SledUtxoMap<P, O>
+where
+    P: Serialize,
+    O: Serialize + DeserializeOwned,
+{
+    type Error = SledUtxoError;
+
+    fn insert(&mut self, outpoint: P, output: O) -> Result<(), SledUtxoError> {
+        self.tree
+            .insert(bincode::serialize(&outpoint)?, bincode::serialize(&output)?)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &P) -> Result<(), SledUtxoError> {
+        self.tree.remove(bincode::serialize(outpoint)?)?;
+        Ok(())
+    }
+
+    /// Applies the batch atomically.
+    fn apply_batch(&mut self, batch: UtxoBatch<P, O>) -> Result<(), SledUtxoError> {
+        let mut sled_batch = sled::Batch::default();
+        for (outpoint, _) in &batch.spent {
+            sled_batch.remove(bincode::serialize(outpoint)?);
+        }
+        for (outpoint, output) in &batch.created {
+            sled_batch.insert(bincode::serialize(outpoint)?, bincode::serialize(output)?);
+        }
+        Ok(self.tree.apply_batch(sled_batch)?)
+    }
+}
+
+impl<P, O> UtxoScan for SledUtxoMap<P, O>
+where
+    P: Serialize + DeserializeOwned,
+    O: DeserializeOwned,
+{
+    type ScanError = SledUtxoError;
+
+    /// Visits outputs in encoded outpoint order.
+    fn scan(&self, f: &mut UtxoVisitor<'_, P, O>) -> Result<(), SledUtxoError> {
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            let outpoint = bincode::deserialize(&key)?;
+            let output = bincode::deserialize(&value)?;
+            if f(&outpoint, &output).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SledUtxoError {
+    #[error("sled error")]
+    Sled(#[from] sled::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+}
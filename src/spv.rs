@@ -0,0 +1,76 @@
+use bitcoin::hashes::{sha256d, Hash as _};
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHeader, TxMerkleNode, Txid};
+use serde::{Deserialize, Serialize};
+
+/// Siblings on the path from a mainchain transaction to its block's merkle
+/// root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleBranch {
+    /// Index of the transaction in the block, its bits tell on which side
+    /// each sibling is.
+    pub position: u32,
+    /// From the leaves up.
+    pub siblings: Vec<TxMerkleNode>,
+}
+
+impl MerkleBranch {
+    /// Merkle root the branch computes for `txid`, `None` if `position` has
+    /// more bits than the branch has levels.
+    pub fn compute_root(&self, txid: &Txid) -> Option<TxMerkleNode> {
+        let levels = self.siblings.len();
+        if levels < 32 && self.position >> levels != 0 {
+            return None;
+        }
+        Some(compute_merkle_root(txid, self.position, &self.siblings))
+    }
+}
+
+pub(crate) fn compute_merkle_root(
+    txid: &Txid,
+    position: u32,
+    siblings: &[TxMerkleNode],
+) -> TxMerkleNode {
+    let mut node = txid.as_hash();
+    for (level, sibling) in siblings.iter().enumerate() {
+        let mut pair = [0; 64];
+        if position.checked_shr(level as u32).unwrap_or(0) & 1 == 0 {
+            pair[..32].copy_from_slice(&node[..]);
+            pair[32..].copy_from_slice(&sibling[..]);
+        } else {
+            pair[..32].copy_from_slice(&sibling[..]);
+            pair[32..].copy_from_slice(&node[..]);
+        }
+        node = sha256d::Hash::hash(&pair);
+    }
+    TxMerkleNode::from_hash(node)
+}
+
+/// Checks that every header has valid proof of work for its own target and
+/// builds on the one before it, returns their total work.
+///
+/// Targets are not checked against the difficulty adjustment, callers
+/// require enough work to make faking the chain too expensive.
+pub fn verify_header_chain(headers: &[BlockHeader]) -> Result<Uint256, SpvError> {
+    let mut work = Uint256::default();
+    for (index, header) in headers.iter().enumerate() {
+        if let Some(prev) = index.checked_sub(1).map(|prev| &headers[prev]) {
+            if header.prev_blockhash != prev.block_hash() {
+                return Err(SpvError::Disconnected { index });
+            }
+        }
+        header
+            .validate_pow(&header.target())
+            .map_err(|_| SpvError::InvalidProofOfWork { index })?;
+        work = work + header.work();
+    }
+    Ok(work)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SpvError {
+    #[error("header {index} doesn't build on the header before it")]
+    Disconnected { index: usize },
+    #[error("header {index} hash is above its target")]
+    InvalidProofOfWork { index: usize },
+}
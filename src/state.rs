@@ -0,0 +1,2527 @@
+use crate::encoding::EncodeError;
+use crate::header_chain::BlockLocator;
+use crate::listener::{Listeners, StateListener};
+use crate::mempool::Mempool;
+use crate::params::ChainParams;
+use crate::stats::Timings;
+use crate::store::{MemoryStore, OverlayStore, ReadStore, StateStore, Table, WriteBatch};
+use crate::types::*;
+use crate::utxo::{UtxoBatch, UtxoMap, UtxoScan, UtxoVisitor, UtxoWrite};
+use crate::validator::{self, State};
+use crate::withdrawal::{self, BundleError, BundleStatus, WithdrawalBundle};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"sdks";
+/// Bumped whenever the payload encoding changes, snapshots of other versions
+/// are rejected by `restore_with`.
+pub const SNAPSHOT_VERSION: u32 = 9;
+
+const HEIGHT_KEY: &[u8] = b"height";
+const MAIN_BLOCK_COUNT_KEY: &[u8] = b"main_block_count";
+const JOURNAL_KEY: &[u8] = b"journal";
+
+/// UTXO set and header chain for a sidechain with authorization type `A` and
+/// custom output content `C`, kept in the storage backend `S`.
+///
+/// Txids, block hashes and merkle roots are computed with `H`, use `Keyed` to
+/// keep the chains of different networks apart. Headers, bodies and
+/// transactions passed in must be hashed with the same function.
+///
+/// Spent outputs are kept in `Table::Outputs` so that bodies can be
+/// disconnected without any extra undo data, unless they are pruned, see
+/// `Pruning`.
+#[derive(Debug, Clone)]
+pub struct StateMachine<A, C, S = MemoryStore, H = DefaultHashFunction> {
+    store: S,
+    // Cached from the store.
+    tip: BlockHash,
+    height: Option<u32>,
+    txindex: bool,
+    pruning: Pruning,
+    params: ChainParams,
+    listeners: Listeners<C>,
+    timings: Timings,
+    _marker: PhantomData<(A, C, H)>,
+}
+
+/// Everything needed to revert a connected block, or to connect it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockUndo<C> {
+    spent: Vec<OutPoint>,
+    created: Vec<(OutPoint, Output<C>)>,
+    txids: Vec<Txid>,
+    /// Encoded body, given back when `reorg_to` disconnects the block.
+    body: Vec<u8>,
+}
+
+/// What is kept of blocks below the undo horizon.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pruning {
+    /// Keep spent outputs and undo data of every block.
+    #[default]
+    Archival,
+    /// Drop spent outputs and undo data of blocks more than `depth` blocks
+    /// below the tip, these blocks can't be disconnected anymore.
+    Prune { depth: u32 },
+}
+
+/// Withdrawal bundle tracked in `Table::Bundles`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub status: BundleStatus,
+    pub spent_utxos: Vec<OutPoint>,
+}
+
+/// Disconnected block, kept to connect it again on a failed reorg.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PoppedBlock<C> {
+    header: Header,
+    undo: BlockUndo<C>,
+    /// Empty unless the transaction index is enabled.
+    transactions: Vec<Transaction<C>>,
+}
+
+/// Progress of an operation spanning several store writes, kept under
+/// `JOURNAL_KEY` until it completes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Journal<C> {
+    /// Tip before the operation.
+    tip: BlockHash,
+    /// Blocks disconnected so far, starting from `tip`. Blocks are only
+    /// connected after all disconnects.
+    disconnected: Vec<PoppedBlock<C>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectResult {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    /// Outpoints spent by the block, in body order.
+    pub spent: Vec<OutPoint>,
+    /// Outpoints created by the block, sorted by serialized outpoint.
+    pub created: Vec<OutPoint>,
+    /// Time spent validating and connecting the block.
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReorgOutcome<A, C> {
+    pub common_ancestor: BlockHash,
+    /// Disconnected blocks, starting from the old tip.
+    pub disconnected: Vec<BlockHash>,
+    /// Bodies of the disconnected blocks in the same order, so that their
+    /// transactions can go back to the mempool, see `Mempool::apply_reorg`.
+    pub disconnected_bodies: Vec<Body<A, C>>,
+    /// Connected blocks, ending with the new tip.
+    pub connected: Vec<BlockHash>,
+}
+
+/// Value held by an address in unspent outputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Value of regular value outputs.
+    pub confirmed: u64,
+    /// Value of custom content outputs.
+    pub custom: u64,
+    /// Value of withdrawal outputs not yet paid out on the mainchain.
+    pub pending_withdrawal: u64,
+}
+
+/// Deposit included in the mainchain without enough confirmations yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingDeposit<C> {
+    pub outpoint: bitcoin::OutPoint,
+    pub output: Output<C>,
+    /// Mainchain confirmations so far.
+    pub confirmations: u32,
+}
+
+/// Value held by a set of addresses, by what it can be used for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletBalance {
+    /// Value of outputs that can be spent in the next block, excluding the
+    /// ones spent by mempool transactions.
+    pub spendable: u64,
+    /// Value of coinbase outputs below the coinbase maturity.
+    pub immature_coinbase: u64,
+    /// Value of unspent mempool outputs and of pending deposits.
+    pub pending_unconfirmed: u64,
+    /// Value of withdrawal outputs, unspent or locked in a bundle.
+    pub locked_in_withdrawals: u64,
+}
+
+impl<A, C, H> Default for StateMachine<A, C, MemoryStore, H> {
+    fn default() -> Self {
+        Self {
+            store: MemoryStore::new(),
+            tip: BlockHash::ZERO,
+            height: None,
+            txindex: false,
+            pruning: Pruning::Archival,
+            params: ChainParams::default(),
+            listeners: Listeners::new(),
+            timings: Timings::default(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<A, C, H> StateMachine<A, C, MemoryStore, H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<A: Serialize, C: Clone + GetValue + Serialize + DeserializeOwned, H: HashFunction>
+    StateMachine<A, C, MemoryStore, H>
+{
+    /// In-memory state starting with the genesis block, see
+    /// `open_with_genesis`.
+    pub fn new_with_genesis(
+        params: ChainParams,
+        genesis_outputs: Vec<Output<C>>,
+    ) -> Result<Self, Error> {
+        Self::open_with_genesis(MemoryStore::new(), params, genesis_outputs)
+    }
+}
+
+fn store_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> Error {
+    Error::Store(Box::new(err))
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    Ok(bincode::serialize(value)?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+/// `Table::AddressOutpoints` key, the address followed by the encoded
+/// outpoint.
+fn address_key(address: &Address, outpoint_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(Address::ENCODED_LENGTH + outpoint_key.len());
+    key.extend_from_slice(&address.to_bytes());
+    key.extend_from_slice(outpoint_key);
+    key
+}
+
+fn put_unspent<C: Serialize>(
+    batch: &mut WriteBatch,
+    key: &[u8],
+    output: &Output<C>,
+) -> Result<(), Error> {
+    batch.put(Table::Outputs, key, encode(output)?);
+    batch.put(Table::UnspentOutpoints, key, []);
+    batch.put(
+        Table::AddressOutpoints,
+        address_key(&output.address, key),
+        [],
+    );
+    Ok(())
+}
+
+/// Regular output refunding a failed withdrawal, see
+/// `ChainParams::refund_failed_withdrawals`.
+fn refund_output<C>(output: Output<C>) -> Output<C> {
+    match output.content {
+        Content::Withdrawal { value, .. } => Output {
+            address: output.address,
+            content: Content::Value(value),
+        },
+        _ => output,
+    }
+}
+
+fn decode_height(table: Table, bytes: &[u8]) -> Result<u32, Error> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::Inconsistent { table })?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn decode_block_hash(table: Table, bytes: &[u8]) -> Result<BlockHash, Error> {
+    let hash = Hash::from_slice(bytes).ok_or(Error::Inconsistent { table })?;
+    Ok(hash.into())
+}
+
+impl<A, C, S: ReadStore, H> StateMachine<A, C, S, H> {
+    /// Loads the state kept in `store`.
+    pub fn open(store: S) -> Result<Self, Error> {
+        let mut state = Self {
+            store,
+            tip: BlockHash::ZERO,
+            height: None,
+            txindex: false,
+            pruning: Pruning::Archival,
+            params: ChainParams::default(),
+            listeners: Listeners::new(),
+            timings: Timings::default(),
+            _marker: PhantomData,
+        };
+        if let Some(height) = state.get(Table::Meta, HEIGHT_KEY)? {
+            let height = decode_height(Table::Meta, &height)?;
+            state.tip = state
+                .get_block_hash_at(height)?
+                .ok_or(Error::Inconsistent {
+                    table: Table::BlockOrder,
+                })?;
+            state.height = Some(height);
+        }
+        Ok(state)
+    }
+
+    /// Enables the transaction index for blocks connected from now on.
+    pub fn with_txindex(mut self, txindex: bool) -> Self {
+        self.txindex = txindex;
+        self
+    }
+
+    pub fn has_txindex(&self) -> bool {
+        self.txindex
+    }
+
+    /// Sets the pruning mode for blocks connected from now on, blocks that
+    /// already fell below the undo horizon are not pruned.
+    pub fn with_pruning(mut self, pruning: Pruning) -> Self {
+        self.pruning = pruning;
+        self
+    }
+
+    pub fn pruning(&self) -> Pruning {
+        self.pruning
+    }
+
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn add_listener(&mut self, listener: Arc<dyn StateListener<C> + Send + Sync>) {
+        self.listeners.push(listener);
+    }
+
+    pub(crate) fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.store.get(table, key).map_err(store_error)
+    }
+
+    pub fn is_unspent(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        self.store
+            .contains(Table::UnspentOutpoints, &encode(outpoint)?)
+            .map_err(store_error)
+    }
+
+    /// Returns the hash of the last connected block, or the all zeros hash if
+    /// no block was connected yet.
+    pub fn get_tip(&self) -> BlockHash {
+        self.tip
+    }
+
+    /// Returns the height of the tip, the first connected block has height 0.
+    pub fn get_height(&self) -> Option<u32> {
+        self.height
+    }
+
+    fn block_count(&self) -> u32 {
+        self.height.map_or(0, |height| height + 1)
+    }
+
+    /// Returns the height of the block that created a coinbase outpoint.
+    pub fn get_coinbase_height(&self, outpoint: &OutPoint) -> Result<Option<u32>, Error> {
+        self.get(Table::CoinbaseHeights, &encode(outpoint)?)?
+            .map(|height| decode_height(Table::CoinbaseHeights, &height))
+            .transpose()
+    }
+
+    /// Number of mainchain blocks connected with `connect_main_block`.
+    pub fn get_main_block_count(&self) -> Result<u32, Error> {
+        self.get(Table::Meta, MAIN_BLOCK_COUNT_KEY)?
+            .map_or(Ok(0), |count| decode_height(Table::Meta, &count))
+    }
+
+    /// Index of the mainchain block that included the deposit, counting
+    /// blocks connected with `connect_main_block`.
+    pub fn get_deposit_height(&self, outpoint: &OutPoint) -> Result<Option<u32>, Error> {
+        self.get(Table::DepositHeights, &encode(outpoint)?)?
+            .map(|height| decode_height(Table::DepositHeights, &height))
+            .transpose()
+    }
+
+    /// Whether the deposit still waits for mainchain confirmations.
+    pub fn is_pending_deposit(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        self.store
+            .contains(Table::PendingDeposits, &encode(outpoint)?)
+            .map_err(store_error)
+    }
+
+    /// Deposits that got their last required confirmation from the mainchain
+    /// block making `main_block_count` blocks, reads every deposit height.
+    fn get_deposits_matured_at(&self, main_block_count: u32) -> Result<Vec<OutPoint>, Error> {
+        let confirmations = self.params.deposit_confirmations;
+        let Some(deposit_height) = main_block_count
+            .checked_sub(confirmations)
+            .filter(|_| confirmations > 1)
+        else {
+            return Ok(Vec::new());
+        };
+        let mut entries = Vec::new();
+        self.store
+            .iterate(Table::DepositHeights, &mut |key, value| {
+                entries.push((key.to_vec(), value.to_vec()));
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        let mut matured = Vec::new();
+        for (key, value) in entries {
+            if decode_height(Table::DepositHeights, &value)? == deposit_height {
+                matured.push(decode(&key)?);
+            }
+        }
+        Ok(matured)
+    }
+
+    /// Checks that coinbase inputs can be spent in the next block.
+    fn check_maturity(&self, inputs: &[OutPoint]) -> Result<(), Error> {
+        let maturity = self.params.coinbase_maturity;
+        if maturity == 0 {
+            return Ok(());
+        }
+        let height = self.block_count();
+        let coinbases = inputs
+            .iter()
+            .filter(|outpoint| matches!(outpoint, OutPoint::Coinbase { .. }));
+        for outpoint in coinbases {
+            // Coinbase outputs connected without a block have no height.
+            if let Some(coinbase_height) = self.get_coinbase_height(outpoint)? {
+                let maturity_height = coinbase_height.saturating_add(maturity);
+                if height < maturity_height {
+                    return Err(Error::ImmatureCoinbase {
+                        outpoint: *outpoint,
+                        height,
+                        maturity_height,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_genesis_hash(&self) -> Result<Option<BlockHash>, Error> {
+        self.get_block_hash_at(0)
+    }
+
+    pub fn get_header(&self, block_hash: &BlockHash) -> Result<Option<Header>, Error> {
+        self.get(Table::Headers, &block_hash.0)?
+            .map(|header| decode(&header))
+            .transpose()
+    }
+
+    pub fn get_block_height(&self, block_hash: &BlockHash) -> Result<Option<u32>, Error> {
+        self.get(Table::Heights, &block_hash.0)?
+            .map(|height| decode_height(Table::Heights, &height))
+            .transpose()
+    }
+
+    pub fn get_block_hash_at(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        self.get(Table::BlockOrder, &height.to_be_bytes())?
+            .map(|block_hash| decode_block_hash(Table::BlockOrder, &block_hash))
+            .transpose()
+    }
+
+    pub fn get_header_at(&self, height: u32) -> Result<Option<Header>, Error> {
+        match self.get_block_hash_at(height)? {
+            Some(block_hash) => self.get_header(&block_hash),
+            None => Ok(None),
+        }
+    }
+
+    /// Locator of the connected chain, see `BlockLocator`.
+    pub fn get_block_locator(&self) -> Result<BlockLocator, Error> {
+        let Some(height) = self.height else {
+            return Ok(BlockLocator::default());
+        };
+        let hashes = BlockLocator::heights(height)
+            .into_iter()
+            .map(|height| {
+                self.get_block_hash_at(height)?.ok_or(Error::Inconsistent {
+                    table: Table::BlockOrder,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BlockLocator { hashes })
+    }
+
+    /// First block of `locator` that is connected and its height, `None` if
+    /// the chains share no block.
+    pub fn find_fork_point(
+        &self,
+        locator: &BlockLocator,
+    ) -> Result<Option<(BlockHash, u32)>, Error> {
+        for block_hash in &locator.hashes {
+            if let Some(height) = self.get_block_height(block_hash)? {
+                return Ok(Some((*block_hash, height)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Up to `limit` connected headers after the fork point with `locator`,
+    /// starting from genesis if there is none.
+    pub fn next_headers(&self, locator: &BlockLocator, limit: usize) -> Result<Vec<Header>, Error> {
+        let start = match self.find_fork_point(locator)? {
+            Some((_, height)) => height + 1,
+            None => 0,
+        };
+        let mut headers = Vec::new();
+        for height in (start..self.block_count()).take(limit) {
+            headers.push(self.get_header_at(height)?.ok_or(Error::Inconsistent {
+                table: Table::BlockOrder,
+            })?);
+        }
+        Ok(headers)
+    }
+
+    fn validate_header(&self, header: &Header) -> Result<(), Error> {
+        let tip = self.get_tip();
+        if header.prev_side_hash != tip {
+            return Err(Error::WrongPrevSideHash {
+                prev_side_hash: header.prev_side_hash,
+                tip,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<A, C: DeserializeOwned, S: ReadStore, H> StateMachine<A, C, S, H> {
+    fn get_unspent(&self, outpoint: &OutPoint) -> Result<Output<C>, Error> {
+        let key = encode(outpoint)?;
+        let output = self
+            .get(Table::Outputs, &key)?
+            .ok_or(Error::UtxoDoesNotExist {
+                outpoint: *outpoint,
+            })?;
+        if !self
+            .store
+            .contains(Table::UnspentOutpoints, &key)
+            .map_err(store_error)?
+        {
+            return Err(Error::UtxoSpent {
+                outpoint: *outpoint,
+            });
+        }
+        decode(&output)
+    }
+
+    pub fn get_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<Output<C>>, Error> {
+        outpoints
+            .iter()
+            .map(|outpoint| self.get_unspent(outpoint))
+            .collect()
+    }
+
+    /// Returns outputs spent by the transaction.
+    pub fn get_inputs(&self, transaction: &Transaction<C>) -> Result<Vec<Output<C>>, Error> {
+        self.get_utxos(&transaction.inputs)
+    }
+
+    /// Returns addresses that must authorize the transaction, one per input.
+    pub fn get_addresses(&self, transaction: &Transaction<C>) -> Result<Vec<Address>, Error> {
+        Ok(self
+            .get_inputs(transaction)?
+            .iter()
+            .map(GetAddress::get_address)
+            .collect())
+    }
+}
+
+impl<A, C: GetValue + DeserializeOwned, S: ReadStore, H> StateMachine<A, C, S, H> {
+    /// Returns unspent outpoints owned by `address`, in key order.
+    pub fn get_address_outpoints(&self, address: &Address) -> Result<Vec<OutPoint>, Error> {
+        let mut keys = Vec::new();
+        self.store
+            .iterate_prefix(
+                Table::AddressOutpoints,
+                &address.to_bytes(),
+                &mut |key, _| {
+                    keys.push(key[Address::ENCODED_LENGTH..].to_vec());
+                    ControlFlow::Continue(())
+                },
+            )
+            .map_err(store_error)?;
+        keys.iter().map(|key| decode(key)).collect()
+    }
+
+    /// Returns the fee paid by the transaction.
+    ///
+    /// NOTE: It does not check authorizations.
+    pub fn get_fee(&self, transaction: &Transaction<C>) -> Result<u64, Error> {
+        let spent_utxos = self.get_inputs(transaction)?;
+        Ok(validator::validate_transaction(&spent_utxos, transaction)?)
+    }
+
+    pub fn get_balance(&self, address: &Address) -> Result<Balance, Error> {
+        let mut balance = Balance::default();
+        for outpoint in self.get_address_outpoints(address)? {
+            let output = self.get_unspent(&outpoint)?;
+            let value = output.get_value();
+            let total = match output.content {
+                Content::Value(_) => &mut balance.confirmed,
+                Content::Custom(_) => &mut balance.custom,
+                Content::Withdrawal { .. } => &mut balance.pending_withdrawal,
+            };
+            *total = total.saturating_add(value);
+        }
+        Ok(balance)
+    }
+}
+
+impl<A, C: Serialize + DeserializeOwned, S: ReadStore, H> StateMachine<A, C, S, H> {
+    /// Spent or unspent output, read from `Table::Outputs`.
+    fn get_output(&self, key: &[u8]) -> Result<Output<C>, Error> {
+        let output = self.get(Table::Outputs, key)?.ok_or(Error::Inconsistent {
+            table: Table::Outputs,
+        })?;
+        decode(&output)
+    }
+
+    /// Returns the block containing the transaction and the transaction,
+    /// requires the transaction index.
+    pub fn get_transaction(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(BlockHash, Transaction<C>)>, Error> {
+        Ok(self
+            .get_transaction_entry(txid)?
+            .map(|(block_hash, _, transaction)| (block_hash, transaction)))
+    }
+
+    /// Returns the block containing the transaction and its position in the
+    /// block body, requires the transaction index.
+    pub fn get_transaction_position(&self, txid: &Txid) -> Result<Option<(BlockHash, u32)>, Error> {
+        Ok(self
+            .get_transaction_entry(txid)?
+            .map(|(block_hash, position, _)| (block_hash, position)))
+    }
+
+    fn get_transaction_entry(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(BlockHash, u32, Transaction<C>)>, Error> {
+        self.get(Table::Transactions, &txid.0)?
+            .map(|entry| decode(&entry))
+            .transpose()
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: GetValue + Serialize + DeserializeOwned,
+    S: ReadStore,
+    H: HashFunction,
+{
+    /// Balance of `addresses`, counting the unconfirmed outputs of `mempool`
+    /// if there is one.
+    ///
+    /// Locked withdrawals and pending deposits aren't indexed by address,
+    /// finding them reads every locked output and pending deposit.
+    pub fn get_wallet_balance<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a Address>,
+        mempool: Option<&Mempool<A, C, H>>,
+    ) -> Result<WalletBalance, Error> {
+        let addresses: HashSet<&Address> = addresses.into_iter().collect();
+        let spent_in_mempool = |outpoint: &OutPoint| {
+            mempool.is_some_and(|mempool| mempool.spender(outpoint).is_some())
+        };
+        let mut balance = WalletBalance::default();
+        for address in &addresses {
+            for outpoint in self.get_address_outpoints(address)? {
+                let output = self.get_unspent(&outpoint)?;
+                let total = if output.content.is_withdrawal() {
+                    &mut balance.locked_in_withdrawals
+                } else if spent_in_mempool(&outpoint) {
+                    continue;
+                } else {
+                    match self.check_maturity(&[outpoint]) {
+                        Ok(()) => &mut balance.spendable,
+                        Err(Error::ImmatureCoinbase { .. }) => &mut balance.immature_coinbase,
+                        Err(err) => return Err(err),
+                    }
+                };
+                *total = total.saturating_add(output.get_value());
+            }
+        }
+        for (_, output) in self.collect_outputs(Table::LockedOutpoints)? {
+            if addresses.contains(&output.address) {
+                balance.locked_in_withdrawals = balance
+                    .locked_in_withdrawals
+                    .saturating_add(output.get_value());
+            }
+        }
+        for (_, output) in self.collect_outputs(Table::PendingDeposits)? {
+            if addresses.contains(&output.address) {
+                balance.pending_unconfirmed = balance
+                    .pending_unconfirmed
+                    .saturating_add(output.get_value());
+            }
+        }
+        for entry in mempool.iter().flat_map(|mempool| mempool.iter()) {
+            for (vout, output) in entry.transaction.transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint::Regular {
+                    txid: entry.txid,
+                    vout: vout as u32,
+                };
+                if addresses.contains(&output.address)
+                    && !output.content.is_withdrawal()
+                    && !spent_in_mempool(&outpoint)
+                {
+                    balance.pending_unconfirmed = balance
+                        .pending_unconfirmed
+                        .saturating_add(output.get_value());
+                }
+            }
+        }
+        Ok(balance)
+    }
+}
+
+/// `UtxoWrite` over a pending batch, reads see the store without the batch.
+///
+/// Removed outputs stay in `Table::Outputs`.
+struct BatchUtxos<'a, A, C, S, H> {
+    state: &'a StateMachine<A, C, S, H>,
+    batch: &'a mut WriteBatch,
+}
+
+impl<A, C: DeserializeOwned, S: ReadStore, H> UtxoMap for BatchUtxos<'_, A, C, S, H> {
+    type OutPoint = OutPoint;
+    type Output = Output<C>;
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output<C>> {
+        self.state.get_utxo(outpoint)
+    }
+}
+
+impl<A, C, S, H> UtxoWrite for BatchUtxos<'_, A, C, S, H>
+where
+    C: Serialize + DeserializeOwned,
+    S: ReadStore,
+{
+    type Error = Error;
+
+    fn insert(&mut self, outpoint: OutPoint, output: Output<C>) -> Result<(), Error> {
+        put_unspent(self.batch, &encode(&outpoint)?, &output)
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        let key = encode(outpoint)?;
+        let address = self.state.get_output(&key)?.address;
+        self.batch
+            .delete(Table::AddressOutpoints, address_key(&address, &key));
+        self.batch.delete(Table::UnspentOutpoints, key);
+        Ok(())
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Clone + Serialize + DeserializeOwned,
+    S: ReadStore,
+{
+    fn utxo_batch(&self, undo: &BlockUndo<C>) -> Result<UtxoBatch<OutPoint, Output<C>>, Error> {
+        let mut spent = Vec::with_capacity(undo.spent.len());
+        for outpoint in &undo.spent {
+            spent.push((*outpoint, self.get_output(&encode(outpoint)?)?));
+        }
+        Ok(UtxoBatch {
+            spent,
+            created: undo.created.clone(),
+        })
+    }
+
+    fn apply(&self, batch: &mut WriteBatch, undo: &BlockUndo<C>) -> Result<(), Error> {
+        let utxo_batch = self.utxo_batch(undo)?;
+        BatchUtxos { state: self, batch }.apply_batch(utxo_batch)
+    }
+
+    /// Returns the restored outputs.
+    fn revert(
+        &self,
+        batch: &mut WriteBatch,
+        undo: &BlockUndo<C>,
+    ) -> Result<Vec<(OutPoint, Output<C>)>, Error> {
+        let utxo_batch = self.utxo_batch(undo)?.inverse();
+        let restored = utxo_batch.created.clone();
+        for (outpoint, _) in &utxo_batch.spent {
+            batch.delete(Table::Outputs, encode(outpoint)?);
+        }
+        BatchUtxos { state: self, batch }.apply_batch(utxo_batch)?;
+        Ok(restored)
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Clone + Serialize + DeserializeOwned,
+    S: ReadStore,
+{
+    pub fn get_unspent_withdrawals(&self) -> Result<HashMap<OutPoint, Output<C>>, Error> {
+        let mut keys = Vec::new();
+        self.store
+            .iterate(Table::UnspentOutpoints, &mut |key, _| {
+                keys.push(key.to_vec());
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        let mut withdrawals = HashMap::new();
+        for key in keys {
+            let output = self.get_output(&key)?;
+            if output.content.is_withdrawal() {
+                withdrawals.insert(decode(&key)?, output);
+            }
+        }
+        Ok(withdrawals)
+    }
+
+    /// Returns unspent withdrawals in bundle order, see `sort_withdrawals`.
+    pub fn get_sorted_unspent_withdrawals(
+        &self,
+    ) -> Result<Vec<(OutPoint, WithdrawalOutput)>, Error> {
+        let mut withdrawals: Vec<_> = self
+            .get_unspent_withdrawals()?
+            .into_iter()
+            .filter_map(|(outpoint, output)| Some((outpoint, output.to_withdrawal()?)))
+            .collect();
+        withdrawal::sort_withdrawals(&mut withdrawals);
+        Ok(withdrawals)
+    }
+
+    /// Builds the next withdrawal bundle out of all unspent withdrawals, see
+    /// `build_withdrawal_bundle`.
+    pub fn get_withdrawal_bundle(&self) -> Result<Option<WithdrawalBundle>, Error> {
+        let withdrawals = self.get_sorted_unspent_withdrawals()?;
+        Ok(self.params.sidechain.build_withdrawal_bundle(withdrawals)?)
+    }
+
+    pub fn is_locked(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        self.store
+            .contains(Table::LockedOutpoints, &encode(outpoint)?)
+            .map_err(store_error)
+    }
+
+    /// Returns the unspent withdrawal output.
+    fn get_unspent_withdrawal(&self, outpoint: &OutPoint) -> Result<Output<C>, Error> {
+        let output = self.get_unspent(outpoint)?;
+        if !output.content.is_withdrawal() {
+            return Err(Error::NotWithdrawal {
+                outpoint: *outpoint,
+            });
+        }
+        Ok(output)
+    }
+
+    /// Returns the locked withdrawal output.
+    fn get_locked(&self, outpoint: &OutPoint) -> Result<Output<C>, Error> {
+        let key = encode(outpoint)?;
+        if !self
+            .store
+            .contains(Table::LockedOutpoints, &key)
+            .map_err(store_error)?
+        {
+            return Err(Error::WithdrawalNotLocked {
+                outpoint: *outpoint,
+            });
+        }
+        self.get_output(&key)
+    }
+
+    fn lock(batch: &mut WriteBatch, outpoint: &OutPoint, output: &Output<C>) -> Result<(), Error> {
+        let key = encode(outpoint)?;
+        batch.delete(Table::AddressOutpoints, address_key(&output.address, &key));
+        batch.delete(Table::UnspentOutpoints, key.clone());
+        batch.put(Table::LockedOutpoints, key, []);
+        Ok(())
+    }
+
+    fn unlock(
+        batch: &mut WriteBatch,
+        outpoint: &OutPoint,
+        output: &Output<C>,
+    ) -> Result<(), Error> {
+        let key = encode(outpoint)?;
+        batch.delete(Table::LockedOutpoints, key.clone());
+        put_unspent(batch, &key, output)
+    }
+
+    pub fn get_bundle(&self, bundle_hash: &bitcoin::Txid) -> Result<Option<BundleEntry>, Error> {
+        self.get(Table::Bundles, &bundle_hash[..])?
+            .map(|entry| decode(&entry))
+            .transpose()
+    }
+
+    pub fn get_bundle_status(
+        &self,
+        bundle_hash: &bitcoin::Txid,
+    ) -> Result<Option<BundleStatus>, Error> {
+        Ok(self.get_bundle(bundle_hash)?.map(|entry| entry.status))
+    }
+
+    /// Returns the bundle entry, checking its status.
+    fn get_bundle_with_status(
+        &self,
+        bundle_hash: &bitcoin::Txid,
+        expected: BundleStatus,
+    ) -> Result<BundleEntry, Error> {
+        let entry = self.get_bundle(bundle_hash)?.ok_or(Error::UnknownBundle {
+            bundle_hash: *bundle_hash,
+        })?;
+        if entry.status != expected {
+            return Err(Error::WrongBundleStatus {
+                bundle_hash: *bundle_hash,
+                status: entry.status,
+                expected,
+            });
+        }
+        Ok(entry)
+    }
+
+    fn put_bundle(
+        batch: &mut WriteBatch,
+        bundle_hash: &bitcoin::Txid,
+        entry: &BundleEntry,
+        status: BundleStatus,
+    ) -> Result<(), Error> {
+        let entry = BundleEntry {
+            status,
+            spent_utxos: entry.spent_utxos.clone(),
+        };
+        batch.put(Table::Bundles, &bundle_hash[..], encode(&entry)?);
+        Ok(())
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Clone + GetValue + Serialize + DeserializeOwned,
+    S: ReadStore,
+    H: HashFunction,
+{
+    /// Collects spent and created outpoints, checking that every input is
+    /// unspent.
+    fn build_undo(&self, body: &Body<A, C>) -> Result<BlockUndo<C>, Error> {
+        let spent = body.get_inputs();
+        for input in &spent {
+            self.get_unspent(input)?;
+        }
+        let mut created = Vec::new();
+        for (outpoint, output) in body.try_get_outputs_with::<H>()? {
+            created.push((encode(&outpoint)?, outpoint, output));
+        }
+        // Keep undo data and `ConnectResult` independent of map order.
+        created.sort_unstable_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        Ok(BlockUndo {
+            spent,
+            created: created
+                .into_iter()
+                .map(|(_, outpoint, output)| (outpoint, output))
+                .collect(),
+            txids: body
+                .transactions
+                .iter()
+                .map(Transaction::try_txid_with::<H>)
+                .collect::<Result<_, _>>()?,
+            // Only blocks kept in the store need it.
+            body: Vec::new(),
+        })
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    A: GetAddress + Verify<C>,
+    A::Error: std::error::Error + Send + Sync + 'static,
+    C: Clone + GetValue + Serialize + DeserializeOwned,
+    S: ReadStore,
+    H: HashFunction,
+{
+    /// Returns the fee paid by transaction if it is valid.
+    pub fn validate_transaction(
+        &self,
+        transaction: &AuthorizedTransaction<A, C>,
+    ) -> Result<u64, Error> {
+        let spent_utxos = self.get_utxos(&transaction.transaction.inputs)?;
+        self.check_maturity(&transaction.transaction.inputs)?;
+        let fee = validator::validate_authorized_transaction(&spent_utxos, transaction)?;
+        A::verify_transaction::<H>(transaction)
+            .map_err(|err| Error::BadAuthorization(Box::new(err)))?;
+        Ok(fee)
+    }
+
+    /// Returns total fee collected by body if it is valid.
+    pub fn validate_body(&self, body: &Body<A, C>) -> Result<u64, Error> {
+        let inputs = body.get_inputs();
+        let spent_utxos = self.get_utxos(&inputs)?;
+        self.check_maturity(&inputs)?;
+        let fees = validator::validate_body(&spent_utxos, body, &self.params)?;
+        A::verify_body::<H>(body).map_err(|err| Error::BadAuthorization(Box::new(err)))?;
+        Ok(fees)
+    }
+
+    pub fn validate_block(&self, header: &Header, body: &Body<A, C>) -> Result<(), Error> {
+        self.validate_header(header)?;
+        let merkle_root = body.try_compute_merkle_root_with::<H>()?;
+        if header.merkle_root != merkle_root {
+            return Err(Error::WrongMerkleRoot {
+                header: header.merkle_root,
+                body: merkle_root,
+            });
+        }
+        self.validate_body(body)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<C> {
+    /// Connected headers in order, the last one is the tip.
+    headers: Vec<Header>,
+    /// Unspent outputs sorted by serialized outpoint.
+    utxos: Vec<(OutPoint, Output<C>)>,
+    /// Withdrawal outputs locked into bundles, sorted by serialized outpoint.
+    locked: Vec<(OutPoint, Output<C>)>,
+    /// Withdrawal bundles sorted by hash.
+    bundles: Vec<(bitcoin::Txid, BundleEntry)>,
+    /// Creation heights of unspent coinbase outputs, in `utxos` order.
+    coinbase_heights: Vec<(OutPoint, u32)>,
+    main_block_count: u32,
+    /// Mainchain heights of all deposits, spent ones too so that they are
+    /// still rejected if connected again, sorted by serialized outpoint.
+    deposit_heights: Vec<(OutPoint, u32)>,
+    /// Deposits waiting for confirmations, sorted by serialized outpoint.
+    pending_deposits: Vec<(OutPoint, Output<C>)>,
+    /// Withdrawals unspent refunds were made from, in `utxos` order.
+    refunded_withdrawals: Vec<(OutPoint, Output<C>)>,
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Serialize + DeserializeOwned,
+    S: ReadStore,
+{
+    /// Writes the UTXO set and the header chain.
+    ///
+    /// Layout is magic, version (u32 LE), payload length (u64 LE), payload and
+    /// blake3 hash of the payload. Undo data is not included, so blocks
+    /// connected before a snapshot can't be disconnected after restoring it.
+    /// Snapshots of identical states are byte for byte identical.
+    pub fn snapshot<W: Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        let mut headers = Vec::new();
+        for height in 0..self.block_count() {
+            let header = self.get_header_at(height)?.ok_or(Error::Inconsistent {
+                table: Table::Headers,
+            })?;
+            headers.push(header);
+        }
+        let utxos = self.collect_outputs(Table::UnspentOutpoints)?;
+        let locked = self.collect_outputs(Table::LockedOutpoints)?;
+        let mut entries = Vec::new();
+        self.store
+            .iterate(Table::Bundles, &mut |key, value| {
+                entries.push((key.to_vec(), value.to_vec()));
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        let mut bundles = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let bundle_hash =
+                bitcoin::hashes::Hash::from_slice(&key).map_err(|_| Error::Inconsistent {
+                    table: Table::Bundles,
+                })?;
+            bundles.push((bitcoin::Txid::from_hash(bundle_hash), decode(&value)?));
+        }
+        let mut coinbase_heights = Vec::new();
+        for (outpoint, _) in &utxos {
+            if let Some(height) = self.get_coinbase_height(outpoint)? {
+                coinbase_heights.push((*outpoint, height));
+            }
+        }
+        let pending_deposits = self.collect_outputs(Table::PendingDeposits)?;
+        let mut entries = Vec::new();
+        self.store
+            .iterate(Table::DepositHeights, &mut |key, value| {
+                entries.push((key.to_vec(), value.to_vec()));
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        let mut deposit_heights = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            deposit_heights.push((decode(&key)?, decode_height(Table::DepositHeights, &value)?));
+        }
+        let mut refunded_withdrawals = Vec::new();
+        for (outpoint, _) in &utxos {
+            if let Some(withdrawal) = self.get(Table::RefundedWithdrawals, &encode(outpoint)?)? {
+                refunded_withdrawals.push((*outpoint, decode(&withdrawal)?));
+            }
+        }
+        let payload = bincode::serialize(&Snapshot::<C> {
+            headers,
+            utxos,
+            locked,
+            bundles,
+            coinbase_heights,
+            main_block_count: self.get_main_block_count()?,
+            deposit_heights,
+            pending_deposits,
+            refunded_withdrawals,
+        })?;
+        let checksum: [u8; 32] = blake3::hash(&payload).into();
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        writer.write_all(&checksum)?;
+        Ok(())
+    }
+
+    /// Deposits waiting for mainchain confirmations, sorted by serialized
+    /// outpoint.
+    pub fn get_pending_deposits(&self) -> Result<Vec<PendingDeposit<C>>, Error> {
+        let main_block_count = self.get_main_block_count()?;
+        let mut pending = Vec::new();
+        for (outpoint, output) in self.collect_outputs(Table::PendingDeposits)? {
+            let OutPoint::Deposit(main_outpoint) = outpoint else {
+                return Err(Error::Inconsistent {
+                    table: Table::PendingDeposits,
+                });
+            };
+            let deposit_height =
+                self.get_deposit_height(&outpoint)?
+                    .ok_or(Error::Inconsistent {
+                        table: Table::DepositHeights,
+                    })?;
+            pending.push(PendingDeposit {
+                outpoint: main_outpoint,
+                output,
+                confirmations: main_block_count.saturating_sub(deposit_height),
+            });
+        }
+        Ok(pending)
+    }
+
+    /// Pending deposits with enough confirmations once there are
+    /// `main_block_count` mainchain blocks.
+    fn get_maturing_deposits(
+        &self,
+        main_block_count: u32,
+    ) -> Result<Vec<(OutPoint, Output<C>)>, Error> {
+        let confirmations = self.params.deposit_confirmations;
+        let mut maturing = Vec::new();
+        for (outpoint, output) in self.collect_outputs(Table::PendingDeposits)? {
+            let deposit_height = self.get_deposit_height(&outpoint)?;
+            if deposit_height
+                .is_some_and(|height| main_block_count.saturating_sub(height) >= confirmations)
+            {
+                maturing.push((outpoint, output));
+            }
+        }
+        Ok(maturing)
+    }
+
+    /// Returns outputs of all outpoints in `table`, sorted by key.
+    fn collect_outputs(&self, table: Table) -> Result<Vec<(OutPoint, Output<C>)>, Error> {
+        let mut keys = Vec::new();
+        self.store
+            .iterate(table, &mut |key, _| {
+                keys.push(key.to_vec());
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        keys.sort();
+        keys.iter()
+            .map(|key| Ok((decode(key)?, self.get_output(key)?)))
+            .collect()
+    }
+}
+
+impl<A, C, S: StateStore, H> StateMachine<A, C, S, H> {
+    /// Returns a read-only view of the current state.
+    ///
+    /// The view is not affected by blocks connected afterwards, several views
+    /// can be read concurrently while the state is mutated.
+    pub fn view(&self) -> Result<StateMachine<A, C, S::View, H>, Error> {
+        Ok(StateMachine {
+            store: self.store.view().map_err(store_error)?,
+            tip: self.tip,
+            height: self.height,
+            txindex: self.txindex,
+            pruning: self.pruning,
+            params: self.params.clone(),
+            listeners: Listeners::new(),
+            timings: self.timings,
+            _marker: PhantomData,
+        })
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        self.store.write(batch).map_err(store_error)
+    }
+}
+
+impl<A, C: Clone + Serialize + DeserializeOwned, S: StateStore, H: HashFunction>
+    StateMachine<A, C, S, H>
+{
+    fn push_block(
+        &mut self,
+        header: Header,
+        undo: BlockUndo<C>,
+        transactions: &[Transaction<C>],
+    ) -> Result<(), Error> {
+        let block_hash = header.hash_with::<H>();
+        let height = self.block_count();
+        let mut batch = WriteBatch::new();
+        self.apply(&mut batch, &undo)?;
+        if self.txindex {
+            for (position, (txid, transaction)) in (0u32..).zip(undo.txids.iter().zip(transactions))
+            {
+                let entry = (block_hash, position, transaction);
+                batch.put(Table::Transactions, txid.0, encode(&entry)?);
+            }
+        }
+        for (outpoint, _) in &undo.created {
+            if let OutPoint::Coinbase { .. } = outpoint {
+                batch.put(
+                    Table::CoinbaseHeights,
+                    encode(outpoint)?,
+                    height.to_be_bytes(),
+                );
+            }
+        }
+        batch.put(Table::Headers, block_hash.0, encode(&header)?);
+        batch.put(Table::Heights, block_hash.0, height.to_be_bytes());
+        batch.put(Table::BlockOrder, height.to_be_bytes(), block_hash.0);
+        batch.put(Table::Undo, block_hash.0, encode(&undo)?);
+        batch.put(Table::Meta, HEIGHT_KEY, height.to_be_bytes());
+        if let Pruning::Prune { depth } = self.pruning {
+            if let Some(pruned_height) = height.checked_sub(depth).and_then(|h| h.checked_sub(1)) {
+                self.prune_block(&mut batch, pruned_height)?;
+            }
+        }
+        self.write(batch)?;
+        self.tip = block_hash;
+        self.height = Some(height);
+        self.listeners.utxos_spent(&undo.spent);
+        self.listeners.utxos_created(&undo.created);
+        self.listeners.block_connected(&header, height);
+        Ok(())
+    }
+
+    /// Drops outputs spent by the block at `height` and its undo data, does
+    /// nothing if it is already pruned.
+    fn prune_block(&self, batch: &mut WriteBatch, height: u32) -> Result<(), Error> {
+        let block_hash = self.get_block_hash_at(height)?.ok_or(Error::Inconsistent {
+            table: Table::BlockOrder,
+        })?;
+        let Some(undo) = self.get(Table::Undo, &block_hash.0)? else {
+            return Ok(());
+        };
+        let undo: BlockUndo<C> = decode(&undo)?;
+        for outpoint in &undo.spent {
+            let key = encode(outpoint)?;
+            batch.delete(Table::RefundedWithdrawals, key.clone());
+            batch.delete(Table::Outputs, key);
+        }
+        batch.delete(Table::Undo, block_hash.0);
+        Ok(())
+    }
+
+    /// Fails if a withdrawal created by the block is locked in a bundle,
+    /// disconnecting it would leave the bundle without its outputs.
+    fn check_unlocked(&self, undo: &BlockUndo<C>) -> Result<(), Error> {
+        for (outpoint, output) in &undo.created {
+            if output.content.is_withdrawal() && self.is_locked(outpoint)? {
+                return Err(Error::WithdrawalLocked {
+                    outpoint: *outpoint,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnects the tip block, recording it in `journal` in the same
+    /// write if there is one.
+    fn pop_block(&mut self, journal: Option<&mut Journal<C>>) -> Result<(), Error> {
+        let height = self.height.ok_or(Error::NoBlockToDisconnect)?;
+        let block_hash = self.tip;
+        let undo: BlockUndo<C> = match self.get(Table::Undo, &block_hash.0)? {
+            Some(undo) => decode(&undo)?,
+            None => return Err(Error::NoUndoData { block_hash }),
+        };
+        self.check_unlocked(&undo)?;
+        let header = self.get_header(&block_hash)?.ok_or(Error::Inconsistent {
+            table: Table::Headers,
+        })?;
+        let mut batch = WriteBatch::new();
+        let restored = self.revert(&mut batch, &undo)?;
+        let mut transactions = Vec::new();
+        if self.txindex {
+            for txid in &undo.txids {
+                // Blocks connected before the index was enabled have no entries.
+                if let Some(entry) = self.get(Table::Transactions, &txid.0)? {
+                    let (_, _, transaction): (BlockHash, u32, Transaction<C>) = decode(&entry)?;
+                    transactions.push(transaction);
+                    batch.delete(Table::Transactions, txid.0);
+                }
+            }
+        }
+        for (outpoint, _) in &undo.created {
+            if let OutPoint::Coinbase { .. } = outpoint {
+                batch.delete(Table::CoinbaseHeights, encode(outpoint)?);
+            }
+        }
+        batch.delete(Table::Headers, block_hash.0);
+        batch.delete(Table::Heights, block_hash.0);
+        batch.delete(Table::BlockOrder, height.to_be_bytes());
+        batch.delete(Table::Undo, block_hash.0);
+        match height.checked_sub(1) {
+            Some(height) => batch.put(Table::Meta, HEIGHT_KEY, height.to_be_bytes()),
+            None => batch.delete(Table::Meta, HEIGHT_KEY),
+        }
+        let created: Vec<_> = undo.created.iter().map(|(outpoint, _)| *outpoint).collect();
+        if let Some(journal) = journal {
+            journal.disconnected.push(PoppedBlock {
+                header,
+                undo,
+                transactions,
+            });
+            let result = encode(journal).and_then(|entry| {
+                batch.put(Table::Meta, JOURNAL_KEY, entry);
+                self.write(batch)
+            });
+            if let Err(err) = result {
+                journal.disconnected.pop();
+                return Err(err);
+            }
+        } else {
+            self.write(batch)?;
+        }
+        self.tip = header.prev_side_hash;
+        self.height = height.checked_sub(1);
+        self.listeners.utxos_spent(&created);
+        self.listeners.utxos_created(&restored);
+        self.listeners.block_disconnected(&header, height);
+        Ok(())
+    }
+
+    /// Disconnects blocks connected after the journaled disconnects and
+    /// connects the journaled blocks again, then drops the journal.
+    fn roll_back(&mut self, journal: Journal<C>) -> Result<(), Error> {
+        let last_kept = journal
+            .disconnected
+            .last()
+            .map_or(journal.tip, |block| block.header.prev_side_hash);
+        while self.tip != last_kept {
+            self.pop_block(None)?;
+        }
+        for block in journal.disconnected.into_iter().rev() {
+            self.push_block(block.header, block.undo, &block.transactions)?;
+        }
+        self.clear_journal()
+    }
+
+    fn clear_journal(&mut self) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(Table::Meta, JOURNAL_KEY);
+        self.write(batch)
+    }
+
+    /// Rolls back a `reorg_to` or `disconnect_main_block` interrupted by a
+    /// crash, restoring the chain it started from. Returns whether there was
+    /// anything to roll back.
+    ///
+    /// Every store write is atomic, but these operations take several writes.
+    /// Their progress is journaled in the store, call this after opening it.
+    pub fn recover(&mut self) -> Result<bool, Error> {
+        let Some(journal) = self.get(Table::Meta, JOURNAL_KEY)? else {
+            return Ok(false);
+        };
+        self.roll_back(decode(&journal)?)?;
+        Ok(true)
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Clone + Serialize + DeserializeOwned,
+    S: StateStore,
+    H: HashFunction,
+{
+    /// Records a bundle proposed to the mainchain, its withdrawals stay
+    /// spendable until the mainchain includes the bundle.
+    ///
+    /// A failed bundle can be proposed again.
+    pub fn propose_withdrawal_bundle(
+        &mut self,
+        bundle: &WithdrawalBundle,
+    ) -> Result<bitcoin::Txid, Error> {
+        let bundle_hash = bundle.hash();
+        if let Some(entry) = self.get_bundle(&bundle_hash)? {
+            if entry.status != BundleStatus::Failed {
+                return Err(Error::WrongBundleStatus {
+                    bundle_hash,
+                    status: entry.status,
+                    expected: BundleStatus::Failed,
+                });
+            }
+        }
+        for outpoint in &bundle.spent_utxos {
+            self.get_unspent_withdrawal(outpoint)?;
+        }
+        let entry = BundleEntry {
+            status: BundleStatus::Proposed,
+            spent_utxos: bundle.spent_utxos.clone(),
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(Table::Bundles, &bundle_hash[..], encode(&entry)?);
+        self.write(batch)?;
+        Ok(bundle_hash)
+    }
+
+    /// Applies two-way peg events of a mainchain block.
+    ///
+    /// Deposits stay pending until they have `deposit_confirmations`
+    /// confirmations, see `ChainParams`, then they become unspent outputs.
+    /// Withdrawals of a created bundle are locked, withdrawals of a failed
+    /// bundle are refunded, that is unlocked and spendable again, as value
+    /// outputs if `ChainParams::refund_failed_withdrawals` is set, and
+    /// withdrawals of a succeeded bundle are spent for good. Every event is
+    /// checked against the current state before anything is written, so a
+    /// failure leaves the state intact. A deposit or withdrawal may appear
+    /// only once in the events, and a deposit connected before is rejected
+    /// until its mainchain block is disconnected, even once it is spent and
+    /// pruned.
+    pub fn connect_main_block(&mut self, events: &MainBlockEvents<C>) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        let mut spent = Vec::new();
+        let mut created = Vec::with_capacity(events.deposits.len());
+        let main_height = self.get_main_block_count()?;
+        let is_pending = self.params.deposit_confirmations > 1;
+        // Reads don't see the batch, so repeats within the events are caught
+        // here.
+        let mut touched = HashSet::new();
+        for (outpoint, output) in &events.deposits {
+            let outpoint = OutPoint::Deposit(*outpoint);
+            let key = encode(&outpoint)?;
+            // Deposit heights outlive pruned outputs, they are only deleted
+            // when the mainchain block is disconnected.
+            if !touched.insert(outpoint) || self.get(Table::DepositHeights, &key)?.is_some() {
+                return Err(Error::DepositExists { outpoint });
+            }
+            if is_pending {
+                batch.put(Table::Outputs, key.clone(), encode(output)?);
+                batch.put(Table::PendingDeposits, key.clone(), []);
+            } else {
+                put_unspent(&mut batch, &key, output)?;
+                created.push((outpoint, output.clone()));
+            }
+            batch.put(Table::DepositHeights, key, main_height.to_be_bytes());
+        }
+        for (outpoint, output) in self.get_maturing_deposits(main_height + 1)? {
+            let key = encode(&outpoint)?;
+            batch.delete(Table::PendingDeposits, key.clone());
+            put_unspent(&mut batch, &key, &output)?;
+            created.push((outpoint, output));
+        }
+        let mut touch = |outpoint: &OutPoint| {
+            if touched.insert(*outpoint) {
+                Ok(())
+            } else {
+                Err(Error::DuplicateWithdrawal {
+                    outpoint: *outpoint,
+                })
+            }
+        };
+        for bundle_hash in &events.withdrawal_bundle_created {
+            let entry = self.get_bundle_with_status(bundle_hash, BundleStatus::Proposed)?;
+            for outpoint in &entry.spent_utxos {
+                touch(outpoint)?;
+                let output = self.get_unspent_withdrawal(outpoint)?;
+                Self::lock(&mut batch, outpoint, &output)?;
+                spent.push(*outpoint);
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Locked)?;
+        }
+        for bundle_hash in &events.withdrawal_bundle_failed {
+            let entry = self.get_bundle_with_status(bundle_hash, BundleStatus::Locked)?;
+            for outpoint in &entry.spent_utxos {
+                touch(outpoint)?;
+                let mut output = self.get_locked(outpoint)?;
+                if self.params.refund_failed_withdrawals {
+                    batch.put(
+                        Table::RefundedWithdrawals,
+                        encode(outpoint)?,
+                        encode(&output)?,
+                    );
+                    output = refund_output(output);
+                }
+                Self::unlock(&mut batch, outpoint, &output)?;
+                created.push((*outpoint, output));
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Failed)?;
+        }
+        for bundle_hash in &events.withdrawal_bundle_succeeded {
+            let entry = self.get_bundle_with_status(bundle_hash, BundleStatus::Locked)?;
+            for outpoint in &entry.spent_utxos {
+                touch(outpoint)?;
+                self.get_locked(outpoint)?;
+                batch.delete(Table::LockedOutpoints, encode(outpoint)?);
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Succeeded)?;
+        }
+        batch.put(
+            Table::Meta,
+            MAIN_BLOCK_COUNT_KEY,
+            (main_height + 1).to_be_bytes(),
+        );
+        self.write(batch)?;
+        self.listeners.utxos_spent(&spent);
+        self.listeners.utxos_created(&created);
+        self.listeners.main_block_connected(events);
+        Ok(())
+    }
+
+    /// Reverts `connect_main_block` with the same events, mainchain blocks
+    /// must be disconnected in reverse order.
+    ///
+    /// Sidechain blocks are disconnected from the tip until none of the
+    /// reverted deposits is spent, their hashes are returned starting from the
+    /// old tip. If anything fails the disconnected blocks are connected again.
+    pub fn disconnect_main_block(
+        &mut self,
+        events: &MainBlockEvents<C>,
+    ) -> Result<Vec<BlockHash>, Error> {
+        let mut journal = Journal {
+            tip: self.tip,
+            disconnected: Vec::new(),
+        };
+        let result = self
+            .get_deposits_matured_at(self.get_main_block_count()?)
+            .and_then(|matured| {
+                let deposits = events
+                    .deposits
+                    .iter()
+                    .map(|(outpoint, _)| OutPoint::Deposit(*outpoint))
+                    .chain(matured);
+                self.disconnect_deposit_spends(deposits, &mut journal)
+            })
+            .and_then(|()| self.revert_main_block(events));
+        if let Err(err) = result {
+            self.roll_back(journal)?;
+            return Err(err);
+        }
+        Ok(journal
+            .disconnected
+            .iter()
+            .map(|block| block.header.hash_with::<H>())
+            .collect())
+    }
+
+    fn disconnect_deposit_spends(
+        &mut self,
+        deposits: impl IntoIterator<Item = OutPoint>,
+        journal: &mut Journal<C>,
+    ) -> Result<(), Error> {
+        for outpoint in deposits {
+            let key = encode(&outpoint)?;
+            // Spent outputs stay in `Table::Outputs` until they are pruned.
+            while self.get(Table::Outputs, &key)?.is_some()
+                && !self.is_unspent(&outpoint)?
+                && !self.is_locked(&outpoint)?
+                && !self.is_pending_deposit(&outpoint)?
+            {
+                self.pop_block(Some(journal))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn revert_main_block(&mut self, events: &MainBlockEvents<C>) -> Result<(), Error> {
+        let main_height = self
+            .get_main_block_count()?
+            .checked_sub(1)
+            .ok_or(Error::NoMainBlockToDisconnect)?;
+        let mut batch = WriteBatch::new();
+        let mut spent = Vec::new();
+        let mut created = Vec::new();
+        for bundle_hash in &events.withdrawal_bundle_succeeded {
+            let entry = self.get_bundle_with_status(bundle_hash, BundleStatus::Succeeded)?;
+            for outpoint in &entry.spent_utxos {
+                let key = encode(outpoint)?;
+                self.get_output(&key)?;
+                batch.put(Table::LockedOutpoints, key, []);
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Locked)?;
+        }
+        for bundle_hash in &events.withdrawal_bundle_failed {
+            // Proposing the bundle again after the failure is dropped as well.
+            let entry = match self.get_bundle_with_status(bundle_hash, BundleStatus::Proposed) {
+                Ok(entry) => entry,
+                Err(_) => self.get_bundle_with_status(bundle_hash, BundleStatus::Failed)?,
+            };
+            for outpoint in &entry.spent_utxos {
+                let key = encode(outpoint)?;
+                let output = match self.get(Table::RefundedWithdrawals, &key)? {
+                    Some(withdrawal) => {
+                        self.get_unspent(outpoint)?;
+                        batch.delete(Table::RefundedWithdrawals, key.clone());
+                        batch.put(Table::Outputs, key, withdrawal.clone());
+                        decode(&withdrawal)?
+                    }
+                    None => self.get_unspent_withdrawal(outpoint)?,
+                };
+                Self::lock(&mut batch, outpoint, &output)?;
+                spent.push(*outpoint);
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Locked)?;
+        }
+        for bundle_hash in &events.withdrawal_bundle_created {
+            let entry = self.get_bundle_with_status(bundle_hash, BundleStatus::Locked)?;
+            for outpoint in &entry.spent_utxos {
+                let output = self.get_locked(outpoint)?;
+                Self::unlock(&mut batch, outpoint, &output)?;
+                created.push((*outpoint, output));
+            }
+            Self::put_bundle(&mut batch, bundle_hash, &entry, BundleStatus::Proposed)?;
+        }
+        for outpoint in self.get_deposits_matured_at(main_height + 1)? {
+            let output = self.get_unspent(&outpoint)?;
+            let key = encode(&outpoint)?;
+            batch.delete(Table::AddressOutpoints, address_key(&output.address, &key));
+            batch.delete(Table::UnspentOutpoints, key.clone());
+            batch.put(Table::PendingDeposits, key, []);
+            spent.push(outpoint);
+        }
+        for (outpoint, output) in &events.deposits {
+            let outpoint = OutPoint::Deposit(*outpoint);
+            let key = encode(&outpoint)?;
+            if self.is_pending_deposit(&outpoint)? {
+                batch.delete(Table::PendingDeposits, key.clone());
+            } else {
+                self.get_unspent(&outpoint)?;
+                batch.delete(Table::AddressOutpoints, address_key(&output.address, &key));
+                batch.delete(Table::UnspentOutpoints, key.clone());
+                spent.push(outpoint);
+            }
+            batch.delete(Table::DepositHeights, key.clone());
+            batch.delete(Table::Outputs, key);
+        }
+        match main_height {
+            0 => batch.delete(Table::Meta, MAIN_BLOCK_COUNT_KEY),
+            main_height => batch.put(Table::Meta, MAIN_BLOCK_COUNT_KEY, main_height.to_be_bytes()),
+        }
+        // Completes `disconnect_main_block`.
+        batch.delete(Table::Meta, JOURNAL_KEY);
+        self.write(batch)?;
+        self.listeners.utxos_spent(&spent);
+        self.listeners.utxos_created(&created);
+        self.listeners.main_block_disconnected(events);
+        Ok(())
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    A: GetAddress + Verify<C> + Serialize + DeserializeOwned,
+    A::Error: std::error::Error + Send + Sync + 'static,
+    C: Clone + GetValue + Serialize + DeserializeOwned,
+    S: StateStore,
+    H: HashFunction,
+{
+    /// Validates the block and connects it in a single store write, nothing
+    /// is changed if anything fails.
+    pub fn connect_block(
+        &mut self,
+        header: &Header,
+        body: &Body<A, C>,
+    ) -> Result<ConnectResult, Error> {
+        let start = Instant::now();
+        self.validate_block(header, body)?;
+        let mut undo = self.build_undo(body)?;
+        undo.body = encode(body)?;
+        let spent = undo.spent.clone();
+        let created = undo.created.iter().map(|(outpoint, _)| *outpoint).collect();
+        self.push_block(*header, undo, &body.transactions)?;
+        let connected_at = Instant::now();
+        let duration = connected_at - start;
+        self.timings.record(connected_at);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("sdk_types_connect_block_seconds").record(duration);
+        #[cfg(debug_assertions)]
+        {
+            let report = self.check_invariants().map_err(store_error)?;
+            debug_assert!(report.is_ok(), "invariants violated: {report:?}");
+        }
+        Ok(ConnectResult {
+            block_hash: self.tip,
+            height: self.block_count() - 1,
+            spent,
+            created,
+            duration,
+        })
+    }
+
+    /// Validates blocks extending the tip as if they were connected in order,
+    /// without changing the state.
+    ///
+    /// Blocks are connected to an in-memory overlay over a view of the store.
+    pub fn validate_chain(
+        &self,
+        blocks: &[(Header, Body<A, C>)],
+    ) -> Result<Vec<ConnectResult>, Error> {
+        let mut overlay = StateMachine::<A, C, _, H> {
+            store: OverlayStore::new(self.store.view().map_err(store_error)?),
+            tip: self.tip,
+            height: self.height,
+            txindex: false,
+            pruning: Pruning::Archival,
+            params: self.params.clone(),
+            listeners: Listeners::new(),
+            timings: Timings::default(),
+            _marker: PhantomData,
+        };
+        let mut results = Vec::with_capacity(blocks.len());
+        for (index, (header, body)) in blocks.iter().enumerate() {
+            let result =
+                overlay
+                    .connect_block(header, body)
+                    .map_err(|err| Error::InvalidBlock {
+                        index,
+                        block_hash: header.hash_with::<H>(),
+                        source: Box::new(err),
+                    })?;
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Disconnects the tip block.
+    ///
+    /// Fails while a withdrawal created by the block is locked in a bundle,
+    /// the bundle has to be reverted with `disconnect_main_block` first.
+    pub fn disconnect_block(&mut self, body: &Body<A, C>) -> Result<(), Error> {
+        let header = self
+            .get_header(&self.tip)?
+            .ok_or(Error::NoBlockToDisconnect)?;
+        let merkle_root = body.try_compute_merkle_root_with::<H>()?;
+        if header.merkle_root != merkle_root {
+            return Err(Error::WrongMerkleRoot {
+                header: header.merkle_root,
+                body: merkle_root,
+            });
+        }
+        self.pop_block(None)
+    }
+
+    /// Switches to the branch ending with the last of `new_tip_headers`.
+    ///
+    /// Leading headers that are already in the current chain are skipped, the
+    /// rest must extend the common ancestor. Blocks after the common ancestor
+    /// are disconnected and the new branch is validated and connected. If any
+    /// new block is invalid the old branch is restored and the error is
+    /// returned. Progress is journaled in the store, see `recover`.
+    ///
+    /// Nothing is disconnected if a block to disconnect created a withdrawal
+    /// that is locked in a bundle, see `disconnect_block`.
+    pub fn reorg_to(
+        &mut self,
+        new_tip_headers: &[Header],
+        bodies: &[Body<A, C>],
+    ) -> Result<ReorgOutcome<A, C>, Error> {
+        if new_tip_headers.len() != bodies.len() {
+            return Err(Error::WrongNumberOfBodies {
+                headers: new_tip_headers.len(),
+                bodies: bodies.len(),
+            });
+        }
+        for pair in new_tip_headers.windows(2) {
+            let prev_hash = pair[0].hash_with::<H>();
+            if pair[1].prev_side_hash != prev_hash {
+                return Err(Error::WrongPrevSideHash {
+                    prev_side_hash: pair[1].prev_side_hash,
+                    tip: prev_hash,
+                });
+            }
+        }
+        let mut known = 0;
+        for header in new_tip_headers {
+            if self.get_block_height(&header.hash_with::<H>())?.is_none() {
+                break;
+            }
+            known += 1;
+        }
+        let common_ancestor = match (new_tip_headers.get(known), known) {
+            (Some(header), _) => header.prev_side_hash,
+            (None, 0) => self.get_tip(),
+            (None, known) => new_tip_headers[known - 1].hash_with::<H>(),
+        };
+        // Number of blocks to keep in the current chain.
+        let keep = if common_ancestor.is_zero() {
+            0
+        } else {
+            self.get_block_height(&common_ancestor)?
+                .ok_or(Error::UnknownCommonAncestor { common_ancestor })?
+                + 1
+        };
+        for height in keep..self.block_count() {
+            let block_hash = self.get_block_hash_at(height)?.ok_or(Error::Inconsistent {
+                table: Table::BlockOrder,
+            })?;
+            let Some(undo) = self.get(Table::Undo, &block_hash.0)? else {
+                return Err(Error::NoUndoData { block_hash });
+            };
+            self.check_unlocked(&decode(&undo)?)?;
+        }
+        let mut journal = Journal {
+            tip: self.tip,
+            disconnected: Vec::new(),
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(Table::Meta, JOURNAL_KEY, encode(&journal)?);
+        self.write(batch)?;
+        while self.block_count() > keep {
+            self.pop_block(Some(&mut journal))?;
+        }
+        let disconnected_bodies = journal
+            .disconnected
+            .iter()
+            .map(|block| decode(&block.undo.body))
+            .collect::<Result<Vec<_>, _>>();
+        let disconnected_bodies = match disconnected_bodies {
+            Ok(bodies) => bodies,
+            Err(err) => {
+                self.roll_back(journal)?;
+                return Err(err);
+            }
+        };
+        let mut connected = Vec::new();
+        for (header, body) in new_tip_headers[known..].iter().zip(&bodies[known..]) {
+            if let Err(err) = self.connect_block(header, body) {
+                self.roll_back(journal)?;
+                return Err(err);
+            }
+            connected.push(header.hash_with::<H>());
+        }
+        self.clear_journal()?;
+        Ok(ReorgOutcome {
+            common_ancestor,
+            disconnected: journal
+                .disconnected
+                .iter()
+                .map(|block| block.header.hash_with::<H>())
+                .collect(),
+            disconnected_bodies,
+            connected,
+        })
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    A: Serialize,
+    C: Clone + GetValue + Serialize + DeserializeOwned,
+    S: StateStore,
+    H: HashFunction,
+{
+    /// Loads the state kept in `store`, connecting the genesis block
+    /// allocating `genesis_outputs` if the store is empty.
+    ///
+    /// The genesis block is not validated, so its coinbase is not limited by
+    /// fees. Its outputs are coinbase outputs at height 0 and mature like any
+    /// other. Fails if the store holds a chain with a different genesis.
+    pub fn open_with_genesis(
+        store: S,
+        params: ChainParams,
+        genesis_outputs: Vec<Output<C>>,
+    ) -> Result<Self, Error> {
+        let mut state = Self::open(store)?.with_params(params);
+        let body = Body::genesis(genesis_outputs);
+        let header = Header::try_genesis_with::<H, _, _>(&body)?;
+        match state.get_block_hash_at(0)? {
+            Some(block_hash) if block_hash != header.hash_with::<H>() => Err(Error::WrongGenesis {
+                expected: header.hash_with::<H>(),
+                found: block_hash,
+            }),
+            Some(_) => Ok(state),
+            None => {
+                let mut undo = state.build_undo(&body)?;
+                undo.body = encode(&body)?;
+                state.push_block(header, undo, &[])?;
+                Ok(state)
+            }
+        }
+    }
+}
+
+impl<A, C, S, H> StateMachine<A, C, S, H>
+where
+    C: Serialize + DeserializeOwned,
+    S: StateStore,
+    H: HashFunction,
+{
+    /// Restores a snapshot into an empty `store`.
+    pub fn restore_with<R: Read>(store: S, mut reader: R) -> Result<Self, SnapshotError> {
+        let mut state = Self::open(store)?;
+        let mut is_empty = state.height.is_none();
+        state
+            .store
+            .iterate(Table::Outputs, &mut |_, _| {
+                is_empty = false;
+                ControlFlow::Break(())
+            })
+            .map_err(store_error)?;
+        if !is_empty {
+            return Err(SnapshotError::StoreNotEmpty);
+        }
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        // Payloads of older versions may still decode, e.g. with addresses
+        // of 32 instead of 33 bytes, but into garbage.
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        let mut length = [0; 8];
+        reader.read_exact(&mut length)?;
+        let length = u64::from_le_bytes(length);
+        // Don't trust the length for preallocation.
+        let mut payload = Vec::new();
+        (&mut reader).take(length).read_to_end(&mut payload)?;
+        if payload.len() as u64 != length {
+            return Err(SnapshotError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut checksum = [0; 32];
+        reader.read_exact(&mut checksum)?;
+        if checksum != <[u8; 32]>::from(blake3::hash(&payload)) {
+            return Err(SnapshotError::BadChecksum);
+        }
+        let snapshot: Snapshot<C> = bincode::deserialize(&payload)?;
+        let mut batch = WriteBatch::new();
+        let mut prev_side_hash = BlockHash::ZERO;
+        for (height, header) in (0u32..).zip(&snapshot.headers) {
+            if header.prev_side_hash != prev_side_hash {
+                return Err(SnapshotError::BadHeaderChain);
+            }
+            let block_hash = header.hash_with::<H>();
+            batch.put(Table::Headers, block_hash.0, encode(header)?);
+            batch.put(Table::Heights, block_hash.0, height.to_be_bytes());
+            batch.put(Table::BlockOrder, height.to_be_bytes(), block_hash.0);
+            batch.put(Table::Meta, HEIGHT_KEY, height.to_be_bytes());
+            prev_side_hash = block_hash;
+        }
+        for (outpoint, output) in &snapshot.utxos {
+            put_unspent(&mut batch, &encode(outpoint)?, output)?;
+        }
+        for (outpoint, output) in &snapshot.locked {
+            let key = encode(outpoint)?;
+            batch.put(Table::Outputs, key.clone(), encode(output)?);
+            batch.put(Table::LockedOutpoints, key, []);
+        }
+        for (outpoint, output) in &snapshot.pending_deposits {
+            let key = encode(outpoint)?;
+            batch.put(Table::Outputs, key.clone(), encode(output)?);
+            batch.put(Table::PendingDeposits, key, []);
+        }
+        for (outpoint, withdrawal) in &snapshot.refunded_withdrawals {
+            batch.put(
+                Table::RefundedWithdrawals,
+                encode(outpoint)?,
+                encode(withdrawal)?,
+            );
+        }
+        for (bundle_hash, entry) in &snapshot.bundles {
+            batch.put(Table::Bundles, &bundle_hash[..], encode(entry)?);
+        }
+        for (outpoint, height) in &snapshot.coinbase_heights {
+            batch.put(
+                Table::CoinbaseHeights,
+                encode(outpoint)?,
+                height.to_be_bytes(),
+            );
+        }
+        if snapshot.main_block_count != 0 {
+            batch.put(
+                Table::Meta,
+                MAIN_BLOCK_COUNT_KEY,
+                snapshot.main_block_count.to_be_bytes(),
+            );
+        }
+        for (outpoint, height) in &snapshot.deposit_heights {
+            batch.put(
+                Table::DepositHeights,
+                encode(outpoint)?,
+                height.to_be_bytes(),
+            );
+        }
+        state.write(batch)?;
+        Ok(Self::open(state.store)?)
+    }
+}
+
+impl<A, C: Serialize + DeserializeOwned, H: HashFunction> StateMachine<A, C, MemoryStore, H> {
+    pub fn restore<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        Self::restore_with(MemoryStore::new(), reader)
+    }
+}
+
+impl<A, C: DeserializeOwned, S: ReadStore, H> UtxoMap for StateMachine<A, C, S, H> {
+    type OutPoint = OutPoint;
+    type Output = Output<C>;
+
+    /// Store errors are reported as missing outputs.
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output<C>> {
+        self.get_unspent(outpoint).ok()
+    }
+
+    fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        !self.is_unspent(outpoint).unwrap_or(false)
+    }
+
+    /// Coinbase maturity, see `ChainParams::coinbase_maturity`. Store errors
+    /// are reported as immature outputs.
+    fn is_mature(&self, outpoint: &OutPoint) -> bool {
+        self.check_maturity(&[*outpoint]).is_ok()
+    }
+}
+
+impl<A, C: DeserializeOwned, S: ReadStore, H> UtxoScan for StateMachine<A, C, S, H> {
+    type ScanError = Error;
+
+    /// Visits outputs in outpoint key order.
+    fn scan(&self, f: &mut UtxoVisitor<'_, OutPoint, Output<C>>) -> Result<(), Error> {
+        // Keys are collected first, views don't support reads while iterating.
+        let mut keys = Vec::new();
+        self.store
+            .iterate(Table::UnspentOutpoints, &mut |key, _| {
+                keys.push(key.to_vec());
+                ControlFlow::Continue(())
+            })
+            .map_err(store_error)?;
+        for key in keys {
+            let output = self.get(Table::Outputs, &key)?.ok_or(Error::Inconsistent {
+                table: Table::Outputs,
+            })?;
+            if f(&decode(&key)?, &decode(&output)?).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Uses the address index instead of scanning every output.
+    fn utxos_by_address(&self, address: &Address) -> Result<Vec<(OutPoint, Output<C>)>, Error> {
+        let mut keys = Vec::new();
+        self.store
+            .iterate_prefix(
+                Table::AddressOutpoints,
+                &address.to_bytes(),
+                &mut |key, _| {
+                    keys.push(key[Address::ENCODED_LENGTH..].to_vec());
+                    ControlFlow::Continue(())
+                },
+            )
+            .map_err(store_error)?;
+        let mut utxos = Vec::with_capacity(keys.len());
+        for key in keys {
+            let outpoint = decode(&key)?;
+            utxos.push((outpoint, self.get_unspent(&outpoint)?));
+        }
+        Ok(utxos)
+    }
+}
+
+impl<A, C, S, H> State<A, C> for StateMachine<A, C, S, H>
+where
+    A: GetAddress + Verify<C>,
+    A::Error: std::error::Error + Send + Sync + 'static,
+    C: Clone + GetValue + Serialize + DeserializeOwned,
+    S: StateStore,
+    H: HashFunction,
+{
+    type Utxo = Output<C>;
+    type Error = Error;
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Output<C>> {
+        self.get_unspent(outpoint).ok()
+    }
+
+    fn validate_transaction(
+        &self,
+        transaction: &AuthorizedTransaction<A, C>,
+    ) -> Result<u64, Error> {
+        StateMachine::validate_transaction(self, transaction)
+    }
+
+    fn validate_body(&self, body: &Body<A, C>) -> Result<u64, Error> {
+        StateMachine::validate_body(self, body)
+    }
+
+    fn connect_body(&mut self, body: &Body<A, C>) -> Result<(), Error> {
+        // Check everything before mutating so a failure leaves the state intact.
+        let undo = self.build_undo(body)?;
+        let mut batch = WriteBatch::new();
+        self.apply(&mut batch, &undo)?;
+        self.write(batch)?;
+        self.listeners.utxos_spent(&undo.spent);
+        self.listeners.utxos_created(&undo.created);
+        Ok(())
+    }
+
+    fn disconnect_body(&mut self, body: &Body<A, C>) -> Result<(), Error> {
+        let undo = BlockUndo {
+            spent: body.get_inputs(),
+            created: body.try_get_outputs_with::<H>()?.into_iter().collect(),
+            txids: Vec::new(),
+            body: Vec::new(),
+        };
+        for (outpoint, _) in &undo.created {
+            self.get_unspent(outpoint)?;
+        }
+        for outpoint in &undo.spent {
+            if self.get(Table::Outputs, &encode(outpoint)?)?.is_none() {
+                return Err(Error::UtxoDoesNotExist {
+                    outpoint: *outpoint,
+                });
+            }
+        }
+        let mut batch = WriteBatch::new();
+        let restored = self.revert(&mut batch, &undo)?;
+        self.write(batch)?;
+        self.listeners
+            .utxos_spent(undo.created.iter().map(|(outpoint, _)| outpoint));
+        self.listeners.utxos_created(&restored);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("validation error")]
+    Validation(#[from] validator::Error),
+    #[error("utxo with outpoint {outpoint} does not exist")]
+    UtxoDoesNotExist { outpoint: OutPoint },
+    #[error("utxo with outpoint {outpoint} is already spent")]
+    UtxoSpent { outpoint: OutPoint },
+    #[error("authorization is invalid")]
+    BadAuthorization(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("header prev side hash does not match tip: {prev_side_hash} != {tip}")]
+    WrongPrevSideHash {
+        prev_side_hash: BlockHash,
+        tip: BlockHash,
+    },
+    #[error("header merkle root does not match body merkle root: {header} != {body}")]
+    WrongMerkleRoot {
+        header: MerkleRoot,
+        body: MerkleRoot,
+    },
+    #[error("there is no block to disconnect")]
+    NoBlockToDisconnect,
+    #[error("there is no undo data for block {block_hash}")]
+    NoUndoData { block_hash: BlockHash },
+    #[error("number of headers != number of bodies: {headers} != {bodies}")]
+    WrongNumberOfBodies { headers: usize, bodies: usize },
+    #[error("common ancestor {common_ancestor} is not in the current chain")]
+    UnknownCommonAncestor { common_ancestor: BlockHash },
+    #[error("storage error")]
+    Store(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("{outpoint} already exists")]
+    DepositExists { outpoint: OutPoint },
+    #[error("output {outpoint} is not a withdrawal")]
+    NotWithdrawal { outpoint: OutPoint },
+    #[error("withdrawal {outpoint} is not locked in a bundle")]
+    WithdrawalNotLocked { outpoint: OutPoint },
+    #[error("withdrawal {outpoint} is locked in a bundle")]
+    WithdrawalLocked { outpoint: OutPoint },
+    #[error("withdrawal {outpoint} is in more than one bundle event")]
+    DuplicateWithdrawal { outpoint: OutPoint },
+    #[error(
+        "{outpoint} can't be spent at height {height}, it matures at height {maturity_height}"
+    )]
+    ImmatureCoinbase {
+        outpoint: OutPoint,
+        height: u32,
+        maturity_height: u32,
+    },
+    #[error("store holds a chain with genesis {found}, expected {expected}")]
+    WrongGenesis {
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    #[error("there is no mainchain block to disconnect")]
+    NoMainBlockToDisconnect,
+    #[error("withdrawal bundle {bundle_hash} is unknown")]
+    UnknownBundle { bundle_hash: bitcoin::Txid },
+    #[error("withdrawal bundle {bundle_hash} is {status:?}, expected {expected:?}")]
+    WrongBundleStatus {
+        bundle_hash: bitcoin::Txid,
+        status: BundleStatus,
+        expected: BundleStatus,
+    },
+    #[error("withdrawal bundle error")]
+    Bundle(#[from] BundleError),
+    #[error("block {block_hash} at index {index} is invalid")]
+    InvalidBlock {
+        index: usize,
+        block_hash: BlockHash,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("state store is inconsistent, missing or malformed {table:?} entry")]
+    Inconsistent { table: Table },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("state error")]
+    State(#[from] Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("not a state snapshot")]
+    BadMagic,
+    #[error("unsupported snapshot version {version}")]
+    UnsupportedVersion { version: u32 },
+    #[error("snapshot checksum does not match")]
+    BadChecksum,
+    #[error("snapshot headers are not linked")]
+    BadHeaderChain,
+    #[error("snapshots can only be restored into an empty store")]
+    StoreNotEmpty,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::KeyAuthorization;
+    use bitcoin::hashes::Hash as _;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use std::convert::Infallible;
+
+    type TestState<S = MemoryStore> = StateMachine<KeyAuthorization, (), S>;
+
+    fn key(byte: u8) -> (SecretKey, Address) {
+        let secret_key = SecretKey::from_slice(&[byte; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        (secret_key, Address::from_public_key(&public_key))
+    }
+
+    fn value(address: Address, value: u64) -> Output<()> {
+        Output {
+            address,
+            content: Content::Value(value),
+        }
+    }
+
+    fn genesis_outputs() -> Vec<Output<()>> {
+        vec![value(key(1).1, 1000)]
+    }
+
+    fn genesis_outpoint() -> OutPoint {
+        OutPoint::Coinbase {
+            merkle_root: Body::<KeyAuthorization, ()>::genesis(genesis_outputs())
+                .compute_merkle_root(),
+            vout: 0,
+        }
+    }
+
+    /// Spends the genesis output, paying `amount` to `to`.
+    fn spend_genesis(to: Address, amount: u64) -> AuthorizedTransaction<KeyAuthorization, ()> {
+        let transaction = Transaction {
+            inputs: vec![genesis_outpoint()],
+            outputs: vec![value(to, amount)],
+        };
+        let authorization =
+            KeyAuthorization::sign(&Secp256k1::new(), &key(1).0, &transaction.txid());
+        AuthorizedTransaction {
+            transaction,
+            authorizations: vec![authorization],
+        }
+    }
+
+    /// Block on top of `prev`, `main` tells apart blocks with equal bodies.
+    fn block(
+        prev: BlockHash,
+        main: u8,
+        transactions: Vec<AuthorizedTransaction<KeyAuthorization, ()>>,
+        coinbase: Vec<Output<()>>,
+    ) -> (Header, Body<KeyAuthorization, ()>) {
+        let body = Body::new(transactions, coinbase);
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: prev,
+            prev_main_hash: bitcoin::BlockHash::from_inner([main; 32]),
+        };
+        (header, body)
+    }
+
+    /// Branch of `length` blocks on top of `prev`, the first one spends the
+    /// genesis output to `to`, paying `fee` to the miner.
+    fn branch(
+        prev: BlockHash,
+        main: u8,
+        length: usize,
+        to: Address,
+        fee: u64,
+    ) -> (Vec<Header>, Vec<Body<KeyAuthorization, ()>>) {
+        let mut headers = Vec::new();
+        let mut bodies = Vec::new();
+        let mut prev = prev;
+        for index in 0..length {
+            let (header, body) = if index == 0 {
+                block(
+                    prev,
+                    main,
+                    vec![spend_genesis(to, 1000 - fee)],
+                    vec![value(key(9).1, fee)],
+                )
+            } else {
+                block(prev, main, Vec::new(), Vec::new())
+            };
+            prev = header.hash();
+            headers.push(header);
+            bodies.push(body);
+        }
+        (headers, bodies)
+    }
+
+    fn connect<S: StateStore>(
+        state: &mut TestState<S>,
+        headers: &[Header],
+        bodies: &[Body<KeyAuthorization, ()>],
+    ) {
+        for (header, body) in headers.iter().zip(bodies) {
+            state.connect_block(header, body).unwrap();
+        }
+    }
+
+    fn snapshot<S: ReadStore>(state: &TestState<S>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        state.snapshot(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reorg_to_switches_branch_and_back() {
+        let mut state =
+            TestState::new_with_genesis(ChainParams::default(), genesis_outputs()).unwrap();
+        let genesis = state.get_tip();
+        let (alice, bob) = (key(2).1, key(3).1);
+        let (a_headers, a_bodies) = branch(genesis, 1, 2, alice, 100);
+        let (b_headers, b_bodies) = branch(genesis, 2, 3, bob, 200);
+        connect(&mut state, &a_headers, &a_bodies);
+        let on_a = snapshot(&state);
+
+        let outcome = state.reorg_to(&b_headers, &b_bodies).unwrap();
+        assert_eq!(outcome.common_ancestor, genesis);
+        assert_eq!(
+            outcome.disconnected,
+            [a_headers[1].hash(), a_headers[0].hash()]
+        );
+        let disconnected_roots: Vec<_> = outcome
+            .disconnected_bodies
+            .iter()
+            .map(Body::compute_merkle_root)
+            .collect();
+        assert_eq!(
+            disconnected_roots,
+            [a_headers[1].merkle_root, a_headers[0].merkle_root]
+        );
+        assert_eq!(
+            outcome.connected,
+            b_headers.iter().map(Header::hash).collect::<Vec<_>>()
+        );
+        assert_eq!(state.get_tip(), b_headers[2].hash());
+        assert_eq!(state.get_balance(&alice).unwrap().confirmed, 0);
+        assert_eq!(state.get_balance(&bob).unwrap().confirmed, 800);
+        assert_eq!(state.get_balance(&key(9).1).unwrap().confirmed, 200);
+
+        // Headers already connected are skipped.
+        let outcome = state.reorg_to(&a_headers, &a_bodies).unwrap();
+        assert_eq!(outcome.disconnected.len(), 3);
+        assert_eq!(outcome.connected.len(), 2);
+        assert_eq!(snapshot(&state), on_a);
+    }
+
+    #[test]
+    fn reorg_to_invalid_branch_restores_old_branch() {
+        let mut state =
+            TestState::new_with_genesis(ChainParams::default(), genesis_outputs()).unwrap();
+        let genesis = state.get_tip();
+        let (a_headers, a_bodies) = branch(genesis, 1, 2, key(2).1, 100);
+        connect(&mut state, &a_headers, &a_bodies);
+        let on_a = snapshot(&state);
+        let (mut b_headers, mut b_bodies) = branch(genesis, 2, 1, key(3).1, 200);
+        // Collects more than the fees.
+        let (header, body) = block(b_headers[0].hash(), 2, Vec::new(), vec![value(key(9).1, 1)]);
+        b_headers.push(header);
+        b_bodies.push(body);
+
+        assert!(matches!(
+            state.reorg_to(&b_headers, &b_bodies),
+            Err(Error::Validation(
+                validator::Error::CoinbaseValueGreaterThanFees { .. }
+            ))
+        ));
+        assert_eq!(state.get_tip(), a_headers[1].hash());
+        assert_eq!(snapshot(&state), on_a);
+        assert!(!state.recover().unwrap());
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut state =
+            TestState::new_with_genesis(ChainParams::default(), genesis_outputs()).unwrap();
+        let (alice, carol) = (key(2).1, key(4).1);
+        let (headers, bodies) = branch(state.get_tip(), 1, 2, alice, 100);
+        connect(&mut state, &headers, &bodies);
+        let events = MainBlockEvents {
+            deposits: vec![(bitcoin::OutPoint::default(), value(carol, 500))],
+            ..MainBlockEvents::default()
+        };
+        state.connect_main_block(&events).unwrap();
+        let bytes = snapshot(&state);
+
+        let mut restored = TestState::restore(bytes.as_slice()).unwrap();
+        assert_eq!(restored.get_tip(), state.get_tip());
+        assert_eq!(restored.block_count(), state.block_count());
+        assert_eq!(
+            restored.get_main_block_count().unwrap(),
+            state.get_main_block_count().unwrap()
+        );
+        assert_eq!(restored.utxos().unwrap(), state.utxos().unwrap());
+        for address in [key(1).1, alice, carol, key(9).1] {
+            assert_eq!(
+                restored.get_balance(&address).unwrap(),
+                state.get_balance(&address).unwrap()
+            );
+        }
+        assert_eq!(snapshot(&restored), bytes);
+        assert!(matches!(
+            restored.connect_main_block(&events),
+            Err(Error::DepositExists { .. })
+        ));
+
+        // Both continue the same way.
+        let (header, body) = block(state.get_tip(), 3, Vec::new(), Vec::new());
+        state.connect_block(&header, &body).unwrap();
+        restored.connect_block(&header, &body).unwrap();
+        assert_eq!(snapshot(&restored), snapshot(&state));
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("crashed")]
+    struct Crashed;
+
+    /// Memory store failing every write after the first `writes_left`, as if
+    /// the process crashed.
+    #[derive(Debug, Clone)]
+    struct CrashingStore {
+        store: MemoryStore,
+        writes_left: usize,
+    }
+
+    impl ReadStore for CrashingStore {
+        type Error = Crashed;
+
+        fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, Crashed> {
+            self.store
+                .get(table, key)
+                .map_err(|err: Infallible| match err {})
+        }
+
+        fn iterate(&self, table: Table, f: &mut crate::store::Visitor<'_>) -> Result<(), Crashed> {
+            self.store.iterate(table, f).map_err(|err| match err {})
+        }
+    }
+
+    impl StateStore for CrashingStore {
+        type View = CrashingStore;
+
+        fn view(&self) -> Result<CrashingStore, Crashed> {
+            Ok(self.clone())
+        }
+
+        fn write(&mut self, batch: WriteBatch) -> Result<(), Crashed> {
+            self.writes_left = self.writes_left.checked_sub(1).ok_or(Crashed)?;
+            self.store.write(batch).map_err(|err| match err {})
+        }
+    }
+
+    #[test]
+    fn recover_rolls_back_interrupted_reorg() {
+        let store = CrashingStore {
+            store: MemoryStore::new(),
+            writes_left: usize::MAX,
+        };
+        let mut state =
+            TestState::open_with_genesis(store, ChainParams::default(), genesis_outputs()).unwrap();
+        let genesis = state.get_tip();
+        let (a_headers, a_bodies) = branch(genesis, 1, 2, key(2).1, 100);
+        let (b_headers, b_bodies) = branch(genesis, 2, 3, key(3).1, 200);
+        connect(&mut state, &a_headers, &a_bodies);
+        let on_a = snapshot(&state);
+        // Crash after every write of the reorg in turn, in the middle of
+        // disconnecting as well as of connecting the new branch.
+        let mut crashes = 0;
+        for writes in 0.. {
+            let mut crashing = TestState::open(CrashingStore {
+                store: state.store.store.clone(),
+                writes_left: writes,
+            })
+            .unwrap();
+            if crashing.reorg_to(&b_headers, &b_bodies).is_ok() {
+                break;
+            }
+            crashes += 1;
+            let mut recovered = TestState::open(crashing.store.store).unwrap();
+            assert_eq!(recovered.recover().unwrap(), writes > 0);
+            assert_eq!(recovered.get_tip(), a_headers[1].hash());
+            assert_eq!(snapshot(&recovered), on_a);
+            assert!(!recovered.recover().unwrap());
+        }
+        // The journal, two disconnects, three connects and clearing the
+        // journal.
+        assert_eq!(crashes, 7);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::state::{Error, StateMachine};
+use crate::store::{ReadStore, Table};
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+/// Summary of the state, see `StateMachine::get_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateStats {
+    pub utxo_count: usize,
+    /// Value of unspent outputs.
+    pub total_value: u128,
+    pub block_count: u32,
+    /// Average wall-clock time between blocks connected since the state was
+    /// opened, `None` until two blocks were connected.
+    pub avg_block_interval: Option<Duration>,
+    /// Value of withdrawal outputs not yet paid out on the mainchain,
+    /// including the ones locked into bundles.
+    pub pending_withdrawal_value: u128,
+}
+
+#[cfg(feature = "metrics")]
+impl StateStats {
+    /// Sets the `sdk_types_*` gauges through the `metrics` facade.
+    pub fn publish(&self) {
+        metrics::gauge!("sdk_types_utxo_count").set(self.utxo_count as f64);
+        metrics::gauge!("sdk_types_total_value").set(self.total_value as f64);
+        metrics::gauge!("sdk_types_block_count").set(self.block_count);
+        if let Some(interval) = self.avg_block_interval {
+            metrics::gauge!("sdk_types_avg_block_interval_seconds").set(interval);
+        }
+        metrics::gauge!("sdk_types_pending_withdrawal_value")
+            .set(self.pending_withdrawal_value as f64);
+    }
+}
+
+/// Wall-clock times of blocks connected since the state was opened.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Timings {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    connected: u32,
+}
+
+impl Timings {
+    pub(crate) fn record(&mut self, connected_at: Instant) {
+        self.first.get_or_insert(connected_at);
+        self.last = Some(connected_at);
+        self.connected += 1;
+    }
+
+    fn avg_interval(&self) -> Option<Duration> {
+        let intervals = self.connected.checked_sub(1).filter(|n| *n > 0)?;
+        Some((self.last? - self.first?) / intervals)
+    }
+}
+
+impl<A, C: GetValue + DeserializeOwned, S: ReadStore, H> StateMachine<A, C, S, H> {
+    /// Reads every unspent and locked output.
+    pub fn get_stats(&self) -> Result<StateStats, Error> {
+        let mut stats = StateStats {
+            block_count: self.get_height().map_or(0, |height| height + 1),
+            avg_block_interval: self.timings().avg_interval(),
+            ..StateStats::default()
+        };
+        for (table, is_unspent) in [
+            (Table::UnspentOutpoints, true),
+            (Table::LockedOutpoints, false),
+        ] {
+            let mut keys = Vec::new();
+            self.store()
+                .iterate(table, &mut |key, _| {
+                    keys.push(key.to_vec());
+                    ControlFlow::Continue(())
+                })
+                .map_err(|err| Error::Store(Box::new(err)))?;
+            for key in keys {
+                let output: Output<C> = match self.store().get(Table::Outputs, &key) {
+                    Ok(Some(output)) => bincode::deserialize(&output)?,
+                    Ok(None) => {
+                        return Err(Error::Inconsistent {
+                            table: Table::Outputs,
+                        })
+                    }
+                    Err(err) => return Err(Error::Store(Box::new(err))),
+                };
+                let value = u128::from(output.get_value());
+                if output.content.is_withdrawal() {
+                    stats.pending_withdrawal_value += value;
+                }
+                if is_unspent {
+                    stats.utxo_count += 1;
+                    stats.total_value += value;
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,392 @@
+use im::OrdMap;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::ops::ControlFlow;
+
+/// Namespaces used by `StateMachine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Table {
+    Meta,
+    Outputs,
+    UnspentOutpoints,
+    Headers,
+    Heights,
+    BlockOrder,
+    Undo,
+    Transactions,
+    AddressOutpoints,
+    LockedOutpoints,
+    Bundles,
+    CoinbaseHeights,
+    DepositHeights,
+    PendingDeposits,
+    RefundedWithdrawals,
+}
+
+impl Table {
+    pub const ALL: [Table; 15] = [
+        Table::Meta,
+        Table::Outputs,
+        Table::UnspentOutpoints,
+        Table::Headers,
+        Table::Heights,
+        Table::BlockOrder,
+        Table::Undo,
+        Table::Transactions,
+        Table::AddressOutpoints,
+        Table::LockedOutpoints,
+        Table::Bundles,
+        Table::CoinbaseHeights,
+        Table::DepositHeights,
+        Table::PendingDeposits,
+        Table::RefundedWithdrawals,
+    ];
+
+    /// Stable name, for backends that keep one database or tree per table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Meta => "meta",
+            Self::Outputs => "outputs",
+            Self::UnspentOutpoints => "unspent_outpoints",
+            Self::Headers => "headers",
+            Self::Heights => "heights",
+            Self::BlockOrder => "block_order",
+            Self::Undo => "undo",
+            Self::Transactions => "transactions",
+            Self::AddressOutpoints => "address_outpoints",
+            Self::LockedOutpoints => "locked_outpoints",
+            Self::Bundles => "bundles",
+            Self::CoinbaseHeights => "coinbase_heights",
+            Self::DepositHeights => "deposit_heights",
+            Self::PendingDeposits => "pending_deposits",
+            Self::RefundedWithdrawals => "refunded_withdrawals",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Put {
+        table: Table,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        table: Table,
+        key: Vec<u8>,
+    },
+}
+
+/// Writes that must be applied all at once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<WriteOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, table: Table, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(WriteOp::Put {
+            table,
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    pub fn delete(&mut self, table: Table, key: impl Into<Vec<u8>>) {
+        self.ops.push(WriteOp::Delete {
+            table,
+            key: key.into(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = WriteOp;
+    type IntoIter = std::vec::IntoIter<WriteOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+/// Callback for `StateStore::iterate`, receives a key and a value.
+pub type Visitor<'a> = dyn FnMut(&[u8], &[u8]) -> ControlFlow<()> + 'a;
+
+/// Read access to a key-value storage backend.
+pub trait ReadStore {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Visits entries of a table in key order until `f` breaks.
+    fn iterate(&self, table: Table, f: &mut Visitor<'_>) -> Result<(), Self::Error>;
+
+    /// Visits entries whose key starts with `prefix` in key order until `f`
+    /// breaks.
+    ///
+    /// The default implementation scans the whole table, backends should
+    /// override it with a range scan.
+    fn iterate_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+    ) -> Result<(), Self::Error> {
+        self.iterate(table, &mut |key, value| {
+            if key.starts_with(prefix) {
+                f(key, value)
+            } else if key > prefix {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+
+    fn contains(&self, table: Table, key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.get(table, key)?.is_some())
+    }
+}
+
+/// Key-value storage backend for `StateMachine`.
+pub trait StateStore: ReadStore {
+    /// Point-in-time read-only view, unaffected by later writes.
+    type View: ReadStore<Error = Self::Error> + Clone + Send + Sync;
+
+    fn view(&self) -> Result<Self::View, Self::Error>;
+
+    /// Applies all operations of the batch atomically, in order.
+    fn write(&mut self, batch: WriteBatch) -> Result<(), Self::Error>;
+
+    fn put(&mut self, table: Table, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+        let mut batch = WriteBatch::new();
+        batch.put(table, key, value);
+        self.write(batch)
+    }
+
+    fn delete(&mut self, table: Table, key: &[u8]) -> Result<(), Self::Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(table, key);
+        self.write(batch)
+    }
+}
+
+/// Default in-memory backend.
+///
+/// Tables are persistent maps, so views are cheap clones that share
+/// structure with the store.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    tables: HashMap<Table, OrdMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReadStore for MemoryStore {
+    type Error = Infallible;
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, Infallible> {
+        Ok(self
+            .tables
+            .get(&table)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn iterate(&self, table: Table, f: &mut Visitor<'_>) -> Result<(), Infallible> {
+        if let Some(entries) = self.tables.get(&table) {
+            for (key, value) in entries {
+                if f(key, value).is_break() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn iterate_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+    ) -> Result<(), Infallible> {
+        if let Some(entries) = self.tables.get(&table) {
+            for (key, value) in entries.range(prefix.to_vec()..) {
+                if !key.starts_with(prefix) || f(key, value).is_break() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateStore for MemoryStore {
+    type View = MemoryStore;
+
+    fn view(&self) -> Result<MemoryStore, Infallible> {
+        Ok(self.clone())
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<(), Infallible> {
+        for op in batch {
+            match op {
+                WriteOp::Put { table, key, value } => {
+                    self.tables.entry(table).or_default().insert(key, value);
+                }
+                WriteOp::Delete { table, key } => {
+                    if let Some(entries) = self.tables.get_mut(&table) {
+                        entries.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uncommitted writes on top of a read-only store, reads see the writes.
+///
+/// Writes are kept in memory and never reach the underlying store.
+#[derive(Debug, Clone)]
+pub struct OverlayStore<S> {
+    base: S,
+    // `None` values are deletions.
+    changes: HashMap<Table, OrdMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl<S> OverlayStore<S> {
+    pub fn new(base: S) -> Self {
+        Self {
+            base,
+            changes: HashMap::new(),
+        }
+    }
+}
+
+impl<S: ReadStore> OverlayStore<S> {
+    /// Visits base entries passed by `visit_base` merged with the changes of
+    /// `table` whose keys start with `prefix`.
+    fn merge(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+        visit_base: impl FnOnce(&mut Visitor<'_>) -> Result<(), S::Error>,
+    ) -> Result<(), S::Error> {
+        let empty = OrdMap::new();
+        let changes = self.changes.get(&table).unwrap_or(&empty);
+        let mut changes = changes
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .peekable();
+        let mut done = false;
+        visit_base(&mut |key, value| {
+            while let Some((changed_key, changed)) = changes.next_if(|(k, _)| k.as_slice() <= key) {
+                if changed_key.as_slice() == key {
+                    let flow = match changed {
+                        Some(changed) => f(key, changed),
+                        None => ControlFlow::Continue(()),
+                    };
+                    done = flow.is_break();
+                    return flow;
+                }
+                if let Some(changed) = changed {
+                    if f(changed_key, changed).is_break() {
+                        done = true;
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            let flow = f(key, value);
+            done = flow.is_break();
+            flow
+        })?;
+        if done {
+            return Ok(());
+        }
+        for (key, value) in changes {
+            if let Some(value) = value {
+                if f(key, value).is_break() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: ReadStore> ReadStore for OverlayStore<S> {
+    type Error = S::Error;
+
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, S::Error> {
+        match self
+            .changes
+            .get(&table)
+            .and_then(|changes| changes.get(key))
+        {
+            Some(value) => Ok(value.clone()),
+            None => self.base.get(table, key),
+        }
+    }
+
+    fn iterate(&self, table: Table, f: &mut Visitor<'_>) -> Result<(), S::Error> {
+        self.merge(table, &[], f, |f| self.base.iterate(table, f))
+    }
+
+    fn iterate_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut Visitor<'_>,
+    ) -> Result<(), S::Error> {
+        self.merge(table, prefix, f, |f| {
+            self.base.iterate_prefix(table, prefix, f)
+        })
+    }
+}
+
+impl<S: ReadStore + Clone + Send + Sync> StateStore for OverlayStore<S> {
+    type View = Self;
+
+    fn view(&self) -> Result<Self, S::Error> {
+        Ok(self.clone())
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<(), S::Error> {
+        for op in batch {
+            match op {
+                WriteOp::Put { table, key, value } => {
+                    self.changes
+                        .entry(table)
+                        .or_default()
+                        .insert(key, Some(value));
+                }
+                WriteOp::Delete { table, key } => {
+                    self.changes.entry(table).or_default().insert(key, None);
+                }
+            }
+        }
+        Ok(())
+    }
+}
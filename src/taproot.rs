@@ -0,0 +1,265 @@
+use crate::encoding::encode_canonical;
+use crate::policy::{Policy, PolicyError, PolicySpend};
+use crate::types::*;
+use bitcoin::hashes::{sha256, Hash as _, HashEngine as _};
+use bitcoin::secp256k1::{schnorr, KeyPair, Message, Parity, Scalar, Secp256k1, XOnlyPublicKey};
+use bitcoin::secp256k1::{Signing, Verification};
+use serde::{Deserialize, Serialize};
+
+const LEAF_TAG: &[u8] = b"sdk_types/leaf";
+const BRANCH_TAG: &[u8] = b"sdk_types/branch";
+const TWEAK_TAG: &[u8] = b"sdk_types/tweak";
+
+/// BIP 340 style hash, domain separated by `tag`.
+fn tagged_hash(tag: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag);
+    engine.input(&tag);
+    for part in parts {
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+pub fn leaf_hash(policy: &Policy) -> [u8; 32] {
+    let policy = encode_canonical(policy).expect("policies always encode");
+    tagged_hash(LEAF_TAG, &[&policy])
+}
+
+/// Children are sorted, so proofs don't need to say which side they are on.
+fn branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    tagged_hash(BRANCH_TAG, &[left, right])
+}
+
+/// Key `internal_key` is tweaked by, to commit to `merkle_root`.
+pub fn taproot_output_key<C: Verification>(
+    secp: &Secp256k1<C>,
+    internal_key: &XOnlyPublicKey,
+    merkle_root: Option<[u8; 32]>,
+) -> Result<(XOnlyPublicKey, Parity), TaprootError> {
+    Ok(internal_key.add_tweak(secp, &tweak(internal_key, merkle_root)?)?)
+}
+
+fn tweak(
+    internal_key: &XOnlyPublicKey,
+    merkle_root: Option<[u8; 32]>,
+) -> Result<Scalar, TaprootError> {
+    let internal_key = internal_key.serialize();
+    let tweak = match &merkle_root {
+        Some(merkle_root) => tagged_hash(TWEAK_TAG, &[&internal_key, merkle_root]),
+        None => tagged_hash(TWEAK_TAG, &[&internal_key]),
+    };
+    Scalar::from_be_bytes(tweak).map_err(|_| TaprootError::InvalidTweak)
+}
+
+/// Internal key and policy tree behind a `Address::V2Taproot` address.
+///
+/// The owner of the internal key spends with a signature alone, revealing
+/// nothing about the policies. Any policy of the tree can spend as well,
+/// by revealing it and its merkle proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaprootCommitment {
+    internal_key: XOnlyPublicKey,
+    leaves: Vec<Policy>,
+    /// Node hashes, from the leaves up to the root. An odd last node is
+    /// carried up to the next level unchanged.
+    levels: Vec<Vec<[u8; 32]>>,
+    output_key: XOnlyPublicKey,
+    parity: Parity,
+}
+
+impl TaprootCommitment {
+    pub fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        internal_key: XOnlyPublicKey,
+        leaves: Vec<Policy>,
+    ) -> Result<Self, TaprootError> {
+        let mut levels = vec![leaves.iter().map(leaf_hash).collect::<Vec<_>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let level = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => branch_hash(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+        }
+        let merkle_root = levels[levels.len() - 1].first().copied();
+        let (output_key, parity) = taproot_output_key(secp, &internal_key, merkle_root)?;
+        Ok(Self {
+            internal_key,
+            leaves,
+            levels,
+            output_key,
+            parity,
+        })
+    }
+
+    pub fn internal_key(&self) -> &XOnlyPublicKey {
+        &self.internal_key
+    }
+
+    pub fn output_key(&self) -> &XOnlyPublicKey {
+        &self.output_key
+    }
+
+    pub fn output_key_parity(&self) -> Parity {
+        self.parity
+    }
+
+    pub fn leaves(&self) -> &[Policy] {
+        &self.leaves
+    }
+
+    /// `None` without leaves, the output key then only commits to the
+    /// internal key.
+    pub fn merkle_root(&self) -> Option<[u8; 32]> {
+        self.levels[self.levels.len() - 1].first().copied()
+    }
+
+    pub fn address(&self) -> Address {
+        Address::V2Taproot(self.output_key.serialize())
+    }
+
+    /// Proof that the `index`th leaf is part of the tree.
+    pub fn control_block(&self, index: usize) -> Option<ControlBlock> {
+        self.leaves.get(index)?;
+        let mut proof = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(ControlBlock {
+            internal_key: self.internal_key,
+            proof,
+        })
+    }
+
+    /// Tweaks the key pair of the internal key into the key pair of the
+    /// output key, for key path spends.
+    pub fn tweak_key_pair<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        key_pair: KeyPair,
+    ) -> Result<KeyPair, TaprootError> {
+        let tweak = tweak(&self.internal_key, self.merkle_root())?;
+        Ok(key_pair.add_xonly_tweak(secp, &tweak)?)
+    }
+}
+
+/// Reveals the internal key and the merkle path of a spent policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlBlock {
+    pub internal_key: XOnlyPublicKey,
+    /// Sibling hashes from the leaf up to the root.
+    pub proof: Vec<[u8; 32]>,
+}
+
+impl ControlBlock {
+    /// Output key committing to `leaf` through this block.
+    pub fn output_key<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        leaf: &Policy,
+    ) -> Result<XOnlyPublicKey, TaprootError> {
+        let merkle_root = self
+            .proof
+            .iter()
+            .fold(leaf_hash(leaf), |node, sibling| branch_hash(&node, sibling));
+        let (output_key, _) = taproot_output_key(secp, &self.internal_key, Some(merkle_root))?;
+        Ok(output_key)
+    }
+}
+
+/// Authorization of an output sent to an `Address::V2Taproot` address.
+///
+/// The address of a spend is the one it commits to, so `validate_body`
+/// rejects script spends revealing a policy outside the committed tree.
+/// `Verify` implementations still have to call `TaprootSpend::verify` and
+/// verify the authorizations of script spends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaprootSpend<A> {
+    Key {
+        output_key: XOnlyPublicKey,
+        signature: schnorr::Signature,
+    },
+    Script {
+        control_block: ControlBlock,
+        spend: PolicySpend<A>,
+    },
+}
+
+impl<A: GetAddress> TaprootSpend<A> {
+    /// Checks the signature of key spends over `txid`, and that script
+    /// spends satisfy their policy at `height`.
+    pub fn verify<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        txid: &Txid,
+        height: u32,
+    ) -> Result<(), TaprootError> {
+        match self {
+            Self::Key {
+                output_key,
+                signature,
+            } => {
+                let message = Message::from_slice(&txid.0)?;
+                Ok(secp.verify_schnorr(signature, &message, output_key)?)
+            }
+            Self::Script { spend, .. } => Ok(spend.check(height)?),
+        }
+    }
+}
+
+impl<A> TaprootSpend<A> {
+    /// Key path spend of `txid`, `key_pair` is the tweaked key pair, see
+    /// `TaprootCommitment::tweak_key_pair`.
+    pub fn sign_key_spend<C: Signing>(
+        secp: &Secp256k1<C>,
+        key_pair: &KeyPair,
+        txid: &Txid,
+    ) -> Self {
+        let message = Message::from_slice(&txid.0).expect("txids are 32 bytes long");
+        Self::Key {
+            output_key: key_pair.x_only_public_key().0,
+            signature: secp.sign_schnorr_no_aux_rand(&message, key_pair),
+        }
+    }
+}
+
+impl<A> GetAddress for TaprootSpend<A> {
+    /// Script spends with an invalid control block get the burn address,
+    /// which matches no spendable output.
+    fn get_address(&self) -> Address {
+        match self {
+            Self::Key { output_key, .. } => Address::V2Taproot(output_key.serialize()),
+            Self::Script {
+                control_block,
+                spend,
+            } => {
+                let secp = Secp256k1::verification_only();
+                match control_block.output_key(&secp, &spend.policy) {
+                    Ok(output_key) => Address::V2Taproot(output_key.serialize()),
+                    Err(_) => Address::burn(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TaprootError {
+    #[error("secp256k1 error")]
+    Secp256k1(#[from] bitcoin::secp256k1::Error),
+    #[error("taproot tweak is not a valid scalar")]
+    InvalidTweak,
+    #[error("policy is not satisfied")]
+    Policy(#[from] PolicyError),
+}
@@ -0,0 +1,222 @@
+use crate::authorization::KeyAuthorization;
+use crate::coin_selection::*;
+use crate::encoding::{encode_canonical, EncodeError};
+use crate::mempool::FeeRate;
+use crate::types::*;
+use serde::Serialize;
+
+/// Encoded size of the largest outpoint, a deposit outpoint.
+const MAX_OUTPOINT_SIZE: u64 = 48;
+
+/// Transaction waiting for the authorizations of its inputs.
+#[derive(Debug, Clone)]
+pub struct UnsignedTransaction<C> {
+    pub transaction: Transaction<C>,
+    /// Outputs spent by the inputs, in input order.
+    pub spent: Vec<Output<C>>,
+    pub fee: u64,
+    /// Position of the change output.
+    pub change_vout: Option<u32>,
+}
+
+/// Builds transactions paying its outputs from a set of candidate coins.
+///
+/// The fee is the estimated encoded size of the authorized transaction
+/// times the fee rate. Change goes back to the change address unless it
+/// would be dust, then it is left to the fee.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionBuilder<C> {
+    outputs: Vec<Output<C>>,
+    fee_rate: FeeRate,
+    long_term_fee_rate: FeeRate,
+    authorization_size: u64,
+    dust_limit: u64,
+    algorithm: Option<CoinSelectionAlgorithm>,
+    change_address: Option<Address>,
+}
+
+impl<C> TransactionBuilder<C> {
+    /// Defaults to `KeyAuthorization` sized authorizations, a dust limit of
+    /// 546 and the selection with the least waste.
+    pub fn new(fee_rate: FeeRate) -> Self {
+        Self {
+            outputs: Vec::new(),
+            fee_rate,
+            long_term_fee_rate: fee_rate,
+            authorization_size: KeyAuthorization::MAX_ENCODED_SIZE,
+            dust_limit: 546,
+            algorithm: None,
+            change_address: None,
+        }
+    }
+
+    pub fn add_output(mut self, output: Output<C>) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    pub fn pay(self, address: Address, value: u64) -> Self {
+        self.add_output(Output {
+            address,
+            content: Content::Value(value),
+        })
+    }
+
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    /// Rate spending inputs is expected to cost eventually, see
+    /// `SelectionParams::long_term_fee_rate`.
+    pub fn long_term_fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.long_term_fee_rate = fee_rate;
+        self
+    }
+
+    /// Largest encoded size of one authorization.
+    pub fn authorization_size(mut self, size: u64) -> Self {
+        self.authorization_size = size;
+        self
+    }
+
+    /// Smallest change output worth creating.
+    pub fn dust_limit(mut self, dust_limit: u64) -> Self {
+        self.dust_limit = dust_limit;
+        self
+    }
+
+    /// `None` picks the selection with the least waste.
+    pub fn algorithm(mut self, algorithm: Option<CoinSelectionAlgorithm>) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn change_address(mut self, address: Address) -> Self {
+        self.change_address = Some(address);
+        self
+    }
+
+    pub fn outputs(&self) -> &[Output<C>] {
+        &self.outputs
+    }
+
+    /// Fee each input adds.
+    pub fn input_fee(&self) -> u64 {
+        self.fee_rate
+            .fee_for(MAX_OUTPOINT_SIZE + self.authorization_size)
+    }
+}
+
+impl<C: Clone + GetValue + Serialize> TransactionBuilder<C> {
+    /// Selection parameters and the encoded size of a change output.
+    fn params(&self) -> Result<(SelectionParams, u64), EncodeError> {
+        let base_size = encoded_size(&AuthorizedTransaction::<(), C> {
+            transaction: Transaction {
+                inputs: Vec::new(),
+                outputs: self.outputs.clone(),
+            },
+            authorizations: Vec::new(),
+        })?;
+        let input_size = MAX_OUTPOINT_SIZE + self.authorization_size;
+        let change_size = encoded_size(&Output::<C> {
+            address: Address::burn(),
+            content: Content::Value(0),
+        })?;
+        let params = SelectionParams {
+            fee_rate: self.fee_rate,
+            long_term_fee_rate: self.long_term_fee_rate,
+            base_size,
+            input_size,
+            change_cost: self.fee_rate.fee_for(change_size)
+                + self.long_term_fee_rate.fee_for(input_size),
+        };
+        Ok((params, change_size))
+    }
+
+    pub fn build(
+        &self,
+        candidates: &[(OutPoint, Output<C>)],
+    ) -> Result<UnsignedTransaction<C>, TransactionBuilderError> {
+        let target = self.outputs.iter().map(GetValue::get_value).sum();
+        let (params, change_size) = self.params()?;
+        let selection = match self.algorithm {
+            Some(algorithm) => select_coins(algorithm, candidates, target, &params)?,
+            None => select_coins_min_waste(candidates, target, &params)?,
+        };
+        let mut fee = selection.fee;
+        let mut outputs = self.outputs.clone();
+        let mut change_vout = None;
+        let change = selection
+            .excess
+            .saturating_sub(self.fee_rate.fee_for(change_size));
+        if selection.needs_change(&params) && change >= self.dust_limit {
+            let address = self
+                .change_address
+                .ok_or(TransactionBuilderError::NoChangeAddress)?;
+            change_vout = Some(outputs.len() as u32);
+            outputs.push(Output {
+                address,
+                content: Content::Value(change),
+            });
+            fee += selection.excess - change;
+        } else {
+            fee += selection.excess;
+        }
+        let (inputs, spent) = selection.inputs.into_iter().unzip();
+        Ok(UnsignedTransaction {
+            transaction: Transaction { inputs, outputs },
+            spent,
+            fee,
+            change_vout,
+        })
+    }
+
+    /// Spends every candidate worth more than its fee, paying the outputs
+    /// and sending everything left to `address` in a last output.
+    pub fn build_sweep(
+        &self,
+        candidates: &[(OutPoint, Output<C>)],
+        address: Address,
+    ) -> Result<UnsignedTransaction<C>, TransactionBuilderError> {
+        let target: u64 = self.outputs.iter().map(GetValue::get_value).sum();
+        let (params, change_size) = self.params()?;
+        let (inputs, spent): (Vec<_>, Vec<_>) = candidates
+            .iter()
+            .filter(|(_, output)| params.effective_value(output.get_value()).is_some())
+            .cloned()
+            .unzip();
+        let available: u64 = spent.iter().map(GetValue::get_value).sum();
+        let size = params.base_size + change_size + params.input_size * inputs.len() as u64;
+        let fee = self.fee_rate.fee_for(size);
+        let needed = target + fee + self.dust_limit;
+        if available < needed {
+            return Err(CoinSelectionError::InsufficientFunds { needed, available }.into());
+        }
+        let mut outputs = self.outputs.clone();
+        outputs.push(Output {
+            address,
+            content: Content::Value(available - target - fee),
+        });
+        Ok(UnsignedTransaction {
+            transaction: Transaction { inputs, outputs },
+            spent,
+            fee,
+            change_vout: None,
+        })
+    }
+}
+
+fn encoded_size<T: Serialize>(value: &T) -> Result<u64, EncodeError> {
+    Ok(encode_canonical(value)?.len() as u64)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionBuilderError {
+    #[error("coin selection failed")]
+    CoinSelection(#[from] CoinSelectionError),
+    #[error("change is needed but there is no change address")]
+    NoChangeAddress,
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
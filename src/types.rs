@@ -1,9 +1,14 @@
 pub use crate::address::*;
+use crate::encoding::{encode_canonical_into, EncodeError};
 pub use crate::hashes::*;
+use bitcoin::hashes::Hash as _;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
-#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Ordered by kind, then by fields, so regular outpoints of a transaction
+/// are contiguous, see `OutPoint::txid_range`.
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum OutPoint {
     // Created by transactions.
     Regular { txid: Txid, vout: u32 },
@@ -13,6 +18,16 @@ pub enum OutPoint {
     Deposit(bitcoin::OutPoint),
 }
 
+impl OutPoint {
+    /// Range of all regular outpoints of a transaction.
+    pub fn txid_range(txid: Txid) -> RangeInclusive<OutPoint> {
+        Self::Regular { txid, vout: 0 }..=Self::Regular {
+            txid,
+            vout: u32::MAX,
+        }
+    }
+}
+
 impl std::fmt::Display for OutPoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -40,7 +55,22 @@ pub enum Content<C> {
     },
 }
 
+/// Variant of a `Content`, without its fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentKind {
+    Custom,
+    Value,
+    Withdrawal,
+}
+
 impl<C> Content<C> {
+    pub fn kind(&self) -> ContentKind {
+        match self {
+            Self::Custom(_) => ContentKind::Custom,
+            Self::Value(_) => ContentKind::Value,
+            Self::Withdrawal { .. } => ContentKind::Withdrawal,
+        }
+    }
     pub fn is_custom(&self) -> bool {
         matches!(self, Self::Custom(_))
     }
@@ -52,6 +82,33 @@ impl<C> Content<C> {
     }
 }
 
+/// Fields of an output with withdrawal content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalOutput {
+    pub address: Address,
+    pub value: u64,
+    pub main_fee: u64,
+    pub main_address: bitcoin::Address,
+}
+
+impl<C> Output<C> {
+    pub fn to_withdrawal(&self) -> Option<WithdrawalOutput> {
+        match &self.content {
+            Content::Withdrawal {
+                value,
+                main_fee,
+                main_address,
+            } => Some(WithdrawalOutput {
+                address: self.address,
+                value: *value,
+                main_fee: *main_fee,
+                main_address: main_address.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
 impl<C> GetAddress for Output<C> {
     #[inline(always)]
     fn get_address(&self) -> Address {
@@ -87,6 +144,19 @@ impl<C: Serialize> Transaction<C> {
     pub fn txid(&self) -> Txid {
         hash(self).into()
     }
+
+    pub fn try_txid(&self) -> Result<Txid, EncodeError> {
+        Ok(try_hash(self)?.into())
+    }
+
+    /// Txid computed with `H` instead of the default hash function.
+    pub fn txid_with<H: HashFunction>(&self) -> Txid {
+        hash_with::<H, _>(self).into()
+    }
+
+    pub fn try_txid_with<H: HashFunction>(&self) -> Result<Txid, EncodeError> {
+        Ok(try_hash_with::<H, _>(self)?.into())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -102,6 +172,75 @@ pub struct AuthorizedTransaction<A, C> {
     pub authorizations: Vec<A>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub merkle_root: MerkleRoot,
+    pub prev_side_hash: BlockHash,
+    /// Mainchain block the block is merge mined on top of, see `verify_bmm`.
+    pub prev_main_hash: bitcoin::BlockHash,
+}
+
+impl Header {
+    /// Header of the genesis body, it is the only header without a parent.
+    pub fn genesis<A, C: Clone + GetValue + Serialize>(body: &Body<A, C>) -> Self {
+        Self {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: BlockHash::ZERO,
+            prev_main_hash: bitcoin::BlockHash::all_zeros(),
+        }
+    }
+
+    pub fn try_genesis<A, C: Clone + GetValue + Serialize>(
+        body: &Body<A, C>,
+    ) -> Result<Self, EncodeError> {
+        Self::try_genesis_with::<DefaultHashFunction, _, _>(body)
+    }
+
+    pub fn try_genesis_with<H: HashFunction, A, C: Clone + GetValue + Serialize>(
+        body: &Body<A, C>,
+    ) -> Result<Self, EncodeError> {
+        Ok(Self {
+            merkle_root: body.try_compute_merkle_root_with::<H>()?,
+            prev_side_hash: BlockHash::ZERO,
+            prev_main_hash: bitcoin::BlockHash::all_zeros(),
+        })
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        hash(self).into()
+    }
+
+    pub fn hash_with<H: HashFunction>(&self) -> BlockHash {
+        hash_with::<H, _>(self).into()
+    }
+}
+
+/// Two-way peg changes caused by one mainchain block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MainBlockEvents<C> {
+    /// Deposits to the sidechain, they become spendable outputs.
+    pub deposits: Vec<(bitcoin::OutPoint, Output<C>)>,
+    /// Proposed bundles included in the mainchain, their withdrawals are
+    /// locked.
+    pub withdrawal_bundle_created: Vec<bitcoin::Txid>,
+    /// Bundles that failed to collect enough acks, their withdrawals are
+    /// refunded.
+    pub withdrawal_bundle_failed: Vec<bitcoin::Txid>,
+    /// Bundles paid out on the mainchain.
+    pub withdrawal_bundle_succeeded: Vec<bitcoin::Txid>,
+}
+
+impl<C> Default for MainBlockEvents<C> {
+    fn default() -> Self {
+        Self {
+            deposits: Vec::new(),
+            withdrawal_bundle_created: Vec::new(),
+            withdrawal_bundle_failed: Vec::new(),
+            withdrawal_bundle_succeeded: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Body<A, C> {
     pub coinbase: Vec<Output<C>>,
@@ -132,11 +271,28 @@ impl<A, C: Clone + GetValue + Serialize> Body<A, C> {
         }
     }
 
+    /// Genesis body, allocating `outputs` as its coinbase.
+    pub fn genesis(outputs: Vec<Output<C>>) -> Self {
+        Self::new(Vec::new(), outputs)
+    }
+
     pub fn compute_merkle_root(&self) -> MerkleRoot {
         // FIXME: Compute actual merkle root instead of just a hash.
         hash(&(&self.coinbase, &self.transactions)).into()
     }
 
+    pub fn compute_merkle_root_with<H: HashFunction>(&self) -> MerkleRoot {
+        hash_with::<H, _>(&(&self.coinbase, &self.transactions)).into()
+    }
+
+    pub fn try_compute_merkle_root(&self) -> Result<MerkleRoot, EncodeError> {
+        self.try_compute_merkle_root_with::<DefaultHashFunction>()
+    }
+
+    pub fn try_compute_merkle_root_with<H: HashFunction>(&self) -> Result<MerkleRoot, EncodeError> {
+        Ok(try_hash_with::<H, _>(&(&self.coinbase, &self.transactions))?.into())
+    }
+
     pub fn get_inputs(&self) -> Vec<OutPoint> {
         self.transactions
             .iter()
@@ -145,34 +301,136 @@ impl<A, C: Clone + GetValue + Serialize> Body<A, C> {
             .collect()
     }
 
+    /// Panics if the body fails to serialize, see `try_get_outputs`.
     pub fn get_outputs(&self) -> HashMap<OutPoint, Output<C>> {
+        self.try_get_outputs()
+            .expect("failed to serialize a type to compute a hash")
+    }
+
+    /// Outputs created by the body, burn outputs are left out since they
+    /// can't be spent.
+    pub fn try_get_outputs(&self) -> Result<HashMap<OutPoint, Output<C>>, EncodeError> {
+        self.try_get_outputs_with::<DefaultHashFunction>()
+    }
+
+    /// Outpoints are computed with `H` instead of the default hash function.
+    pub fn try_get_outputs_with<H: HashFunction>(
+        &self,
+    ) -> Result<HashMap<OutPoint, Output<C>>, EncodeError> {
         let mut outputs = HashMap::new();
-        let merkle_root = self.compute_merkle_root();
+        let merkle_root = self.try_compute_merkle_root_with::<H>()?;
         for (vout, output) in self.coinbase.iter().enumerate() {
             let vout = vout as u32;
+            if output.address.is_burn() {
+                continue;
+            }
             let outpoint = OutPoint::Coinbase { merkle_root, vout };
             outputs.insert(outpoint, output.clone());
         }
         for transaction in &self.transactions {
-            let txid = transaction.txid();
+            let txid = transaction.try_txid_with::<H>()?;
             for (vout, output) in transaction.outputs.iter().enumerate() {
                 let vout = vout as u32;
+                if output.address.is_burn() {
+                    continue;
+                }
                 let outpoint = OutPoint::Regular { txid, vout };
                 outputs.insert(outpoint, output.clone());
             }
         }
-        outputs
+        Ok(outputs)
     }
 
-    pub fn get_coinbase_value(&self) -> u64 {
-        self.coinbase.iter().map(|output| output.get_value()).sum()
+    /// Value sent to `Address::burn` by the transactions of the body, `None`
+    /// if it overflows.
+    pub fn get_burned_value(&self) -> Option<u64> {
+        self.transactions
+            .iter()
+            .flat_map(|transaction| transaction.outputs.iter())
+            .filter(|output| output.address.is_burn())
+            .try_fold(0u64, |sum, output| sum.checked_add(output.get_value()))
+    }
+
+    /// Value of the coinbase outputs, `None` if it overflows.
+    pub fn get_coinbase_value(&self) -> Option<u64> {
+        self.coinbase
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.get_value()))
     }
 }
 
+/// Computes the merkle root of a body one transaction at a time, without
+/// holding the whole body or its encoding in memory.
+///
+/// The result equals `Body::compute_merkle_root_with::<H>` of the same body.
+pub struct BodyHasher<H: HashFunction = DefaultHashFunction> {
+    engine: H::Engine,
+    expected: usize,
+    pushed: usize,
+}
+
+impl<H: HashFunction> BodyHasher<H> {
+    /// The transaction count is part of the hashed encoding, so it must be
+    /// known upfront.
+    pub fn new<C: Serialize>(
+        coinbase: &[Output<C>],
+        transaction_count: usize,
+    ) -> Result<Self, EncodeError> {
+        let mut engine = H::Engine::default();
+        encode_canonical_into(&mut engine, coinbase)?;
+        encode_canonical_into(&mut engine, &(transaction_count as u64))?;
+        Ok(Self {
+            engine,
+            expected: transaction_count,
+            pushed: 0,
+        })
+    }
+
+    pub fn push_transaction<C: Serialize>(
+        &mut self,
+        transaction: &Transaction<C>,
+    ) -> Result<(), EncodeError> {
+        encode_canonical_into(&mut self.engine, transaction)?;
+        self.pushed += 1;
+        Ok(())
+    }
+
+    /// Fails unless exactly `transaction_count` transactions were pushed.
+    pub fn finish(self) -> Result<MerkleRoot, TransactionCountMismatch> {
+        if self.pushed != self.expected {
+            return Err(TransactionCountMismatch {
+                expected: self.expected,
+                pushed: self.pushed,
+            });
+        }
+        Ok(H::finalize(self.engine).into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("expected {expected} transactions, {pushed} were pushed")]
+pub struct TransactionCountMismatch {
+    pub expected: usize,
+    pub pushed: usize,
+}
+
 pub trait GetAddress {
     fn get_address(&self) -> Address;
 }
 
+/// Authorizations sign txids computed with the chain's `HashFunction` `H`.
+pub trait Verify<C> {
+    type Error;
+    fn verify_transaction<H: HashFunction>(
+        transaction: &AuthorizedTransaction<Self, C>,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized;
+    fn verify_body<H: HashFunction>(body: &Body<Self, C>) -> Result<(), Self::Error>
+    where
+        Self: Sized;
+}
+
 pub trait GetValue {
     fn get_value(&self) -> u64;
 }
@@ -0,0 +1,827 @@
+use crate::encoding::EncodeError;
+use crate::params::ChainParams;
+use crate::types::*;
+use crate::validator;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::ops::{Bound, ControlFlow, RangeBounds};
+
+/// Read access to a set of unspent outputs.
+pub trait UtxoMap {
+    type OutPoint;
+    type Output;
+
+    fn get_utxo(&self, outpoint: &Self::OutPoint) -> Option<Self::Output>;
+
+    /// Hints that the outpoints are about to be looked up, so backends with
+    /// slow point lookups can fetch them all at once. Does nothing by default.
+    fn prefetch(&self, _outpoints: &[Self::OutPoint]) {}
+
+    /// Returns `None` if any of the outpoints is missing.
+    fn get_utxos(&self, outpoints: &[Self::OutPoint]) -> Option<Vec<Self::Output>> {
+        outpoints
+            .iter()
+            .map(|outpoint| self.get_utxo(outpoint))
+            .collect()
+    }
+
+    /// Returns true if outpoint is not in the map, either because it was spent
+    /// or because it was never created.
+    fn is_spent(&self, outpoint: &Self::OutPoint) -> bool {
+        self.get_utxo(outpoint).is_none()
+    }
+
+    fn any_spent(&self, outpoints: &[Self::OutPoint]) -> bool {
+        outpoints.iter().any(|outpoint| self.is_spent(outpoint))
+    }
+
+    /// Returns false if the output can't be spent in the next block yet,
+    /// like a coinbase output below the coinbase maturity. Maps that don't
+    /// know the chain height treat every output as mature.
+    fn is_mature(&self, _outpoint: &Self::OutPoint) -> bool {
+        true
+    }
+}
+
+/// Borrowed access for maps that keep outputs in memory, avoids cloning
+/// outputs that are only read.
+pub trait UtxoMapRef: UtxoMap {
+    fn get_utxo_ref(&self, outpoint: &Self::OutPoint) -> Option<&Self::Output>;
+
+    /// Returns `None` if any of the outpoints is missing.
+    fn get_utxo_refs(&self, outpoints: &[Self::OutPoint]) -> Option<Vec<&Self::Output>> {
+        outpoints
+            .iter()
+            .map(|outpoint| self.get_utxo_ref(outpoint))
+            .collect()
+    }
+}
+
+/// Visitor called by `UtxoScan::scan` for every unspent output.
+pub type UtxoVisitor<'a, P, O> = dyn FnMut(&P, &O) -> ControlFlow<()> + 'a;
+
+/// Outputs returned by `UtxoScan` methods.
+pub type ScanResult<M> =
+    Result<Vec<(<M as UtxoMap>::OutPoint, <M as UtxoMap>::Output)>, <M as UtxoScan>::ScanError>;
+
+/// Iteration over all unspent outputs of a map.
+pub trait UtxoScan: UtxoMap {
+    type ScanError;
+
+    /// Calls `f` for every unspent output until it breaks. Outputs are visited
+    /// in outpoint order for sorted maps and in no particular order otherwise.
+    fn scan(
+        &self,
+        f: &mut UtxoVisitor<'_, Self::OutPoint, Self::Output>,
+    ) -> Result<(), Self::ScanError>;
+
+    /// Unspent outputs for which `filter` returns true.
+    fn utxos_where(
+        &self,
+        mut filter: impl FnMut(&Self::OutPoint, &Self::Output) -> bool,
+    ) -> ScanResult<Self>
+    where
+        Self::OutPoint: Clone,
+        Self::Output: Clone,
+    {
+        let mut utxos = Vec::new();
+        self.scan(&mut |outpoint, output| {
+            if filter(outpoint, output) {
+                utxos.push((outpoint.clone(), output.clone()));
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(utxos)
+    }
+
+    fn utxos(&self) -> ScanResult<Self>
+    where
+        Self::OutPoint: Clone,
+        Self::Output: Clone,
+    {
+        self.utxos_where(|_, _| true)
+    }
+
+    fn utxos_by_address(&self, address: &Address) -> ScanResult<Self>
+    where
+        Self::OutPoint: Clone,
+        Self::Output: Clone + GetAddress,
+    {
+        self.utxos_where(|_, output| output.get_address() == *address)
+    }
+
+    fn utxos_by_kind<C>(&self, kind: ContentKind) -> ScanResult<Self>
+    where
+        Self: UtxoMap<Output = Output<C>>,
+        Self::OutPoint: Clone,
+        C: Clone,
+    {
+        self.utxos_where(|_, output| output.content.kind() == kind)
+    }
+
+    fn utxo_count(&self) -> Result<usize, Self::ScanError> {
+        let mut count = 0;
+        self.scan(&mut |_, _| {
+            count += 1;
+            ControlFlow::Continue(())
+        })?;
+        Ok(count)
+    }
+
+    /// Sum of the values of all unspent outputs.
+    fn total_value(&self) -> Result<u128, Self::ScanError>
+    where
+        Self::Output: GetValue,
+    {
+        let mut total: u128 = 0;
+        self.scan(&mut |_, output| {
+            total += u128::from(output.get_value());
+            ControlFlow::Continue(())
+        })?;
+        Ok(total)
+    }
+}
+
+fn scan_entries<'a, P: 'a, O: 'a>(
+    entries: impl IntoIterator<Item = (&'a P, &'a O)>,
+    f: &mut UtxoVisitor<'_, P, O>,
+) -> Result<(), Infallible> {
+    for (outpoint, output) in entries {
+        if f(outpoint, output).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Outputs spent and created at once, e.g. by a body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoBatch<P, O> {
+    /// Spent outputs are kept so that the batch can be inverted.
+    pub spent: Vec<(P, O)>,
+    pub created: Vec<(P, O)>,
+}
+
+impl<P, O> UtxoBatch<P, O> {
+    /// Batch that undoes this one.
+    pub fn inverse(self) -> Self {
+        Self {
+            spent: self.created,
+            created: self.spent,
+        }
+    }
+}
+
+impl<P, O> Default for UtxoBatch<P, O> {
+    fn default() -> Self {
+        Self {
+            spent: Vec::new(),
+            created: Vec::new(),
+        }
+    }
+}
+
+/// Write access to a set of unspent outputs.
+///
+/// Maps implementing `UtxoWrite` can connect and disconnect bodies with
+/// `connect_body` and `disconnect_body`.
+pub trait UtxoWrite: UtxoMap {
+    type Error;
+
+    fn insert(&mut self, outpoint: Self::OutPoint, output: Self::Output)
+        -> Result<(), Self::Error>;
+
+    fn remove(&mut self, outpoint: &Self::OutPoint) -> Result<(), Self::Error>;
+
+    /// Removes spent outputs, then inserts created ones.
+    fn apply_batch(
+        &mut self,
+        batch: UtxoBatch<Self::OutPoint, Self::Output>,
+    ) -> Result<(), Self::Error> {
+        for (outpoint, _) in &batch.spent {
+            self.remove(outpoint)?;
+        }
+        for (outpoint, output) in batch.created {
+            self.insert(outpoint, output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validates the body against `utxos` and applies it, returns the batch to
+/// pass to `disconnect_body`.
+///
+/// NOTE: It does not verify authorizations, see `validator::validate_body`.
+pub fn connect_body<A, C, M>(
+    utxos: &mut M,
+    body: &Body<A, C>,
+    params: &ChainParams,
+) -> Result<UtxoBatch<OutPoint, Output<C>>, UtxoError<M::Error>>
+where
+    A: GetAddress,
+    C: Clone + GetValue + Serialize,
+    M: UtxoWrite<OutPoint = OutPoint, Output = Output<C>>,
+{
+    connect_body_with::<DefaultHashFunction, A, C, M>(utxos, body, params)
+}
+
+/// `connect_body` for chains hashing with `H`.
+pub fn connect_body_with<H, A, C, M>(
+    utxos: &mut M,
+    body: &Body<A, C>,
+    params: &ChainParams,
+) -> Result<UtxoBatch<OutPoint, Output<C>>, UtxoError<M::Error>>
+where
+    H: HashFunction,
+    A: GetAddress,
+    C: Clone + GetValue + Serialize,
+    M: UtxoWrite<OutPoint = OutPoint, Output = Output<C>>,
+{
+    let inputs = body.get_inputs();
+    utxos.prefetch(&inputs);
+    let mut spent = Vec::with_capacity(inputs.len());
+    for outpoint in inputs {
+        let output = utxos
+            .get_utxo(&outpoint)
+            .ok_or(validator::Error::UtxoDoesNotExist { outpoint })?;
+        spent.push((outpoint, output));
+    }
+    let spent_utxos: Vec<_> = spent.iter().map(|(_, output)| output).collect();
+    validator::validate_body(&spent_utxos, body, params)?;
+    let batch = UtxoBatch {
+        spent,
+        created: body.try_get_outputs_with::<H>()?.into_iter().collect(),
+    };
+    utxos.apply_batch(batch.clone()).map_err(UtxoError::Write)?;
+    Ok(batch)
+}
+
+/// Reverts a batch returned by `connect_body`, bodies must be disconnected
+/// in reverse order.
+pub fn disconnect_body<M: UtxoWrite>(
+    utxos: &mut M,
+    batch: UtxoBatch<M::OutPoint, M::Output>,
+) -> Result<(), M::Error> {
+    utxos.apply_batch(batch.inverse())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UtxoError<E> {
+    #[error("validation error")]
+    Validation(#[from] validator::Error),
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+    #[error("utxo write error")]
+    Write(#[source] E),
+}
+
+impl<P: Eq + std::hash::Hash, O: Clone> UtxoMap for HashMap<P, O> {
+    type OutPoint = P;
+    type Output = O;
+
+    fn get_utxo(&self, outpoint: &P) -> Option<O> {
+        self.get(outpoint).cloned()
+    }
+
+    fn is_spent(&self, outpoint: &P) -> bool {
+        !self.contains_key(outpoint)
+    }
+}
+
+impl<P: Eq + std::hash::Hash, O: Clone> UtxoWrite for HashMap<P, O> {
+    type Error = Infallible;
+
+    fn insert(&mut self, outpoint: P, output: O) -> Result<(), Infallible> {
+        HashMap::insert(self, outpoint, output);
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &P) -> Result<(), Infallible> {
+        HashMap::remove(self, outpoint);
+        Ok(())
+    }
+}
+
+impl<P: Eq + std::hash::Hash, O: Clone> UtxoMapRef for HashMap<P, O> {
+    fn get_utxo_ref(&self, outpoint: &P) -> Option<&O> {
+        self.get(outpoint)
+    }
+}
+
+impl<P: Eq + std::hash::Hash, O: Clone> UtxoScan for HashMap<P, O> {
+    type ScanError = Infallible;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, P, O>) -> Result<(), Infallible> {
+        scan_entries(self, f)
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoMap for BTreeMap<P, O> {
+    type OutPoint = P;
+    type Output = O;
+
+    fn get_utxo(&self, outpoint: &P) -> Option<O> {
+        self.get(outpoint).cloned()
+    }
+
+    fn is_spent(&self, outpoint: &P) -> bool {
+        !self.contains_key(outpoint)
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoWrite for BTreeMap<P, O> {
+    type Error = Infallible;
+
+    fn insert(&mut self, outpoint: P, output: O) -> Result<(), Infallible> {
+        BTreeMap::insert(self, outpoint, output);
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &P) -> Result<(), Infallible> {
+        BTreeMap::remove(self, outpoint);
+        Ok(())
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoMapRef for BTreeMap<P, O> {
+    fn get_utxo_ref(&self, outpoint: &P) -> Option<&O> {
+        self.get(outpoint)
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoScan for BTreeMap<P, O> {
+    type ScanError = Infallible;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, P, O>) -> Result<(), Infallible> {
+        scan_entries(self, f)
+    }
+}
+
+/// Outputs in a vector sorted by outpoint.
+///
+/// Lookups are binary searches and writes shift the vector, so it suits
+/// sets that are mostly read. Like `BTreeMap` it supports range scans, e.g.
+/// `range(OutPoint::txid_range(txid))` for all outputs of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortedUtxoVec<P, O> {
+    entries: Vec<(P, O)>,
+}
+
+impl<P, O> Default for SortedUtxoVec<P, O> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<P: Ord, O> SortedUtxoVec<P, O> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries in outpoint order.
+    pub fn entries(&self) -> &[(P, O)] {
+        &self.entries
+    }
+
+    /// Entries with outpoints in `range`, in outpoint order.
+    pub fn range(&self, range: impl RangeBounds<P>) -> &[(P, O)] {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.entries.partition_point(|(key, _)| key < start),
+            Bound::Excluded(start) => self.entries.partition_point(|(key, _)| key <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.entries.partition_point(|(key, _)| key <= end),
+            Bound::Excluded(end) => self.entries.partition_point(|(key, _)| key < end),
+            Bound::Unbounded => self.entries.len(),
+        };
+        self.entries.get(start..end).unwrap_or(&[])
+    }
+
+    fn position(&self, outpoint: &P) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(key, _)| key.cmp(outpoint))
+    }
+}
+
+impl<P: Ord, O> FromIterator<(P, O)> for SortedUtxoVec<P, O> {
+    /// Later entries replace earlier ones with the same outpoint.
+    fn from_iter<I: IntoIterator<Item = (P, O)>>(iter: I) -> Self {
+        let mut entries: Vec<_> = iter.into_iter().collect();
+        // Stable, so the last duplicate ends up last.
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut deduped: Vec<(P, O)> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match deduped.last_mut() {
+                Some(last) if last.0 == entry.0 => *last = entry,
+                _ => deduped.push(entry),
+            }
+        }
+        Self { entries: deduped }
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoMap for SortedUtxoVec<P, O> {
+    type OutPoint = P;
+    type Output = O;
+
+    fn get_utxo(&self, outpoint: &P) -> Option<O> {
+        self.get_utxo_ref(outpoint).cloned()
+    }
+
+    fn is_spent(&self, outpoint: &P) -> bool {
+        self.position(outpoint).is_err()
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoWrite for SortedUtxoVec<P, O> {
+    type Error = Infallible;
+
+    fn insert(&mut self, outpoint: P, output: O) -> Result<(), Infallible> {
+        match self.position(&outpoint) {
+            Ok(index) => self.entries[index].1 = output,
+            Err(index) => self.entries.insert(index, (outpoint, output)),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &P) -> Result<(), Infallible> {
+        if let Ok(index) = self.position(outpoint) {
+            self.entries.remove(index);
+        }
+        Ok(())
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoMapRef for SortedUtxoVec<P, O> {
+    fn get_utxo_ref(&self, outpoint: &P) -> Option<&O> {
+        let index = self.position(outpoint).ok()?;
+        Some(&self.entries[index].1)
+    }
+}
+
+impl<P: Ord, O: Clone> UtxoScan for SortedUtxoVec<P, O> {
+    type ScanError = Infallible;
+
+    fn scan(&self, f: &mut UtxoVisitor<'_, P, O>) -> Result<(), Infallible> {
+        scan_entries(self.entries.iter().map(|(p, o)| (p, o)), f)
+    }
+}
+
+impl<M: UtxoMap> UtxoMap for &M {
+    type OutPoint = M::OutPoint;
+    type Output = M::Output;
+
+    fn get_utxo(&self, outpoint: &Self::OutPoint) -> Option<Self::Output> {
+        (**self).get_utxo(outpoint)
+    }
+
+    fn prefetch(&self, outpoints: &[Self::OutPoint]) {
+        (**self).prefetch(outpoints)
+    }
+
+    fn is_spent(&self, outpoint: &Self::OutPoint) -> bool {
+        (**self).is_spent(outpoint)
+    }
+
+    fn is_mature(&self, outpoint: &Self::OutPoint) -> bool {
+        (**self).is_mature(outpoint)
+    }
+}
+
+impl<M: UtxoMapRef> UtxoMapRef for &M {
+    fn get_utxo_ref(&self, outpoint: &Self::OutPoint) -> Option<&Self::Output> {
+        (**self).get_utxo_ref(outpoint)
+    }
+}
+
+impl<M: UtxoScan> UtxoScan for &M {
+    type ScanError = M::ScanError;
+
+    fn scan(
+        &self,
+        f: &mut UtxoVisitor<'_, Self::OutPoint, Self::Output>,
+    ) -> Result<(), M::ScanError> {
+        (**self).scan(f)
+    }
+}
+
+/// In-memory additions and removals on top of a base state.
+///
+/// Used to validate chains of unconfirmed transactions without mutating the
+/// base state.
+pub struct Overlay<'a, S: UtxoMap> {
+    base: &'a S,
+    added: HashMap<S::OutPoint, S::Output>,
+    removed: HashSet<S::OutPoint>,
+}
+
+impl<'a, S> Overlay<'a, S>
+where
+    S: UtxoMap,
+    S::OutPoint: Eq + std::hash::Hash,
+{
+    pub fn new(base: &'a S) -> Self {
+        Self {
+            base,
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    pub fn base(&self) -> &'a S {
+        self.base
+    }
+
+    pub fn insert(&mut self, outpoint: S::OutPoint, output: S::Output) {
+        self.removed.remove(&outpoint);
+        self.added.insert(outpoint, output);
+    }
+
+    pub fn remove(&mut self, outpoint: S::OutPoint) {
+        self.added.remove(&outpoint);
+        self.removed.insert(outpoint);
+    }
+
+    pub fn clear(&mut self) {
+        self.added.clear();
+        self.removed.clear();
+    }
+}
+
+impl<'a, S, C> Overlay<'a, S>
+where
+    S: UtxoMap<OutPoint = OutPoint, Output = Output<C>>,
+    C: Clone + Serialize,
+{
+    /// Spends transaction inputs and adds its outputs.
+    ///
+    /// NOTE: It does not validate the transaction.
+    pub fn connect_transaction(&mut self, transaction: &Transaction<C>) -> Result<(), EncodeError> {
+        self.connect_transaction_with::<DefaultHashFunction>(transaction)
+    }
+
+    /// `connect_transaction` for chains hashing with `H`.
+    pub fn connect_transaction_with<H: HashFunction>(
+        &mut self,
+        transaction: &Transaction<C>,
+    ) -> Result<(), EncodeError> {
+        let txid = transaction.try_txid_with::<H>()?;
+        for input in &transaction.inputs {
+            self.remove(*input);
+        }
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            let vout = vout as u32;
+            self.insert(OutPoint::Regular { txid, vout }, output.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S> UtxoMap for Overlay<'a, S>
+where
+    S: UtxoMap,
+    S::OutPoint: Eq + std::hash::Hash,
+    S::Output: Clone,
+{
+    type OutPoint = S::OutPoint;
+    type Output = S::Output;
+
+    fn get_utxo(&self, outpoint: &S::OutPoint) -> Option<S::Output> {
+        if self.removed.contains(outpoint) {
+            return None;
+        }
+        match self.added.get(outpoint) {
+            Some(output) => Some(output.clone()),
+            None => self.base.get_utxo(outpoint),
+        }
+    }
+
+    fn prefetch(&self, outpoints: &[S::OutPoint]) {
+        self.base.prefetch(outpoints)
+    }
+
+    fn is_spent(&self, outpoint: &S::OutPoint) -> bool {
+        if self.removed.contains(outpoint) {
+            return true;
+        }
+        !self.added.contains_key(outpoint) && self.base.is_spent(outpoint)
+    }
+
+    /// Added outputs are created by transactions, never by a coinbase.
+    fn is_mature(&self, outpoint: &S::OutPoint) -> bool {
+        self.added.contains_key(outpoint) || self.base.is_mature(outpoint)
+    }
+}
+
+impl<'a, S> UtxoMapRef for Overlay<'a, S>
+where
+    S: UtxoMapRef,
+    S::OutPoint: Eq + std::hash::Hash,
+    S::Output: Clone,
+{
+    fn get_utxo_ref(&self, outpoint: &S::OutPoint) -> Option<&S::Output> {
+        if self.removed.contains(outpoint) {
+            return None;
+        }
+        match self.added.get(outpoint) {
+            Some(output) => Some(output),
+            None => self.base.get_utxo_ref(outpoint),
+        }
+    }
+}
+
+impl<'a, S> UtxoScan for Overlay<'a, S>
+where
+    S: UtxoScan,
+    S::OutPoint: Eq + std::hash::Hash,
+    S::Output: Clone,
+{
+    type ScanError = S::ScanError;
+
+    /// Visits added outputs first, then the remaining base outputs.
+    fn scan(&self, f: &mut UtxoVisitor<'_, S::OutPoint, S::Output>) -> Result<(), S::ScanError> {
+        for (outpoint, output) in &self.added {
+            if f(outpoint, output).is_break() {
+                return Ok(());
+            }
+        }
+        self.base.scan(&mut |outpoint, output| {
+            if self.removed.contains(outpoint) || self.added.contains_key(outpoint) {
+                return ControlFlow::Continue(());
+            }
+            f(outpoint, output)
+        })
+    }
+}
+
+impl<'a, S> UtxoWrite for Overlay<'a, S>
+where
+    S: UtxoMap,
+    S::OutPoint: Eq + std::hash::Hash + Clone,
+    S::Output: Clone,
+{
+    type Error = Infallible;
+
+    fn insert(&mut self, outpoint: S::OutPoint, output: S::Output) -> Result<(), Infallible> {
+        Overlay::insert(self, outpoint, output);
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &S::OutPoint) -> Result<(), Infallible> {
+        Overlay::remove(self, outpoint.clone());
+        Ok(())
+    }
+}
+
+/// Changes in `delta` on top of `base`, with `None` entries in `delta`
+/// marking spent outputs.
+///
+/// Unlike `Overlay` both layers are generic, so deltas can be any map and
+/// layers can be stacked, e.g. a block template on top of a mempool view on
+/// top of the chain state. Writes only go to `delta`.
+#[derive(Debug, Clone, Default)]
+pub struct LayeredUtxoMap<Base, Delta> {
+    base: Base,
+    delta: Delta,
+}
+
+impl<Base, Delta> LayeredUtxoMap<Base, Delta> {
+    pub fn new(base: Base, delta: Delta) -> Self {
+        Self { base, delta }
+    }
+
+    pub fn base(&self) -> &Base {
+        &self.base
+    }
+
+    pub fn delta(&self) -> &Delta {
+        &self.delta
+    }
+
+    pub fn into_parts(self) -> (Base, Delta) {
+        (self.base, self.delta)
+    }
+}
+
+impl<Base, Delta: Default> LayeredUtxoMap<Base, Delta> {
+    /// Layer with an empty delta.
+    pub fn on(base: Base) -> Self {
+        Self::new(base, Delta::default())
+    }
+
+    /// Drops all changes, returning the previous delta.
+    pub fn take_delta(&mut self) -> Delta {
+        std::mem::take(&mut self.delta)
+    }
+}
+
+impl<Base, Delta, O> UtxoMap for LayeredUtxoMap<Base, Delta>
+where
+    Base: UtxoMap<Output = O>,
+    Delta: UtxoMap<OutPoint = Base::OutPoint, Output = Option<O>>,
+{
+    type OutPoint = Base::OutPoint;
+    type Output = O;
+
+    fn get_utxo(&self, outpoint: &Self::OutPoint) -> Option<O> {
+        match self.delta.get_utxo(outpoint) {
+            Some(output) => output,
+            None => self.base.get_utxo(outpoint),
+        }
+    }
+
+    fn prefetch(&self, outpoints: &[Self::OutPoint]) {
+        self.base.prefetch(outpoints)
+    }
+
+    fn is_spent(&self, outpoint: &Self::OutPoint) -> bool {
+        match self.delta.get_utxo(outpoint) {
+            Some(output) => output.is_none(),
+            None => self.base.is_spent(outpoint),
+        }
+    }
+
+    /// Outputs created in the delta are treated as mature, deltas hold
+    /// unconfirmed transactions.
+    fn is_mature(&self, outpoint: &Self::OutPoint) -> bool {
+        match self.delta.get_utxo(outpoint) {
+            Some(_) => true,
+            None => self.base.is_mature(outpoint),
+        }
+    }
+}
+
+impl<Base, Delta, O> UtxoMapRef for LayeredUtxoMap<Base, Delta>
+where
+    Base: UtxoMapRef<Output = O>,
+    Delta: UtxoMapRef<OutPoint = Base::OutPoint, Output = Option<O>>,
+{
+    fn get_utxo_ref(&self, outpoint: &Self::OutPoint) -> Option<&O> {
+        match self.delta.get_utxo_ref(outpoint) {
+            Some(output) => output.as_ref(),
+            None => self.base.get_utxo_ref(outpoint),
+        }
+    }
+}
+
+impl<Base, Delta, O> UtxoWrite for LayeredUtxoMap<Base, Delta>
+where
+    Base: UtxoMap<Output = O>,
+    Base::OutPoint: Clone,
+    Delta: UtxoWrite<OutPoint = Base::OutPoint, Output = Option<O>>,
+{
+    type Error = Delta::Error;
+
+    fn insert(&mut self, outpoint: Self::OutPoint, output: O) -> Result<(), Delta::Error> {
+        self.delta.insert(outpoint, Some(output))
+    }
+
+    fn remove(&mut self, outpoint: &Self::OutPoint) -> Result<(), Delta::Error> {
+        self.delta.insert(outpoint.clone(), None)
+    }
+}
+
+// Deltas are expected to be in memory, so scanning them can't fail.
+impl<Base, Delta, O> UtxoScan for LayeredUtxoMap<Base, Delta>
+where
+    Base: UtxoScan<Output = O>,
+    Delta: UtxoScan<OutPoint = Base::OutPoint, Output = Option<O>, ScanError = Infallible>,
+{
+    type ScanError = Base::ScanError;
+
+    /// Visits outputs created in the delta first, then the remaining base
+    /// outputs.
+    fn scan(&self, f: &mut UtxoVisitor<'_, Base::OutPoint, O>) -> Result<(), Base::ScanError> {
+        let mut done = false;
+        let Ok(()) = self.delta.scan(&mut |outpoint, output| match output {
+            Some(output) => {
+                let flow = f(outpoint, output);
+                done = flow.is_break();
+                flow
+            }
+            None => ControlFlow::Continue(()),
+        });
+        if done {
+            return Ok(());
+        }
+        self.base.scan(&mut |outpoint, output| {
+            // Missing from the delta, so neither spent nor replaced.
+            if self.delta.is_spent(outpoint) {
+                f(outpoint, output)
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+}
@@ -1,16 +1,21 @@
+use crate::params::{BurnedValue, ChainParams};
 use crate::types::*;
 use serde::Serialize;
+use std::borrow::Borrow;
 use std::collections::HashSet;
+use subtle::ConstantTimeEq as _;
 
 // Returns the fee paid by transaction if it is valid.
-pub fn validate_transaction<C: GetValue>(
-    spent_utxos: &[Output<C>],
+//
+// Spent utxos can be owned or borrowed, e.g. from `UtxoMapRef::get_utxo_refs`.
+pub fn validate_transaction<C: GetValue, O: Borrow<Output<C>>>(
+    spent_utxos: &[O],
     transaction: &Transaction<C>,
 ) -> Result<u64, Error> {
     // Accounting
     let (value_in, value_out) = {
-        let value_in: u64 = spent_utxos.iter().map(|i| i.get_value()).sum();
-        let value_out: u64 = transaction.outputs.iter().map(|o| o.get_value()).sum();
+        let value_in = checked_sum(spent_utxos.iter().map(|i| i.borrow().get_value()))?;
+        let value_out = checked_sum(transaction.outputs.iter().map(|o| o.get_value()))?;
         (value_in, value_out)
     };
     if value_in < value_out {
@@ -22,21 +27,76 @@ pub fn validate_transaction<C: GetValue>(
     Ok(value_in - value_out)
 }
 
-/// Returns total fee collected by body if it is valid.
+/// Returns the fee paid by transaction if it is valid, checking that
+/// authorization addresses match spent utxo addresses.
+///
+/// NOTE: It does not verify authorizations, see `validate_body`.
+pub fn validate_authorized_transaction<A: GetAddress, C: GetValue, O: Borrow<Output<C>>>(
+    spent_utxos: &[O],
+    transaction: &AuthorizedTransaction<A, C>,
+) -> Result<u64, Error> {
+    if transaction.authorizations.len() != spent_utxos.len() {
+        return Err(Error::WrongNumberOfAuthorizations {
+            authorizations: transaction.authorizations.len(),
+            inputs: spent_utxos.len(),
+        });
+    }
+    let inputs = &transaction.transaction.inputs;
+    for ((spent_utxo, authorization), input) in spent_utxos
+        .iter()
+        .zip(&transaction.authorizations)
+        .zip(inputs)
+    {
+        let authorization_address = authorization.get_address();
+        let utxo_address = spent_utxo.borrow().get_address();
+        if utxo_address.is_burn() {
+            return Err(Error::BurnOutputSpent { input: *input });
+        }
+        if !bool::from(authorization_address.ct_eq(&utxo_address)) {
+            return Err(Error::AddressesDontMatch {
+                authorization_address,
+                utxo_address,
+            });
+        }
+    }
+    validate_transaction(spent_utxos, &transaction.transaction)
+}
+
+/// Returns total fee collected by body if it is valid, including burned value
+/// if `params` say so.
 ///
 /// NOTE: It does not verify authorizations! It only checks if authorization
 /// address matches the spent utxo address.
-pub fn validate_body<A: GetAddress, C: GetValue + Clone + Serialize>(
-    spent_utxos: &[Output<C>],
+pub fn validate_body<A: GetAddress, C: GetValue + Clone + Serialize, O: Borrow<Output<C>>>(
+    spent_utxos: &[O],
     body: &Body<A, C>,
+    params: &ChainParams,
 ) -> Result<u64, Error> {
     let mut fees: u64 = 0;
 
+    if body.authorizations.len() != spent_utxos.len() {
+        return Err(Error::WrongNumberOfAuthorizations {
+            authorizations: body.authorizations.len(),
+            inputs: spent_utxos.len(),
+        });
+    }
+
     // Authorization public key matches spent utxo address
-    for (spent_utxo, authorization) in spent_utxos.iter().zip(body.authorizations.iter()) {
+    let inputs = body
+        .transactions
+        .iter()
+        .flat_map(|transaction| transaction.inputs.iter());
+    for ((spent_utxo, authorization), input) in spent_utxos
+        .iter()
+        .zip(body.authorizations.iter())
+        .zip(inputs)
+    {
         let authorization_address = authorization.get_address();
-        let utxo_address = spent_utxo.get_address();
-        if authorization_address != utxo_address {
+        let utxo_address = spent_utxo.borrow().get_address();
+        if utxo_address.is_burn() {
+            return Err(Error::BurnOutputSpent { input: *input });
+        }
+        if !bool::from(authorization_address.ct_eq(&utxo_address)) {
             return Err(Error::AddressesDontMatch {
                 authorization_address,
                 utxo_address,
@@ -60,12 +120,20 @@ pub fn validate_body<A: GetAddress, C: GetValue + Clone + Serialize>(
     {
         let mut index = 0;
         for transaction in &body.transactions {
-            let spent_utxos = &spent_utxos[index..transaction.inputs.len()];
+            let spent_utxos = &spent_utxos[index..index + transaction.inputs.len()];
             index += transaction.inputs.len();
-            fees += validate_transaction(spent_utxos, transaction)?;
+            fees = fees
+                .checked_add(validate_transaction(spent_utxos, transaction)?)
+                .ok_or(Error::ValueOverflow)?;
         }
     }
-    let coinbase_value = body.get_coinbase_value();
+    if params.burned_value == BurnedValue::Fee {
+        fees = body
+            .get_burned_value()
+            .and_then(|burned| fees.checked_add(burned))
+            .ok_or(Error::ValueOverflow)?;
+    }
+    let coinbase_value = body.get_coinbase_value().ok_or(Error::ValueOverflow)?;
     if coinbase_value > fees {
         return Err(Error::CoinbaseValueGreaterThanFees {
             coinbase_value,
@@ -75,17 +143,35 @@ pub fn validate_body<A: GetAddress, C: GetValue + Clone + Serialize>(
     Ok(fees)
 }
 
-pub trait State<C> {
+fn checked_sum(mut values: impl Iterator<Item = u64>) -> Result<u64, Error> {
+    values.try_fold(0u64, |sum, value| {
+        sum.checked_add(value).ok_or(Error::ValueOverflow)
+    })
+}
+
+pub trait State<A, C> {
+    /// Entry stored for every unspent outpoint.
+    type Utxo;
     type Error;
-    fn validate_transaction(&self, transaction: &Transaction<C>) -> Result<(), Self::Error>;
-    fn validate_body<A>(&self, body: &Body<A, C>) -> Result<(), Self::Error>;
-    fn connect_body<A>(&mut self, body: &Body<A, C>) -> Result<(), Self::Error>;
+    fn get_utxo(&self, outpoint: &OutPoint) -> Option<Self::Utxo>;
+    /// Returns the fee paid by transaction if it is valid.
+    fn validate_transaction(
+        &self,
+        transaction: &AuthorizedTransaction<A, C>,
+    ) -> Result<u64, Self::Error>;
+    /// Returns total fee collected by body if it is valid.
+    fn validate_body(&self, body: &Body<A, C>) -> Result<u64, Self::Error>;
+    fn connect_body(&mut self, body: &Body<A, C>) -> Result<(), Self::Error>;
+    /// Reverts a previously connected body, it must be the last one connected.
+    fn disconnect_body(&mut self, body: &Body<A, C>) -> Result<(), Self::Error>;
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("utxo with outpoint {outpoint} does not exist")]
     UtxoDoesNotExist { outpoint: OutPoint },
+    #[error("outpoint {input} is a burn output and can't be spent")]
+    BurnOutputSpent { input: OutPoint },
     #[error("outpoint {input} is double spent")]
     DoubleSpent { input: OutPoint },
     #[error("coinbase value > fees value: {coinbase_value} > {fees}")]
@@ -95,6 +181,13 @@ pub enum Error {
         authorization_address: Address,
         utxo_address: Address,
     },
+    #[error("number of authorizations != number of inputs: {authorizations} != {inputs}")]
+    WrongNumberOfAuthorizations {
+        authorizations: usize,
+        inputs: usize,
+    },
     #[error("transaction value in < value out: {value_in} < {value_out}")]
     ValueInLessThanValueOut { value_in: u64, value_out: u64 },
+    #[error("total value overflows u64")]
+    ValueOverflow,
 }
@@ -0,0 +1,147 @@
+use crate::types::*;
+use bitcoin::bech32;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use rand::RngCore as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// Attempts between checks of the shared state.
+const BATCH_SIZE: u64 = 256;
+
+/// Options of `vanity_search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VanityOptions {
+    /// Worker threads, all available cores by default.
+    pub threads: usize,
+    /// Gives up after about this many attempts, `None` searches forever.
+    pub max_attempts: Option<u64>,
+}
+
+impl Default for VanityOptions {
+    fn default() -> Self {
+        Self {
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VanityStats {
+    /// Keys tried by all threads together.
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
+impl VanityStats {
+    pub fn attempts_per_second(&self) -> f64 {
+        self.attempts as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VanityKey {
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+    pub address: Address,
+    /// The address in the format that was searched.
+    pub encoded: String,
+}
+
+/// Grinds keys until the encoding of their `Address::from_public_key`
+/// address continues with `prefix`.
+///
+/// Only pubkey hash addresses are searched, their encodings always start
+/// with `1` in base58check and with the human readable part and `1q` in
+/// bech32m, `prefix` is matched after that. Returns `None` as the key once
+/// `max_attempts` is reached. Expect `58^n` or `32^n` attempts for a prefix
+/// of `n` characters.
+pub fn vanity_search(
+    prefix: &str,
+    format: &AddressFormat,
+    options: &VanityOptions,
+) -> Result<(Option<VanityKey>, VanityStats), VanityError> {
+    let target = match format {
+        AddressFormat::Base58 => {
+            check_charset(prefix, BASE58_ALPHABET)?;
+            format!("1{prefix}")
+        }
+        AddressFormat::Bech32m { hrp } => {
+            let prefix = prefix.to_lowercase();
+            check_charset(&prefix, BECH32_CHARSET)?;
+            // Validates the human readable part.
+            Address::burn().to_bech32m(hrp)?;
+            format!("{}1q{prefix}", hrp.to_lowercase())
+        }
+    };
+    let started = Instant::now();
+    let done = AtomicBool::new(false);
+    let attempts = AtomicU64::new(0);
+    let found = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads.max(1) {
+            scope.spawn(|| {
+                let secp = Secp256k1::signing_only();
+                let mut rng = rand::thread_rng();
+                let mut secret = [0; 32];
+                while !done.load(Ordering::Relaxed) {
+                    let mut tried = 0;
+                    while tried < BATCH_SIZE {
+                        tried += 1;
+                        rng.fill_bytes(&mut secret);
+                        let Ok(secret_key) = SecretKey::from_slice(&secret) else {
+                            continue;
+                        };
+                        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+                        let address = Address::from_public_key(&public_key);
+                        let encoded = match format {
+                            AddressFormat::Base58 => address.to_base58(),
+                            AddressFormat::Bech32m { hrp } => address
+                                .to_bech32m(hrp)
+                                .expect("human readable part was validated"),
+                        };
+                        if encoded.starts_with(&target) {
+                            done.store(true, Ordering::Relaxed);
+                            *found.lock().unwrap_or_else(|err| err.into_inner()) =
+                                Some(VanityKey {
+                                    secret_key,
+                                    public_key,
+                                    address,
+                                    encoded,
+                                });
+                            break;
+                        }
+                    }
+                    let total = attempts.fetch_add(tried, Ordering::Relaxed) + tried;
+                    if options.max_attempts.is_some_and(|max| total >= max) {
+                        done.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let stats = VanityStats {
+        attempts: attempts.into_inner(),
+        elapsed: started.elapsed(),
+    };
+    let found = found.into_inner().unwrap_or_else(|err| err.into_inner());
+    Ok((found, stats))
+}
+
+fn check_charset(prefix: &str, charset: &str) -> Result<(), VanityError> {
+    match prefix.chars().find(|c| !charset.contains(*c)) {
+        Some(character) => Err(VanityError::InvalidCharacter { character }),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum VanityError {
+    #[error("{character:?} can't appear in this address format")]
+    InvalidCharacter { character: char },
+    #[error("invalid human readable part")]
+    Hrp(#[from] bech32::Error),
+}
@@ -0,0 +1,486 @@
+use crate::authorization::KeyAuthorization;
+use crate::encoding::EncodeError;
+use crate::mempool::FeeRate;
+use crate::transaction_builder::{
+    TransactionBuilder, TransactionBuilderError, UnsignedTransaction,
+};
+use crate::types::*;
+use crate::watch_only::{WatchOnlyError, WatchOnlyWallet};
+use bitcoin::secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr as _;
+
+/// Account wallets derive their keys under by default, BIP 44 style.
+pub const DEFAULT_ACCOUNT_PATH: &str = "m/44'/0'/0'";
+
+/// Unused addresses in a row after which discovery stops, as in BIP 44.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Produces the authorization of an input spending an output sent to
+/// `address`, in the transaction with `txid`.
+pub trait Signer<A> {
+    type Error;
+    /// `None` if the signer holds no key for `address`.
+    fn sign(&self, address: &Address, txid: &Txid) -> Result<Option<A>, Self::Error>;
+}
+
+/// Index after the last used address of a chain, deriving addresses from
+/// index 0 on until `gap_limit` in a row are unused.
+pub(crate) fn discover_next_index<E>(
+    gap_limit: u32,
+    mut derive_address: impl FnMut(u32) -> Result<Address, E>,
+    mut is_used: impl FnMut(&Address) -> bool,
+) -> Result<u32, E> {
+    let mut next_index = 0;
+    let mut index = 0;
+    while index - next_index < gap_limit {
+        if is_used(&derive_address(index)?) {
+            next_index = index + 1;
+        }
+        index += 1;
+    }
+    Ok(next_index)
+}
+
+/// Wallets telling their own addresses apart.
+pub trait IsMine {
+    fn is_mine(&self, address: &Address) -> bool;
+}
+
+impl IsMine for HashSet<Address> {
+    fn is_mine(&self, address: &Address) -> bool {
+        self.contains(address)
+    }
+}
+
+/// Branch of an account, like BIP 44 external and internal chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum KeyChain {
+    /// Addresses handed out to payers.
+    Receive,
+    /// Addresses for change outputs of own transactions.
+    Change,
+}
+
+impl KeyChain {
+    pub(crate) fn child_number(self) -> ChildNumber {
+        match self {
+            Self::Receive => ChildNumber::Normal { index: 0 },
+            Self::Change => ChildNumber::Normal { index: 1 },
+        }
+    }
+}
+
+/// Where a wallet key sits in its account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyIndex {
+    pub chain: KeyChain,
+    pub index: u32,
+}
+
+/// HD wallet deriving `Address::from_public_key` addresses under
+/// `account/chain/index`.
+///
+/// The account key is overwritten when the wallet is dropped, every clone
+/// erases its own copy. Only derivation indices are kept, keys are derived again when signing.
+/// Derivation indices have to be restored with `set_next_index` after
+/// loading a wallet from its seed.
+#[derive(Clone)]
+pub struct Wallet {
+    secp: Secp256k1<All>,
+    account: ExtendedPrivKey,
+    account_path: DerivationPath,
+    next_receive: u32,
+    next_change: u32,
+    addresses: HashMap<Address, KeyIndex>,
+    /// Network withdrawals must pay out on.
+    main_network: bitcoin::Network,
+}
+
+impl Wallet {
+    /// Wallet with the default account of the master key derived from
+    /// `seed`.
+    pub fn from_seed(seed: &[u8]) -> Result<Self, WalletError> {
+        let mut master = ExtendedPrivKey::new_master(bitcoin::Network::Bitcoin, seed)?;
+        let account_path =
+            DerivationPath::from_str(DEFAULT_ACCOUNT_PATH).expect("the default path is valid");
+        let wallet = Self::new(&master, account_path);
+        erase(&mut master);
+        wallet
+    }
+
+    pub fn new(
+        master: &ExtendedPrivKey,
+        account_path: DerivationPath,
+    ) -> Result<Self, WalletError> {
+        let secp = Secp256k1::new();
+        let account = master.derive_priv(&secp, &account_path)?;
+        Ok(Self {
+            secp,
+            account,
+            account_path,
+            next_receive: 0,
+            next_change: 0,
+            addresses: HashMap::new(),
+            main_network: bitcoin::Network::Bitcoin,
+        })
+    }
+
+    pub fn account_path(&self) -> &DerivationPath {
+        &self.account_path
+    }
+
+    /// Mainchain network withdrawal addresses are checked against, mainnet
+    /// by default.
+    pub fn with_main_network(mut self, main_network: bitcoin::Network) -> Self {
+        self.main_network = main_network;
+        self
+    }
+
+    pub fn main_network(&self) -> bitcoin::Network {
+        self.main_network
+    }
+
+    /// Derives the next unused address of `chain`.
+    pub fn new_address(&mut self, chain: KeyChain) -> Result<Address, WalletError> {
+        let index = self.next_index(chain);
+        let address = self.derive_address(chain, index)?;
+        match chain {
+            KeyChain::Receive => self.next_receive += 1,
+            KeyChain::Change => self.next_change += 1,
+        }
+        Ok(address)
+    }
+
+    pub fn receive_address(&mut self) -> Result<Address, WalletError> {
+        self.new_address(KeyChain::Receive)
+    }
+
+    pub fn change_address(&mut self) -> Result<Address, WalletError> {
+        self.new_address(KeyChain::Change)
+    }
+
+    /// Index the next address of `chain` is derived at.
+    pub fn next_index(&self, chain: KeyChain) -> u32 {
+        match chain {
+            KeyChain::Receive => self.next_receive,
+            KeyChain::Change => self.next_change,
+        }
+    }
+
+    /// Restores the next indices of both chains, past the last address
+    /// `is_used` reports, scanning until `gap_limit` addresses in a row are
+    /// unused. Addresses of the gap stay derived, so outputs sent to them
+    /// are recognized.
+    pub fn discover_addresses(
+        &mut self,
+        gap_limit: u32,
+        mut is_used: impl FnMut(&Address) -> bool,
+    ) -> Result<(), WalletError> {
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            let next_index = discover_next_index(
+                gap_limit,
+                |index| self.derive_address(chain, index),
+                &mut is_used,
+            )?;
+            self.set_next_index(chain, next_index)?;
+        }
+        Ok(())
+    }
+
+    /// Derives all addresses of `chain` below `next_index` that aren't
+    /// derived yet, indices never go back.
+    pub fn set_next_index(&mut self, chain: KeyChain, next_index: u32) -> Result<(), WalletError> {
+        for index in self.next_index(chain)..next_index {
+            self.derive_address(chain, index)?;
+        }
+        match chain {
+            KeyChain::Receive => self.next_receive = self.next_receive.max(next_index),
+            KeyChain::Change => self.next_change = self.next_change.max(next_index),
+        }
+        Ok(())
+    }
+
+    /// Derives the address at `index` of `chain` and remembers it, without
+    /// advancing the next index.
+    fn derive_address(&mut self, chain: KeyChain, index: u32) -> Result<Address, WalletError> {
+        let public_key = PublicKey::from_secret_key(&self.secp, &self.secret_key(chain, index)?);
+        let address = Address::from_public_key(&public_key);
+        self.addresses.insert(address, KeyIndex { chain, index });
+        Ok(address)
+    }
+
+    fn secret_key(&self, chain: KeyChain, index: u32) -> Result<SecretKey, WalletError> {
+        let path = [chain.child_number(), ChildNumber::from_normal_idx(index)?];
+        Ok(self.account.derive_priv(&self.secp, &path)?.private_key)
+    }
+
+    pub fn is_mine(&self, address: &Address) -> bool {
+        self.addresses.contains_key(address)
+    }
+
+    pub fn key_index(&self, address: &Address) -> Option<KeyIndex> {
+        self.addresses.get(address).copied()
+    }
+
+    /// Full path of the key of `address`, from the master key.
+    pub fn derivation_path(&self, address: &Address) -> Option<DerivationPath> {
+        let KeyIndex { chain, index } = self.key_index(address)?;
+        let index = ChildNumber::from_normal_idx(index).ok()?;
+        Some(self.account_path.extend([chain.child_number(), index]))
+    }
+
+    /// Builds a transaction from `candidates`, change goes to the next change
+    /// address, which is only used up if there is change.
+    pub fn create_transaction<C: Clone + GetValue + Serialize>(
+        &mut self,
+        builder: &TransactionBuilder<C>,
+        candidates: &[(OutPoint, Output<C>)],
+    ) -> Result<UnsignedTransaction<C>, WalletError> {
+        let index = self.next_index(KeyChain::Change);
+        let change_address = self.derive_address(KeyChain::Change, index)?;
+        let transaction = builder
+            .clone()
+            .change_address(change_address)
+            .build(candidates)?;
+        if transaction.change_vout.is_some() {
+            self.next_change += 1;
+        }
+        Ok(transaction)
+    }
+
+    /// Authorizes every input of `unsigned`, in input order, the validator
+    /// pairs inputs and authorizations by position. Fails listing every
+    /// input the wallet has no key for.
+    pub fn sign_transaction<C: Serialize>(
+        &self,
+        unsigned: UnsignedTransaction<C>,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        self.sign_transaction_with::<DefaultHashFunction, C>(unsigned)
+    }
+
+    /// `sign_transaction` for chains hashing with `H`.
+    pub fn sign_transaction_with<H: HashFunction, C: Serialize>(
+        &self,
+        unsigned: UnsignedTransaction<C>,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        if unsigned.spent.len() != unsigned.transaction.inputs.len() {
+            return Err(WalletError::InputCountMismatch {
+                inputs: unsigned.transaction.inputs.len(),
+                spent: unsigned.spent.len(),
+            });
+        }
+        let txid = unsigned.transaction.try_txid_with::<H>()?;
+        let mut authorizations = Vec::with_capacity(unsigned.spent.len());
+        let mut missing = Vec::new();
+        for (input, output) in unsigned.spent.iter().enumerate() {
+            match self.sign(&output.address, &txid)? {
+                Some(authorization) => authorizations.push(authorization),
+                None => missing.push(input),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(WalletError::MissingKeys { inputs: missing });
+        }
+        Ok(AuthorizedTransaction {
+            transaction: unsigned.transaction,
+            authorizations,
+        })
+    }
+
+    /// Builds and signs one transaction paying every `(address, value)` of
+    /// `payments`, coins are selected from `candidates`.
+    pub fn create_batch_payment<C: Clone + GetValue + Serialize>(
+        &mut self,
+        candidates: &[(OutPoint, Output<C>)],
+        payments: &[(Address, u64)],
+        fee_rate: FeeRate,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        if payments.is_empty() {
+            return Err(WalletError::NoPayments);
+        }
+        let builder = payments.iter().fold(
+            TransactionBuilder::new(fee_rate),
+            |builder, (address, value)| builder.pay(*address, *value),
+        );
+        let unsigned = self.create_transaction(&builder, candidates)?;
+        self.sign_transaction(unsigned)
+    }
+
+    /// Builds and signs a transaction withdrawing `value` to `main_address` on
+    /// the mainchain, paying `main_fee` towards the bundle fee.
+    ///
+    /// The withdrawal output goes to a new change address, which gets the
+    /// value back if the bundle fails.
+    pub fn create_withdrawal<C: Clone + GetValue + Serialize>(
+        &mut self,
+        candidates: &[(OutPoint, Output<C>)],
+        main_address: bitcoin::Address,
+        value: u64,
+        main_fee: u64,
+        fee_rate: FeeRate,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        if !main_address.is_valid_for_network(self.main_network) {
+            return Err(WalletError::WrongNetwork {
+                network: main_address.network,
+                expected: self.main_network,
+            });
+        }
+        let dust_value = main_address.script_pubkey().dust_value().to_sat();
+        if value < dust_value {
+            return Err(WalletError::WithdrawalDust { value, dust_value });
+        }
+        let builder = TransactionBuilder::new(fee_rate).add_output(Output {
+            address: self.change_address()?,
+            content: Content::Withdrawal {
+                value,
+                main_fee,
+                main_address,
+            },
+        });
+        let unsigned = self.create_transaction(&builder, candidates)?;
+        self.sign_transaction(unsigned)
+    }
+
+    /// Builds and signs a transaction sending the value of every candidate
+    /// worth more than its fee to `address`. Outputs with withdrawal or
+    /// custom content are left alone.
+    pub fn sweep_to<C: Clone + GetValue + Serialize>(
+        &self,
+        candidates: &[(OutPoint, Output<C>)],
+        address: Address,
+        fee_rate: FeeRate,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        let candidates: Vec<_> = candidates
+            .iter()
+            .filter(|(_, output)| output.content.is_value())
+            .cloned()
+            .collect();
+        let unsigned = TransactionBuilder::new(fee_rate).build_sweep(&candidates, address)?;
+        self.sign_transaction(unsigned)
+    }
+
+    /// Builds and signs a transaction merging up to `max_inputs` of the
+    /// smallest candidates worth more than their fee into one output to a new
+    /// change address, best done while fees are low.
+    pub fn consolidate<C: Clone + GetValue + Serialize>(
+        &mut self,
+        candidates: &[(OutPoint, Output<C>)],
+        max_inputs: usize,
+        fee_rate: FeeRate,
+    ) -> Result<AuthorizedTransaction<KeyAuthorization, C>, WalletError> {
+        let builder = TransactionBuilder::new(fee_rate);
+        let mut candidates: Vec<_> = candidates
+            .iter()
+            .filter(|(_, output)| {
+                output.content.is_value() && output.get_value() > builder.input_fee()
+            })
+            .cloned()
+            .collect();
+        candidates.sort_by_key(|(_, output)| output.get_value());
+        candidates.truncate(max_inputs);
+        if candidates.len() < 2 {
+            return Err(WalletError::NothingToConsolidate);
+        }
+        let index = self.next_index(KeyChain::Change);
+        let address = self.derive_address(KeyChain::Change, index)?;
+        let unsigned = builder.build_sweep(&candidates, address)?;
+        self.next_change += 1;
+        self.sign_transaction(unsigned)
+    }
+
+    /// Watch-only wallet of the account, with the same derived addresses.
+    pub fn watch_only(&self) -> Result<WatchOnlyWallet, WatchOnlyError> {
+        let account = ExtendedPubKey::from_priv(&self.secp, &self.account);
+        let mut watch_only = WatchOnlyWallet::from_xpub(account, self.account_path.clone());
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            watch_only.set_next_index(chain, self.next_index(chain))?;
+        }
+        Ok(watch_only)
+    }
+
+    /// Derived addresses, in no particular order.
+    pub fn addresses(&self) -> impl Iterator<Item = (&Address, &KeyIndex)> {
+        self.addresses.iter()
+    }
+}
+
+impl IsMine for Wallet {
+    fn is_mine(&self, address: &Address) -> bool {
+        Wallet::is_mine(self, address)
+    }
+}
+
+impl Signer<KeyAuthorization> for Wallet {
+    type Error = WalletError;
+
+    fn sign(
+        &self,
+        address: &Address,
+        txid: &Txid,
+    ) -> Result<Option<KeyAuthorization>, WalletError> {
+        let Some(KeyIndex { chain, index }) = self.key_index(address) else {
+            return Ok(None);
+        };
+        let secret_key = self.secret_key(chain, index)?;
+        Ok(Some(KeyAuthorization::sign(&self.secp, &secret_key, txid)))
+    }
+}
+
+impl std::fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wallet")
+            .field("account_path", &self.account_path)
+            .field("next_receive", &self.next_receive)
+            .field("next_change", &self.next_change)
+            .field("addresses", &self.addresses.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Wallet {
+    fn drop(&mut self) {
+        erase(&mut self.account);
+    }
+}
+
+/// Overwrites the private key and chain code of `key`, with a volatile write
+/// so that it isn't optimized away as a dead store.
+fn erase(key: &mut ExtendedPrivKey) {
+    let erased = ExtendedPrivKey {
+        private_key: bitcoin::secp256k1::ONE_KEY,
+        chain_code: bip32::ChainCode::from(&[0; 32][..]),
+        ..*key
+    };
+    // SAFETY: `key` is a valid and aligned exclusive reference, and
+    // `ExtendedPrivKey` has no drop glue.
+    unsafe { std::ptr::write_volatile(key, erased) };
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalletError {
+    #[error("key derivation error")]
+    Bip32(#[from] bip32::Error),
+    #[error("failed to build transaction")]
+    Build(#[from] TransactionBuilderError),
+    #[error("no key for inputs {inputs:?}")]
+    MissingKeys { inputs: Vec<usize> },
+    #[error("transaction has {inputs} inputs, {spent} spent outputs are given")]
+    InputCountMismatch { inputs: usize, spent: usize },
+    #[error("no payments")]
+    NoPayments,
+    #[error("fewer than two outputs to consolidate")]
+    NothingToConsolidate,
+    #[error("mainchain address is for {network}, expected {expected}")]
+    WrongNetwork {
+        network: bitcoin::Network,
+        expected: bitcoin::Network,
+    },
+    #[error("withdrawal of {value} is below the mainchain dust value {dust_value}")]
+    WithdrawalDust { value: u64, dust_value: u64 },
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
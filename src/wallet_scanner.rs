@@ -0,0 +1,471 @@
+use crate::encoding::EncodeError;
+use crate::types::*;
+use crate::wallet::IsMine;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::marker::PhantomData;
+
+/// Unspent output of a wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletUtxo<C> {
+    pub output: Output<C>,
+    /// Height of the block creating the output, `None` for deposits.
+    pub height: Option<u32>,
+}
+
+/// Where a wallet transaction stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionStatus {
+    /// Not in a block yet.
+    Pending,
+    Confirmed {
+        height: u32,
+    },
+    /// Pending while a confirmed transaction spent one of its inputs.
+    Conflicted,
+    /// Confirmed in a block that was disconnected since.
+    Reorged,
+}
+
+impl TransactionStatus {
+    pub fn height(&self) -> Option<u32> {
+        match self {
+            Self::Confirmed { height } => Some(*height),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TransactionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pending => write!(f, "pending"),
+            Self::Confirmed { height } => write!(f, "confirmed at {height}"),
+            Self::Conflicted => write!(f, "conflicted"),
+            Self::Reorged => write!(f, "reorged"),
+        }
+    }
+}
+
+/// Transaction sending from or to a wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalletTransaction {
+    pub txid: Txid,
+    pub status: TransactionStatus,
+    /// Value of the outputs sent to the wallet.
+    pub received: u64,
+    /// Value of the wallet outputs spent.
+    pub sent: u64,
+    /// Fee of the transaction if the wallet paid it, that is if it spends
+    /// only wallet outputs.
+    pub fee: Option<u64>,
+}
+
+impl WalletTransaction {
+    /// Change of the wallet balance.
+    pub fn net(&self) -> i128 {
+        i128::from(self.received) - i128::from(self.sent)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BlockUndo<C> {
+    created: Vec<OutPoint>,
+    spent: Vec<(OutPoint, WalletUtxo<C>)>,
+    confirmed: Vec<Txid>,
+}
+
+/// Tracks the outputs and transactions of a wallet from the blocks of the
+/// chain.
+///
+/// Blocks and mainchain blocks have to be passed to the scanner as they are
+/// connected to and disconnected from the `StateMachine`. Which outputs are
+/// the wallet's is decided when a block is connected, `rescan_from` finds
+/// outputs of addresses added to the wallet later. Txids and coinbase
+/// outpoints are computed with the chain's `HashFunction` `H`.
+#[derive(Debug, Clone)]
+pub struct WalletScanner<C, H = DefaultHashFunction> {
+    utxos: BTreeMap<OutPoint, WalletUtxo<C>>,
+    /// In the order they were first seen.
+    transactions: Vec<WalletTransaction>,
+    positions: HashMap<Txid, usize>,
+    /// Inputs of pending transactions.
+    pending_inputs: HashMap<OutPoint, Txid>,
+    /// Height of the first block with undo data.
+    start_height: u32,
+    /// One per connected block, from `start_height` on.
+    undo: VecDeque<BlockUndo<C>>,
+    _marker: PhantomData<H>,
+}
+
+impl<C, H> Default for WalletScanner<C, H> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<C, H> WalletScanner<C, H> {
+    /// Scanner expecting the block at `start_height` first, the height the
+    /// wallet was created at.
+    pub fn new(start_height: u32) -> Self {
+        Self {
+            utxos: BTreeMap::new(),
+            transactions: Vec::new(),
+            positions: HashMap::new(),
+            pending_inputs: HashMap::new(),
+            start_height,
+            undo: VecDeque::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Height of the last connected block.
+    pub fn tip_height(&self) -> Option<u32> {
+        (self.start_height + self.undo.len() as u32).checked_sub(1)
+    }
+
+    /// Height the next connected block must have.
+    pub fn next_height(&self) -> u32 {
+        self.start_height + self.undo.len() as u32
+    }
+
+    /// Blocks on top of `height`, itself included, `0` for heights above
+    /// the tip.
+    pub fn confirmations(&self, height: u32) -> u32 {
+        match self.tip_height() {
+            Some(tip) if tip >= height => tip - height + 1,
+            _ => 0,
+        }
+    }
+
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<&WalletUtxo<C>> {
+        self.utxos.get(outpoint)
+    }
+
+    /// Unspent outputs in outpoint order.
+    pub fn utxos(&self) -> impl Iterator<Item = (&OutPoint, &WalletUtxo<C>)> {
+        self.utxos.iter()
+    }
+
+    /// Unspent outputs with at least `min_confirmations` confirmations, as
+    /// coin selection candidates. Deposits count as confirmed.
+    pub fn candidates(&self, min_confirmations: u32) -> Vec<(OutPoint, Output<C>)>
+    where
+        C: Clone,
+    {
+        self.utxos
+            .iter()
+            .filter(|(_, utxo)| {
+                utxo.height
+                    .is_none_or(|height| self.confirmations(height) >= min_confirmations)
+            })
+            .map(|(outpoint, utxo)| (*outpoint, utxo.output.clone()))
+            .collect()
+    }
+
+    pub fn balance(&self) -> u64
+    where
+        C: GetValue,
+    {
+        self.utxos
+            .values()
+            .map(|utxo| utxo.output.get_value())
+            .sum()
+    }
+
+    /// Transactions in the order they were first seen.
+    pub fn transactions(&self) -> &[WalletTransaction] {
+        &self.transactions
+    }
+
+    pub fn get_transaction(&self, txid: &Txid) -> Option<&WalletTransaction> {
+        Some(&self.transactions[*self.positions.get(txid)?])
+    }
+
+    /// Confirmations of the transaction, `0` unless it is confirmed.
+    pub fn transaction_confirmations(&self, txid: &Txid) -> u32 {
+        self.get_transaction(txid)
+            .and_then(|transaction| transaction.status.height())
+            .map_or(0, |height| self.confirmations(height))
+    }
+
+    /// Marks a pending transaction conflicted, for example when the mempool
+    /// drops it for a conflict. Returns false if it isn't pending.
+    pub fn set_conflicted(&mut self, txid: &Txid) -> bool {
+        match self.positions.get(txid) {
+            Some(position) if self.transactions[*position].status == TransactionStatus::Pending => {
+                self.transactions[*position].status = TransactionStatus::Conflicted;
+                self.pending_inputs.retain(|_, spender| spender != txid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the transactions as CSV with a header row, heights and fees are
+    /// empty if unknown.
+    pub fn write_csv<W: std::io::Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writeln!(writer, "txid,status,height,received,sent,fee")?;
+        for transaction in &self.transactions {
+            let status = match transaction.status {
+                TransactionStatus::Pending => "pending",
+                TransactionStatus::Confirmed { .. } => "confirmed",
+                TransactionStatus::Conflicted => "conflicted",
+                TransactionStatus::Reorged => "reorged",
+            };
+            let height = transaction.status.height().map(|height| height.to_string());
+            let fee = transaction.fee.map(|fee| fee.to_string());
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                transaction.txid,
+                status,
+                height.unwrap_or_default(),
+                transaction.received,
+                transaction.sent,
+                fee.unwrap_or_default(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adds or updates the transaction with `txid`.
+    fn record(&mut self, transaction: WalletTransaction) {
+        match self.positions.get(&transaction.txid) {
+            Some(position) => self.transactions[*position] = transaction,
+            None => {
+                self.positions
+                    .insert(transaction.txid, self.transactions.len());
+                self.transactions.push(transaction);
+            }
+        }
+    }
+
+    fn set_status(&mut self, txid: &Txid, status: TransactionStatus) {
+        if let Some(position) = self.positions.get(txid) {
+            self.transactions[*position].status = status;
+        }
+    }
+
+    /// Reverts the last connected block, returns its height.
+    pub fn disconnect_block(&mut self) -> Option<u32> {
+        let height = self.tip_height()?;
+        let undo = self.undo.pop_back()?;
+        self.utxos.extend(undo.spent);
+        for outpoint in &undo.created {
+            self.utxos.remove(outpoint);
+        }
+        for txid in &undo.confirmed {
+            self.set_status(txid, TransactionStatus::Reorged);
+        }
+        Some(height)
+    }
+
+    /// Forgets deposits of `events`, mainchain blocks must be disconnected
+    /// in reverse order.
+    pub fn disconnect_main_block(&mut self, events: &MainBlockEvents<C>) {
+        for (outpoint, _) in &events.deposits {
+            self.utxos.remove(&OutPoint::Deposit(*outpoint));
+        }
+    }
+}
+
+impl<C: Clone + GetValue + Serialize, H: HashFunction> WalletScanner<C, H> {
+    /// Records the outputs `body` sends to `wallet` and the wallet outputs
+    /// it spends.
+    pub fn connect_block<A>(
+        &mut self,
+        wallet: &impl IsMine,
+        height: u32,
+        body: &Body<A, C>,
+    ) -> Result<(), WalletScannerError> {
+        if height != self.next_height() {
+            return Err(WalletScannerError::UnexpectedHeight {
+                height,
+                expected: self.next_height(),
+            });
+        }
+        let mut undo = BlockUndo {
+            created: Vec::new(),
+            spent: Vec::new(),
+            confirmed: Vec::new(),
+        };
+        let merkle_root = body.try_compute_merkle_root_with::<H>()?;
+        for (vout, output) in body.coinbase.iter().enumerate() {
+            let outpoint = OutPoint::Coinbase {
+                merkle_root,
+                vout: vout as u32,
+            };
+            self.credit(wallet, &mut undo, outpoint, output, height);
+        }
+        for transaction in &body.transactions {
+            let txid = transaction.try_txid_with::<H>()?;
+            for outpoint in &transaction.inputs {
+                let spender = self.pending_inputs.get(outpoint).copied();
+                if let Some(spender) = spender.filter(|spender| *spender != txid) {
+                    self.set_conflicted(&spender);
+                }
+            }
+            self.pending_inputs.retain(|_, spender| *spender != txid);
+            let (sent, fee) = self.spent_value(transaction);
+            for outpoint in &transaction.inputs {
+                if let Some(utxo) = self.utxos.remove(outpoint) {
+                    undo.spent.push((*outpoint, utxo));
+                }
+            }
+            let mut received = 0;
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint::Regular {
+                    txid,
+                    vout: vout as u32,
+                };
+                if self.credit(wallet, &mut undo, outpoint, output, height) {
+                    received += output.get_value();
+                }
+            }
+            if sent > 0 || received > 0 || self.positions.contains_key(&txid) {
+                self.record(WalletTransaction {
+                    txid,
+                    status: TransactionStatus::Confirmed { height },
+                    received,
+                    sent,
+                    fee,
+                });
+                undo.confirmed.push(txid);
+            }
+        }
+        self.undo.push_back(undo);
+        Ok(())
+    }
+
+    fn credit(
+        &mut self,
+        wallet: &impl IsMine,
+        undo: &mut BlockUndo<C>,
+        outpoint: OutPoint,
+        output: &Output<C>,
+        height: u32,
+    ) -> bool {
+        if output.address.is_burn() || !wallet.is_mine(&output.address) {
+            return false;
+        }
+        let utxo = WalletUtxo {
+            output: output.clone(),
+            height: Some(height),
+        };
+        self.utxos.insert(outpoint, utxo);
+        undo.created.push(outpoint);
+        true
+    }
+
+    /// Value of the wallet outputs `transaction` spends, and its fee if they
+    /// are all of its inputs.
+    fn spent_value(&self, transaction: &Transaction<C>) -> (u64, Option<u64>) {
+        let mut sent = 0;
+        let mut all_mine = true;
+        for outpoint in &transaction.inputs {
+            match self.utxos.get(outpoint) {
+                Some(utxo) => sent += utxo.output.get_value(),
+                None => all_mine = false,
+            }
+        }
+        let outputs: u64 = transaction.outputs.iter().map(GetValue::get_value).sum();
+        let fee = if all_mine {
+            sent.checked_sub(outputs)
+        } else {
+            None
+        };
+        (sent, fee)
+    }
+
+    /// Records an unconfirmed transaction spending from or sending to
+    /// `wallet`, like one the wallet broadcast or one from the mempool.
+    /// Returns false if it doesn't concern the wallet or is confirmed.
+    pub fn add_pending(
+        &mut self,
+        wallet: &impl IsMine,
+        transaction: &Transaction<C>,
+    ) -> Result<bool, EncodeError> {
+        let txid = transaction.try_txid_with::<H>()?;
+        let confirmed = self
+            .get_transaction(&txid)
+            .is_some_and(|transaction| transaction.status.height().is_some());
+        if confirmed {
+            return Ok(false);
+        }
+        let (sent, fee) = self.spent_value(transaction);
+        let received = transaction
+            .outputs
+            .iter()
+            .filter(|output| !output.address.is_burn() && wallet.is_mine(&output.address))
+            .map(GetValue::get_value)
+            .sum();
+        if sent == 0 && received == 0 {
+            return Ok(false);
+        }
+        for outpoint in &transaction.inputs {
+            self.pending_inputs.insert(*outpoint, txid);
+        }
+        self.record(WalletTransaction {
+            txid,
+            status: TransactionStatus::Pending,
+            received,
+            sent,
+            fee,
+        });
+        Ok(true)
+    }
+
+    /// Records the deposits of `events` sent to `wallet`.
+    pub fn connect_main_block(&mut self, wallet: &impl IsMine, events: &MainBlockEvents<C>) {
+        for (outpoint, output) in &events.deposits {
+            if wallet.is_mine(&output.address) {
+                let utxo = WalletUtxo {
+                    output: output.clone(),
+                    height: None,
+                };
+                self.utxos.insert(OutPoint::Deposit(*outpoint), utxo);
+            }
+        }
+    }
+
+    /// Disconnects blocks down to `height` and connects `bodies` from
+    /// `height` on, for wallets that gained addresses used in older blocks.
+    ///
+    /// Starting below the height the scanner started at drops all blocks, so
+    /// `bodies` must then hold every block up to the tip.
+    pub fn rescan_from<'a, A: 'a>(
+        &mut self,
+        wallet: &impl IsMine,
+        height: u32,
+        bodies: impl IntoIterator<Item = &'a Body<A, C>>,
+    ) -> Result<(), WalletScannerError>
+    where
+        C: 'a,
+    {
+        if height > self.next_height() {
+            return Err(WalletScannerError::UnexpectedHeight {
+                height,
+                expected: self.next_height(),
+            });
+        }
+        while self.next_height() > height.max(self.start_height) {
+            self.disconnect_block();
+        }
+        if height < self.start_height {
+            self.start_height = height;
+        }
+        for body in bodies {
+            self.connect_block(wallet, self.next_height(), body)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalletScannerError {
+    #[error("block at height {height} connected, expected {expected}")]
+    UnexpectedHeight { height: u32, expected: u32 },
+    #[error("encode error")]
+    Encode(#[from] EncodeError),
+}
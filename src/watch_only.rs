@@ -0,0 +1,221 @@
+use crate::psst::Psst;
+use crate::transaction_builder::{TransactionBuilder, TransactionBuilderError};
+use crate::types::*;
+use crate::utxo::{ScanResult, UtxoScan};
+use crate::wallet::{discover_next_index, IsMine, KeyChain, KeyIndex};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPubKey};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+
+/// Wallet without secret keys, tracking the addresses of an account's
+/// extended public key and imported addresses.
+///
+/// Builds PSSTs for an external signer, like a `Wallet` holding the account's
+/// private key or a hardware wallet.
+#[derive(Debug, Clone)]
+pub struct WatchOnlyWallet {
+    secp: Secp256k1<VerifyOnly>,
+    /// Account key and its path from the master key.
+    account: Option<(ExtendedPubKey, DerivationPath)>,
+    next_receive: u32,
+    next_change: u32,
+    addresses: HashMap<Address, KeyIndex>,
+    imported: HashSet<Address>,
+}
+
+impl Default for WatchOnlyWallet {
+    fn default() -> Self {
+        Self {
+            secp: Secp256k1::verification_only(),
+            account: None,
+            next_receive: 0,
+            next_change: 0,
+            addresses: HashMap::new(),
+            imported: HashSet::new(),
+        }
+    }
+}
+
+impl WatchOnlyWallet {
+    /// Wallet of imported addresses only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wallet deriving addresses from `account`, the key at `account_path`.
+    pub fn from_xpub(account: ExtendedPubKey, account_path: DerivationPath) -> Self {
+        Self {
+            account: Some((account, account_path)),
+            ..Self::default()
+        }
+    }
+
+    pub fn account(&self) -> Option<&ExtendedPubKey> {
+        self.account.as_ref().map(|(account, _)| account)
+    }
+
+    pub fn account_path(&self) -> Option<&DerivationPath> {
+        self.account.as_ref().map(|(_, path)| path)
+    }
+
+    /// Watches `address`, returns false if it was watched already.
+    pub fn import_address(&mut self, address: Address) -> bool {
+        !self.addresses.contains_key(&address) && self.imported.insert(address)
+    }
+
+    /// Derives the next unused address of `chain`.
+    pub fn new_address(&mut self, chain: KeyChain) -> Result<Address, WatchOnlyError> {
+        let index = self.next_index(chain);
+        let address = self.derive_address(chain, index)?;
+        match chain {
+            KeyChain::Receive => self.next_receive += 1,
+            KeyChain::Change => self.next_change += 1,
+        }
+        Ok(address)
+    }
+
+    pub fn receive_address(&mut self) -> Result<Address, WatchOnlyError> {
+        self.new_address(KeyChain::Receive)
+    }
+
+    pub fn change_address(&mut self) -> Result<Address, WatchOnlyError> {
+        self.new_address(KeyChain::Change)
+    }
+
+    /// Index the next address of `chain` is derived at.
+    pub fn next_index(&self, chain: KeyChain) -> u32 {
+        match chain {
+            KeyChain::Receive => self.next_receive,
+            KeyChain::Change => self.next_change,
+        }
+    }
+
+    /// Restores the next indices of both chains, past the last address
+    /// `is_used` reports, scanning until `gap_limit` addresses in a row are
+    /// unused. Addresses of the gap stay derived, so outputs sent to them
+    /// are recognized.
+    pub fn discover_addresses(
+        &mut self,
+        gap_limit: u32,
+        mut is_used: impl FnMut(&Address) -> bool,
+    ) -> Result<(), WatchOnlyError> {
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            let next_index = discover_next_index(
+                gap_limit,
+                |index| self.derive_address(chain, index),
+                &mut is_used,
+            )?;
+            self.set_next_index(chain, next_index)?;
+        }
+        Ok(())
+    }
+
+    /// Derives all addresses of `chain` below `next_index` that aren't
+    /// derived yet, indices never go back.
+    pub fn set_next_index(
+        &mut self,
+        chain: KeyChain,
+        next_index: u32,
+    ) -> Result<(), WatchOnlyError> {
+        for index in self.next_index(chain)..next_index {
+            self.derive_address(chain, index)?;
+        }
+        match chain {
+            KeyChain::Receive => self.next_receive = self.next_receive.max(next_index),
+            KeyChain::Change => self.next_change = self.next_change.max(next_index),
+        }
+        Ok(())
+    }
+
+    fn derive_address(&mut self, chain: KeyChain, index: u32) -> Result<Address, WatchOnlyError> {
+        let (account, _) = self.account.as_ref().ok_or(WatchOnlyError::NoAccount)?;
+        let path = [chain.child_number(), ChildNumber::from_normal_idx(index)?];
+        let public_key = account.derive_pub(&self.secp, &path)?.public_key;
+        let address = Address::from_public_key(&public_key);
+        self.imported.remove(&address);
+        self.addresses.insert(address, KeyIndex { chain, index });
+        Ok(address)
+    }
+
+    pub fn is_mine(&self, address: &Address) -> bool {
+        self.addresses.contains_key(address) || self.imported.contains(address)
+    }
+
+    /// `None` for imported addresses.
+    pub fn key_index(&self, address: &Address) -> Option<KeyIndex> {
+        self.addresses.get(address).copied()
+    }
+
+    /// Full path of the key of `address`, from the master key.
+    pub fn derivation_path(&self, address: &Address) -> Option<DerivationPath> {
+        let (_, account_path) = self.account.as_ref()?;
+        let KeyIndex { chain, index } = self.key_index(address)?;
+        let index = ChildNumber::from_normal_idx(index).ok()?;
+        Some(account_path.extend([chain.child_number(), index]))
+    }
+
+    /// Derived and imported addresses, in no particular order.
+    pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+        self.addresses.keys().chain(&self.imported)
+    }
+
+    /// Unspent outputs of `utxos` sent to watched addresses.
+    pub fn unspent<C: Clone, M>(&self, utxos: &M) -> ScanResult<M>
+    where
+        M: UtxoScan<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        utxos.utxos_where(|_, output| self.is_mine(&output.address))
+    }
+
+    /// Value of the unspent outputs of `utxos` sent to watched addresses.
+    pub fn balance<C: GetValue, M>(&self, utxos: &M) -> Result<u64, M::ScanError>
+    where
+        M: UtxoScan<OutPoint = OutPoint, Output = Output<C>>,
+    {
+        let mut balance = 0;
+        utxos.scan(&mut |_, output| {
+            if self.is_mine(&output.address) {
+                balance += output.get_value();
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(balance)
+    }
+
+    /// Builds an unsigned transaction from `candidates`, change goes to the
+    /// next change address, which is only used up if there is change.
+    pub fn create_psst<A, C: Clone + GetValue + Serialize>(
+        &mut self,
+        builder: &TransactionBuilder<C>,
+        candidates: &[(OutPoint, Output<C>)],
+    ) -> Result<Psst<A, C>, WatchOnlyError> {
+        let index = self.next_index(KeyChain::Change);
+        let change_address = self.derive_address(KeyChain::Change, index)?;
+        let unsigned = builder
+            .clone()
+            .change_address(change_address)
+            .build(candidates)?;
+        if unsigned.change_vout.is_some() {
+            self.next_change += 1;
+        }
+        Ok(Psst::new(unsigned, |address| self.derivation_path(address)))
+    }
+}
+
+impl IsMine for WatchOnlyWallet {
+    fn is_mine(&self, address: &Address) -> bool {
+        WatchOnlyWallet::is_mine(self, address)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WatchOnlyError {
+    #[error("key derivation error")]
+    Bip32(#[from] bip32::Error),
+    #[error("wallet has no account key to derive addresses from")]
+    NoAccount,
+    #[error("failed to build transaction")]
+    Build(#[from] TransactionBuilderError),
+}
@@ -0,0 +1,438 @@
+use crate::mempool::FeeRate;
+use crate::types::*;
+use bitcoin::blockdata::opcodes::all::{OP_NOP5, OP_PUSHNUM_1, OP_RETURN};
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::{PackedLockTime, Script, TxIn, TxOut};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// Mainchain limit on bundle transaction weight, the standard transaction
+/// weight limit.
+pub const MAX_BUNDLE_WEIGHT: usize = 400_000;
+
+/// Mainchain minimum relay fee rate, 1 sat per vbyte.
+pub const MIN_RELAY_FEE_RATE: FeeRate = FeeRate { fee: 1, size: 1 };
+
+/// Mainchain payout of sidechain withdrawals (BIP300 M6).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawalBundle {
+    /// Sidechain withdrawal outputs paid out by the bundle, they are locked
+    /// while the bundle is pending.
+    pub spent_utxos: Vec<OutPoint>,
+    /// Bundle transaction without inputs, the first output commits to the
+    /// total mainchain fee and the rest pay withdrawal addresses.
+    pub transaction: bitcoin::Transaction,
+}
+
+impl WithdrawalBundle {
+    /// Blinded M6 id, the txid of the bundle transaction without inputs.
+    pub fn hash(&self) -> bitcoin::Txid {
+        self.transaction.txid()
+    }
+
+    /// Total mainchain fee, committed to by the first output.
+    pub fn fee(&self) -> Result<u64, BundleError> {
+        let script = &self
+            .transaction
+            .output
+            .first()
+            .ok_or(BundleError::NoFeeCommitment)?
+            .script_pubkey;
+        let mut instructions = script.instructions();
+        match (
+            instructions.next(),
+            instructions.next(),
+            instructions.next(),
+        ) {
+            (Some(Ok(Instruction::Op(OP_RETURN))), Some(Ok(Instruction::PushBytes(fee))), None) => {
+                let fee = fee.try_into().map_err(|_| BundleError::NoFeeCommitment)?;
+                Ok(u64::from_le_bytes(fee))
+            }
+            _ => Err(BundleError::NoFeeCommitment),
+        }
+    }
+
+    /// Value paid out to mainchain addresses.
+    pub fn payout_value(&self) -> Result<u64, BundleError> {
+        self.transaction
+            .output
+            .iter()
+            .skip(1)
+            .try_fold(0u64, |total, payout| total.checked_add(payout.value))
+            .ok_or(BundleError::ValueOverflow)
+    }
+
+    /// Mainchain M6 transaction paying out the bundle from the sidechain
+    /// treasury output at `treasury` holding `treasury_value`.
+    ///
+    /// The first output returns the rest of the treasury to
+    /// `treasury_script(sidechain_number)`, followed by the bundle outputs in
+    /// bundle order. The fee is what the outputs leave of the treasury, so
+    /// every node builds the same transaction out of the same bundle.
+    pub fn to_m6(
+        &self,
+        treasury: bitcoin::OutPoint,
+        treasury_value: u64,
+        sidechain_number: u8,
+    ) -> Result<bitcoin::Transaction, BundleError> {
+        let required = self
+            .payout_value()?
+            .checked_add(self.fee()?)
+            .ok_or(BundleError::ValueOverflow)?;
+        let change =
+            treasury_value
+                .checked_sub(required)
+                .ok_or(BundleError::InsufficientTreasury {
+                    treasury_value,
+                    required,
+                })?;
+        let mut output = Vec::with_capacity(self.transaction.output.len() + 1);
+        output.push(TxOut {
+            value: change,
+            script_pubkey: treasury_script(sidechain_number),
+        });
+        output.extend_from_slice(&self.transaction.output);
+        Ok(bitcoin::Transaction {
+            version: self.transaction.version,
+            lock_time: self.transaction.lock_time,
+            input: vec![TxIn {
+                previous_output: treasury,
+                ..TxIn::default()
+            }],
+            output,
+        })
+    }
+
+    /// Virtual size of the M6 transaction paying out the bundle, it doesn't
+    /// depend on the treasury.
+    pub fn m6_vsize(&self) -> Result<u64, BundleError> {
+        Ok((self.m6_weight()? as u64).div_ceil(4))
+    }
+
+    /// Weight of the M6 transaction paying out the bundle, with the treasury
+    /// input and output.
+    pub fn m6_weight(&self) -> Result<usize, BundleError> {
+        let m6 = self.to_m6(bitcoin::OutPoint::null(), u64::MAX, 0)?;
+        Ok(m6.weight())
+    }
+
+    /// Checks that the committed fee pays at least `min_fee_rate` for the M6
+    /// transaction, so mainchain nodes relay it.
+    pub fn check_relay_fee(&self, min_fee_rate: FeeRate) -> Result<(), BundleError> {
+        let fee = self.fee()?;
+        let required = min_fee_rate.fee_for(self.m6_vsize()?);
+        if fee < required {
+            return Err(BundleError::BelowRelayFee { fee, required });
+        }
+        Ok(())
+    }
+}
+
+/// Amounts of a withdrawal costing the user a fixed total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalSplit {
+    /// Paid out on the mainchain.
+    pub value: u64,
+    /// Bundle fee share of the withdrawal, see `payout_main_fee`.
+    pub main_fee: u64,
+    /// Fee of the sidechain transaction creating the withdrawal.
+    pub side_fee: u64,
+}
+
+/// Mainchain fee for the bytes a payout to `main_address` adds to a bundle at
+/// `main_fee_rate`, in sats per vbyte.
+pub fn payout_main_fee(main_address: &bitcoin::Address, main_fee_rate: FeeRate) -> u64 {
+    let payout = TxOut {
+        value: 0,
+        script_pubkey: main_address.script_pubkey(),
+    };
+    main_fee_rate.fee_for(bitcoin::consensus::serialize(&payout).len() as u64)
+}
+
+/// Splits `total` into the sidechain fee, the mainchain fee of the payout at
+/// `main_fee_rate` and the value paid out, which gets the rest. The value
+/// must not be dust for `main_address`.
+pub fn split_withdrawal(
+    total: u64,
+    side_fee: u64,
+    main_address: &bitcoin::Address,
+    main_fee_rate: FeeRate,
+) -> Result<WithdrawalSplit, BundleError> {
+    let main_fee = payout_main_fee(main_address, main_fee_rate);
+    let dust_value = main_address.script_pubkey().dust_value().to_sat();
+    let required = side_fee
+        .checked_add(main_fee)
+        .and_then(|fees| fees.checked_add(dust_value))
+        .ok_or(BundleError::ValueOverflow)?;
+    if total < required {
+        return Err(BundleError::TotalTooLow { total, required });
+    }
+    Ok(WithdrawalSplit {
+        value: total - side_fee - main_fee,
+        main_fee,
+        side_fee,
+    })
+}
+
+/// Script of the sidechain treasury output (BIP300 CTIP), OP_DRIVECHAIN
+/// followed by the sidechain number and OP_TRUE.
+pub fn treasury_script(sidechain_number: u8) -> Script {
+    Builder::new()
+        .push_opcode(OP_NOP5)
+        .push_slice(&[sidechain_number])
+        .push_opcode(OP_PUSHNUM_1)
+        .into_script()
+}
+
+/// Blinded M6 id of an M6 transaction, the txid without its inputs and
+/// treasury output. It is the `WithdrawalBundle::hash` of the bundle the
+/// transaction pays out.
+pub fn blinded_m6_hash(m6: &bitcoin::Transaction) -> bitcoin::Txid {
+    bitcoin::Transaction {
+        version: m6.version,
+        lock_time: m6.lock_time,
+        input: Vec::new(),
+        output: m6.output.iter().skip(1).cloned().collect(),
+    }
+    .txid()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BundleStatus {
+    /// Proposed to the mainchain, withdrawals are still spendable.
+    Proposed,
+    /// Included in the mainchain, withdrawals are locked.
+    Locked,
+    /// Failed on the mainchain, withdrawals were refunded.
+    Failed,
+    /// Paid out on the mainchain.
+    Succeeded,
+}
+
+/// Sorts withdrawals in bundle order, by mainchain fee, highest first and
+/// then by serialized outpoint.
+pub fn sort_withdrawals(withdrawals: &mut [(OutPoint, WithdrawalOutput)]) {
+    withdrawals.sort_by_cached_key(|(outpoint, withdrawal)| {
+        (
+            Reverse(withdrawal.main_fee),
+            bincode::serialize(outpoint).unwrap_or_default(),
+        )
+    });
+}
+
+/// Builds a bundle out of unspent withdrawal outputs.
+///
+/// Withdrawals are taken in `sort_withdrawals` order while the M6 transaction
+/// paying them out, treasury input and output included, fits into
+/// `max_weight`. Payouts to the same mainchain address are aggregated.
+/// Returns `None` if no withdrawal fits.
+pub fn build_withdrawal_bundle(
+    withdrawals: impl IntoIterator<Item = (OutPoint, WithdrawalOutput)>,
+    max_weight: usize,
+) -> Result<Option<WithdrawalBundle>, BundleError> {
+    let mut withdrawals: Vec<_> = withdrawals.into_iter().collect();
+    sort_withdrawals(&mut withdrawals);
+    let mut fee: u64 = 0;
+    let mut payouts: Vec<TxOut> = Vec::new();
+    let mut bundle = WithdrawalBundle {
+        spent_utxos: Vec::new(),
+        transaction: bitcoin::Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        },
+    };
+    for (outpoint, withdrawal) in withdrawals {
+        let WithdrawalOutput {
+            value,
+            main_fee,
+            main_address,
+            ..
+        } = withdrawal;
+        let mut next_payouts = payouts.clone();
+        let script_pubkey = main_address.script_pubkey();
+        match next_payouts
+            .iter_mut()
+            .find(|payout| payout.script_pubkey == script_pubkey)
+        {
+            Some(payout) => {
+                payout.value = payout
+                    .value
+                    .checked_add(value)
+                    .ok_or(BundleError::ValueOverflow)?;
+            }
+            None => next_payouts.push(TxOut {
+                value,
+                script_pubkey,
+            }),
+        }
+        let next_fee = fee
+            .checked_add(main_fee)
+            .ok_or(BundleError::ValueOverflow)?;
+        let mut output = vec![fee_output(next_fee)];
+        output.extend_from_slice(&next_payouts);
+        bundle.transaction.output = output;
+        if bundle.m6_weight()? > max_weight {
+            continue;
+        }
+        bundle.spent_utxos.push(outpoint);
+        fee = next_fee;
+        payouts = next_payouts;
+    }
+    if bundle.spent_utxos.is_empty() {
+        return Ok(None);
+    }
+    let mut output = vec![fee_output(fee)];
+    output.extend(payouts);
+    bundle.transaction.output = output;
+    Ok(Some(bundle))
+}
+
+fn fee_output(fee: u64) -> TxOut {
+    TxOut {
+        value: 0,
+        script_pubkey: Script::new_op_return(&fee.to_le_bytes()),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("bundle value overflows u64")]
+    ValueOverflow,
+    #[error("bundle transaction doesn't commit to its fee")]
+    NoFeeCommitment,
+    #[error("treasury holds {treasury_value}, the bundle needs {required}")]
+    InsufficientTreasury { treasury_value: u64, required: u64 },
+    #[error("bundle fee {fee} is below the relay minimum {required}")]
+    BelowRelayFee { fee: u64, required: u64 },
+    #[error(
+        "withdrawal total {total} doesn't cover its fees and dust, at least {required} is needed"
+    )]
+    TotalTooLow { total: u64, required: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash as _;
+
+    fn main_address(index: i64) -> bitcoin::Address {
+        let script = Builder::new().push_int(index).into_script();
+        bitcoin::Address::p2wsh(&script, bitcoin::Network::Regtest)
+    }
+
+    fn withdrawal(
+        index: u8,
+        main_address: bitcoin::Address,
+        value: u64,
+        main_fee: u64,
+    ) -> (OutPoint, WithdrawalOutput) {
+        let outpoint = OutPoint::Regular {
+            txid: Txid([index; 32]),
+            vout: 0,
+        };
+        let withdrawal = WithdrawalOutput {
+            address: Address::from([index; 32]),
+            value,
+            main_fee,
+            main_address,
+        };
+        (outpoint, withdrawal)
+    }
+
+    #[test]
+    fn bundle_aggregates_payouts_in_fee_order() {
+        let withdrawals = vec![
+            withdrawal(0, main_address(0), 1000, 10),
+            withdrawal(1, main_address(1), 2000, 30),
+            withdrawal(2, main_address(0), 3000, 20),
+        ];
+        let outpoints: Vec<_> = withdrawals.iter().map(|(outpoint, _)| *outpoint).collect();
+        let bundle = build_withdrawal_bundle(withdrawals, MAX_BUNDLE_WEIGHT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            bundle.spent_utxos,
+            [outpoints[1], outpoints[2], outpoints[0]]
+        );
+        assert_eq!(bundle.fee().unwrap(), 60);
+        assert_eq!(bundle.payout_value().unwrap(), 6000);
+        let payouts: Vec<_> = bundle
+            .transaction
+            .output
+            .iter()
+            .skip(1)
+            .map(|payout| (payout.script_pubkey.clone(), payout.value))
+            .collect();
+        assert_eq!(
+            payouts,
+            [
+                (main_address(1).script_pubkey(), 2000),
+                (main_address(0).script_pubkey(), 4000),
+            ]
+        );
+        assert!(build_withdrawal_bundle(Vec::new(), MAX_BUNDLE_WEIGHT)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn bundle_weight_includes_treasury() {
+        let withdrawals: Vec<_> = (0..3)
+            .map(|index| withdrawal(index, main_address(index.into()), 1000, 10))
+            .collect();
+        let two = build_withdrawal_bundle(withdrawals[..2].to_vec(), MAX_BUNDLE_WEIGHT)
+            .unwrap()
+            .unwrap();
+        let max_weight = two.m6_weight().unwrap();
+        assert!(two.transaction.weight() < max_weight);
+
+        let bundle = build_withdrawal_bundle(withdrawals.clone(), max_weight)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bundle, two);
+        let bundle = build_withdrawal_bundle(withdrawals.clone(), max_weight - 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bundle.spent_utxos.len(), 1);
+        // Fits without the treasury input and output, but the M6 doesn't.
+        let one = build_withdrawal_bundle(withdrawals[..1].to_vec(), MAX_BUNDLE_WEIGHT)
+            .unwrap()
+            .unwrap();
+        assert!(
+            build_withdrawal_bundle(withdrawals, one.transaction.weight())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn m6_pays_out_bundle_from_treasury() {
+        let withdrawals = vec![
+            withdrawal(0, main_address(0), 1000, 10),
+            withdrawal(1, main_address(1), 2000, 30),
+        ];
+        let bundle = build_withdrawal_bundle(withdrawals, MAX_BUNDLE_WEIGHT)
+            .unwrap()
+            .unwrap();
+        let treasury = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_inner([7; 32]),
+            vout: 1,
+        };
+        let m6 = bundle.to_m6(treasury, 10_000, 3).unwrap();
+        assert_eq!(blinded_m6_hash(&m6), bundle.hash());
+        assert_eq!(m6.input.len(), 1);
+        assert_eq!(m6.input[0].previous_output, treasury);
+        assert_eq!(m6.output[0].script_pubkey, treasury_script(3));
+        assert_eq!(m6.output[0].value, 10_000 - 3000 - 40);
+        assert_eq!(m6.output[1..], bundle.transaction.output[..]);
+        assert!(matches!(
+            bundle.to_m6(treasury, 3039, 3),
+            Err(BundleError::InsufficientTreasury {
+                treasury_value: 3039,
+                required: 3040,
+            })
+        ));
+    }
+}